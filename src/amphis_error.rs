@@ -0,0 +1,109 @@
+use std::io::ErrorKind;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CrudError {
+    #[error("no database {0}: neither the leaf directory nor the table directory exists")]
+    NotFound(String),
+    /// Stored data is broken, e.g. a CRC mismatch
+    #[error("data corruption: {0}")]
+    Corruption(String),
+    #[error("serialization failed: {0}")]
+    Serialization(String),
+    #[error("invalid config: {0}")]
+    InvalidConfig(#[from] ConfigError),
+    /// The request can't be applied, e.g. an empty key
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    /// The key is longer than `max_key_size` or can't fit in a leaf page
+    #[error("too large key: {size} bytes, the limit is {limit} bytes")]
+    KeyTooLarge { size: usize, limit: usize },
+    /// The value is longer than `max_value_size` or too many overflow pages for the key
+    #[error("too large value: {size} bytes, the limit is {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+    /// Another instance has opened the database
+    #[error("database {0} has been already opened")]
+    AlreadyOpen(String),
+    /// Level 0 had too many tables until the write stall timed out
+    #[error("writes have stalled since Level 0 has {0} tables")]
+    WriteStall(usize),
+    /// An operation with a timeout didn't finish in time
+    #[error("the operation timed out")]
+    TimedOut,
+    /// The version of the key at the sequence has been overwritten or compacted away
+    #[error("the version at sequence {0} is unavailable")]
+    VersionUnavailable(u64),
+    /// The error which `amphis::server::Server` returned to the client
+    #[error("server error: {0}")]
+    Remote(String),
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for CrudError {
+    fn from(e: std::io::Error) -> Self {
+        if let Some(inner) = e.get_ref() {
+            if let Some(corruption) = inner.downcast_ref::<CorruptionError>() {
+                return CrudError::Corruption(corruption.0.clone());
+            }
+            if let Some(serialization) = inner.downcast_ref::<bincode::ErrorKind>() {
+                return CrudError::Serialization(serialization.to_string());
+            }
+        }
+        match e.kind() {
+            ErrorKind::InvalidInput => CrudError::InvalidInput(e.to_string()),
+            ErrorKind::TimedOut => CrudError::TimedOut,
+            _ => CrudError::Io(e),
+        }
+    }
+}
+
+impl From<bincode::Error> for CrudError {
+    fn from(e: bincode::Error) -> Self {
+        CrudError::Serialization(e.to_string())
+    }
+}
+
+/// Stored data is broken
+/// This is returned as the inner error of `std::io::Error` with `ErrorKind::InvalidData`
+/// and converted to `CrudError::Corruption`
+#[derive(Debug, Error)]
+#[error("data corruption: {0}")]
+pub struct CorruptionError(pub String);
+
+impl From<CorruptionError> for std::io::Error {
+    fn from(e: CorruptionError) -> Self {
+        std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("invalid config value of {field}: {reason}")]
+    InvalidValue { field: &'static str, reason: String },
+    #[error("failed to load the config: {0}")]
+    Load(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io_error() {
+        let e: std::io::Error = CorruptionError("broken".to_owned()).into();
+        assert!(matches!(CrudError::from(e), CrudError::Corruption(m) if m == "broken"));
+
+        let e = std::io::Error::other(*bincode::deserialize::<String>(&[1]).unwrap_err());
+        assert!(matches!(CrudError::from(e), CrudError::Serialization(_)));
+
+        let e = std::io::Error::new(ErrorKind::InvalidInput, "too large");
+        assert!(matches!(CrudError::from(e), CrudError::InvalidInput(m) if m == "too large"));
+
+        let e = std::io::Error::new(ErrorKind::TimedOut, "the deadline has passed");
+        assert!(matches!(CrudError::from(e), CrudError::TimedOut));
+
+        let e = std::io::Error::new(ErrorKind::NotFound, "no file");
+        assert!(matches!(CrudError::from(e), CrudError::Io(e) if e.kind() == ErrorKind::NotFound));
+    }
+}
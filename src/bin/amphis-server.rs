@@ -0,0 +1,25 @@
+//! Standalone server of a KVS
+//! Usage: `amphis-server [<address> [<name>]]`, e.g. `amphis-server 127.0.0.1:7070 amphis`
+//! The KVS is opened with `config.toml` in the current directory
+use amphis::config::Config;
+use amphis::kvs::KVS;
+use amphis::server::Server;
+use log::info;
+use std::sync::Arc;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7070";
+const DEFAULT_NAME: &str = "amphis";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    let address = args.next().unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let name = args.next().unwrap_or_else(|| DEFAULT_NAME.to_string());
+
+    let kvs = Arc::new(KVS::new(&name, Config::new()?)?);
+    let num_threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let server = Server::bind(&address, kvs, num_threads)?;
+    info!("Serving {} on {}", name, server.local_addr()?);
+
+    Ok(server.serve()?)
+}
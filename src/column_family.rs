@@ -0,0 +1,779 @@
+use bytes::Bytes;
+use crossbeam_channel::Sender;
+use log::{debug, info, trace};
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::amphis_error::CrudError;
+use crate::compaction_worker::CompactionSignal;
+use crate::config::Config;
+use crate::entry::Modifier;
+use crate::flush_writer::{self, FlushSignal, FlushWriter};
+use crate::fptree_manager::{FPTreeManager, LockedFPTrees};
+use crate::ingest::{IngestLevel, IngestOptions};
+use crate::jsonl;
+use crate::kvs::{Iter, Scan, Snapshot, Stats, WriteBatch};
+use crate::read_cache::{Cached, ReadCache};
+use crate::scan;
+use crate::sstable_manager::SstableManager;
+use crate::util::data_util::{self, VersionAt};
+use crate::util::file_util;
+use crate::util::lock_util::MutexExt;
+use crate::wal;
+
+pub(crate) type CfId = usize;
+/// All opened column families indexed by `CfId`
+pub(crate) type ColumnFamilies = Arc<RwLock<Vec<Arc<ColumnFamily>>>>;
+
+/// The number of pairs put at once by `import_jsonl`
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+/// A keyspace which has its own FPTrees and SSTables
+///
+/// All column families of a KVS share the config, the flush writer thread and
+/// the compaction worker thread.
+pub struct ColumnFamily {
+    id: CfId,
+    name: String,
+    fptree_manager: Arc<FPTreeManager>,
+    sstable_manager: Arc<SstableManager>,
+    flush_writer: Mutex<FlushWriter>,
+    flush_count: Arc<AtomicU64>,
+    /// Lookup results of hot keys, which are invalidated by writes
+    read_cache: ReadCache,
+    /// The current FPTree is reopened instead of being flushed on restart
+    recover_fptree: bool,
+    /// The FPTree is never flushed to SSTables
+    in_memory: bool,
+    sender: Sender<FlushSignal>,
+    compaction_sender: Sender<CompactionSignal>,
+}
+
+impl ColumnFamily {
+    /// Open the column family stored with `name` and flush the existing trees
+    /// With `recover_fptree`, the last tree is reopened instead of being flushed
+    /// `sequence` is raised to the largest sequence stored in the column family
+    pub(crate) fn open(
+        id: CfId,
+        name: &str,
+        config: Config,
+        sequence: Arc<AtomicU64>,
+        sender: Sender<FlushSignal>,
+        compaction_sender: Sender<CompactionSignal>,
+    ) -> Result<Self, CrudError> {
+        let path = config.get_leaf_dir_path(name);
+
+        let (sstable_manager, next_table_id) = SstableManager::new(name, config.clone())?;
+        let sstable_manager = Arc::new(sstable_manager);
+
+        let mut flush_writer = FlushWriter::new(name, config.clone(), next_table_id);
+        let recover_fptree = config.get_recover_fptree();
+        let in_memory = config.get_in_memory();
+        let mut has_recovered = false;
+        let mut fptree_manager = None;
+        let mut reopened_id = None;
+        if !in_memory && Path::new(&path).exists() {
+            let mut fptree_ids = Vec::new();
+            for entry in std::fs::read_dir(&path)? {
+                let entry_path = entry?.path();
+                // a WAL remains even if the leaf file has been lost
+                if let Some(fptree_id) = file_util::get_tree_id(&entry_path)
+                    .or_else(|| file_util::get_wal_tree_id(&entry_path))
+                {
+                    debug!("found FPTree ID: {}", fptree_id);
+                    fptree_ids.push(fptree_id);
+                }
+            }
+            // older trees are flushed first since newer tables shadow them
+            fptree_ids.sort_unstable();
+            fptree_ids.dedup();
+
+            if recover_fptree {
+                if let Some(&fptree_id) = fptree_ids.last() {
+                    fptree_manager =
+                        FPTreeManager::recover(name, fptree_id, config.clone(), sequence.clone())?;
+                    if fptree_manager.is_some() {
+                        info!("FPTree {} of {} has been reopened", fptree_id, name);
+                        fptree_ids.pop();
+                        reopened_id = Some(fptree_id);
+                    }
+                }
+            }
+
+            // flush the exsting trees
+            for fptree_id in fptree_ids {
+                FPTreeManager::replay_wal(name, fptree_id, &config)?;
+                let table_info = flush_writer.flush_with_file(name, fptree_id)?;
+                sstable_manager.register(table_info)?;
+                has_recovered = true;
+                let leaf_file = config.get_leaf_file_path(name, fptree_id);
+                std::fs::remove_file(leaf_file)?;
+                wal::remove(&config.get_wal_file_path(name, fptree_id))?;
+            }
+
+            // remove files of the trees which have been already flushed
+            for entry in std::fs::read_dir(path)? {
+                let entry_path = entry?.path();
+                let range_tombstone_id = file_util::get_range_tombstone_tree_id(&entry_path);
+                if file_util::get_obsolete_tree_id(&entry_path).is_some()
+                    || (range_tombstone_id.is_some() && range_tombstone_id != reopened_id)
+                {
+                    std::fs::remove_file(entry_path)?;
+                }
+            }
+        }
+
+        let read_cache = ReadCache::new(config.get_read_cache_bytes());
+        // the flushed trees are in the tables
+        sequence.fetch_max(sstable_manager.get_max_sequence(), Ordering::AcqRel);
+        let fptree_manager = match fptree_manager {
+            Some(fptree_manager) => fptree_manager,
+            None => FPTreeManager::new(name, config, sequence)?,
+        };
+        let fptree_manager = Arc::new(fptree_manager);
+        if has_recovered {
+            let _ = compaction_sender.send(CompactionSignal::MaybeCompact(id));
+        }
+
+        info!("Column family {} has been opened", name);
+        Ok(ColumnFamily {
+            id,
+            name: name.to_string(),
+            fptree_manager,
+            sstable_manager,
+            flush_count: flush_writer.get_flush_count(),
+            flush_writer: Mutex::new(flush_writer),
+            read_cache,
+            recover_fptree,
+            in_memory,
+            sender,
+            compaction_sender,
+        })
+    }
+
+    pub(crate) fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Flush the current FPTree if needed or `force` is set
+    /// This is called by the flush writer thread
+    pub(crate) fn flush_fptree(&self, force: bool) -> Result<(), CrudError> {
+        if self.in_memory {
+            return Ok(());
+        }
+
+        let mut flush_writer = self.flush_writer.lock_or_recover();
+        let flushed = flush_writer::flush_fptree(
+            &mut flush_writer,
+            &self.fptree_manager,
+            &self.sstable_manager,
+            force,
+        )?;
+        if flushed {
+            let _ = self
+                .compaction_sender
+                .send(CompactionSignal::MaybeCompact(self.id));
+        }
+
+        Ok(())
+    }
+
+    /// Flush the current FPTree before shutting down
+    /// The FPTree is kept when it will be reopened on restart
+    /// Otherwise, the files of the new empty FPTree are removed not to be flushed on restart
+    pub(crate) fn flush_on_shutdown(&self) -> Result<(), CrudError> {
+        if self.recover_fptree || self.in_memory {
+            return Ok(());
+        }
+
+        self.flush_fptree(true)?;
+        if self.fptree_manager.discard_empty_fptree()? {
+            debug!("Removed the empty FPTree of {} on shutdown", self.name);
+        }
+
+        Ok(())
+    }
+
+    /// Write the sorted pairs to a new SSTable without the FPTree
+    /// The current FPTree is flushed first so that the ingested pairs overwrite older values
+    /// Nothing is ingested if `pairs` returns an error
+    pub(crate) fn ingest_sorted(
+        &self,
+        pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), CrudError>>,
+        options: &IngestOptions,
+    ) -> Result<(), CrudError> {
+        self.check_on_disk("ingesting pairs")?;
+        self.flush()?;
+
+        let sequence = self.fptree_manager.next_sequence();
+        let mut flush_writer = self.flush_writer.lock_or_recover();
+        if let Some(table_info) = flush_writer.write_sorted(pairs, sequence)? {
+            debug!(
+                "Ingested {} pairs to SSTable ID {}",
+                table_info.entry_count, table_info.id
+            );
+            match options.level {
+                IngestLevel::Top => self.sstable_manager.register(table_info)?,
+                IngestLevel::Bottom => self
+                    .sstable_manager
+                    .register_to_bottom(table_info, options.check_overlap)?,
+            }
+            self.read_cache.clear();
+            let _ = self
+                .compaction_sender
+                .send(CompactionSignal::MaybeCompact(self.id));
+        }
+
+        Ok(())
+    }
+
+    /// Flush the current FPTree, and then link the current SSTables into `dir`
+    pub(crate) fn checkpoint(&self, dir: &Path) -> Result<(), CrudError> {
+        self.check_on_disk("checkpointing")?;
+        self.flush()?;
+        debug!("Checkpointing {} to {:?}", self.name, dir);
+
+        self.sstable_manager.checkpoint(dir)
+    }
+
+    /// Return `CrudError::InvalidInput` for the operation writing SSTables in memory
+    fn check_on_disk(&self, operation: &str) -> Result<(), CrudError> {
+        if self.in_memory {
+            return Err(CrudError::InvalidInput(format!(
+                "{} isn't supported in memory",
+                operation
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Compact SSTables if some levels exceed their limits
+    /// This is called by the compaction worker thread
+    pub(crate) fn try_compact(&self) -> Result<(), CrudError> {
+        if self.sstable_manager.compact()? {
+            // cached values may have been dropped by the compaction filter
+            self.read_cache.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Merge all SSTables and block until the merged tables are persisted
+    pub(crate) fn compact(&self) -> Result<(), CrudError> {
+        if self.sstable_manager.compact_all()? {
+            self.read_cache.clear();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        trace!(
+            "Put K: {}, V: {}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(value)
+        );
+
+        self.put_encoded(key, &data_util::encode_value(value, None))
+    }
+
+    /// Put the key-value pair which expires after `ttl`
+    pub(crate) fn put_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), CrudError> {
+        trace!("Put K: {} with TTL {:?}", String::from_utf8_lossy(key), ttl);
+
+        // a TTL too long to be represented never expires
+        let ttl_millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expiry = data_util::current_millis().saturating_add(ttl_millis);
+        self.put_encoded(key, &data_util::encode_value(value, Some(expiry)))
+    }
+
+    fn put_encoded(&self, key: &[u8], encoded: &[u8]) -> Result<(), CrudError> {
+        self.fptree_manager.check_entry(key, encoded)?;
+        self.sstable_manager.wait_for_l0_compaction()?;
+        self.fptree_manager.put(key, encoded)?;
+        self.read_cache.invalidate(key);
+
+        if self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(())
+    }
+
+    /// Apply all mutations in the batch atomically
+    /// Readers see either all of them or none of them
+    pub(crate) fn write(&self, batch: WriteBatch) -> Result<(), CrudError> {
+        trace!("Writing a batch of {} entries", batch.len());
+
+        self.sstable_manager.wait_for_l0_compaction()?;
+        self.fptree_manager.put_batch(batch.entries())?;
+        for (key, _) in batch.entries() {
+            self.read_cache.invalidate(key);
+        }
+
+        if self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(())
+    }
+
+    /// Flush the current FPTree to a new SSTable
+    /// This blocks until the SSTable is persisted and registered
+    pub(crate) fn flush(&self) -> Result<(), CrudError> {
+        debug!("Flushing the current FPTree");
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.sender
+            .send(FlushSignal::Flush(self.id, tx))
+            .map_err(|_| std::io::Error::other("the flush writer has stopped"))?;
+
+        rx.recv()
+            .map_err(|_| std::io::Error::other("the flush writer has stopped"))?
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        trace!("Getting from K: {}", String::from_utf8_lossy(key));
+
+        self.get_until(key, None)
+    }
+
+    /// Get the value, or give up with `CrudError::TimedOut` when the lookup takes the timeout
+    pub(crate) fn get_timeout(
+        &self,
+        key: &[u8],
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        trace!(
+            "Getting from K: {} in {:?}",
+            String::from_utf8_lossy(key),
+            timeout
+        );
+
+        self.get_until(key, Instant::now().checked_add(timeout))
+    }
+
+    fn get_until(
+        &self,
+        key: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        match self.get_encoded(key, deadline)? {
+            Some(v) => {
+                Ok(data_util::get_live_value(&v, data_util::current_millis())?.map(|v| v.to_vec()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value which the key had when the write of `sequence` was applied
+    /// Return `CrudError::VersionUnavailable` if the version has been overwritten in the same
+    /// FPTree or a compaction, or a range tombstone covers the key
+    pub(crate) fn get_at(&self, key: &[u8], sequence: u64) -> Result<Option<Vec<u8>>, CrudError> {
+        trace!(
+            "Getting from K: {} at sequence {}",
+            String::from_utf8_lossy(key),
+            sequence
+        );
+
+        let version = match self.fptree_manager.get_at(key, sequence)? {
+            VersionAt::NotFound => self.sstable_manager.get_at(key, sequence)?,
+            version => version,
+        };
+        match version {
+            VersionAt::Found(v) => {
+                Ok(data_util::get_live_value(&v, data_util::current_millis())?.map(|v| v.to_vec()))
+            }
+            VersionAt::NotFound => Ok(None),
+            VersionAt::Unavailable => Err(CrudError::VersionUnavailable(sequence)),
+        }
+    }
+
+    /// Get the value as a slice of the encoded value copied from the FPTree or an SSTable
+    pub(crate) fn get_bytes(&self, key: &[u8]) -> Result<Option<Bytes>, CrudError> {
+        trace!("Getting bytes from K: {}", String::from_utf8_lossy(key));
+
+        match self.get_encoded(key, None)? {
+            Some(v) => {
+                let v = Bytes::from(v);
+                let live = data_util::get_live_value(&v, data_util::current_millis())?;
+                Ok(live.map(|live| v.slice_ref(live)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the encoded value or the tombstone of the key
+    /// A cached result is returned without reading the FPTrees and the tables
+    /// The deadline is checked before locking the FPTrees and the tables, and before reading
+    /// each table
+    fn get_encoded(
+        &self,
+        key: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        let generation = match self.read_cache.get(key) {
+            Cached::Hit(value) => return Ok(value),
+            Cached::Miss(generation) => generation,
+        };
+
+        data_util::check_deadline(deadline)?;
+        let value = match self.fptree_manager.get(key)? {
+            Some(r) => Some(r),
+            None => self.sstable_manager.get_until(key, deadline)?,
+        };
+        self.read_cache.insert(key, value.as_deref(), generation);
+
+        Ok(value)
+    }
+
+    /// Whether the key may exist without reading SSTable files
+    /// The FPTrees answer exactly, and SSTables answer by their bloom filters
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        match self.fptree_manager.get(key) {
+            Ok(Some(v)) => {
+                // a tombstone or an expired value is the newest version
+                matches!(
+                    data_util::get_live_value(&v, data_util::current_millis()),
+                    Ok(Some(_)) | Err(_)
+                )
+            }
+            Ok(None) => self.sstable_manager.may_contain(key),
+            // the key can't be excluded
+            Err(_) => true,
+        }
+    }
+
+    /// Get values of multiple keys in the same order as `keys`
+    pub(crate) fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, CrudError> {
+        trace!("Getting {} keys", keys.len());
+
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|a, b| keys[*a].cmp(&keys[*b]));
+        let sorted_keys: Vec<&[u8]> = order.iter().map(|i| keys[*i].as_slice()).collect();
+
+        let mut sorted_results = self.fptree_manager.get_many(&sorted_keys)?;
+        self.sstable_manager
+            .get_many(&sorted_keys, &mut sorted_results)?;
+
+        let now = data_util::current_millis();
+        let mut results = vec![None; keys.len()];
+        for (i, result) in order.into_iter().zip(sorted_results) {
+            if let Some(v) = result {
+                results[i] = data_util::get_live_value(&v, now)?.map(|v| v.to_vec());
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Return an iterator over key-value pairs in `[start, end)` in the key order
+    pub(crate) fn scan(&self, start: &[u8], end: &[u8]) -> Result<Scan, CrudError> {
+        trace!(
+            "Scanning from K: {} to K: {}",
+            String::from_utf8_lossy(start),
+            String::from_utf8_lossy(end)
+        );
+
+        self.scan_range(start, Some(end))
+    }
+
+    /// Return an iterator over key-value pairs whose keys start with `prefix`
+    pub(crate) fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan, CrudError> {
+        trace!("Scanning with prefix: {:?}", prefix);
+
+        self.scan_range(prefix, scan::prefix_end(prefix).as_deref())
+    }
+
+    /// Return an iterator over all live key-value pairs in the key order
+    pub(crate) fn iter(&self) -> Result<Iter, CrudError> {
+        trace!("Iterating all pairs");
+
+        self.scan_range(&[], None)
+    }
+
+    /// Write all live key-value pairs as JSON Lines in the key order
+    pub(crate) fn export_jsonl(&self, w: &mut dyn Write) -> Result<(), CrudError> {
+        jsonl::export(self.iter()?, w)
+    }
+
+    /// Write each pair in `[start, end)` as its length-prefixed key and value
+    /// Pairs are read one by one, so a slow writer just pauses the scan
+    pub(crate) fn scan_to_writer(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        w: &mut dyn Write,
+    ) -> Result<u64, CrudError> {
+        let mut count = 0;
+        for pair in self.scan(start, end)? {
+            let (key, value) = pair?;
+            w.write_all(&(key.len() as u32).to_le_bytes())?;
+            w.write_all(&key)?;
+            w.write_all(&(value.len() as u32).to_le_bytes())?;
+            w.write_all(&value)?;
+            count += 1;
+        }
+        w.flush()?;
+
+        Ok(count)
+    }
+
+    /// Put all key-value pairs in JSON Lines by batches
+    /// Pairs in the batches written before an error remain
+    pub(crate) fn import_jsonl(&self, r: &mut dyn BufRead) -> Result<(), CrudError> {
+        let mut batch = WriteBatch::new();
+        for pair in jsonl::import(r) {
+            let (key, value) = pair?;
+            batch.put(&key, &value);
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.write(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            self.write(batch)?;
+        }
+
+        Ok(())
+    }
+
+    fn scan_range(&self, start: &[u8], end: Option<&[u8]>) -> Result<Scan, CrudError> {
+        // FPTrees should be read before SSTables not to miss flushed data
+        let mut sources = self.fptree_manager.range(start, end)?;
+        sources.extend(self.sstable_manager.scan(start, end)?);
+
+        Ok(Scan::new(sources, start, end, data_util::current_millis()))
+    }
+
+    /// Return the approximate number of keys
+    /// Overwritten keys and tombstones which haven't been compacted are also counted
+    pub(crate) fn approximate_len(&self) -> usize {
+        self.fptree_manager.approximate_len() + self.sstable_manager.approximate_len()
+    }
+
+    /// Return the total size of leaf files and SSTable files in bytes
+    pub(crate) fn size_on_disk(&self) -> Result<u64, CrudError> {
+        Ok(self.fptree_manager.size_on_disk()? + self.sstable_manager.size_on_disk()?)
+    }
+
+    pub(crate) fn level_summary(&self) -> Vec<(usize, usize, u64)> {
+        self.sstable_manager.level_summary()
+    }
+
+    pub(crate) fn space_amplification(&self) -> f64 {
+        self.sstable_manager.space_amplification()
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            flush_count: self.flush_count.load(Ordering::Relaxed),
+            root_split_count: self.fptree_manager.get_root_split_count(),
+            read_cache_hits: self.read_cache.get_hits(),
+            ..self.sstable_manager.stats()
+        }
+    }
+
+    pub(crate) fn debug_dump(&self, w: &mut dyn Write) -> Result<(), CrudError> {
+        writeln!(w, "column family {}", self.name)?;
+        self.fptree_manager.debug_dump(w)?;
+        writeln!(w, "SSTables:")?;
+        self.sstable_manager.debug_dump(w)
+    }
+
+    /// Return a read-only view of the current state
+    /// Writes after this call are invisible in the snapshot
+    pub(crate) fn snapshot(&self) -> Result<Snapshot, CrudError> {
+        // SSTables should be captured before the FPTree switch
+        let (fptrees, tables) = self
+            .fptree_manager
+            .freeze(|| self.sstable_manager.get_tables())?;
+
+        Ok(Snapshot::new(fptrees, tables, self.sstable_manager.clone()))
+    }
+
+    /// Set `new` only when the current value is `expected`
+    /// `None` as `expected` means that the key doesn't exist, and `None` as `new` deletes the key
+    /// Return whether the value has been swapped
+    pub(crate) fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, CrudError> {
+        trace!("Compare-and-swap K: {}", String::from_utf8_lossy(key));
+
+        // an empty value is just a tombstone
+        let encoded = new
+            .map(|v| data_util::encode_value(v, None))
+            .unwrap_or_default();
+        self.fptree_manager.check_entry(key, &encoded)?;
+
+        self.sstable_manager.wait_for_l0_compaction()?;
+        let swapped = self.fptree_manager.write_exclusively(|fptrees| {
+            let current = self.get_locked(fptrees, key)?;
+            if current.as_deref() != expected {
+                return Ok(false);
+            }
+
+            fptrees.put(key, &encoded)?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.read_cache.invalidate(key);
+        }
+
+        if swapped && self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(swapped)
+    }
+
+    /// Put the key-value pair and return the previous value
+    pub(crate) fn replace(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        trace!("Replace K: {}", String::from_utf8_lossy(key));
+
+        let encoded = data_util::encode_value(value, None);
+        self.fptree_manager.check_entry(key, &encoded)?;
+
+        self.sstable_manager.wait_for_l0_compaction()?;
+        let previous = self.fptree_manager.write_exclusively(|fptrees| {
+            let previous = self.get_locked(fptrees, key)?;
+            fptrees.put(key, &encoded)?;
+            Ok(previous)
+        })?;
+        self.read_cache.invalidate(key);
+
+        if self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(previous)
+    }
+
+    /// Modify the live value of the key by `modify`, or put `default()` when it doesn't exist
+    /// Return the value after the write, and the live value is kept without `modify`
+    pub(crate) fn upsert(
+        &self,
+        key: &[u8],
+        modify: Option<Modifier<'_>>,
+        default: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Vec<u8>, CrudError> {
+        trace!("Upsert K: {}", String::from_utf8_lossy(key));
+
+        self.sstable_manager.wait_for_l0_compaction()?;
+        let (value, written) = self.fptree_manager.write_exclusively(|fptrees| {
+            let value = match (self.get_locked(fptrees, key)?, modify) {
+                (Some(mut value), Some(modify)) => {
+                    modify(&mut value);
+                    value
+                }
+                (Some(value), None) => return Ok((value, false)),
+                (None, _) => default(),
+            };
+
+            let encoded = data_util::encode_value(&value, None);
+            self.fptree_manager.check_entry(key, &encoded)?;
+            fptrees.put(key, &encoded)?;
+            Ok((value, true))
+        })?;
+        if written {
+            self.read_cache.invalidate(key);
+        }
+
+        if written && self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(value)
+    }
+
+    /// Delete the key and return whether a live value existed
+    pub(crate) fn remove(&self, key: &[u8]) -> Result<bool, CrudError> {
+        trace!("Remove K: {}", String::from_utf8_lossy(key));
+
+        self.fptree_manager.check_entry(key, &[])?;
+        self.sstable_manager.wait_for_l0_compaction()?;
+        let existed = self.fptree_manager.write_exclusively(|fptrees| {
+            if self.get_locked(fptrees, key)?.is_none() {
+                return Ok(false);
+            }
+            // just add a tombstone
+            fptrees.put(key, &[])?;
+            Ok(true)
+        })?;
+        if existed {
+            self.read_cache.invalidate(key);
+        }
+
+        if existed && self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(existed)
+    }
+
+    /// Get the live value while the FPTrees are locked by `write_exclusively`
+    fn get_locked(
+        &self,
+        fptrees: &LockedFPTrees,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        let current = match fptrees.get(key)? {
+            Some(v) => Some(v),
+            None => self.sstable_manager.get(key)?,
+        };
+
+        match current {
+            Some(v) => {
+                Ok(data_util::get_live_value(&v, data_util::current_millis())?.map(|v| v.to_vec()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Delete all keys in `[start, end)`
+    pub(crate) fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), CrudError> {
+        trace!(
+            "Deleting from K: {} to K: {}",
+            String::from_utf8_lossy(start),
+            String::from_utf8_lossy(end)
+        );
+        if start >= end {
+            return Ok(());
+        }
+
+        self.sstable_manager.wait_for_l0_compaction()?;
+        self.fptree_manager.delete_range(start, end)?;
+        self.read_cache.clear();
+
+        if self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> Result<(), CrudError> {
+        trace!("Deleting from K: {}", String::from_utf8_lossy(key));
+
+        self.sstable_manager.wait_for_l0_compaction()?;
+        self.fptree_manager.delete(key)?;
+        self.read_cache.invalidate(key);
+
+        if self.fptree_manager.need_flush() {
+            let _ = self.sender.send(FlushSignal::TryFlush(self.id));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+/// What a compaction does with a key-value pair
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// The pair is removed like a deleted one
+    /// It's replaced with a tombstone unless the output is in the bottom level, so older
+    /// values of the key in deeper levels don't appear again
+    Drop,
+}
+
+/// Policy applied to each live pair merged by compactions, e.g. custom retention rules
+///
+/// `decide` is invoked on the compaction worker thread for the newest value of each key,
+/// and tombstones and expired values aren't passed. A pair stays until its table is
+/// compacted, so a lookup can still read a pair which would be dropped.
+pub trait CompactionFilter: Send + Sync {
+    /// Decide whether the pair is kept in the output of the compaction into `level`
+    fn decide(&self, key: &[u8], value: &[u8], level: usize) -> FilterDecision;
+}
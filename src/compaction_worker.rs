@@ -0,0 +1,33 @@
+use crossbeam_channel::Receiver;
+use log::error;
+use std::thread::{self, JoinHandle};
+
+use crate::column_family::{CfId, ColumnFamilies};
+use crate::util::lock_util::RwLockExt;
+
+#[derive(Debug, Clone)]
+pub enum CompactionSignal {
+    /// Compact SSTables of the column family if some levels exceed their limits
+    MaybeCompact(CfId),
+    /// Stop the thread
+    Shutdown,
+}
+
+pub fn spawn_compaction_worker(
+    receiver: Receiver<CompactionSignal>,
+    column_families: ColumnFamilies,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let get_cf = |id: CfId| column_families.read_or_recover()[id].clone();
+        for signal in receiver {
+            match signal {
+                CompactionSignal::MaybeCompact(id) => {
+                    if let Err(e) = get_cf(id).try_compact() {
+                        error!("Compaction failed: {}", e);
+                    }
+                }
+                CompactionSignal::Shutdown => break,
+            }
+        }
+    })
+}
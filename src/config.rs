@@ -1,13 +1,97 @@
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::amphis_error::ConfigError;
+use crate::compaction_filter::CompactionFilter;
+use crate::fault::FaultInjector;
+use crate::fptree::leaf_manager::{
+    get_max_data_size, get_max_value_size, validate_data_alignment, validate_fingerprint_bits,
+    validate_leaf_size, validate_num_slot, DEFAULT_ALLOCATION_BYTES, DEFAULT_FINGERPRINT_BITS,
+    DEFAULT_LEAF_SIZE, DEFAULT_MMAP_CACHE_PAGES, DEFAULT_NUM_SLOT,
+};
+use crate::listener::Listener;
+use crate::rate_limiter::RateLimiter;
+use crate::sstable_manager::{DEFAULT_BLOCK_CACHE_BYTES, DEFAULT_BLOCK_SIZE};
+use crate::storage::{FsStorage, Storage};
+use crate::util::data_util::{self, MAX_VALUE_HEADER_SIZE};
 
 const CONFIG_FILE: &str = "config.toml";
+const ENV_PREFIX: &str = "AMPHIS";
+// (environment variable name without the prefix, config key)
+const ENV_KEYS: [(&str, &str); 37] = [
+    ("leaf_dir", "directories.leaf_dir"),
+    ("table_dir", "directories.table_dir"),
+    ("root_split_threshold", "fp_tree.root_split_threshold"),
+    ("memtable_bytes", "fp_tree.memtable_bytes"),
+    ("num_slot", "fp_tree.num_slot"),
+    ("leaf_size", "fp_tree.leaf_size"),
+    ("data_alignment", "fp_tree.data_alignment"),
+    ("leaf_allocation", "fp_tree.leaf_allocation"),
+    ("recover_fptree", "fp_tree.recover_fptree"),
+    ("in_memory", "fp_tree.in_memory"),
+    ("durability", "fp_tree.durability"),
+    ("durability_interval_ms", "fp_tree.durability_interval_ms"),
+    ("mmap_cache_pages", "fp_tree.mmap_cache_pages"),
+    ("fingerprint_bits", "fp_tree.fingerprint_bits"),
+    ("fingerprint_hash", "fp_tree.fingerprint_hash"),
+    ("max_key_size", "fp_tree.max_key_size"),
+    ("max_value_size", "fp_tree.max_value_size"),
+    ("bloom_items_count", "bloom_filter.items_count"),
+    ("bloom_fp_rate", "bloom_filter.fp_rate"),
+    ("block_size", "sstable.block_size"),
+    ("compression", "sstable.compression"),
+    ("block_cache_bytes", "sstable.block_cache_bytes"),
+    ("parallel_lookup", "sstable.parallel_lookup"),
+    ("verify_tables", "sstable.verify_tables"),
+    ("flush_parallelism", "sstable.flush_parallelism"),
+    ("l0_compaction_trigger", "compaction.l0_compaction_trigger"),
+    ("level_base_bytes", "compaction.level_base_bytes"),
+    ("level_multiplier", "compaction.level_multiplier"),
+    ("target_table_bytes", "compaction.target_table_bytes"),
+    ("max_l0_tables", "compaction.max_l0_tables"),
+    (
+        "write_stall_timeout_ms",
+        "compaction.write_stall_timeout_ms",
+    ),
+    (
+        "max_space_amplification",
+        "compaction.max_space_amplification",
+    ),
+    ("compaction_bytes_per_sec", "compaction.bytes_per_sec"),
+    ("wal_sync", "wal.sync"),
+    ("wal_sync_interval_ms", "wal.sync_interval_ms"),
+    ("checksum", "checksum.algorithm"),
+    ("read_cache_bytes", "read_cache.bytes"),
+];
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     directories: Directories,
     fp_tree: FpTree,
     bloom_filter: BloomFilter,
+    #[serde(default)]
+    sstable: Sstable,
+    #[serde(default)]
+    compaction: Compaction,
+    #[serde(default)]
+    wal: Wal,
+    #[serde(default)]
+    checksum: ChecksumConfig,
+    #[serde(default)]
+    read_cache: ReadCache,
+    #[serde(skip)]
+    listeners: Vec<Arc<dyn Listener>>,
+    #[serde(skip)]
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    #[serde(skip, default = "default_storage")]
+    storage: Arc<dyn Storage>,
+    #[serde(skip)]
+    fault_injector: Arc<FaultInjector>,
+    #[serde(skip)]
+    rate_limiter: Arc<RateLimiter>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -19,12 +103,255 @@ struct Directories {
 #[derive(Clone, Serialize, Deserialize)]
 struct FpTree {
     root_split_threshold: usize,
+    #[serde(default)]
+    memtable_bytes: Option<usize>,
+    #[serde(default = "default_num_slot")]
+    num_slot: usize,
+    #[serde(default = "default_leaf_size")]
+    leaf_size: usize,
+    #[serde(default = "default_data_alignment")]
+    data_alignment: usize,
+    #[serde(default)]
+    leaf_allocation: Option<usize>,
+    #[serde(default)]
+    recover_fptree: bool,
+    #[serde(default)]
+    in_memory: bool,
+    #[serde(default)]
+    durability: DurabilityMode,
+    #[serde(default = "default_durability_interval_ms")]
+    durability_interval_ms: u64,
+    #[serde(default = "default_mmap_cache_pages")]
+    mmap_cache_pages: usize,
+    #[serde(default = "default_fingerprint_bits")]
+    fingerprint_bits: usize,
+    #[serde(default)]
+    fingerprint_hash: FingerprintHash,
+    #[serde(default)]
+    max_key_size: Option<usize>,
+    #[serde(default)]
+    max_value_size: Option<usize>,
+}
+
+fn default_storage() -> Arc<dyn Storage> {
+    Arc::new(FsStorage)
+}
+
+fn default_num_slot() -> usize {
+    DEFAULT_NUM_SLOT
+}
+
+fn default_leaf_size() -> usize {
+    DEFAULT_LEAF_SIZE
+}
+
+fn default_data_alignment() -> usize {
+    data_util::DEFAULT_DATA_ALIGNMENT
+}
+
+fn default_durability_interval_ms() -> u64 {
+    100
+}
+
+fn default_mmap_cache_pages() -> usize {
+    DEFAULT_MMAP_CACHE_PAGES
+}
+
+fn default_fingerprint_bits() -> usize {
+    DEFAULT_FINGERPRINT_BITS
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DurabilityMode {
+    #[default]
+    PerWrite,
+    Batched,
+    OnFlushOnly,
+}
+
+/// When writes to leaves are synced to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync each write before it returns
+    /// No write is lost by a crash
+    PerWrite,
+    /// Sync the leaf file at a write when this time has passed since the last sync
+    /// No timer syncs it, so the writes after the last sync stay unsynced until the next write
+    /// They can be lost by an OS crash or a power failure, however long the writes stay idle
+    Batched(Duration),
+    /// Sync only when the FPTree is flushed to an SSTable or closed
+    /// All writes to the current FPTree can be lost by an OS crash or a power failure
+    OnFlushOnly,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Sstable {
+    block_size: usize,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default = "default_block_cache_bytes")]
+    block_cache_bytes: usize,
+    #[serde(default)]
+    parallel_lookup: bool,
+    #[serde(default)]
+    verify_tables: bool,
+    #[serde(default = "default_flush_parallelism")]
+    flush_parallelism: usize,
+}
+
+fn default_block_cache_bytes() -> usize {
+    DEFAULT_BLOCK_CACHE_BYTES
+}
+
+fn default_flush_parallelism() -> usize {
+    1
+}
+
+impl Default for Sstable {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            compression: Compression::None,
+            block_cache_bytes: DEFAULT_BLOCK_CACHE_BYTES,
+            parallel_lookup: false,
+            verify_tables: false,
+            flush_parallelism: default_flush_parallelism(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Compaction {
+    l0_compaction_trigger: usize,
+    level_base_bytes: usize,
+    level_multiplier: usize,
+    target_table_bytes: usize,
+    #[serde(default)]
+    max_l0_tables: Option<usize>,
+    #[serde(default = "default_write_stall_timeout_ms")]
+    write_stall_timeout_ms: u64,
+    #[serde(default)]
+    max_space_amplification: Option<f64>,
+    #[serde(default)]
+    bytes_per_sec: Option<u64>,
+}
+
+fn default_write_stall_timeout_ms() -> u64 {
+    10_000
+}
+
+impl Default for Compaction {
+    fn default() -> Self {
+        Self {
+            l0_compaction_trigger: 4,
+            level_base_bytes: 16 * 1024 * 1024,
+            level_multiplier: 10,
+            target_table_bytes: 4 * 1024 * 1024,
+            max_l0_tables: None,
+            write_stall_timeout_ms: default_write_stall_timeout_ms(),
+            max_space_amplification: None,
+            bytes_per_sec: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Wal {
+    sync: WalSyncMode,
+    sync_interval_ms: u64,
+}
+
+impl Default for Wal {
+    fn default() -> Self {
+        Self {
+            sync: WalSyncMode::Never,
+            sync_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct ChecksumConfig {
+    #[serde(default)]
+    algorithm: Checksum,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct ReadCache {
+    #[serde(default)]
+    bytes: usize,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WalSyncMode {
+    Always,
+    Interval,
+    Never,
+}
+
+/// When the write-ahead log is synced to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalSync {
+    /// Sync after each write
+    Always,
+    /// Sync at a write when this number of milliseconds have passed since the last sync
+    Interval(u64),
+    /// Leave it to the OS
+    Never,
+}
+
+/// Codec to compress each data block of SSTables
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// Algorithm of checksums of key-value pairs in leaves and blocks of SSTables
+/// Each leaf file and SSTable records its algorithm, so files written with the other one are
+/// still verified
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Checksum {
+    /// CRC-32 (IEEE) computed in software, which existing files were written with
+    #[default]
+    Crc32,
+    /// CRC-32C (Castagnoli) computed by SSE 4.2 or ARMv8 instructions when available
+    Crc32c,
+}
+
+/// Hash function of fingerprints which skip reading keys of other slots in a leaf
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FingerprintHash {
+    /// SipHash of the standard library, which existing leaves were written with
+    #[default]
+    Sip,
+    /// XXH3, which is faster for long keys
+    XxHash,
+}
+
+/// Condition to flush the active FPTree
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushTrigger {
+    /// Flush when the root has been split this number of times
+    RootSplits(usize),
+    /// Flush when keys and values of this number of bytes have been written
+    Bytes(usize),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 struct BloomFilter {
     items_count: usize,
     fp_rate: f64,
+    /// The rate of each level from Level 0, and deeper levels use the last one
+    #[serde(default)]
+    level_fp_rates: Vec<f64>,
 }
 
 impl Default for Config {
@@ -36,49 +363,230 @@ impl Default for Config {
             },
             fp_tree: FpTree {
                 root_split_threshold: 6,
+                memtable_bytes: None,
+                num_slot: DEFAULT_NUM_SLOT,
+                leaf_size: DEFAULT_LEAF_SIZE,
+                data_alignment: data_util::DEFAULT_DATA_ALIGNMENT,
+                leaf_allocation: None,
+                recover_fptree: false,
+                in_memory: false,
+                durability: DurabilityMode::PerWrite,
+                durability_interval_ms: default_durability_interval_ms(),
+                mmap_cache_pages: DEFAULT_MMAP_CACHE_PAGES,
+                fingerprint_bits: DEFAULT_FINGERPRINT_BITS,
+                fingerprint_hash: FingerprintHash::Sip,
+                max_key_size: None,
+                max_value_size: None,
             },
             bloom_filter: BloomFilter {
                 items_count: 8192,
                 fp_rate: 0.01,
+                level_fp_rates: Vec::new(),
             },
+            sstable: Sstable::default(),
+            compaction: Compaction::default(),
+            wal: Wal::default(),
+            checksum: ChecksumConfig::default(),
+            read_cache: ReadCache::default(),
+            listeners: Vec::new(),
+            compaction_filter: None,
+            storage: default_storage(),
+            fault_injector: Arc::new(FaultInjector::default()),
+            rate_limiter: Arc::new(RateLimiter::default()),
         }
     }
 }
 
 impl Config {
-    pub fn new() -> Self {
+    /// Read `config.toml` and environment variables like `AMPHIS_LEAF_DIR`
+    /// The precedence is environment variables > the config file > the default values
+    pub fn new() -> Result<Self, ConfigError> {
+        let config = Self::load(CONFIG_FILE, ENV_PREFIX)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn load(config_file: &str, env_prefix: &str) -> Result<Self, ConfigError> {
+        let load_error = |e: config::ConfigError| ConfigError::Load(e.to_string());
         let mut config = config::Config::default();
-        if Path::new(CONFIG_FILE).exists() {
+        config
+            .merge(config::Config::try_from(&Config::default()).map_err(load_error)?)
+            .map_err(load_error)?;
+        if Path::new(config_file).exists() {
             config
-                .merge(config::File::with_name(CONFIG_FILE))
-                .expect("reading a config file failed");
+                .merge(config::File::with_name(config_file))
+                .map_err(load_error)?;
+        }
+
+        // environment variables have flat names
+        let mut env = config::Config::default();
+        env.merge(config::Environment::with_prefix(env_prefix))
+            .map_err(load_error)?;
+        for (name, key) in ENV_KEYS {
+            if let Ok(value) = env.get_str(name) {
+                config.set(key, value).map_err(load_error)?;
+            }
+        }
+
+        config.try_into().map_err(load_error)
+    }
+
+    /// Check that all values are in their valid ranges
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field: &'static str, reason: &str| {
+            Err(ConfigError::InvalidValue {
+                field,
+                reason: reason.to_owned(),
+            })
+        };
+
+        if self.directories.leaf_dir.is_empty() {
+            return invalid("leaf_dir", "empty directory");
+        }
+        if self.directories.table_dir.is_empty() {
+            return invalid("table_dir", "empty directory");
+        }
+        if self.fp_tree.root_split_threshold == 0 {
+            return invalid("root_split_threshold", "should be positive");
         }
-        config.try_into().expect("deserializing config failed")
+        if self.fp_tree.memtable_bytes == Some(0) {
+            return invalid("memtable_bytes", "should be positive");
+        }
+        if let Err(e) = validate_fingerprint_bits(self.fp_tree.fingerprint_bits) {
+            return invalid("fingerprint_bits", &e.to_string());
+        }
+        if let Err(e) = validate_num_slot(self.fp_tree.num_slot, self.fp_tree.fingerprint_bits) {
+            return invalid("num_slot", &e.to_string());
+        }
+        if let Err(e) = validate_leaf_size(self.fp_tree.leaf_size) {
+            return invalid("leaf_size", &e.to_string());
+        }
+        if let Err(e) = validate_data_alignment(self.fp_tree.data_alignment) {
+            return invalid("data_alignment", &e.to_string());
+        }
+        if self.fp_tree.leaf_allocation == Some(0) {
+            return invalid("leaf_allocation", "should be positive");
+        }
+        if self.fp_tree.max_key_size == Some(0) {
+            return invalid("max_key_size", "should be positive");
+        }
+        if self.fp_tree.max_value_size == Some(0) {
+            return invalid("max_value_size", "should be positive");
+        }
+        // the largest key and value with an expiry should be stored in leaves
+        let leaf_size = self.fp_tree.leaf_size;
+        let max_key_size = self.fp_tree.max_key_size.unwrap_or(0);
+        if data_util::get_data_size(max_key_size, MAX_VALUE_HEADER_SIZE)
+            > get_max_data_size(leaf_size)
+        {
+            return invalid("max_key_size", "the key doesn't fit in a leaf page");
+        }
+        if self.fp_tree.max_value_size.unwrap_or(0) + MAX_VALUE_HEADER_SIZE
+            > get_max_value_size(leaf_size, max_key_size)
+        {
+            return invalid("max_value_size", "the value can't be stored with the key");
+        }
+        if self.fp_tree.in_memory && self.fp_tree.recover_fptree {
+            return invalid("recover_fptree", "no FPTree is reopened in memory");
+        }
+        if self.fp_tree.durability_interval_ms == 0 {
+            return invalid("durability_interval_ms", "should be positive");
+        }
+        if self.bloom_filter.items_count == 0 {
+            return invalid("bloom_items_count", "should be positive");
+        }
+        // NaN is also rejected
+        if !(self.bloom_filter.fp_rate > 0.0 && self.bloom_filter.fp_rate < 1.0) {
+            return invalid("bloom_fp_rate", "should be in (0, 1)");
+        }
+        if !self
+            .bloom_filter
+            .level_fp_rates
+            .iter()
+            .all(|fp_rate| *fp_rate > 0.0 && *fp_rate < 1.0)
+        {
+            return invalid("bloom_level_fp_rates", "should be in (0, 1)");
+        }
+        if self.sstable.block_size == 0 {
+            return invalid("block_size", "should be positive");
+        }
+        if self.sstable.flush_parallelism == 0 {
+            return invalid("flush_parallelism", "should be positive");
+        }
+        if self.compaction.l0_compaction_trigger == 0 {
+            return invalid("l0_compaction_trigger", "should be positive");
+        }
+        if self.compaction.level_base_bytes == 0 {
+            return invalid("level_base_bytes", "should be positive");
+        }
+        if self.compaction.level_multiplier < 2 {
+            return invalid("level_multiplier", "should be 2 or more");
+        }
+        if self.compaction.target_table_bytes == 0 {
+            return invalid("target_table_bytes", "should be positive");
+        }
+        // writes would stall forever if Level 0 weren't compacted
+        if self
+            .compaction
+            .max_l0_tables
+            .is_some_and(|max| max < self.compaction.l0_compaction_trigger)
+        {
+            return invalid("max_l0_tables", "should be l0_compaction_trigger or more");
+        }
+        if self.compaction.write_stall_timeout_ms == 0 {
+            return invalid("write_stall_timeout_ms", "should be positive");
+        }
+        // the amplification is never less than 1
+        if self
+            .compaction
+            .max_space_amplification
+            .is_some_and(|ratio| ratio < 1.0 || ratio.is_nan())
+        {
+            return invalid("max_space_amplification", "should be 1 or more");
+        }
+        if self.compaction.bytes_per_sec == Some(0) {
+            return invalid("compaction_bytes_per_sec", "should be positive");
+        }
+        if self.wal.sync_interval_ms == 0 {
+            return invalid("wal_sync_interval_ms", "should be positive");
+        }
+
+        Ok(())
+    }
+
+    /// Return a builder starting from the default values
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
     }
 
     #[cfg(test)]
     pub fn new_for_testing() -> Self {
+        Self::builder_for_testing().build()
+    }
+
+    /// Return a builder whose directories are a temporary directory
+    #[cfg(test)]
+    pub fn builder_for_testing() -> ConfigBuilder {
         let temp_dir = tempfile::tempdir().expect("no temp directry");
         let path_str = temp_dir.path().to_str().expect("no path");
-        let mut config = config::Config::default();
 
-        config
-            .set_default("directories.leaf_dir", path_str)
-            .expect("cannot parse the key");
-        config
-            .set_default("directories.table_dir", path_str)
-            .expect("cannot parse the key");
-        config
-            .set_default("fp_tree.root_split_threshold", 6)
-            .expect("cannot parse the key");
-        config
-            .set_default("bloom_filter.items_count", 8192)
-            .expect("cannot parse the key");
-        config
-            .set_default("bloom_filter.fp_rate", 0.01)
-            .expect("cannot parse the key");
+        ConfigBuilder::new()
+            .leaf_dir(path_str)
+            .table_dir(path_str)
+            .root_split_threshold(6)
+            .bloom_items_count(8192)
+            .bloom_fp_rate(0.01)
+    }
 
-        config.try_into().expect("deserializing config failed")
+    /// Return the same config whose leaf and table directories are `dir`
+    /// Listeners aren't inherited not to be notified of events of another database
+    pub(crate) fn with_directory(&self, dir: &str) -> Self {
+        let mut config = self.clone();
+        config.directories.leaf_dir = dir.to_owned();
+        config.directories.table_dir = dir.to_owned();
+        config.listeners = Vec::new();
+        config
     }
 
     pub fn get_leaf_dir_path(&self, name: &str) -> String {
@@ -93,6 +601,26 @@ impl Config {
         format!("{}/leaves-{}.amph", self.get_leaf_dir_path(name), id)
     }
 
+    pub fn get_obsolete_leaf_file_path(&self, name: &str, id: usize) -> String {
+        format!(
+            "{}/obsolete-leaves-{}.amph",
+            self.get_leaf_dir_path(name),
+            id
+        )
+    }
+
+    pub fn get_range_tombstone_file_path(&self, name: &str, id: usize) -> String {
+        format!(
+            "{}/range-tombstones-{}.amph",
+            self.get_leaf_dir_path(name),
+            id
+        )
+    }
+
+    pub fn get_wal_file_path(&self, name: &str, id: usize) -> String {
+        format!("{}/wal-{}.amph", self.get_leaf_dir_path(name), id)
+    }
+
     pub fn get_table_file_path(&self, name: &str, id: usize) -> String {
         format!("{}/sstable-{}.amph", self.get_table_dir_path(name), id)
     }
@@ -101,6 +629,78 @@ impl Config {
         self.fp_tree.root_split_threshold
     }
 
+    /// `memtable_bytes` takes precedence over `root_split_threshold`
+    pub fn get_flush_trigger(&self) -> FlushTrigger {
+        match self.fp_tree.memtable_bytes {
+            Some(bytes) => FlushTrigger::Bytes(bytes),
+            None => FlushTrigger::RootSplits(self.fp_tree.root_split_threshold),
+        }
+    }
+
+    pub fn get_num_slot(&self) -> usize {
+        self.fp_tree.num_slot
+    }
+
+    pub fn get_leaf_size(&self) -> usize {
+        self.fp_tree.leaf_size
+    }
+
+    pub fn get_data_alignment(&self) -> usize {
+        self.fp_tree.data_alignment
+    }
+
+    /// The number of leaves appended to the leaf file at once
+    /// It's `DEFAULT_ALLOCATION_BYTES` of leaves by default, and at least one leaf
+    pub fn get_leaf_allocation(&self) -> usize {
+        self.fp_tree
+            .leaf_allocation
+            .unwrap_or_else(|| (DEFAULT_ALLOCATION_BYTES / self.fp_tree.leaf_size).max(1))
+    }
+
+    pub fn get_recover_fptree(&self) -> bool {
+        self.fp_tree.recover_fptree
+    }
+
+    /// Whether leaves are kept in memory without any file
+    pub fn get_in_memory(&self) -> bool {
+        self.fp_tree.in_memory
+    }
+
+    /// `durability_interval_ms` is used only for `Durability::Batched`
+    pub fn get_durability(&self) -> Durability {
+        match self.fp_tree.durability {
+            DurabilityMode::PerWrite => Durability::PerWrite,
+            DurabilityMode::Batched => {
+                Durability::Batched(Duration::from_millis(self.fp_tree.durability_interval_ms))
+            }
+            DurabilityMode::OnFlushOnly => Durability::OnFlushOnly,
+        }
+    }
+
+    pub fn get_mmap_cache_pages(&self) -> usize {
+        self.fp_tree.mmap_cache_pages
+    }
+
+    pub fn get_fingerprint_bits(&self) -> usize {
+        self.fp_tree.fingerprint_bits
+    }
+
+    pub fn get_fingerprint_hash(&self) -> FingerprintHash {
+        self.fp_tree.fingerprint_hash
+    }
+
+    /// The maximum size of keys to be put
+    /// `None` when keys are limited only by the leaf size
+    pub fn get_max_key_size(&self) -> Option<usize> {
+        self.fp_tree.max_key_size
+    }
+
+    /// The maximum size of values to be put
+    /// `None` when values are limited only by the leaf size
+    pub fn get_max_value_size(&self) -> Option<usize> {
+        self.fp_tree.max_value_size
+    }
+
     pub fn get_filter_items_count(&self) -> usize {
         self.bloom_filter.items_count
     }
@@ -109,9 +709,432 @@ impl Config {
         self.bloom_filter.fp_rate
     }
 
+    /// The false positive rate of filters of tables in the level
+    /// It's `bloom_fp_rate` when the rates of levels aren't set
+    pub fn get_level_filter_fp_rate(&self, level: usize) -> f64 {
+        let rates = &self.bloom_filter.level_fp_rates;
+        match rates.get(level).or(rates.last()) {
+            Some(fp_rate) => *fp_rate,
+            None => self.bloom_filter.fp_rate,
+        }
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.sstable.block_size
+    }
+
+    pub fn get_compression(&self) -> Compression {
+        self.sstable.compression
+    }
+
+    pub fn get_block_cache_bytes(&self) -> usize {
+        self.sstable.block_cache_bytes
+    }
+
+    pub fn get_parallel_lookup(&self) -> bool {
+        self.sstable.parallel_lookup
+    }
+
+    pub fn get_verify_tables(&self) -> bool {
+        self.sstable.verify_tables
+    }
+
+    pub fn get_flush_parallelism(&self) -> usize {
+        self.sstable.flush_parallelism
+    }
+
+    pub fn get_l0_compaction_trigger(&self) -> usize {
+        self.compaction.l0_compaction_trigger
+    }
+
+    /// The maximum total size of tables in the level (1 or deeper)
+    pub fn get_level_max_bytes(&self, level: usize) -> usize {
+        let exp = u32::try_from(level.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.compaction
+            .level_multiplier
+            .saturating_pow(exp)
+            .saturating_mul(self.compaction.level_base_bytes)
+    }
+
+    pub fn get_target_table_bytes(&self) -> usize {
+        self.compaction.target_table_bytes
+    }
+
+    /// Writes stall while Level 0 has this number of tables
+    /// `None` when writes never stall
+    pub fn get_max_l0_tables(&self) -> Option<usize> {
+        self.compaction.max_l0_tables
+    }
+
+    /// A stalled write fails after this time
+    pub fn get_write_stall_timeout(&self) -> Duration {
+        Duration::from_millis(self.compaction.write_stall_timeout_ms)
+    }
+
+    /// All tables are compacted when the space amplification exceeds this ratio
+    /// `None` when the amplification doesn't trigger compactions
+    pub fn get_max_space_amplification(&self) -> Option<f64> {
+        self.compaction.max_space_amplification
+    }
+
+    /// The rate of writes to SSTables by flushes and compactions
+    /// `None` when the writes aren't limited
+    pub fn get_compaction_bytes_per_sec(&self) -> Option<u64> {
+        self.compaction.bytes_per_sec
+    }
+
+    /// `sync_interval_ms` is used only for `WalSync::Interval`
+    pub fn get_wal_sync(&self) -> WalSync {
+        match self.wal.sync {
+            WalSyncMode::Always => WalSync::Always,
+            WalSyncMode::Interval => WalSync::Interval(self.wal.sync_interval_ms),
+            WalSyncMode::Never => WalSync::Never,
+        }
+    }
+
+    /// The checksum algorithm of new leaf files and SSTables
+    pub fn get_checksum(&self) -> Checksum {
+        self.checksum.algorithm
+    }
+
+    /// The total size of keys and values cached for lookups of each column family
+    pub fn get_read_cache_bytes(&self) -> usize {
+        self.read_cache.bytes
+    }
+
+    pub fn get_listeners(&self) -> &[Arc<dyn Listener>] {
+        &self.listeners
+    }
+
+    pub fn get_compaction_filter(&self) -> Option<&Arc<dyn CompactionFilter>> {
+        self.compaction_filter.as_ref()
+    }
+
+    pub fn get_storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub fn get_fault_injector(&self) -> &Arc<FaultInjector> {
+        &self.fault_injector
+    }
+
+    pub(crate) fn get_rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.rate_limiter
+    }
+
+    /// Share a new limiter of `compaction_bytes_per_sec` among the clones of this config
+    pub(crate) fn with_new_rate_limiter(self) -> Self {
+        Config {
+            rate_limiter: Arc::new(RateLimiter::new(self.compaction.bytes_per_sec)),
+            ..self
+        }
+    }
+
     pub fn get_metadata_path(&self, name: &str) -> String {
         format!("{}/metadata.amph", self.get_table_dir_path(name))
     }
+
+    /// The file locked while the database is opened
+    pub fn get_lock_file_path(&self, name: &str) -> String {
+        format!("{}/LOCK", self.get_leaf_dir_path(name))
+    }
+}
+
+/// Builder to make `Config` without a config file
+#[derive(Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The directory stores leaf files of FPTree
+    pub fn leaf_dir(mut self, leaf_dir: &str) -> Self {
+        self.config.directories.leaf_dir = leaf_dir.to_owned();
+        self
+    }
+
+    /// The directory stores sstable files
+    pub fn table_dir(mut self, table_dir: &str) -> Self {
+        self.config.directories.table_dir = table_dir.to_owned();
+        self
+    }
+
+    /// Flush the FPTree when the root has been split this number of times
+    pub fn root_split_threshold(mut self, threshold: usize) -> Self {
+        self.config.fp_tree.root_split_threshold = threshold;
+        self.config.fp_tree.memtable_bytes = None;
+        self
+    }
+
+    /// Flush the FPTree when keys and values of this number of bytes have been written
+    pub fn memtable_bytes(mut self, bytes: usize) -> Self {
+        self.config.fp_tree.memtable_bytes = Some(bytes);
+        self
+    }
+
+    pub fn flush_trigger(self, trigger: FlushTrigger) -> Self {
+        match trigger {
+            FlushTrigger::RootSplits(threshold) => self.root_split_threshold(threshold),
+            FlushTrigger::Bytes(bytes) => self.memtable_bytes(bytes),
+        }
+    }
+
+    /// The number of key-value slots in each leaf (a multiple of 8)
+    pub fn num_slot(mut self, num_slot: usize) -> Self {
+        self.config.fp_tree.num_slot = num_slot;
+        self
+    }
+
+    /// The size of each leaf in bytes (a multiple of 4096)
+    pub fn leaf_size(mut self, leaf_size: usize) -> Self {
+        self.config.fp_tree.leaf_size = leaf_size;
+        self
+    }
+
+    /// The alignment of key-value pairs in a leaf (a power of two up to 4096)
+    /// A smaller alignment packs more small pairs in a leaf, while a pair can share a page with others
+    pub fn data_alignment(mut self, alignment: usize) -> Self {
+        self.config.fp_tree.data_alignment = alignment;
+        self
+    }
+
+    /// The number of leaves appended to the leaf file when no free leaf remains
+    /// Fewer leaves save the disk for small datasets, and more leaves extend the file less often
+    pub fn leaf_allocation(mut self, leaves: usize) -> Self {
+        self.config.fp_tree.leaf_allocation = Some(leaves);
+        self
+    }
+
+    /// Reopen the last FPTree on startup instead of flushing it to an SSTable
+    pub fn recover_fptree(mut self, recover_fptree: bool) -> Self {
+        self.config.fp_tree.recover_fptree = recover_fptree;
+        self
+    }
+
+    /// Keep leaves in memory and never flush the FPTree, so nothing is written to the disk
+    /// All data is lost when the KVS is dropped
+    pub fn in_memory(mut self, in_memory: bool) -> Self {
+        self.config.fp_tree.in_memory = in_memory;
+        self
+    }
+
+    /// Sync writes to leaves with the policy
+    pub fn durability(mut self, durability: Durability) -> Self {
+        match durability {
+            Durability::PerWrite => self.config.fp_tree.durability = DurabilityMode::PerWrite,
+            Durability::Batched(interval) => {
+                self.config.fp_tree.durability = DurabilityMode::Batched;
+                self.config.fp_tree.durability_interval_ms = interval.as_millis() as u64;
+            }
+            Durability::OnFlushOnly => self.config.fp_tree.durability = DurabilityMode::OnFlushOnly,
+        }
+        self
+    }
+
+    /// The number of leaf pages whose mappings are kept for reads and writes of key-value pairs
+    /// 0 maps a page for each access
+    pub fn mmap_cache_pages(mut self, pages: usize) -> Self {
+        self.config.fp_tree.mmap_cache_pages = pages;
+        self
+    }
+
+    /// The width of fingerprints of keys in leaves: 8 or 16
+    /// Wider fingerprints read fewer keys of other slots, but the header of a leaf is larger
+    pub fn fingerprint_bits(mut self, bits: usize) -> Self {
+        self.config.fp_tree.fingerprint_bits = bits;
+        self
+    }
+
+    /// The hash function of fingerprints of keys in leaves
+    pub fn fingerprint_hash(mut self, hash: FingerprintHash) -> Self {
+        self.config.fp_tree.fingerprint_hash = hash;
+        self
+    }
+
+    /// The maximum number of items in each bloom filter
+    /// Reject a put of a longer key with `CrudError::KeyTooLarge`
+    pub fn max_key_size(mut self, size: usize) -> Self {
+        self.config.fp_tree.max_key_size = Some(size);
+        self
+    }
+
+    /// Reject a put of a longer value with `CrudError::ValueTooLarge`
+    pub fn max_value_size(mut self, size: usize) -> Self {
+        self.config.fp_tree.max_value_size = Some(size);
+        self
+    }
+
+    pub fn bloom_items_count(mut self, items_count: usize) -> Self {
+        self.config.bloom_filter.items_count = items_count;
+        self
+    }
+
+    /// The expected rate of false positive in a bloom filter
+    pub fn bloom_fp_rate(mut self, fp_rate: f64) -> Self {
+        self.config.bloom_filter.fp_rate = fp_rate;
+        self
+    }
+
+    /// The expected rates of false positive in bloom filters of each level from Level 0
+    /// Deeper levels use the last rate, so higher rates of the deep levels save space
+    /// while Level 0 tables, which every lookup checks, keep tight filters
+    pub fn bloom_level_fp_rates(mut self, fp_rates: &[f64]) -> Self {
+        self.config.bloom_filter.level_fp_rates = fp_rates.to_vec();
+        self
+    }
+
+    /// The size of each data block of an SSTable in bytes
+    /// The index has the first key of every block, and a lookup reads only one block
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.config.sstable.block_size = block_size;
+        self
+    }
+
+    /// Compress data blocks of new SSTables with the codec
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.sstable.compression = compression;
+        self
+    }
+
+    /// The total size of decoded data blocks cached for lookups
+    /// 0 disables the cache
+    pub fn block_cache_bytes(mut self, block_cache_bytes: usize) -> Self {
+        self.config.sstable.block_cache_bytes = block_cache_bytes;
+        self
+    }
+
+    /// Read SSTables concurrently for a lookup
+    /// This is useful when many tables have to be read for a key
+    pub fn parallel_lookup(mut self, parallel_lookup: bool) -> Self {
+        self.config.sstable.parallel_lookup = parallel_lookup;
+        self
+    }
+
+    /// Verify the checksum of every SSTable when opening a database
+    /// A table failing the verification is quarantined and isn't read
+    pub fn verify_tables(mut self, verify_tables: bool) -> Self {
+        self.config.sstable.verify_tables = verify_tables;
+        self
+    }
+
+    /// Write parts of a flushed table with this number of threads
+    /// This shortens flushes of large FPTrees on a fast disk
+    pub fn flush_parallelism(mut self, parallelism: usize) -> Self {
+        self.config.sstable.flush_parallelism = parallelism;
+        self
+    }
+
+    /// Compact Level 0 when it has this number of tables
+    pub fn l0_compaction_trigger(mut self, trigger: usize) -> Self {
+        self.config.compaction.l0_compaction_trigger = trigger;
+        self
+    }
+
+    /// The maximum total size of tables in Level 1
+    pub fn level_base_bytes(mut self, bytes: usize) -> Self {
+        self.config.compaction.level_base_bytes = bytes;
+        self
+    }
+
+    /// Each deeper level can be this number of times as large as the previous level
+    pub fn level_multiplier(mut self, multiplier: usize) -> Self {
+        self.config.compaction.level_multiplier = multiplier;
+        self
+    }
+
+    /// A compaction splits its output into tables of about this size
+    pub fn target_table_bytes(mut self, bytes: usize) -> Self {
+        self.config.compaction.target_table_bytes = bytes;
+        self
+    }
+
+    /// Stall writes while Level 0 has this number of tables
+    pub fn max_l0_tables(mut self, max: usize) -> Self {
+        self.config.compaction.max_l0_tables = Some(max);
+        self
+    }
+
+    /// Fail a stalled write after this time
+    pub fn write_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.config.compaction.write_stall_timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    /// Compact all tables when the size of tables exceeds this ratio to the live data
+    pub fn max_space_amplification(mut self, ratio: f64) -> Self {
+        self.config.compaction.max_space_amplification = Some(ratio);
+        self
+    }
+
+    /// Limit writes to SSTables by flushes and compactions to this rate
+    /// All column families draw from one budget not to starve foreground reads
+    pub fn compaction_bytes_per_sec(mut self, bytes_per_sec: u64) -> Self {
+        self.config.compaction.bytes_per_sec = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sync the write-ahead log of the FPTree with the policy
+    pub fn wal_sync(mut self, sync: WalSync) -> Self {
+        match sync {
+            WalSync::Always => self.config.wal.sync = WalSyncMode::Always,
+            WalSync::Interval(ms) => {
+                self.config.wal.sync = WalSyncMode::Interval;
+                self.config.wal.sync_interval_ms = ms;
+            }
+            WalSync::Never => self.config.wal.sync = WalSyncMode::Never,
+        }
+        self
+    }
+
+    /// Compute checksums of new leaf files and SSTables by the algorithm
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.config.checksum.algorithm = checksum;
+        self
+    }
+
+    /// The total size of keys and values whose lookup results are cached for hot keys
+    /// A cached lookup reads neither the FPTrees nor the SSTables
+    /// 0 disables the cache, which is the default
+    pub fn read_cache_bytes(mut self, read_cache_bytes: usize) -> Self {
+        self.config.read_cache.bytes = read_cache_bytes;
+        self
+    }
+
+    /// Add a listener of flushes and compactions
+    /// Listeners can't be set by `config.toml`
+    pub fn listener(mut self, listener: Arc<dyn Listener>) -> Self {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    /// Decide whether each pair merged by compactions is kept
+    /// The filter can't be set by `config.toml`
+    pub fn compaction_filter(mut self, filter: Arc<dyn CompactionFilter>) -> Self {
+        self.config.compaction_filter = Some(filter);
+        self
+    }
+
+    /// Open, rename and remove SSTables and leaf files through the storage
+    /// The default is `FsStorage`, and the storage can't be set by `config.toml`
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.config.storage = storage;
+        self
+    }
+
+    /// Inject failures at the fault points armed by the injector
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn fault_injector(mut self, fault_injector: Arc<FaultInjector>) -> Self {
+        self.config.fault_injector = fault_injector;
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
 }
 
 #[cfg(test)]
@@ -120,11 +1143,309 @@ mod tests {
 
     #[test]
     fn test_config() {
-        let config = Config::new();
+        let config = Config::new().unwrap();
         assert_eq!(config.directories.leaf_dir, "data");
         assert_eq!(config.directories.table_dir, "data");
         assert_eq!(config.fp_tree.root_split_threshold, 4);
+        assert_eq!(config.get_flush_trigger(), FlushTrigger::RootSplits(4));
+        assert_eq!(config.fp_tree.num_slot, 32);
+        assert_eq!(config.fp_tree.leaf_size, 1024 * 1024);
+        assert_eq!(config.get_data_alignment(), 4096);
+        assert_eq!(config.get_leaf_allocation(), 4);
+        assert!(!config.get_recover_fptree());
+        assert!(!config.get_in_memory());
+        assert_eq!(config.get_durability(), Durability::PerWrite);
+        assert_eq!(config.get_mmap_cache_pages(), 64);
+        assert_eq!(config.get_fingerprint_bits(), 8);
+        assert_eq!(config.get_fingerprint_hash(), FingerprintHash::Sip);
+        assert_eq!(config.get_max_key_size(), None);
+        assert_eq!(config.get_max_value_size(), None);
         assert_eq!(config.bloom_filter.items_count, 8192);
         assert_eq!(config.bloom_filter.fp_rate, 0.01);
+        assert_eq!(config.get_level_filter_fp_rate(3), 0.01);
+        assert_eq!(config.sstable.block_size, 4096);
+        assert_eq!(config.get_compression(), Compression::None);
+        assert_eq!(config.get_block_cache_bytes(), 8 * 1024 * 1024);
+        assert!(!config.get_parallel_lookup());
+        assert!(!config.get_verify_tables());
+        assert_eq!(config.get_flush_parallelism(), 1);
+        assert_eq!(config.get_l0_compaction_trigger(), 4);
+        assert_eq!(config.get_level_max_bytes(1), 16 * 1024 * 1024);
+        assert_eq!(config.get_level_max_bytes(2), 160 * 1024 * 1024);
+        assert_eq!(config.get_target_table_bytes(), 4 * 1024 * 1024);
+        assert_eq!(config.get_max_l0_tables(), None);
+        assert_eq!(config.get_write_stall_timeout(), Duration::from_secs(10));
+        assert_eq!(config.get_max_space_amplification(), None);
+        assert_eq!(config.get_compaction_bytes_per_sec(), None);
+        assert_eq!(config.get_wal_sync(), WalSync::Never);
+        assert_eq!(config.get_checksum(), Checksum::Crc32);
+        assert_eq!(config.get_read_cache_bytes(), 0);
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = Config::builder()
+            .leaf_dir("leaves")
+            .table_dir("tables")
+            .root_split_threshold(2)
+            .num_slot(64)
+            .leaf_size(64 * 1024)
+            .data_alignment(64)
+            .leaf_allocation(2)
+            .in_memory(true)
+            .durability(Durability::Batched(Duration::from_millis(10)))
+            .mmap_cache_pages(8)
+            .fingerprint_bits(16)
+            .fingerprint_hash(FingerprintHash::XxHash)
+            .max_key_size(256)
+            .max_value_size(4096)
+            .bloom_items_count(1024)
+            .bloom_fp_rate(0.05)
+            .bloom_level_fp_rates(&[0.001, 0.01, 0.1])
+            .block_size(8192)
+            .compression(Compression::Zstd)
+            .block_cache_bytes(0)
+            .parallel_lookup(true)
+            .verify_tables(true)
+            .flush_parallelism(4)
+            .l0_compaction_trigger(2)
+            .level_base_bytes(1024)
+            .level_multiplier(4)
+            .target_table_bytes(256)
+            .max_l0_tables(8)
+            .write_stall_timeout(Duration::from_millis(500))
+            .max_space_amplification(2.0)
+            .compaction_bytes_per_sec(1024 * 1024)
+            .wal_sync(WalSync::Interval(100))
+            .checksum(Checksum::Crc32c)
+            .read_cache_bytes(1024)
+            .build();
+        assert_eq!(config.get_leaf_dir_path("t"), "leaves/t");
+        assert_eq!(config.get_table_dir_path("t"), "tables/t");
+        assert_eq!(config.get_root_split_threshold(), 2);
+        assert_eq!(config.get_num_slot(), 64);
+        assert_eq!(config.get_leaf_size(), 64 * 1024);
+        assert_eq!(config.get_data_alignment(), 64);
+        assert_eq!(config.get_leaf_allocation(), 2);
+        assert!(config.get_in_memory());
+        assert_eq!(
+            config.get_durability(),
+            Durability::Batched(Duration::from_millis(10))
+        );
+        assert_eq!(config.get_mmap_cache_pages(), 8);
+        assert_eq!(config.get_fingerprint_bits(), 16);
+        assert_eq!(config.get_fingerprint_hash(), FingerprintHash::XxHash);
+        assert_eq!(config.get_max_key_size(), Some(256));
+        assert_eq!(config.get_max_value_size(), Some(4096));
+        assert_eq!(config.get_filter_items_count(), 1024);
+        assert_eq!(config.get_filter_fp_rate(), 0.05);
+        assert_eq!(config.get_level_filter_fp_rate(0), 0.001);
+        assert_eq!(config.get_level_filter_fp_rate(2), 0.1);
+        assert_eq!(config.get_level_filter_fp_rate(5), 0.1);
+        assert_eq!(config.get_block_size(), 8192);
+        assert_eq!(config.get_compression(), Compression::Zstd);
+        assert_eq!(config.get_block_cache_bytes(), 0);
+        assert!(config.get_parallel_lookup());
+        assert!(config.get_verify_tables());
+        assert_eq!(config.get_flush_parallelism(), 4);
+        assert_eq!(config.get_l0_compaction_trigger(), 2);
+        assert_eq!(config.get_level_max_bytes(1), 1024);
+        assert_eq!(config.get_level_max_bytes(3), 16 * 1024);
+        assert_eq!(config.get_level_max_bytes(usize::MAX), usize::MAX);
+        assert_eq!(config.get_target_table_bytes(), 256);
+        assert_eq!(config.get_max_l0_tables(), Some(8));
+        assert_eq!(config.get_write_stall_timeout(), Duration::from_millis(500));
+        assert_eq!(config.get_max_space_amplification(), Some(2.0));
+        assert_eq!(config.get_compaction_bytes_per_sec(), Some(1024 * 1024));
+        assert_eq!(config.get_wal_sync(), WalSync::Interval(100));
+        assert_eq!(config.get_checksum(), Checksum::Crc32c);
+        assert_eq!(config.get_read_cache_bytes(), 1024);
+
+        // unset fields are the default values
+        let config = ConfigBuilder::new().leaf_dir("leaves").build();
+        assert_eq!(config.get_table_dir_path("t"), "data/t");
+        assert_eq!(config.get_num_slot(), DEFAULT_NUM_SLOT);
+        assert_eq!(config.get_leaf_size(), DEFAULT_LEAF_SIZE);
+        // the default allocation is relative to the leaf size
+        let config = ConfigBuilder::new().leaf_size(64 * 1024).build();
+        assert_eq!(config.get_leaf_allocation(), 64);
+        let config = ConfigBuilder::new().leaf_size(8 * 1024 * 1024).build();
+        assert_eq!(config.get_leaf_allocation(), 1);
+    }
+
+    #[test]
+    fn test_flush_trigger() {
+        let config = Config::builder().memtable_bytes(1024).build();
+        assert_eq!(config.get_flush_trigger(), FlushTrigger::Bytes(1024));
+
+        let config = Config::builder()
+            .flush_trigger(FlushTrigger::Bytes(1024))
+            .flush_trigger(FlushTrigger::RootSplits(3))
+            .build();
+        assert_eq!(config.get_flush_trigger(), FlushTrigger::RootSplits(3));
+    }
+
+    #[test]
+    fn test_env_override() {
+        // a different prefix not to affect other tests
+        const PREFIX: &str = "AMPHIS_ENV_TEST";
+        std::env::set_var("AMPHIS_ENV_TEST_LEAF_DIR", "env_leaves");
+        std::env::set_var("AMPHIS_ENV_TEST_ROOT_SPLIT_THRESHOLD", "8");
+        std::env::set_var("AMPHIS_ENV_TEST_MEMTABLE_BYTES", "4096");
+        std::env::set_var("AMPHIS_ENV_TEST_BLOOM_FP_RATE", "0.02");
+        std::env::set_var("AMPHIS_ENV_TEST_COMPRESSION", "lz4");
+        std::env::set_var("AMPHIS_ENV_TEST_CHECKSUM", "crc32c");
+        std::env::set_var("AMPHIS_ENV_TEST_DURABILITY", "on_flush_only");
+        std::env::set_var("AMPHIS_ENV_TEST_WAL_SYNC", "interval");
+        std::env::set_var("AMPHIS_ENV_TEST_WAL_SYNC_INTERVAL_MS", "10");
+        std::env::set_var("AMPHIS_ENV_TEST_MAX_VALUE_SIZE", "1024");
+
+        let config = Config::load(CONFIG_FILE, PREFIX).unwrap();
+        // overridden
+        assert_eq!(config.directories.leaf_dir, "env_leaves");
+        assert_eq!(config.fp_tree.root_split_threshold, 8);
+        assert_eq!(config.get_flush_trigger(), FlushTrigger::Bytes(4096));
+        assert_eq!(config.bloom_filter.fp_rate, 0.02);
+        assert_eq!(config.get_compression(), Compression::Lz4);
+        assert_eq!(config.get_checksum(), Checksum::Crc32c);
+        assert_eq!(config.get_durability(), Durability::OnFlushOnly);
+        assert_eq!(config.get_wal_sync(), WalSync::Interval(10));
+        assert_eq!(config.get_max_value_size(), Some(1024));
+        // from the config file
+        assert_eq!(config.directories.table_dir, "data");
+        assert_eq!(config.bloom_filter.items_count, 8192);
+
+        // only default values without the config file
+        std::env::remove_var("AMPHIS_ENV_TEST_MEMTABLE_BYTES");
+        let config = Config::load("not_exist.toml", PREFIX).unwrap();
+        assert_eq!(config.directories.leaf_dir, "env_leaves");
+        assert_eq!(config.fp_tree.root_split_threshold, 8);
+        assert_eq!(config.directories.table_dir, "data");
+        assert_eq!(config.get_num_slot(), DEFAULT_NUM_SLOT);
+        assert_eq!(config.get_flush_trigger(), FlushTrigger::RootSplits(8));
+
+        std::env::remove_var("AMPHIS_ENV_TEST_LEAF_DIR");
+        std::env::remove_var("AMPHIS_ENV_TEST_ROOT_SPLIT_THRESHOLD");
+        std::env::remove_var("AMPHIS_ENV_TEST_BLOOM_FP_RATE");
+        std::env::remove_var("AMPHIS_ENV_TEST_COMPRESSION");
+        std::env::remove_var("AMPHIS_ENV_TEST_CHECKSUM");
+        std::env::remove_var("AMPHIS_ENV_TEST_DURABILITY");
+        std::env::remove_var("AMPHIS_ENV_TEST_WAL_SYNC");
+        std::env::remove_var("AMPHIS_ENV_TEST_WAL_SYNC_INTERVAL_MS");
+        std::env::remove_var("AMPHIS_ENV_TEST_MAX_VALUE_SIZE");
+    }
+
+    fn assert_invalid(builder: ConfigBuilder, expected: &str) {
+        match builder.build().validate() {
+            Err(ConfigError::InvalidValue { field, .. }) => assert_eq!(field, expected),
+            _ => panic!("{} should be invalid", expected),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+        assert!(Config::new_for_testing().validate().is_ok());
+
+        assert_invalid(Config::builder().leaf_dir(""), "leaf_dir");
+        assert_invalid(Config::builder().table_dir(""), "table_dir");
+        assert_invalid(
+            Config::builder().root_split_threshold(0),
+            "root_split_threshold",
+        );
+        assert_invalid(Config::builder().memtable_bytes(0), "memtable_bytes");
+        assert_invalid(Config::builder().num_slot(12), "num_slot");
+        assert_invalid(Config::builder().fingerprint_bits(12), "fingerprint_bits");
+        // the header with wider fingerprints doesn't fit in the header region
+        assert!(Config::builder().num_slot(224).build().validate().is_ok());
+        assert_invalid(
+            Config::builder().num_slot(224).fingerprint_bits(16),
+            "num_slot",
+        );
+        assert_invalid(Config::builder().leaf_size(1000), "leaf_size");
+        assert_invalid(Config::builder().data_alignment(0), "data_alignment");
+        assert_invalid(Config::builder().data_alignment(8192), "data_alignment");
+        assert_invalid(Config::builder().leaf_allocation(0), "leaf_allocation");
+        assert_invalid(Config::builder().max_key_size(0), "max_key_size");
+        assert_invalid(Config::builder().max_value_size(0), "max_value_size");
+        // a page of 16 KiB has 8 KiB for key-value pairs
+        let builder = Config::builder().leaf_size(16 * 1024).max_key_size(1024);
+        assert!(builder
+            .clone()
+            .max_value_size(7000)
+            .build()
+            .validate()
+            .is_ok());
+        // values which don't fit in a page are stored in overflow pages
+        assert!(builder
+            .clone()
+            .max_value_size(8 * 1024 * 1024)
+            .build()
+            .validate()
+            .is_ok());
+        assert_invalid(
+            builder.clone().max_value_size(64 * 1024 * 1024),
+            "max_value_size",
+        );
+        assert_invalid(builder.max_key_size(8192), "max_key_size");
+        assert_invalid(
+            Config::builder().in_memory(true).recover_fptree(true),
+            "recover_fptree",
+        );
+        assert_invalid(
+            Config::builder().durability(Durability::Batched(Duration::ZERO)),
+            "durability_interval_ms",
+        );
+        assert_invalid(Config::builder().bloom_items_count(0), "bloom_items_count");
+        assert_invalid(Config::builder().bloom_fp_rate(0.0), "bloom_fp_rate");
+        assert_invalid(Config::builder().bloom_fp_rate(1.0), "bloom_fp_rate");
+        assert_invalid(Config::builder().bloom_fp_rate(f64::NAN), "bloom_fp_rate");
+        assert_invalid(
+            Config::builder().bloom_level_fp_rates(&[0.01, 1.0]),
+            "bloom_level_fp_rates",
+        );
+        assert_invalid(Config::builder().block_size(0), "block_size");
+        assert_invalid(Config::builder().flush_parallelism(0), "flush_parallelism");
+        assert_invalid(
+            Config::builder().l0_compaction_trigger(0),
+            "l0_compaction_trigger",
+        );
+        assert_invalid(Config::builder().level_base_bytes(0), "level_base_bytes");
+        assert_invalid(Config::builder().level_multiplier(1), "level_multiplier");
+        assert_invalid(
+            Config::builder().target_table_bytes(0),
+            "target_table_bytes",
+        );
+        assert_invalid(Config::builder().max_l0_tables(3), "max_l0_tables");
+        assert_invalid(
+            Config::builder().max_space_amplification(0.5),
+            "max_space_amplification",
+        );
+        assert_invalid(
+            Config::builder().compaction_bytes_per_sec(0),
+            "compaction_bytes_per_sec",
+        );
+        assert_invalid(
+            Config::builder().write_stall_timeout(Duration::ZERO),
+            "write_stall_timeout_ms",
+        );
+        assert_invalid(
+            Config::builder().wal_sync(WalSync::Interval(0)),
+            "wal_sync_interval_ms",
+        );
+    }
+
+    #[test]
+    fn test_invalid_env() {
+        std::env::set_var("AMPHIS_INVALID_TEST_BLOOM_FP_RATE", "0");
+        let config = Config::load(CONFIG_FILE, "AMPHIS_INVALID_TEST").unwrap();
+        assert!(config.validate().is_err());
+
+        std::env::set_var("AMPHIS_INVALID_TEST_BLOOM_FP_RATE", "not a number");
+        assert!(matches!(
+            Config::load(CONFIG_FILE, "AMPHIS_INVALID_TEST"),
+            Err(ConfigError::Load(_))
+        ));
+        std::env::remove_var("AMPHIS_INVALID_TEST_BLOOM_FP_RATE");
     }
 }
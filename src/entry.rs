@@ -0,0 +1,56 @@
+use crate::amphis_error::CrudError;
+use crate::column_family::ColumnFamily;
+
+/// A closure applied to the live value of a key
+pub(crate) type Modifier<'a> = Box<dyn FnOnce(&mut Vec<u8>) + 'a>;
+
+/// A key to be read and written atomically, made by `KVS::entry`
+/// Nothing is read or written until `or_insert` or `or_insert_with` is called
+#[must_use = "an entry does nothing until `or_insert` or `or_insert_with` is called"]
+pub struct Entry<'a> {
+    cf: &'a ColumnFamily,
+    key: Vec<u8>,
+    modify: Option<Modifier<'a>>,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(cf: &'a ColumnFamily, key: &[u8]) -> Self {
+        Entry {
+            cf,
+            key: key.to_vec(),
+            modify: None,
+        }
+    }
+
+    /// Modify the live value when the key exists
+    /// The closures are applied in order, and the modified value is written without expiry
+    pub fn and_modify<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut Vec<u8>) + 'a,
+    {
+        self.modify = Some(match self.modify.take() {
+            Some(modify) => Box::new(move |value| {
+                modify(value);
+                f(value);
+            }),
+            None => Box::new(f),
+        });
+        self
+    }
+
+    /// Insert `default` when the key doesn't exist, and return the value after the write
+    pub fn or_insert(self, default: &[u8]) -> Result<Vec<u8>, CrudError> {
+        self.or_insert_with(|| default.to_vec())
+    }
+
+    /// Insert the result of `default` when the key doesn't exist,
+    /// and return the value after the write
+    /// Closures are called while reads and writes of the column family are blocked,
+    /// so they shouldn't access the KVS
+    pub fn or_insert_with<F>(self, default: F) -> Result<Vec<u8>, CrudError>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        self.cf.upsert(&self.key, self.modify, default)
+    }
+}
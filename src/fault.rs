@@ -0,0 +1,92 @@
+//! Named points to inject I/O failures for crash-recovery tests
+//!
+//! `LeafManager`, `TableWriter` and `SstableManager` check their points with the
+//! `FaultInjector` of `Config`. A point armed by `FaultInjector::arm()` returns an error
+//! once it's reached, and the database is expected to be reopened like after a crash.
+//! Points can be armed only with the `fault-injection` feature, and checking them is a
+//! no-op otherwise.
+#[cfg(any(test, feature = "fault-injection"))]
+use std::collections::HashMap;
+#[cfg(any(test, feature = "fault-injection"))]
+use std::sync::Mutex;
+
+#[cfg(any(test, feature = "fault-injection"))]
+use crate::util::lock_util::MutexExt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// After a leaf header is persisted, before the following data write
+    LeafHeaderCommitted,
+    /// Before a data block of an SSTable is written
+    TableBlockWrite,
+    /// After the info of a new table is appended to the metadata, before it's synced
+    MetadataAppended,
+}
+
+/// Armed fault points of a database
+#[derive(Default)]
+pub struct FaultInjector {
+    /// The number of times each point is passed before it fails
+    #[cfg(any(test, feature = "fault-injection"))]
+    armed: Mutex<HashMap<FaultPoint, usize>>,
+}
+
+impl FaultInjector {
+    /// Fail the point after passing it `skip` times
+    /// The point is disarmed when it fails
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn arm(&self, point: FaultPoint, skip: usize) {
+        self.armed.lock_or_recover().insert(point, skip);
+    }
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn disarm(&self, point: FaultPoint) {
+        self.armed.lock_or_recover().remove(&point);
+    }
+
+    /// Return the injected error if the point fails
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub(crate) fn check(&self, point: FaultPoint) -> Result<(), std::io::Error> {
+        let mut armed = self.armed.lock_or_recover();
+        match armed.get_mut(&point) {
+            Some(0) => {
+                armed.remove(&point);
+                Err(std::io::Error::other(format!(
+                    "injected fault at {:?}",
+                    point
+                )))
+            }
+            Some(skip) => {
+                *skip -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(any(test, feature = "fault-injection")))]
+    #[inline]
+    pub(crate) fn check(&self, _point: FaultPoint) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arm_and_disarm() {
+        let injector = FaultInjector::default();
+        injector.arm(FaultPoint::TableBlockWrite, 1);
+        assert!(injector.check(FaultPoint::MetadataAppended).is_ok());
+        assert!(injector.check(FaultPoint::TableBlockWrite).is_ok());
+        assert!(injector.check(FaultPoint::TableBlockWrite).is_err());
+        // it fails only once
+        assert!(injector.check(FaultPoint::TableBlockWrite).is_ok());
+
+        injector.arm(FaultPoint::TableBlockWrite, 0);
+        injector.disarm(FaultPoint::TableBlockWrite);
+        assert!(injector.check(FaultPoint::TableBlockWrite).is_ok());
+    }
+}
@@ -1,58 +1,85 @@
-use bloomfilter::Bloom;
-use crossbeam_channel::Receiver;
-use log::{debug, trace};
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, error, trace};
 use mockall_double::double;
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread::{self, JoinHandle};
 
+use crate::amphis_error::CrudError;
+use crate::column_family::{CfId, ColumnFamilies};
 use crate::config::Config;
-use crate::fptree::leaf_manager::NUM_SLOT;
-use crate::fptree::Leaf;
+use crate::fptree::{KvPair, Leaf};
 use crate::fptree_manager::FPTreeManager;
-use crate::sparse_index::SparseIndex;
-use crate::sstable_manager::{SstableManager, TableId, TableInfo};
+use crate::range_tombstone::{self, RangeTombstone};
+use crate::sstable_manager::{Segment, SstableManager, TableId, TableInfo, TableWriter};
 use crate::util::data_util;
 
 #[double]
 use crate::fptree::leaf_manager::LeafManager;
-
-const WRITE_BUFFER_SIZE: usize = 1 << 18;
+use crate::util::lock_util::RwLockExt;
 
 #[derive(Debug, Clone)]
 pub enum FlushSignal {
-    TryFlush,
+    TryFlush(CfId),
+    /// Flush the current FPTree and reply the result
+    Flush(CfId, Sender<Result<(), CrudError>>),
+    /// Flush all column families except ones reopened on restart, and stop the thread
     Shutdown,
 }
 
 pub fn spawn_flush_writer(
-    mut flush_writer: FlushWriter,
     receiver: Receiver<FlushSignal>,
-    fptree_manager: Arc<FPTreeManager>,
-    sstable_manager: Arc<SstableManager>,
+    column_families: ColumnFamilies,
 ) -> JoinHandle<()> {
     thread::spawn(move || {
+        let get_cf = |id: CfId| column_families.read_or_recover()[id].clone();
         for signal in receiver {
             match signal {
-                FlushSignal::TryFlush => {
-                    // TODO: error handling
-                    if let Some(first_leaf) = fptree_manager.prepare_flush().unwrap() {
-                        let table_info = flush_writer.flush(first_leaf).unwrap();
-                        sstable_manager.register(table_info).unwrap();
-                        fptree_manager.switch_fptree().unwrap();
+                FlushSignal::TryFlush(id) => {
+                    if let Err(e) = get_cf(id).flush_fptree(false) {
+                        error!("Flush failed: {}", e);
                     }
                 }
+                FlushSignal::Flush(id, reply) => {
+                    let _ = reply.send(get_cf(id).flush_fptree(true));
+                }
                 FlushSignal::Shutdown => break,
             }
         }
+
+        let column_families = column_families.read_or_recover().clone();
+        for column_family in column_families {
+            if let Err(e) = column_family.flush_on_shutdown() {
+                error!("Flush failed: {}", e);
+            }
+        }
     })
 }
 
+/// Return whether a new table has been registered
+pub fn flush_fptree(
+    flush_writer: &mut FlushWriter,
+    fptree_manager: &FPTreeManager,
+    sstable_manager: &SstableManager,
+    force: bool,
+) -> Result<bool, CrudError> {
+    match fptree_manager.prepare_flush(force)? {
+        Some((first_leaf, range_tombstones)) => {
+            let table_info = flush_writer.flush(first_leaf, range_tombstones)?;
+            sstable_manager.register(table_info)?;
+            fptree_manager.switch_fptree()?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
 pub struct FlushWriter {
     name: String,
     config: Config,
     table_id: TableId,
+    /// The number of flushed FPTrees
+    flush_count: Arc<AtomicU64>,
 }
 
 impl FlushWriter {
@@ -61,20 +88,30 @@ impl FlushWriter {
             name: name.to_string(),
             config,
             table_id,
+            flush_count: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// Return the counter of flushes which can be read without locking the writer
+    pub fn get_flush_count(&self) -> Arc<AtomicU64> {
+        self.flush_count.clone()
+    }
+
     /// flush the current tree
-    pub fn flush(&mut self, first_leaf: Arc<RwLock<Leaf>>) -> Result<TableInfo, std::io::Error> {
+    pub fn flush(
+        &mut self,
+        first_leaf: Arc<RwLock<Leaf>>,
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> Result<TableInfo, CrudError> {
         debug!(
             "Starting flush FPTree of {} to SSTable ID {}",
             self.name, self.table_id
         );
-        let leaf_manager = first_leaf.read().unwrap().get_leaf_manager();
-        let id_list = leaf_manager.read().unwrap().get_leaf_id_chain();
+        let leaf_manager = first_leaf.read_or_recover().get_leaf_manager();
+        let id_list = leaf_manager.read_or_recover().get_leaf_id_chain();
         trace!("leaf ID list: {:?}", id_list);
 
-        self.flush_kv(leaf_manager, id_list)
+        self.flush_kv(leaf_manager, id_list, range_tombstones)
     }
 
     /// flush all leaves in a leaf file
@@ -82,74 +119,435 @@ impl FlushWriter {
         &mut self,
         name: &str,
         fptree_id: usize,
-    ) -> Result<TableInfo, std::io::Error> {
+    ) -> Result<TableInfo, CrudError> {
         let leaf_manager = LeafManager::new(name, fptree_id, &self.config)?;
         let id_list = leaf_manager.get_leaf_id_chain();
         debug!("leaf ID list: {:?}", id_list);
+        let range_tombstones =
+            range_tombstone::load(&self.config.get_range_tombstone_file_path(name, fptree_id))?;
+
+        self.flush_kv(
+            Arc::new(RwLock::new(leaf_manager)),
+            id_list,
+            range_tombstones,
+        )
+    }
+
+    /// Write key-value pairs in the strictly ascending key order to a new table
+    /// Return `None` when no pair is given
+    /// All pairs are stamped with `sequence`
+    pub fn write_sorted(
+        &mut self,
+        pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), CrudError>>,
+        sequence: u64,
+    ) -> Result<Option<TableInfo>, CrudError> {
+        // the filter is made for the expected number of pairs if it's known
+        let items_count = pairs
+            .size_hint()
+            .0
+            .max(self.config.get_filter_items_count());
+        debug!(
+            "Writing sorted pairs of {} to SSTable ID {}",
+            self.name, self.table_id
+        );
+        let mut writer = self.create_new_table(items_count)?;
+        let mut last_key: Option<Vec<u8>> = None;
+        for pair in pairs {
+            let (key, value) = match pair {
+                Ok(pair) => pair,
+                Err(e) => {
+                    writer.abort()?;
+                    return Err(e);
+                }
+            };
+            if last_key.as_ref().is_some_and(|last| key <= *last) {
+                writer.abort()?;
+                return Err(CrudError::InvalidInput(format!(
+                    "key {:?} isn't larger than the previous key",
+                    key
+                )));
+            }
+            let value = data_util::stamp_sequence(&data_util::encode_value(&value, None), sequence);
+            if let Err(e) = writer.add(&key, &value) {
+                writer.abort()?;
+                return Err(e.into());
+            }
+            last_key = Some(key);
+        }
+        if writer.entry_count() == 0 {
+            writer.abort()?;
+            return Ok(None);
+        }
 
-        self.flush_kv(Arc::new(RwLock::new(leaf_manager)), id_list)
+        Ok(Some(writer.finish(Vec::new())?))
     }
 
-    fn create_new_table(&mut self) -> Result<(TableId, File), std::io::Error> {
+    /// Create a writer of a Level 0 table
+    fn create_new_table(&mut self, items_count: usize) -> Result<TableWriter, CrudError> {
         let id = self.table_id;
-        let table_file_path = self.config.get_table_file_path(&self.name, id);
-        let table_file = File::create(table_file_path)?;
+        let writer = TableWriter::new(
+            id,
+            &self.config.get_table_file_path(&self.name, id),
+            0,
+            items_count,
+            &self.config,
+        )?;
 
         // odd ID used by compactions
         self.table_id += 2;
 
-        Ok((id, table_file))
+        Ok(writer)
     }
 
+    /// Write all pairs in the leaves to a new table
+    ///
+    /// Each pair is checked by its CRC when it's read from the leaf, so the pair written to
+    /// the table is valid and is protected by the CRC of its block. The CRC of each block is
+    /// computed only once, and the CRC of the whole table is combined from them.
     fn flush_kv(
         &mut self,
         leaf_manager: Arc<RwLock<LeafManager>>,
         id_list: Vec<usize>,
-    ) -> Result<TableInfo, std::io::Error> {
-        let mut offset = 0;
-        let (table_id, table_file) = self.create_new_table()?;
-        let mut index = SparseIndex::new();
-        let mut filter = Bloom::new_for_fp_rate(
-            self.config.get_filter_items_count(),
-            self.config.get_filter_fp_rate(),
-        );
-        let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, &table_file);
-        for id in id_list {
-            let header = leaf_manager
-                .read()
-                .unwrap()
-                .get_header(id)
-                .expect("The header doesn't exist");
-            let mut kv_pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(NUM_SLOT);
-            for slot in 0..NUM_SLOT {
-                if header.is_slot_set(slot) {
-                    let (page_id, data_offset, key_size, value_size) = header.get_kv_info(slot);
-                    let (key, value) = leaf_manager.read().unwrap().read_data(
-                        page_id,
-                        data_offset,
-                        key_size,
-                        value_size,
-                    )?;
-                    kv_pairs.push((key, value));
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> Result<TableInfo, CrudError> {
+        // the filter is made for the number of pairs in the leaves
+        let items_count = count_pairs(&leaf_manager, &id_list).max(1);
+        let mut writer = self.create_new_table(items_count)?;
+        let format_version = leaf_manager.read_or_recover().get_format_version();
+        let now = data_util::current_millis();
+        let parallelism = self.config.get_flush_parallelism().min(id_list.len());
+        if parallelism > 1 {
+            // each thread writes a segment of consecutive leaves
+            let chunk_size = id_list.len().div_ceil(parallelism);
+            let segments = thread::scope(|s| {
+                let mut handles = Vec::new();
+                for (i, ids) in id_list.chunks(chunk_size).enumerate() {
+                    let mut segment = writer.new_segment(i)?;
+                    let leaf_manager = &leaf_manager;
+                    handles.push(s.spawn(move || -> Result<Segment, CrudError> {
+                        for &id in ids {
+                            for (key, value) in read_leaf(leaf_manager, id, format_version, now)? {
+                                segment.add(&key, &value)?;
+                            }
+                        }
+                        Ok(segment.finish()?)
+                    }));
                 }
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("the segment writer panicked"))
+                    .collect::<Result<Vec<_>, CrudError>>()
+            })?;
+            for segment in segments {
+                writer.append_segment(segment)?;
             }
-            // it is enough to sort only kv_pairs since all leaves are ordered
-            kv_pairs.sort();
-            for (key, value) in kv_pairs {
-                filter.set(&key);
-                index.insert(&key, offset);
-                offset += data_util::get_data_size(key.len(), value.len());
-                writer.write_all(&data_util::format_data_with_crc(&key, &value))?;
+        } else {
+            for id in id_list {
+                for (key, value) in read_leaf(&leaf_manager, id, format_version, now)? {
+                    writer.add(&key, &value)?;
+                }
             }
         }
-        table_file.sync_all()?;
-
-        Ok(TableInfo {
-            id: table_id,
-            size: table_file.metadata()?.len() as _,
-            level: 0,
-            filter,
-            index,
+
+        let table_info = writer.finish(range_tombstones)?;
+        self.flush_count.fetch_add(1, Ordering::Relaxed);
+        for listener in self.config.get_listeners() {
+            listener.on_flush(table_info.id, table_info.entry_count, table_info.size);
+        }
+
+        Ok(table_info)
+    }
+}
+
+/// Count key-value pairs including tombstones in the leaves
+fn count_pairs(leaf_manager: &RwLock<LeafManager>, id_list: &[usize]) -> usize {
+    let leaf_manager = leaf_manager.read_or_recover();
+    id_list
+        .iter()
+        .map(|&id| {
+            leaf_manager
+                .get_header(id)
+                .expect("The header doesn't exist")
+                .count_set_slots()
         })
+        .sum()
+}
+
+/// Read key-value pairs of the leaf in the key order
+/// Values are converted to the current format, and expired values to tombstones
+fn read_leaf(
+    leaf_manager: &RwLock<LeafManager>,
+    id: usize,
+    format_version: u8,
+    now: u64,
+) -> Result<Vec<KvPair>, CrudError> {
+    let header = leaf_manager
+        .read_or_recover()
+        .get_header(id)
+        .expect("The header doesn't exist");
+    let num_slot = header.get_num_slot();
+    let mut kv_pairs: Vec<KvPair> = Vec::with_capacity(num_slot);
+    for slot in 0..num_slot {
+        if header.is_slot_set(slot) {
+            let (page_id, data_offset, key_size, value_size) = header.get_kv_info(slot);
+            let leaf_manager = leaf_manager.read_or_recover();
+            let (key, mut value) =
+                leaf_manager.read_data(page_id, data_offset, key_size, value_size)?;
+            if header.is_overflow(slot) {
+                value = leaf_manager.read_overflow(&value)?;
+            }
+            kv_pairs.push((key, value));
+        }
+    }
+    // it is enough to sort only kv_pairs since all leaves are ordered
+    kv_pairs.sort();
+    for (_, value) in kv_pairs.iter_mut() {
+        *value = data_util::upgrade_value(format_version, std::mem::take(value));
+        if !data_util::is_tombstone(value) && data_util::get_live_value(value, now)?.is_none() {
+            // keep the expired key as a tombstone to hide older values
+            *value = data_util::to_tombstone(value)?;
+        }
+    }
+
+    Ok(kv_pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fptree::leaf_manager::{LeafHeader, DEFAULT_LEAF_SIZE, DEFAULT_NUM_SLOT};
+
+    #[test]
+    fn test_flush_kv() {
+        let config = Config::new_for_testing();
+        let (sstable_manager, table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+
+        // leaf 0 has keys 0..32 and leaf 1 has keys 32..64 in a reversed order
+        let mut leaf_manager = LeafManager::default();
+        leaf_manager
+            .expect_get_format_version()
+            .return_const(data_util::FORMAT_VERSION);
+        leaf_manager.expect_get_header().returning(|id| {
+            let mut header = LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE);
+            for slot in 0..32 {
+                header.set_slot(slot);
+                header.set_kv_info(slot, id, id * 32 + 31 - slot, 6, 8);
+            }
+            Some(header)
+        });
+        leaf_manager
+            .expect_read_data()
+            .returning(|_, offset, _, _| {
+                let key = format!("key{:03}", offset).into_bytes();
+                let value = data_util::encode_value(format!("val{:03}", offset).as_bytes(), None);
+                Ok((key, value))
+            });
+
+        let mut flush_writer = FlushWriter::new("test", config, table_id);
+        let table_info = flush_writer
+            .flush_kv(Arc::new(RwLock::new(leaf_manager)), vec![0, 1], Vec::new())
+            .expect("flush failed");
+        assert_eq!(table_info.id, table_id);
+        sstable_manager
+            .register(table_info)
+            .expect("register failed");
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = sstable_manager
+            .table_iter(table_id)
+            .expect("cannot open")
+            .map(|kv| kv.expect("read failed"))
+            .collect();
+        assert_eq!(pairs.len(), 64);
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            assert_eq!(key, format!("key{:03}", i).into_bytes());
+            assert_eq!(
+                data_util::get_live_value(&value, 0).expect("invalid value"),
+                Some(format!("val{:03}", i).as_bytes())
+            );
+        }
+
+        assert!(sstable_manager.table_iter(table_id + 2).is_err());
+    }
+
+    #[test]
+    fn test_filter_size() {
+        let config = Config::new_for_testing();
+        let (_sstable_manager, table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+
+        // each leaf has 32 keys
+        let mut leaf_manager = LeafManager::default();
+        leaf_manager
+            .expect_get_format_version()
+            .return_const(data_util::FORMAT_VERSION);
+        leaf_manager.expect_get_header().returning(|id| {
+            let mut header = LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE);
+            for slot in 0..32 {
+                header.set_slot(slot);
+                header.set_kv_info(slot, id, id * 32 + slot, 8, 8);
+            }
+            Some(header)
+        });
+        leaf_manager
+            .expect_read_data()
+            .returning(|_, offset, _, _| {
+                let key = format!("key{:05}", offset).into_bytes();
+                let value = data_util::encode_value(b"value", None);
+                Ok((key, value))
+            });
+        let leaf_manager = Arc::new(RwLock::new(leaf_manager));
+
+        let mut flush_writer = FlushWriter::new("test", config.clone(), table_id);
+        let small = flush_writer
+            .flush_kv(leaf_manager.clone(), vec![0], Vec::new())
+            .expect("flush failed");
+        let large = flush_writer
+            .flush_kv(leaf_manager, (0..1000).collect(), Vec::new())
+            .expect("flush failed");
+        for (table_info, entry_count) in [(&small, 32), (&large, 32000)] {
+            assert_eq!(table_info.entry_count, entry_count);
+            let params = table_info.filter_params.expect("no filter params");
+            assert_eq!(params.items_count, entry_count);
+        }
+        // not made for the static `items_count`
+        let expected_bits = |items_count: usize| {
+            let bits = -(items_count as f64) * config.get_filter_fp_rate().ln() / 2f64.ln().powi(2);
+            bits.ceil() as u64
+        };
+        assert!(small.filter.number_of_bits() < expected_bits(64));
+        assert!(large.filter.number_of_bits() >= expected_bits(32000));
+        let ratio = large.filter.number_of_bits() as f64 / small.filter.number_of_bits() as f64;
+        assert!((900.0..1100.0).contains(&ratio), "ratio {}", ratio);
+    }
+
+    #[test]
+    fn test_parallel_flush() {
+        let builder = Config::builder_for_testing().block_size(256);
+        let config = builder.clone().build();
+        let (sstable_manager, table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+
+        // each of 10 leaves has 32 keys in a reversed order
+        let mut leaf_manager = LeafManager::default();
+        leaf_manager
+            .expect_get_format_version()
+            .return_const(data_util::FORMAT_VERSION);
+        leaf_manager.expect_get_header().returning(|id| {
+            let mut header = LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE);
+            for slot in 0..32 {
+                header.set_slot(slot);
+                header.set_kv_info(slot, id, id * 32 + 31 - slot, 6, 8);
+            }
+            Some(header)
+        });
+        leaf_manager
+            .expect_read_data()
+            .returning(|_, offset, _, _| {
+                let key = format!("key{:03}", offset).into_bytes();
+                let value = data_util::encode_value(format!("val{:03}", offset).as_bytes(), None);
+                Ok((key, value))
+            });
+        let leaf_manager = Arc::new(RwLock::new(leaf_manager));
+        let id_list: Vec<usize> = (0..10).collect();
+
+        let mut serial_writer = FlushWriter::new("test", config.clone(), table_id);
+        let serial_info = serial_writer
+            .flush_kv(leaf_manager.clone(), id_list.clone(), Vec::new())
+            .expect("flush failed");
+        let parallel_config = builder.flush_parallelism(4).build();
+        let mut parallel_writer = FlushWriter::new("test", parallel_config, table_id + 2);
+        let parallel_info = parallel_writer
+            .flush_kv(leaf_manager, id_list, Vec::new())
+            .expect("flush failed");
+        assert_eq!(parallel_info.entry_count, serial_info.entry_count);
+        assert_eq!(parallel_info.key_range, serial_info.key_range);
+        sstable_manager
+            .register(serial_info)
+            .expect("register failed");
+        sstable_manager
+            .register(parallel_info)
+            .expect("register failed");
+        // the checksum combined from the segments
+        for id in [table_id, table_id + 2] {
+            sstable_manager
+                .verify_table(id)
+                .expect("verification failed");
+        }
+
+        let read_table = |id| -> Vec<(Vec<u8>, Vec<u8>)> {
+            sstable_manager
+                .table_iter(id)
+                .expect("cannot open")
+                .map(|kv| kv.expect("read failed"))
+                .collect()
+        };
+        let pairs = read_table(table_id + 2);
+        assert_eq!(pairs.len(), 320);
+        assert_eq!(pairs, read_table(table_id));
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            assert_eq!(key, format!("key{:03}", i).into_bytes());
+            assert_eq!(sstable_manager.get(&key).expect("read failed"), Some(value));
+        }
+
+        // all segments have been removed
+        for entry in
+            std::fs::read_dir(config.get_table_dir_path("test")).expect("cannot read the directory")
+        {
+            let path = entry.expect("cannot read the entry").path();
+            assert_ne!(path.extension(), Some("segment".as_ref()));
+        }
+    }
+
+    #[test]
+    fn test_flush_checksum_bytes() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (sstable_manager, table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+
+        // each of 100 leaves has 32 keys with 200-byte values
+        let mut leaf_manager = LeafManager::default();
+        leaf_manager
+            .expect_get_format_version()
+            .return_const(data_util::FORMAT_VERSION);
+        leaf_manager.expect_get_header().returning(|id| {
+            let mut header = LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE);
+            for slot in 0..32 {
+                header.set_slot(slot);
+                header.set_kv_info(slot, id, id * 32 + slot, 8, 201);
+            }
+            Some(header)
+        });
+        leaf_manager
+            .expect_read_data()
+            .returning(|_, offset, _, _| {
+                let key = format!("key{:05}", offset).into_bytes();
+                Ok((key, data_util::encode_value(&[b'v'; 200], None)))
+            });
+
+        let mut flush_writer = FlushWriter::new("test", config, table_id);
+        let before = data_util::CHECKSUM_BYTES.with(|bytes| bytes.get());
+        let table_info = flush_writer
+            .flush_kv(
+                Arc::new(RwLock::new(leaf_manager)),
+                (0..100).collect(),
+                Vec::new(),
+            )
+            .expect("flush failed");
+        let hashed = data_util::CHECKSUM_BYTES.with(|bytes| bytes.get()) - before;
+        debug!(
+            "CRCs of {} bytes were computed for the table of {} bytes",
+            hashed, table_info.size
+        );
+        // each byte is hashed once, not for both the CRC of its block and the whole table
+        assert!(hashed <= table_info.size);
+        assert!(hashed > table_info.size * 9 / 10);
+
+        sstable_manager
+            .register(table_info)
+            .expect("register failed");
+        sstable_manager
+            .verify_table(table_id)
+            .expect("verification failed");
     }
 }
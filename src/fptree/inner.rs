@@ -1,11 +1,16 @@
 use log::trace;
+use std::io::Write;
 use std::sync::Arc;
 use std::sync::RwLock;
 
 use super::node::Node;
+use crate::util::lock_util::RwLockExt;
 
 const FANOUT: usize = 3;
 
+/// A node paired with its minimum key
+pub type KeyedNode = (Vec<u8>, Arc<RwLock<dyn Node + Send + Sync>>);
+
 pub struct Inner {
     keys: Vec<Vec<u8>>,
     children: Vec<Arc<RwLock<dyn Node + Send + Sync>>>,
@@ -63,7 +68,7 @@ impl Node for Inner {
     ) -> Result<Option<Vec<u8>>, std::io::Error> {
         let mut ret: Option<Vec<u8>> = None;
         let child = self.get_child(key).unwrap();
-        let new_child = child.read().unwrap().get_next().unwrap();
+        let new_child = child.read_or_recover().get_next().unwrap();
 
         match self.keys.binary_search(&inserted_key.to_vec()) {
             Ok(_) => panic!("should not reach here"),
@@ -86,15 +91,22 @@ impl Node for Inner {
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
         match self.get_child(key) {
-            Some(c) => c.read().unwrap().get(key),
+            Some(c) => c.read_or_recover().get(key),
             None => Ok(None),
         }
     }
 
+    fn remove(&mut self, key: &[u8]) -> Result<(), std::io::Error> {
+        match self.get_child(key) {
+            Some(c) => c.write_or_recover().remove(key),
+            None => Ok(()),
+        }
+    }
+
     fn split(&mut self) -> Result<Vec<u8>, std::io::Error> {
-        let new_keys = self.keys.split_off((FANOUT + 1) / 2);
+        let new_keys = self.keys.split_off(FANOUT.div_ceil(2));
         let split_key = new_keys.first().unwrap().clone();
-        let new_children = self.children.split_off((FANOUT + 1) / 2 + 1);
+        let new_children = self.children.split_off(FANOUT.div_ceil(2) + 1);
 
         let mut new_inner = Inner::new();
         for new_key in new_keys.into_iter().skip(1) {
@@ -118,6 +130,15 @@ impl Node for Inner {
         // nothing to do for the inner
         Ok(())
     }
+
+    fn dump(&self, w: &mut dyn Write, depth: usize) -> Result<(), std::io::Error> {
+        writeln!(w, "{:indent$}inner {}", "", self, indent = depth * 2)?;
+        for child in &self.children {
+            child.read_or_recover().dump(w, depth + 1)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Inner {
@@ -143,6 +164,33 @@ impl Inner {
     }
 }
 
+/// Build the parent level of `nodes` in the key order
+pub fn build_parents(mut nodes: Vec<KeyedNode>) -> Vec<KeyedNode> {
+    let mut parents = Vec::new();
+    while !nodes.is_empty() {
+        // leave a room for a split, but a parent shouldn't have only one child
+        let num_children = if nodes.len() <= FANOUT + 1 {
+            nodes.len()
+        } else {
+            FANOUT
+        };
+        let rest = nodes.split_off(num_children);
+        let mut children = nodes.into_iter();
+        let (min_key, first) = children.next().expect("no child");
+        let mut inner = Inner::new();
+        inner.add_child(first);
+        for (key, child) in children {
+            inner.add_key(key);
+            inner.add_child(child);
+        }
+        let parent: Arc<RwLock<dyn Node + Send + Sync>> = Arc::new(RwLock::new(inner));
+        parents.push((min_key, parent));
+        nodes = rest;
+    }
+
+    parents
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,22 +230,25 @@ mod tests {
                 Ok(None)
             }
         }
+        fn remove(&mut self, _key: &[u8]) -> Result<(), std::io::Error> {
+            Ok(())
+        }
         fn split(&mut self) -> Result<Vec<u8>, std::io::Error> {
             Ok(Vec::new())
         }
         fn commit(&self) -> Result<(), std::io::Error> {
             Ok(())
         }
+        fn dump(&self, w: &mut dyn Write, depth: usize) -> Result<(), std::io::Error> {
+            writeln!(w, "{:indent$}leaf {}", "", self.val, indent = depth * 2)
+        }
     }
 
     #[test]
     fn test_get_next() {
         // a new inner doesn't have the next
         let mut inner = Inner::new();
-        let not_exists = match inner.get_next() {
-            Some(_) => false,
-            None => true,
-        };
+        let not_exists = inner.get_next().is_none();
         assert!(not_exists);
 
         // added the next
@@ -211,33 +262,33 @@ mod tests {
     #[test]
     fn test_get_child() {
         let mut inner = Inner::new();
-        inner.add_key(vec![10 as u8]);
+        inner.add_key(vec![10_u8]);
 
         let mut new_child1 = Inner::new();
-        new_child1.add_key(vec![1 as u8]);
+        new_child1.add_key(vec![1_u8]);
         let arc_new_child1: Arc<RwLock<dyn Node + Send + Sync>> = Arc::new(RwLock::new(new_child1));
         inner.add_child(arc_new_child1.clone());
 
         let mut new_child2 = Inner::new();
-        new_child2.add_key(vec![11 as u8]);
+        new_child2.add_key(vec![11_u8]);
         let arc_new_child2: Arc<RwLock<dyn Node + Send + Sync>> = Arc::new(RwLock::new(new_child2));
         inner.add_child(arc_new_child2.clone());
 
-        let child1 = inner.get_child(&vec![0u8]).unwrap();
+        let child1 = inner.get_child(&[0u8]).unwrap();
         assert!(Arc::ptr_eq(&child1, &arc_new_child1));
-        let child2 = inner.get_child(&vec![11u8]).unwrap();
+        let child2 = inner.get_child(&[11u8]).unwrap();
         assert!(Arc::ptr_eq(&child2, &arc_new_child2));
     }
 
     #[test]
     fn test_need_split() {
         let mut inner = Inner::new();
-        assert_eq!(inner.need_split(), false);
+        assert!(!inner.need_split());
 
         for i in 0..(FANOUT + 1) {
             inner.add_key(vec![i as u8]);
         }
-        assert_eq!(inner.need_split(), true);
+        assert!(inner.need_split());
     }
 
     #[test]
@@ -255,10 +306,7 @@ mod tests {
         let inserted = "key1".as_bytes().to_vec();
         inner.insert(&key0, &inserted).unwrap();
 
-        let not_exists = match inner.get_next() {
-            Some(_) => false,
-            None => true,
-        };
+        let not_exists = inner.get_next().is_none();
         assert!(not_exists);
         assert_eq!(inner.keys.len(), 3);
         assert_eq!(inner.keys[0], key0);
@@ -327,4 +375,38 @@ mod tests {
             panic!("the next inner should exist");
         }
     }
+
+    #[test]
+    fn test_build_parents() {
+        let leaves: Vec<Arc<RwLock<dyn Node + Send + Sync>>> = (0..8)
+            .map(|i| Arc::new(RwLock::new(MockLeaf { val: i })) as _)
+            .collect();
+        let nodes = leaves
+            .iter()
+            .enumerate()
+            .map(|(i, leaf)| (vec![i as u8 * 10], leaf.clone()))
+            .collect();
+
+        // 8 leaves are grouped into 3, 3 and 2 children
+        let parents = build_parents(nodes);
+        assert_eq!(parents.len(), 3);
+        assert_eq!(parents[1].0, vec![30]);
+        let root = build_parents(parents);
+        assert_eq!(root.len(), 1);
+        assert_eq!(root[0].0, vec![0]);
+
+        let route = |key: u8| {
+            let mut node = root[0].1.clone();
+            while !node.read().unwrap().is_leaf() {
+                let child = node.read().unwrap().get_child(&[key]).unwrap();
+                node = child;
+            }
+            node
+        };
+        for (i, leaf) in leaves.iter().enumerate() {
+            let key = i as u8 * 10;
+            assert!(Arc::ptr_eq(&route(key), leaf));
+            assert!(Arc::ptr_eq(&route(key + 9), leaf));
+        }
+    }
 }
@@ -1,6 +1,5 @@
 use log::trace;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
+use std::io::Write;
 use std::sync::{Arc, RwLock};
 
 cfg_if::cfg_if! {
@@ -10,8 +9,13 @@ cfg_if::cfg_if! {
         use crate::fptree::leaf_manager::LeafManager;
     }
 }
-use super::leaf_manager::{LeafHeader, INITIAL_TAIL_OFFSET, NUM_SLOT};
+use super::leaf_manager::{
+    get_end_tail_offset, get_max_data_size, LeafHeader, OverflowRef, INITIAL_TAIL_OFFSET,
+};
 use super::node::Node;
+use crate::amphis_error::CorruptionError;
+use crate::util::data_util;
+use crate::util::lock_util::RwLockExt;
 
 type KvPair = (Vec<u8>, Vec<u8>, usize);
 
@@ -22,6 +26,10 @@ pub struct Leaf {
     page_id: usize,
     next: Option<Arc<RwLock<Leaf>>>,
     is_root: bool,
+    /// Extension pages without valid data, which are freed after the header is committed
+    unused_pages: Vec<usize>,
+    /// The alignment of key-value pairs written by the leaf manager
+    data_alignment: usize,
 }
 
 impl Node for Leaf {
@@ -53,80 +61,56 @@ impl Node for Leaf {
     }
 
     fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
-        let mut ret: Option<Vec<u8>> = None;
-
-        self.invalidate_data(key)?;
-
-        if self.header.need_split() {
-            let split_key = self.split()?;
-            let new_leaf = self.get_next().expect("no next leaf");
-            if split_key.as_slice() < key {
-                self.commit()?;
-
-                // TODO: when the new leaf is split
-                new_leaf.write().unwrap().insert(key, value)?;
-                return Ok(Some(split_key));
-            } else {
-                new_leaf.read().unwrap().commit()?;
-            }
-
-            ret = Some(split_key);
+        let data_size = data_util::get_data_size(key.len(), value.len());
+        if data_util::round_up_size(data_size, self.data_alignment)
+            > get_max_data_size(self.header.get_leaf_size())
+        {
+            // the leaf page has only the reference to the pages of the value
+            let overflow_ref = self.leaf_manager.write_or_recover().write_overflow(value)?;
+            return self.insert_stored(key, &overflow_ref, true);
         }
 
-        let slot = self.header.get_empty_slot().expect("no empty slot");
-        loop {
-            let offset = self.header.get_tail_offset();
-            let tail_offset =
-                self.leaf_manager
-                    .read()
-                    .unwrap()
-                    .write_data(self.page_id, offset, key, value)?;
-            match tail_offset {
-                Some(tail_offset) => {
-                    self.update_header_for_write(slot, tail_offset, key, value);
-                    break;
-                }
-                None => {
-                    // not enough space to write
-                    self.append_new_page()?;
-                }
-            }
-        }
-        self.commit()?;
-
-        trace!("Leaf: {}, key {:?}", self, key);
-        Ok(ret)
+        self.insert_stored(key, value, false)
     }
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
         trace!("Read from Leaf: {}", self);
         for slot in self.get_existing_slots(key) {
             let (page_id, data_offset, key_size, value_size) = self.header.get_kv_info(slot);
-            let (actual_key, value) = self.leaf_manager.read().unwrap().read_data(
-                page_id,
-                data_offset,
-                key_size,
-                value_size,
-            )?;
+            let leaf_manager = self.leaf_manager.read_or_recover();
+            let (actual_key, value) =
+                leaf_manager.read_data(page_id, data_offset, key_size, value_size)?;
             if actual_key == *key {
-                return Ok(Some(value.clone()));
+                if self.header.is_overflow(slot) {
+                    return Ok(Some(leaf_manager.read_overflow(&value)?));
+                }
+                return Ok(Some(value));
             }
         }
 
         Ok(None)
     }
 
+    fn remove(&mut self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.invalidate_data(key)?;
+        self.commit()?;
+        self.free_unused_pages();
+
+        Ok(())
+    }
+
     fn split(&mut self) -> Result<Vec<u8>, std::io::Error> {
         let mut new_leaf = Leaf::new(self.leaf_manager.clone())?;
 
-        let mut kv_pairs = self.get_kv_pairs()?;
+        // values in overflow pages are moved with their references
+        let mut kv_pairs = self.get_stored_pairs()?;
         kv_pairs.sort();
         let new_first = kv_pairs.len() / 2;
         let split_key = kv_pairs[new_first].0.clone();
 
         for (k, v, slot) in kv_pairs.split_off(new_first) {
-            new_leaf.insert(&k, &v)?;
-            self.header.unset_slot(slot);
+            new_leaf.insert_stored(&k, &v, self.header.is_overflow(slot))?;
+            self.unset_slot(slot);
         }
 
         if let Some(n) = &self.next {
@@ -149,15 +133,27 @@ impl Node for Leaf {
 
     fn commit(&self) -> Result<(), std::io::Error> {
         self.leaf_manager
-            .read()
-            .unwrap()
+            .read_or_recover()
             .commit_header(self.id, &self.header)
     }
+
+    fn dump(&self, w: &mut dyn Write, depth: usize) -> Result<(), std::io::Error> {
+        writeln!(
+            w,
+            "{:indent$}leaf {}: {}/{} slots",
+            "",
+            self.id,
+            self.len(),
+            self.header.get_num_slot(),
+            indent = depth * 2
+        )
+    }
 }
 
 impl Leaf {
     pub fn new(leaf_manager: Arc<RwLock<LeafManager>>) -> Result<Self, std::io::Error> {
-        let (id, header) = leaf_manager.write().unwrap().allocate_leaf()?;
+        let (id, header) = leaf_manager.write_or_recover().allocate_leaf()?;
+        let data_alignment = leaf_manager.read_or_recover().get_data_alignment();
 
         Ok(Leaf {
             leaf_manager,
@@ -166,6 +162,34 @@ impl Leaf {
             page_id: id,
             next: None,
             is_root: false,
+            unused_pages: Vec::new(),
+            data_alignment,
+        })
+    }
+
+    /// Load the persisted leaf followed by `next`
+    pub fn open(
+        leaf_manager: Arc<RwLock<LeafManager>>,
+        id: usize,
+        next: Option<Arc<RwLock<Leaf>>>,
+    ) -> Result<Self, std::io::Error> {
+        let header = leaf_manager
+            .read_or_recover()
+            .get_header(id)
+            .ok_or_else(|| CorruptionError(format!("no header of leaf {}", id)))?;
+        // new data is appended to the last extension page
+        let page_id = header.get_ext().unwrap_or(id);
+        let data_alignment = leaf_manager.read_or_recover().get_data_alignment();
+
+        Ok(Leaf {
+            leaf_manager,
+            header,
+            id,
+            page_id,
+            next,
+            is_root: false,
+            unused_pages: Vec::new(),
+            data_alignment,
         })
     }
 
@@ -173,14 +197,125 @@ impl Leaf {
         self.leaf_manager.clone()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty()
+    }
+
+    /// The number of key-value pairs including tombstones
+    pub fn len(&self) -> usize {
+        self.header.count_set_slots()
+    }
+
+    /// The occupancy of slots as `#` for a used slot and `.` for an empty one
+    pub fn get_slot_map(&self) -> String {
+        (0..self.header.get_num_slot())
+            .map(|slot| {
+                if self.header.is_slot_set(slot) {
+                    '#'
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_id(&self) -> usize {
+        self.id
+    }
+
+    pub fn get_next_leaf(&self) -> Option<Arc<RwLock<Leaf>>> {
+        self.next.clone()
+    }
+
+    /// Insert the pair with the value stored in the leaf page
+    /// `value` is an `OverflowRef` when `overflow` is true
+    fn insert_stored(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        overflow: bool,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut ret: Option<Vec<u8>> = None;
+
+        // compact before invalidating the old value not to overwrite committed data
+        if self.needs_compaction(key, value) {
+            self.compact()?;
+        }
+        self.invalidate_data(key)?;
+
+        if self.header.need_split() {
+            let split_key = self.split()?;
+            let new_leaf = self.get_next_leaf().expect("no next leaf");
+            if split_key.as_slice() < key {
+                self.commit()?;
+                self.free_unused_pages();
+
+                // the new leaf has only the upper half of the slots, so it isn't split again
+                let new_split_key = new_leaf
+                    .write_or_recover()
+                    .insert_stored(key, value, overflow)?;
+                if new_split_key.is_some() {
+                    // the parent can't take the second split key
+                    return Err(std::io::Error::other("the new leaf has been split again"));
+                }
+                return Ok(Some(split_key));
+            } else {
+                new_leaf.read_or_recover().commit()?;
+            }
+
+            ret = Some(split_key);
+        }
+
+        let slot = self.header.get_empty_slot().expect("no empty slot");
+        loop {
+            let offset = self.header.get_tail_offset();
+            let tail_offset =
+                self.leaf_manager
+                    .read_or_recover()
+                    .write_data(self.page_id, offset, key, value)?;
+            match tail_offset {
+                Some(tail_offset) => {
+                    self.update_header_for_write(slot, tail_offset, key, value);
+                    if overflow {
+                        self.header.set_overflow(slot);
+                    }
+                    break;
+                }
+                None => {
+                    // not enough space to write
+                    self.append_new_page()?;
+                }
+            }
+        }
+        self.commit()?;
+        self.free_unused_pages();
+
+        trace!("Leaf: {}, key {:?}", self, key);
+        Ok(ret)
+    }
+
     pub fn get_kv_pairs(&self) -> Result<Vec<KvPair>, std::io::Error> {
-        let mut kv_pairs: Vec<KvPair> = Vec::with_capacity(NUM_SLOT);
+        let mut kv_pairs = self.get_stored_pairs()?;
+        for (_, value, slot) in kv_pairs.iter_mut() {
+            if self.header.is_overflow(*slot) {
+                *value = self.leaf_manager.read_or_recover().read_overflow(value)?;
+            }
+        }
+
+        Ok(kv_pairs)
+    }
 
-        for slot in 0..NUM_SLOT {
+    /// Return the pairs with the values stored in the leaf pages
+    /// The value of an overflow slot is its `OverflowRef`
+    fn get_stored_pairs(&self) -> Result<Vec<KvPair>, std::io::Error> {
+        let num_slot = self.header.get_num_slot();
+        let mut kv_pairs: Vec<KvPair> = Vec::with_capacity(num_slot);
+
+        for slot in 0..num_slot {
             if self.header.is_slot_set(slot) {
                 let (page_id, data_offset, key_size, value_size) = self.header.get_kv_info(slot);
 
-                let (key, value) = self.leaf_manager.read().unwrap().read_data(
+                let (key, value) = self.leaf_manager.read_or_recover().read_data(
                     page_id,
                     data_offset,
                     key_size,
@@ -193,38 +328,33 @@ impl Leaf {
         Ok(kv_pairs)
     }
 
-    fn calc_key_hash(&self, key: &[u8]) -> u8 {
-        let mut hasher = DefaultHasher::new();
-        for b in key {
-            hasher.write_u8(*b);
-        }
-
-        hasher.finish() as u8
-    }
-
+    /// Return the slots whose fingerprints match the key
     fn get_existing_slots(&self, key: &[u8]) -> Vec<usize> {
-        let mut slots = Vec::new();
-        let hash = self.calc_key_hash(key);
-        for (slot, fp) in self.header.get_fingerprints().iter().enumerate() {
-            if self.header.is_slot_set(slot) && *fp == hash {
-                slots.push(slot);
-            }
-        }
-
-        slots
+        let fingerprint = self.header.calc_fingerprint(key);
+        (0..self.header.get_num_slot())
+            .filter(|slot| {
+                self.header.is_slot_set(*slot) && self.header.get_fingerprint(*slot) == fingerprint
+            })
+            .collect()
     }
 
     fn invalidate_data(&mut self, key: &[u8]) -> Result<(), std::io::Error> {
         for slot in self.get_existing_slots(key) {
             let (page_id, data_offset, key_size, value_size) = self.header.get_kv_info(slot);
-            let (actual_key, _value) = self.leaf_manager.read().unwrap().read_data(
+            let (actual_key, value) = self.leaf_manager.read_or_recover().read_data(
                 page_id,
                 data_offset,
                 key_size,
                 value_size,
             )?;
             if actual_key == *key {
-                self.header.unset_slot(slot);
+                if self.header.is_overflow(slot) {
+                    // the overflow pages are used only by this value
+                    let overflow_ref =
+                        OverflowRef::from_bytes(&value, self.header.get_leaf_size())?;
+                    self.unused_pages.extend(overflow_ref.get_page_ids());
+                }
+                self.unset_slot(slot);
                 break;
             }
         }
@@ -232,6 +362,110 @@ impl Leaf {
         Ok(())
     }
 
+    /// Unset the slot and collect its page if no data in the page is valid
+    fn unset_slot(&mut self, slot: usize) {
+        let (page_id, _, _, _) = self.header.get_kv_info(slot);
+        self.header.unset_slot(slot);
+        self.collect_unused_page(page_id);
+    }
+
+    fn collect_unused_page(&mut self, page_id: usize) {
+        // the leaf's own page has the header, and new data is appended to the current page
+        if page_id == self.id || page_id == self.page_id || self.unused_pages.contains(&page_id) {
+            return;
+        }
+        let in_use = (0..self.header.get_num_slot()).any(|slot| {
+            self.header.is_slot_set(slot) && self.header.get_kv_info(slot).0 == page_id
+        });
+        if !in_use {
+            self.unused_pages.push(page_id);
+        }
+    }
+
+    /// Return the unused pages to the leaf manager
+    /// This has to be called after the header which doesn't refer to them is committed
+    fn free_unused_pages(&mut self) {
+        if self.unused_pages.is_empty() {
+            return;
+        }
+        let mut leaf_manager = self.leaf_manager.write_or_recover();
+        for page_id in self.unused_pages.drain(..) {
+            leaf_manager.free_page(page_id);
+        }
+    }
+
+    /// Return true when the pair doesn't fit in the current page
+    /// and the live pairs take less than half of a page
+    fn needs_compaction(&self, key: &[u8], value: &[u8]) -> bool {
+        let aligned_size = |key_size, value_size| {
+            data_util::round_up_size(
+                data_util::get_data_size(key_size, value_size),
+                self.data_alignment,
+            )
+        };
+        let end_tail_offset = get_end_tail_offset(self.header.get_leaf_size());
+        let new_size = aligned_size(key.len(), value.len());
+        if self.header.get_tail_offset() + new_size <= end_tail_offset {
+            return false;
+        }
+
+        let live_size: usize = (0..self.header.get_num_slot())
+            .filter(|slot| self.header.is_slot_set(*slot))
+            .map(|slot| {
+                let (_, _, key_size, value_size) = self.header.get_kv_info(slot);
+                aligned_size(key_size, value_size)
+            })
+            .sum();
+
+        (live_size + new_size) * 2 <= end_tail_offset - INITIAL_TAIL_OFFSET
+    }
+
+    /// Rewrite the live pairs from the head of a page to reclaim space of invalidated data
+    /// The leaf's own page is reused when no live pair is in it
+    fn compact(&mut self) -> Result<(), std::io::Error> {
+        let mut old_pages = vec![self.page_id];
+        for slot in 0..self.header.get_num_slot() {
+            if self.header.is_slot_set(slot) {
+                old_pages.push(self.header.get_kv_info(slot).0);
+            }
+        }
+        let target = if self.page_id != self.id && !old_pages.contains(&self.id) {
+            self.id
+        } else {
+            self.leaf_manager.write_or_recover().allocate_ext_page()?
+        };
+        trace!("compact leaf {} to page {}", self.id, target);
+
+        // values in overflow pages aren't rewritten
+        let kv_pairs = self.get_stored_pairs()?;
+        self.page_id = target;
+        self.header.set_ext(target);
+        self.header.set_tail_offset(INITIAL_TAIL_OFFSET);
+        for (key, value, slot) in kv_pairs {
+            let offset = self.header.get_tail_offset();
+            let tail_offset = self
+                .leaf_manager
+                .read_or_recover()
+                .write_data(target, offset, &key, &value)?
+                .expect("live pairs should fit in a page");
+            let overflow = self.header.is_overflow(slot);
+            self.header
+                .set_kv_info(slot, target, offset, key.len(), value.len());
+            if overflow {
+                self.header.set_overflow(slot);
+            }
+            self.header.set_tail_offset(tail_offset);
+        }
+        self.commit()?;
+
+        for page_id in old_pages {
+            self.collect_unused_page(page_id);
+        }
+        self.free_unused_pages();
+
+        Ok(())
+    }
+
     fn update_header_for_write(
         &mut self,
         slot: usize,
@@ -241,19 +475,17 @@ impl Leaf {
     ) {
         let offset = self.header.get_tail_offset();
         self.header.set_slot(slot);
-        self.header.set_fingerprint(slot, self.calc_key_hash(key));
+        self.header
+            .set_fingerprint(slot, self.header.calc_fingerprint(key));
         self.header
             .set_kv_info(slot, self.page_id, offset, key.len(), value.len());
         self.header.set_tail_offset(tail_offset);
     }
 
     fn append_new_page(&mut self) -> Result<(), std::io::Error> {
-        let new_page_id = self
-            .leaf_manager
-            .write()
-            .unwrap()
-            .allocate_ext_page(self.id)?;
-        self.page_id = new_page_id;
+        let new_page_id = self.leaf_manager.write_or_recover().allocate_ext_page()?;
+        let prev_page_id = std::mem::replace(&mut self.page_id, new_page_id);
+        self.collect_unused_page(prev_page_id);
         self.header.set_tail_offset(INITIAL_TAIL_OFFSET);
         self.header.set_ext(new_page_id);
 
@@ -271,17 +503,41 @@ impl std::fmt::Display for Leaf {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::FingerprintHash;
+    use crate::fptree::leaf_manager::DEFAULT_NUM_SLOT as NUM_SLOT;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
     const DATA_UNIT: usize = 4 * 1024;
     const LEAF_SIZE: usize = 1024 * 1024;
 
     fn make_new_leaf(id: usize) -> Leaf {
+        make_new_leaf_with_fingerprint(id, FingerprintHash::Sip, 8)
+    }
+
+    fn make_new_leaf_with_fingerprint(
+        id: usize,
+        fingerprint_hash: FingerprintHash,
+        fingerprint_bits: usize,
+    ) -> Leaf {
         let mut mock_leaf_manager = LeafManager::default();
-        mock_leaf_manager
-            .expect_allocate_leaf()
-            .returning(move || Ok((id, LeafHeader::new())));
+        mock_leaf_manager.expect_allocate_leaf().returning(move || {
+            Ok((
+                id,
+                LeafHeader::with_fingerprint(
+                    NUM_SLOT,
+                    LEAF_SIZE,
+                    fingerprint_hash,
+                    fingerprint_bits,
+                ),
+            ))
+        });
         mock_leaf_manager
             .expect_commit_header()
             .returning(move |_, _| Ok(()));
+        mock_leaf_manager
+            .expect_get_data_alignment()
+            .return_const(DATA_UNIT);
 
         Leaf::new(Arc::new(RwLock::new(mock_leaf_manager))).unwrap()
     }
@@ -289,19 +545,13 @@ mod tests {
     #[test]
     fn test_get_next() {
         let mut leaf = make_new_leaf(0);
-        let not_exists = match leaf.get_next() {
-            Some(_) => false,
-            None => true,
-        };
+        let not_exists = leaf.get_next().is_none();
         assert!(not_exists);
 
         let new_leaf: Arc<RwLock<Leaf>> = Arc::new(RwLock::new(make_new_leaf(1)));
         leaf.next = Some(new_leaf.clone());
 
-        let exists = match leaf.get_next() {
-            Some(_) => true,
-            None => false,
-        };
+        let exists = leaf.get_next().is_some();
         assert!(exists);
     }
 
@@ -321,7 +571,7 @@ mod tests {
 
         let expected_tail_offset = DATA_UNIT * 2;
         assert!(leaf.header.is_slot_set(0));
-        assert_eq!(leaf.header.get_fingerprints()[0], 192);
+        assert_eq!(leaf.header.get_fingerprint(0), 192);
         assert_eq!(leaf.header.get_tail_offset(), expected_tail_offset);
         assert_eq!(
             leaf.header.get_kv_info(0),
@@ -347,7 +597,7 @@ mod tests {
             .write()
             .unwrap()
             .expect_allocate_ext_page()
-            .returning(|id| Ok(id + 1));
+            .returning(|| Ok(1));
 
         let k0 = vec![0; 256 * 1024];
         let v0 = vec![0; 256 * 1024];
@@ -365,6 +615,102 @@ mod tests {
         assert_eq!(leaf.header.get_ext().expect("no ext page"), leaf.id + 1);
     }
 
+    #[test]
+    fn test_free_unused_pages() {
+        let mut leaf = make_new_leaf(0);
+        let key = b"key".to_vec();
+        let freed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let mut leaf_manager = leaf.leaf_manager.write().unwrap();
+            // each page has only one key-value pair
+            leaf_manager
+                .expect_write_data()
+                .returning(|_, offset, _, _| {
+                    if offset == INITIAL_TAIL_OFFSET {
+                        Ok(Some(LEAF_SIZE))
+                    } else {
+                        Ok(None)
+                    }
+                });
+            let read_key = key.clone();
+            leaf_manager
+                .expect_read_data()
+                .returning(move |_, _, _, _| Ok((read_key.clone(), Vec::new())));
+            let mut next_page_id = 0;
+            leaf_manager.expect_allocate_ext_page().returning(move || {
+                next_page_id += 1;
+                Ok(next_page_id)
+            });
+            let freed = freed.clone();
+            leaf_manager
+                .expect_free_page()
+                .returning(move |id| freed.lock().unwrap().push(id));
+        }
+
+        // large values which aren't compacted
+        let values: Vec<Vec<u8>> = (0..4).map(|i| vec![i; LEAF_SIZE / 3]).collect();
+
+        // the leaf's own page isn't freed
+        leaf.insert(&key, &values[0]).unwrap();
+        leaf.insert(&key, &values[1]).unwrap();
+        assert!(freed.lock().unwrap().is_empty());
+
+        // the previous extension page is freed when it is switched
+        leaf.insert(&key, &values[2]).unwrap();
+        leaf.insert(&key, &values[3]).unwrap();
+        assert_eq!(*freed.lock().unwrap(), vec![1, 2]);
+        assert_eq!(leaf.len(), 1);
+        let slot = (0..NUM_SLOT).find(|s| leaf.header.is_slot_set(*s)).unwrap();
+        assert_eq!(leaf.header.get_kv_info(slot).0, 3);
+    }
+
+    #[test]
+    fn test_compaction() {
+        const NUM_UPDATES: usize = 5000;
+        let mut leaf = make_new_leaf(0);
+        let key = b"key".to_vec();
+        let allocated = Arc::new(std::sync::Mutex::new(0));
+        let freed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let mut leaf_manager = leaf.leaf_manager.write().unwrap();
+            leaf_manager
+                .expect_write_data()
+                .returning(|_, offset, _, _| {
+                    if offset + DATA_UNIT <= LEAF_SIZE - DATA_UNIT {
+                        Ok(Some(offset + DATA_UNIT))
+                    } else {
+                        Ok(None)
+                    }
+                });
+            let read_key = key.clone();
+            leaf_manager
+                .expect_read_data()
+                .returning(move |_, _, _, _| Ok((read_key.clone(), b"value".to_vec())));
+            let allocated = allocated.clone();
+            leaf_manager.expect_allocate_ext_page().returning(move || {
+                let mut allocated = allocated.lock().unwrap();
+                *allocated += 1;
+                Ok(*allocated)
+            });
+            let freed = freed.clone();
+            leaf_manager
+                .expect_free_page()
+                .returning(move |id| freed.lock().unwrap().push(id));
+        }
+
+        for _ in 0..NUM_UPDATES {
+            leaf.insert(&key, b"value").unwrap();
+        }
+
+        assert!(leaf.get_next().is_none());
+        assert_eq!(leaf.len(), 1);
+        // the leaf's own page and an extension page are used alternately
+        let units_per_page = (LEAF_SIZE - 2 * DATA_UNIT) / DATA_UNIT;
+        let allocated = *allocated.lock().unwrap();
+        assert!(allocated <= NUM_UPDATES / units_per_page / 2 + 1);
+        assert!(allocated - freed.lock().unwrap().len() <= 1);
+    }
+
     #[test]
     fn test_insert_any_slot() {
         let mut leaf = make_new_leaf(0);
@@ -391,7 +737,7 @@ mod tests {
 
         let expected_tail_offset = DATA_UNIT + (any_slot + 1) * DATA_UNIT;
         assert!(leaf.header.is_slot_set(any_slot));
-        assert_eq!(leaf.header.get_fingerprints()[any_slot], 192);
+        assert_eq!(leaf.header.get_fingerprint(any_slot), 192);
         assert_eq!(leaf.header.get_tail_offset(), expected_tail_offset);
         assert_eq!(
             leaf.header.get_kv_info(any_slot),
@@ -426,10 +772,94 @@ mod tests {
         let v = vec![3u8];
         assert_eq!(leaf.get(&k).unwrap().unwrap(), v);
 
-        let k = vec![8 as u8];
+        let k = vec![8_u8];
         assert_eq!(leaf.get(&k).unwrap(), None);
     }
 
+    #[test]
+    fn test_remove() {
+        let mut leaf = make_new_leaf(0);
+        leaf.leaf_manager
+            .write()
+            .unwrap()
+            .expect_write_data()
+            .returning(|_, offset, _, _| Ok(Some(offset + DATA_UNIT)));
+        leaf.leaf_manager
+            .write()
+            .unwrap()
+            .expect_read_data()
+            .returning(|_, offset, _, _| {
+                let kv = vec![(offset / DATA_UNIT - 1) as u8];
+                Ok((kv.clone(), kv.clone()))
+            });
+
+        for i in 0..5 {
+            let k = vec![i as u8];
+            let v = vec![i as u8];
+            leaf.insert(&k, &v).unwrap();
+        }
+
+        leaf.remove(&[3u8]).unwrap();
+        assert_eq!(leaf.get(&[3u8]).unwrap(), None);
+        assert_eq!(leaf.get(&[2u8]).unwrap(), Some(vec![2u8]));
+        assert_eq!(leaf.len(), 4);
+
+        // a missing key is ignored
+        leaf.remove(&[8u8]).unwrap();
+        assert_eq!(leaf.len(), 4);
+    }
+
+    #[test]
+    fn test_fingerprint_collisions() {
+        // keys whose 8-bit SipHash fingerprints are the same
+        let sip_header = LeafHeader::new(NUM_SLOT, LEAF_SIZE);
+        let mut keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key{}", i).into_bytes())
+            .filter(|k| sip_header.calc_fingerprint(k) == 192)
+            .take(NUM_SLOT / 2 + 1)
+            .collect();
+        let missing = keys.pop().unwrap();
+
+        let count_reads = |fingerprint_hash, fingerprint_bits| -> usize {
+            let mut leaf = make_new_leaf_with_fingerprint(0, fingerprint_hash, fingerprint_bits);
+            let written = Arc::new(Mutex::new(HashMap::new()));
+            let reads = Arc::new(AtomicUsize::new(0));
+            {
+                let mut leaf_manager = leaf.leaf_manager.write().unwrap();
+                let w = written.clone();
+                leaf_manager
+                    .expect_write_data()
+                    .returning(move |_, offset, k, v| {
+                        w.lock().unwrap().insert(offset, (k.to_vec(), v.to_vec()));
+                        Ok(Some(offset + DATA_UNIT))
+                    });
+                let r = reads.clone();
+                leaf_manager
+                    .expect_read_data()
+                    .returning(move |_, offset, _, _| {
+                        r.fetch_add(1, Ordering::Relaxed);
+                        Ok(written.lock().unwrap()[&offset].clone())
+                    });
+            }
+            for key in &keys {
+                leaf.insert(key, b"value").unwrap();
+            }
+
+            reads.store(0, Ordering::Relaxed);
+            for key in &keys {
+                assert_eq!(leaf.get(key).unwrap(), Some(b"value".to_vec()));
+            }
+            assert_eq!(leaf.get(&missing).unwrap(), None);
+            reads.load(Ordering::Relaxed)
+        };
+
+        // a lookup reads all colliding keys in the preceding slots
+        let n = keys.len();
+        assert_eq!(count_reads(FingerprintHash::Sip, 8), n * (n + 1) / 2 + n);
+        // only the key itself is read
+        assert_eq!(count_reads(FingerprintHash::XxHash, 16), n);
+    }
+
     #[test]
     fn test_update() {
         let mut leaf = make_new_leaf(0);
@@ -494,10 +924,7 @@ mod tests {
         assert_eq!(split_key, vec!((NUM_SLOT / 2) as u8));
         assert!((0..(NUM_SLOT / 2)).all(|i| { leaf.header.is_slot_set(i) }));
         assert!(((NUM_SLOT / 2)..NUM_SLOT).all(|i| { !leaf.header.is_slot_set(i) }));
-        let exists = match leaf.get_next() {
-            Some(_) => true,
-            None => false,
-        };
+        let exists = leaf.get_next().is_some();
         assert!(exists);
     }
 }
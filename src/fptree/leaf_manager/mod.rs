@@ -1,47 +1,113 @@
+mod page_cache;
+mod storage;
 mod types;
 
 use log::{debug, trace, warn};
-use memmap::{MmapMut, MmapOptions};
-use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::convert::TryInto;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
 use std::io::ErrorKind;
-use std::sync::{Arc, RwLock};
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
-use crate::config::Config;
+use crate::amphis_error::CorruptionError;
+use crate::config::{Checksum, Config, Durability, FingerprintHash};
+use crate::fault::{FaultInjector, FaultPoint};
 use crate::util::data_util;
-use crate::util::file_util;
+use crate::util::lock_util::{MutexExt, RwLockExt};
 
+use page_cache::PageMmap;
+pub use page_cache::DEFAULT_MMAP_CACHE_PAGES;
+use storage::{Buffer, FileStorage, LeafStorage, MemoryStorage};
 pub use types::{
-    LeafHeader, END_TAIL_OFFSET, INITIAL_TAIL_OFFSET, LEAF_HEADER_SIZE, LEAF_SIZE, NUM_ALLOCATION,
-    NUM_SLOT,
+    get_end_tail_offset, get_max_data_size, get_max_value_size, get_overflow_chunk_size,
+    validate_data_alignment, validate_fingerprint_bits, validate_leaf_size, validate_num_slot,
+    LeafHeader, OverflowRef, DEFAULT_ALLOCATION_BYTES, DEFAULT_FINGERPRINT_BITS, DEFAULT_LEAF_SIZE,
+    DEFAULT_NUM_SLOT, INITIAL_TAIL_OFFSET,
 };
-use types::{HEADER_MAGIC, LEN_HEADER_MAGIC};
 
 #[cfg(test)]
 use mockall::automock;
 
+/// Two header slots at the head and the tail of a leaf
+/// A header is written to the stale slot not to break the current one by a torn write
+struct HeaderSlots {
+    mmaps: [Buffer; 2],
+    /// The slot of the newest valid header
+    current: usize,
+}
+
 pub struct LeafManager {
-    leaves_file: File,
+    /// The leaf file, or buffers in memory with `in_memory`
+    storage: Box<dyn LeafStorage>,
     free_leaves: VecDeque<usize>,
-    header_mmap: HashMap<usize, Arc<RwLock<MmapMut>>>,
+    header_mmap: HashMap<usize, Arc<RwLock<HeaderSlots>>>,
+    /// The sequence number of the next header write in the file
+    next_seq: AtomicU64,
+    format_version: u8,
+    num_slot: usize,
+    fingerprint_hash: FingerprintHash,
+    fingerprint_bits: usize,
+    /// The checksum algorithm of all headers and key-value pairs in the file
+    checksum: Checksum,
+    leaf_size: usize,
+    /// Key-value pairs are written from multiples of this size
+    /// It isn't recorded since pairs are read at the offsets in headers
+    data_alignment: usize,
+    /// The number of leaves appended at once
+    leaf_allocation: usize,
+    file_path: String,
+    durability: Durability,
+    last_sync: Mutex<Instant>,
+    fault_injector: Arc<FaultInjector>,
+    /// The number of mapped pages
+    #[cfg(test)]
+    map_count: Arc<AtomicUsize>,
 }
 
 #[cfg_attr(test, automock)]
 impl LeafManager {
     pub fn new(name: &str, id: usize, config: &Config) -> Result<Self, std::io::Error> {
-        let data_dir = config.get_leaf_dir_path(name);
-        if let Err(e) = std::fs::create_dir_all(&data_dir) {
-            unreachable!("Creating {} failed: {}", data_dir, e);
-        }
+        let num_slot = config.get_num_slot();
+        let fingerprint_bits = config.get_fingerprint_bits();
+        validate_fingerprint_bits(fingerprint_bits)?;
+        validate_num_slot(num_slot, fingerprint_bits)?;
+        let leaf_size = config.get_leaf_size();
+        validate_leaf_size(leaf_size)?;
+        let data_alignment = config.get_data_alignment();
+        validate_data_alignment(data_alignment)?;
 
-        let file_path = config.get_leaf_file_path(name, id);
-        let (file, is_created) = file_util::open_file(&file_path)?;
+        #[cfg(test)]
+        let map_count = Arc::new(AtomicUsize::new(0));
+        let (storage, is_created): (Box<dyn LeafStorage>, bool) = if config.get_in_memory() {
+            (Box::new(MemoryStorage::new(leaf_size)), true)
+        } else {
+            let (storage, is_created) = FileStorage::open(name, id, config)?;
+            #[cfg(test)]
+            let storage = storage.with_map_count(map_count.clone());
+            (Box::new(storage), is_created)
+        };
         let mut manager = LeafManager {
-            leaves_file: file,
+            storage,
             free_leaves: VecDeque::new(),
             header_mmap: HashMap::new(),
+            next_seq: AtomicU64::new(1),
+            format_version: data_util::FORMAT_VERSION,
+            num_slot,
+            fingerprint_hash: config.get_fingerprint_hash(),
+            fingerprint_bits,
+            checksum: config.get_checksum(),
+            leaf_size,
+            data_alignment,
+            leaf_allocation: config.get_leaf_allocation(),
+            file_path: config.get_leaf_file_path(name, id),
+            durability: config.get_durability(),
+            last_sync: Mutex::new(Instant::now()),
+            fault_injector: config.get_fault_injector().clone(),
+            #[cfg(test)]
+            map_count,
         };
 
         if !is_created {
@@ -58,46 +124,48 @@ impl LeafManager {
         }
 
         let new_id = self.free_leaves.pop_front().unwrap();
+        let mut slots = self.mmap_headers(new_id)?;
+        // the first header is written to the head
+        slots.current = 1;
         self.header_mmap
-            .insert(new_id, Arc::new(RwLock::new(self.mmap_header(new_id)?)));
+            .insert(new_id, Arc::new(RwLock::new(slots)));
 
         trace!("New leaf is allocated: {}", new_id);
-        Ok((new_id, LeafHeader::new()))
+        Ok((
+            new_id,
+            LeafHeader::with_fingerprint(
+                self.num_slot,
+                self.leaf_size,
+                self.fingerprint_hash,
+                self.fingerprint_bits,
+            )
+            .with_checksum(self.checksum),
+        ))
     }
 
-    pub fn allocate_ext_page(&mut self, id: usize) -> Result<usize, std::io::Error> {
+    /// Allocate a page to extend a leaf
+    /// The leaf records the page in its header since the page has no header
+    pub fn allocate_ext_page(&mut self) -> Result<usize, std::io::Error> {
         if self.free_leaves.is_empty() {
             self.allocate_new_leaves()?;
         }
         let new_id = self.free_leaves.pop_front().unwrap();
-        self.header_mmap
-            .insert(new_id, Arc::new(RwLock::new(self.mmap_header(new_id)?)));
-
-        // update the last leaf to be appended
-        let mut last_id = id;
-        let mut last_header = self
-            .get_header(id)
-            .expect("the appended header doesn't exist");
-        while let Some(ext) = last_header.get_ext() {
-            last_id = ext;
-            last_header = self
-                .get_header(ext)
-                .expect("the appended header doesn't exist");
-        }
-        last_header.set_ext(new_id);
-        self.commit_header(last_id, &last_header)?;
 
+        trace!("New extension page is allocated: {}", new_id);
         Ok(new_id)
     }
 
+    /// Return the page to be reused by the next allocation
+    pub fn free_page(&mut self, id: usize) {
+        trace!("Page {} is freed", id);
+        self.free_leaves.push_front(id);
+    }
+
     fn allocate_new_leaves(&mut self) -> Result<(), std::io::Error> {
         trace!("New leaf group is allocated");
-        let file_size = self.leaves_file.metadata()?.len() as usize;
-        let start_id = file_size / LEAF_SIZE;
-        let end_id = start_id + NUM_ALLOCATION;
-
-        let new_size = file_size + NUM_ALLOCATION * LEAF_SIZE;
-        self.leaves_file.set_len(new_size as u64)?;
+        let start_id = self.storage.num_pages()?;
+        let end_id = start_id + self.leaf_allocation;
+        self.storage.extend(self.leaf_allocation)?;
 
         for id in start_id..end_id {
             self.free_leaves.push_back(id);
@@ -106,45 +174,46 @@ impl LeafManager {
         Ok(())
     }
 
-    fn mmap_header(&self, id: usize) -> Result<MmapMut, std::io::Error> {
-        // TODO: protect the header when write failure (tail header)
-        let offset = id * LEAF_SIZE;
-        let mmap = unsafe {
-            MmapOptions::new()
-                .offset(offset as u64)
-                .len(LEAF_HEADER_SIZE)
-                .map_mut(&self.leaves_file)?
-        };
+    /// Map the header slots of the leaf without choosing the current one
+    fn mmap_headers(&self, id: usize) -> Result<HeaderSlots, std::io::Error> {
+        let offset = id * self.leaf_size;
+        Ok(HeaderSlots {
+            mmaps: [
+                self.storage.map_header(offset)?,
+                self.storage
+                    .map_header(offset + get_end_tail_offset(self.leaf_size))?,
+            ],
+            current: 0,
+        })
+    }
 
-        Ok(mmap)
+    /// Return the whole page, whose mapping is cached for the leaf file
+    fn mmap_page(&self, id: usize) -> Result<PageMmap, std::io::Error> {
+        self.storage.map_page(id)
     }
 
     pub fn get_header(&self, id: usize) -> Option<LeafHeader> {
         match self.header_mmap.get(&id) {
-            Some(mmap) => {
-                let header: LeafHeader =
-                    bincode::deserialize(mmap.read().unwrap().as_ref()).unwrap();
+            Some(slots) => {
+                let slots = slots.read_or_recover();
+                let header = LeafHeader::from_bytes(&slots.mmaps[slots.current]).unwrap();
                 Some(header)
             }
             None => None,
         }
     }
 
+    /// Write the header to the stale slot, and then switch to it after the write is persisted
     pub fn commit_header(&self, id: usize, header: &LeafHeader) -> Result<(), std::io::Error> {
-        let mut mmap = self.header_mmap.get(&id).unwrap().write().unwrap();
-        let mut encoded: Vec<u8> = match bincode::serialize(header) {
-            Ok(b) => b,
-            // TODO: replace with an amphis error
-            Err(_) => {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    "failed to serialize a leaf header",
-                ))
-            }
-        };
-        encoded.extend(&data_util::calc_crc(&encoded).to_le_bytes());
-        mmap.copy_from_slice(&encoded);
-        mmap.flush()
+        let mut slots = self.header_mmap.get(&id).unwrap().write_or_recover();
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let encoded = header.to_bytes(seq)?;
+        let stale = 1 - slots.current;
+        slots.mmaps[stale][..encoded.len()].copy_from_slice(&encoded);
+        self.sync(&slots.mmaps[stale], 0, INITIAL_TAIL_OFFSET)?;
+        slots.current = stale;
+
+        self.fault_injector.check(FaultPoint::LeafHeaderCommitted)
     }
 
     pub fn read_data(
@@ -154,17 +223,20 @@ impl LeafManager {
         key_size: usize,
         value_size: usize,
     ) -> Result<(Vec<u8>, Vec<u8>), std::io::Error> {
-        let data_offset = id * LEAF_SIZE + offset;
         let data_size = data_util::get_data_size(key_size, value_size);
-        let mmap = unsafe {
-            MmapOptions::new()
-                .offset(data_offset as u64)
-                .len(data_size)
-                .map(&self.leaves_file)?
-        };
+        if offset + data_size > self.leaf_size {
+            return Err(CorruptionError(format!(
+                "the data at {} of {} bytes exceeds page {}",
+                offset, data_size, id
+            ))
+            .into());
+        }
+        let page = self.mmap_page(id)?;
+        let page = page.read_or_recover();
+        let mmap = &page[offset..offset + data_size];
         let bound_offset = data_util::get_bound_offset(key_size);
-        data_util::check_slot_crc(&mmap[..bound_offset])?;
-        data_util::check_slot_crc(&mmap[bound_offset..])?;
+        data_util::check_slot_crc(&mmap[..bound_offset], self.checksum)?;
+        data_util::check_slot_crc(&mmap[bound_offset..], self.checksum)?;
         let (key_start, key_end) = data_util::get_key_offset(key_size);
         if value_size == 0 {
             Ok((mmap[key_start..key_end].to_vec(), Vec::new()))
@@ -185,60 +257,264 @@ impl LeafManager {
         value: &[u8],
     ) -> Result<Option<usize>, std::io::Error> {
         let data_size = data_util::get_data_size(key.len(), value.len());
-        let aligned_tail = offset + data_util::round_up_size(data_size);
-        if aligned_tail > END_TAIL_OFFSET {
+        let aligned_tail = offset + data_util::round_up_size(data_size, self.data_alignment);
+        if aligned_tail > get_end_tail_offset(self.leaf_size) {
             return Ok(None);
         }
-        let data_offset = id * LEAF_SIZE + offset;
-        let mut mmap = unsafe {
-            MmapOptions::new()
-                .offset(data_offset as u64)
-                .len(data_size)
-                .map_mut(&self.leaves_file)?
-        };
-
-        let data = data_util::format_data_with_crc(key, value);
-        mmap.copy_from_slice(&data);
-        mmap.flush()?;
+        let data = data_util::format_data_with_crc(key, value, self.checksum);
+        let page = self.mmap_page(id)?;
+        let mut page = page.write_or_recover();
+        page[offset..offset + data_size].copy_from_slice(&data);
+        self.sync(&page, offset, data_size)?;
 
         Ok(Some(aligned_tail))
     }
 
+    /// Write a value which doesn't fit in a page to new overflow pages
+    /// Return the `OverflowRef` to be stored in the leaf page instead of the value
+    pub fn write_overflow(&mut self, value: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let chunk_size = get_overflow_chunk_size(self.leaf_size);
+        let mut page_ids = Vec::with_capacity(value.len().div_ceil(chunk_size));
+        for chunk in value.chunks(chunk_size) {
+            let page_id = self.allocate_ext_page()?;
+            page_ids.push(page_id);
+            if let Err(e) = self.write_data(page_id, INITIAL_TAIL_OFFSET, &[], chunk) {
+                for page_id in page_ids {
+                    self.free_page(page_id);
+                }
+                return Err(e);
+            }
+        }
+        trace!(
+            "A value of {} bytes is written to overflow pages {:?}",
+            value.len(),
+            page_ids
+        );
+
+        Ok(OverflowRef::new(value.len(), page_ids).to_bytes())
+    }
+
+    /// Reassemble the value from the overflow pages of the `OverflowRef`
+    pub fn read_overflow(&self, overflow_ref: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let overflow_ref = OverflowRef::from_bytes(overflow_ref, self.leaf_size)?;
+        let chunk_size = get_overflow_chunk_size(self.leaf_size);
+        let mut value = Vec::with_capacity(overflow_ref.get_value_size());
+        for page_id in overflow_ref.get_page_ids() {
+            let size = chunk_size.min(overflow_ref.get_value_size() - value.len());
+            let (_, chunk) = self.read_data(*page_id, INITIAL_TAIL_OFFSET, 0, size)?;
+            value.extend(chunk);
+        }
+
+        Ok(value)
+    }
+
+    /// Persist the written region of the mapping according to the durability
+    /// A sync of the file also persists the other regions written before
+    /// A batched sync happens only at a write, not when the interval passes without writes
+    fn sync(&self, mmap: &Buffer, offset: usize, len: usize) -> Result<(), std::io::Error> {
+        match self.durability {
+            Durability::PerWrite => mmap.flush_range(offset, len),
+            Durability::Batched(interval) => {
+                let mut last_sync = self.last_sync.lock_or_recover();
+                if last_sync.elapsed() >= interval {
+                    self.storage.sync()?;
+                    *last_sync = Instant::now();
+                }
+                Ok(())
+            }
+            Durability::OnFlushOnly => Ok(()),
+        }
+    }
+
+    /// Return the alignment of key-value pairs written to leaves
+    pub fn get_data_alignment(&self) -> usize {
+        self.data_alignment
+    }
+
+    /// Return the format version of values written in this leaf file
+    pub fn get_format_version(&self) -> u8 {
+        self.format_version
+    }
+
+    /// Mark the leaf file as obsolete after its data has been flushed
+    /// The file is renamed not to be recovered, and removed when the manager is dropped
+    pub fn set_obsolete(&mut self) -> Result<(), std::io::Error> {
+        self.storage.set_obsolete()
+    }
+
+    /// Return the IDs of leaves linked from the first leaf
+    /// The chain ends at a leaf whose header is missing or invalid, e.g. by a torn write
     pub fn get_leaf_id_chain(&self) -> Vec<usize> {
         let mut leaf_id_chain = Vec::new();
-        let mut header = self.get_header(0).expect("no header for the first leaf");
-        leaf_id_chain.push(0);
-
-        while let Some(next) = header.get_next() {
-            leaf_id_chain.push(next);
-            header = self.get_header(next).expect("the header should exist");
+        let mut next = Some(0);
+        while let Some(id) = next {
+            let header = match self.get_header(id) {
+                Some(header) => header,
+                None => {
+                    warn!(
+                        "The leaf chain of {} ends before leaf {} without a valid header",
+                        self.file_path, id
+                    );
+                    break;
+                }
+            };
+            if leaf_id_chain.contains(&id) {
+                warn!(
+                    "The leaf chain of {} ends at leaf {} linked twice",
+                    self.file_path, id
+                );
+                break;
+            }
+            leaf_id_chain.push(id);
+            next = header.get_next();
         }
 
         leaf_id_chain
     }
 
+    /// Rebuild the free list to reopen the FPTree with the leaves in `leaf_id_chain`
+    /// Pages which aren't used by the leaves are reused
+    pub fn reclaim_pages(&mut self, leaf_id_chain: &[usize]) -> Result<(), std::io::Error> {
+        let leaf_ids: HashSet<usize> = leaf_id_chain.iter().cloned().collect();
+        let mut used_pages = leaf_ids.clone();
+        for id in &leaf_ids {
+            let header = self.get_header(*id).expect("the header should exist");
+            used_pages.extend(header.get_ext());
+            for slot in 0..header.get_num_slot() {
+                if !header.is_slot_set(slot) {
+                    continue;
+                }
+                let (page_id, offset, key_size, value_size) = header.get_kv_info(slot);
+                used_pages.insert(page_id);
+                if header.is_overflow(slot) {
+                    let (_, overflow_ref) =
+                        self.read_data(page_id, offset, key_size, value_size)?;
+                    let overflow_ref = OverflowRef::from_bytes(&overflow_ref, self.leaf_size)?;
+                    used_pages.extend(overflow_ref.get_page_ids());
+                }
+            }
+        }
+        // headers of unreachable leaves are dropped
+        self.header_mmap.retain(|id, _| leaf_ids.contains(id));
+
+        let num_pages = self.storage.num_pages()?;
+        self.free_leaves = (0..num_pages)
+            .filter(|id| !used_pages.contains(id))
+            .collect();
+
+        Ok(())
+    }
+
     fn recover_state(&mut self) -> Result<(), std::io::Error> {
-        let file_size = self.leaves_file.metadata()?.len() as usize;
-        for id in 0..(file_size / LEAF_SIZE) {
-            let mmap = self.mmap_header(id)?;
+        let num_pages = self.storage.num_pages()?;
+        if num_pages == 0 {
+            return Ok(());
+        }
 
-            // validate the header
-            let magic = u32::from_le_bytes(mmap[0..LEN_HEADER_MAGIC].try_into().unwrap());
-            if magic != HEADER_MAGIC {
-                warn!("Header magic was not found");
-                self.free_leaves.push_back(id);
-                continue;
+        // the first leaf is always at the head of the file regardless of the leaf size
+        // only the head slot is read since the tail slot depends on the leaf size
+        if let Ok(header) = LeafHeader::from_bytes(&self.storage.map_header(0)?) {
+            if header.get_leaf_size() != self.leaf_size {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} was written with the leaf size {}, but the configured leaf size is {}",
+                        self.file_path,
+                        header.get_leaf_size(),
+                        self.leaf_size
+                    ),
+                ));
             }
+        }
+
+        // the number of slots and the fingerprint written in the file are used instead of the
+        // configured ones so that a new header fits in the header region
+        // the checksum is also kept since key-value pairs of all leaves are checked by it
+        let mut recovered_num_slot = None;
+        let mut recovered_checksum = None;
+        let mut recovered_fingerprint = None;
+        let mut max_seq = 0;
+        for id in 0..num_pages {
+            let mut slots = self.mmap_headers(id)?;
 
-            match data_util::check_header_crc(&mmap) {
-                Ok(_) => {
-                    self.header_mmap.insert(id, Arc::new(RwLock::new(mmap)));
+            // validate the header
+            let header = match read_newest_header(&mut slots) {
+                Ok((header, seq)) => {
+                    // a stale header on a reused page never wins over new writes
+                    max_seq = max_seq.max(seq);
+                    header
                 }
-                Err(_) => {
+                Err(e) => {
                     // TODO: check another header field
-                    warn!("header's CRC check failed");
+                    warn!("Invalid header of leaf {}: {}", id, e);
                     self.free_leaves.push_back(id);
+                    continue;
                 }
+            };
+
+            if header.get_leaf_size() != self.leaf_size {
+                warn!(
+                    "The leaf size of leaf {} is inconsistent: {}",
+                    id,
+                    header.get_leaf_size()
+                );
+                self.free_leaves.push_back(id);
+                continue;
+            }
+            let num_slot = header.get_num_slot();
+            if *recovered_num_slot.get_or_insert(num_slot) != num_slot {
+                warn!(
+                    "The number of slots of leaf {} is inconsistent: {}",
+                    id, num_slot
+                );
+                self.free_leaves.push_back(id);
+                continue;
+            }
+            let checksum = header.get_checksum();
+            if *recovered_checksum.get_or_insert(checksum) != checksum {
+                warn!(
+                    "The checksum of leaf {} is inconsistent: {:?}",
+                    id, checksum
+                );
+                self.free_leaves.push_back(id);
+                continue;
+            }
+            recovered_fingerprint
+                .get_or_insert((header.get_fingerprint_hash(), header.get_fingerprint_bits()));
+            // values are read in the oldest format in the file
+            self.format_version = self.format_version.min(header.get_format_version());
+            self.header_mmap.insert(id, Arc::new(RwLock::new(slots)));
+        }
+        self.next_seq = AtomicU64::new(max_seq + 1);
+
+        if let Some(num_slot) = recovered_num_slot {
+            if num_slot != self.num_slot {
+                warn!(
+                    "The leaf file has {} slots per leaf, not the configured {}",
+                    num_slot, self.num_slot
+                );
+                self.num_slot = num_slot;
+            }
+        }
+        if let Some(checksum) = recovered_checksum {
+            if checksum != self.checksum {
+                warn!(
+                    "The leaf file has checksums by {:?}, not the configured {:?}",
+                    checksum, self.checksum
+                );
+                self.checksum = checksum;
+            }
+        }
+        if let Some((fingerprint_hash, fingerprint_bits)) = recovered_fingerprint {
+            if (fingerprint_hash, fingerprint_bits)
+                != (self.fingerprint_hash, self.fingerprint_bits)
+            {
+                warn!(
+                    "The leaf file has {}-bit fingerprints by {:?}, not the configured ones",
+                    fingerprint_bits, fingerprint_hash
+                );
+                self.fingerprint_hash = fingerprint_hash;
+                self.fingerprint_bits = fingerprint_bits;
             }
         }
 
@@ -246,13 +522,34 @@ impl LeafManager {
     }
 }
 
+/// Decode the header with the largest sequence number among the valid ones
+/// and set the slot as the current one
+fn read_newest_header(slots: &mut HeaderSlots) -> Result<(LeafHeader, u64), std::io::Error> {
+    let head = LeafHeader::from_bytes_with_seq(&slots.mmaps[0]);
+    let tail = LeafHeader::from_bytes_with_seq(&slots.mmaps[1]);
+    let (current, newest) = match (head, tail) {
+        (Ok(head), Ok(tail)) if tail.1 > head.1 => (1, tail),
+        (Ok(head), _) => (0, head),
+        (Err(_), Ok(tail)) => (1, tail),
+        (Err(e), Err(_)) => return Err(e),
+    };
+    slots.current = current;
+
+    Ok(newest)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+
+    const NUM_ALLOCATION: usize = 16;
 
     #[test]
     fn test_allocate_page() {
-        let config = Config::new_for_testing();
+        let config = Config::builder_for_testing()
+            .leaf_allocation(NUM_ALLOCATION)
+            .build();
         let mut manager =
             LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
         let (id, header) = manager.allocate_leaf().expect("page allocation failed");
@@ -261,18 +558,19 @@ mod tests {
         assert_eq!(header.get_ext(), None);
         assert_eq!(manager.free_leaves.len(), NUM_ALLOCATION - 1);
 
-        // commit the header for the next allocation
-        manager.commit_header(id, &header).expect("commit failed");
-
-        // allocate an extension page
+        // allocate an extension page before the header is committed
         let ext_id = manager
-            .allocate_ext_page(id)
+            .allocate_ext_page()
             .expect("extension page allocation failed");
-        // load the root header
-        let mut header = manager.get_header(id).expect("no header");
+        assert_ne!(ext_id, id);
+        assert_eq!(manager.free_leaves.len(), NUM_ALLOCATION - 2);
+        let mut header = header;
+        header.set_ext(ext_id);
+        manager.commit_header(id, &header).expect("commit failed");
+        let header = manager.get_header(id).expect("no header");
         assert_eq!(header.get_next(), None);
         assert_eq!(header.get_ext(), Some(ext_id));
-        assert_eq!(manager.free_leaves.len(), NUM_ALLOCATION - 2);
+        let mut header = header;
 
         // allocate new pages
         let (next_id, next_header) = manager.allocate_leaf().expect("page allocation failed");
@@ -286,6 +584,197 @@ mod tests {
         assert_eq!(leaf_id_chain, vec![id, next_id]);
     }
 
+    #[test]
+    fn test_reclaim_pages() {
+        let config = Config::builder_for_testing()
+            .leaf_allocation(NUM_ALLOCATION)
+            .build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        // leaf 0 has data in page 1 and page 2 and it is followed by leaf 3
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        let old_ext_id = manager.allocate_ext_page().expect("allocation failed");
+        let ext_id = manager.allocate_ext_page().expect("allocation failed");
+        let (next_id, next_header) = manager.allocate_leaf().expect("page allocation failed");
+        header.set_slot(0);
+        header.set_kv_info(0, old_ext_id, INITIAL_TAIL_OFFSET, 1, 1);
+        header.set_ext(ext_id);
+        header.set_next(next_id);
+        manager.commit_header(id, &header).expect("commit failed");
+        manager
+            .commit_header(next_id, &next_header)
+            .expect("commit failed");
+        // leaf 4 isn't linked
+        let (orphan_id, orphan_header) = manager.allocate_leaf().expect("allocation failed");
+        manager
+            .commit_header(orphan_id, &orphan_header)
+            .expect("commit failed");
+        drop(manager);
+
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let leaf_id_chain = manager.get_leaf_id_chain();
+        assert_eq!(leaf_id_chain, vec![id, next_id]);
+        manager
+            .reclaim_pages(&leaf_id_chain)
+            .expect("reclaim failed");
+        assert!(manager.get_header(orphan_id).is_none());
+        let mut free_leaves: Vec<usize> = manager.free_leaves.iter().cloned().collect();
+        free_leaves.sort();
+        assert_eq!(free_leaves, (4..NUM_ALLOCATION).collect::<Vec<_>>());
+        let (new_id, _) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(new_id, orphan_id);
+
+        // a freed page is reused before the file is extended
+        manager.free_page(old_ext_id);
+        let num_pages = manager.storage.num_pages().expect("no file");
+        let page_id = manager.allocate_ext_page().expect("allocation failed");
+        assert_eq!(page_id, old_ext_id);
+        assert_eq!(manager.storage.num_pages().expect("no file"), num_pages);
+    }
+
+    #[test]
+    fn test_allocate_one_leaf_at_a_time() {
+        let config = Config::builder_for_testing().leaf_allocation(1).build();
+        let leaf_file = config.get_leaf_file_path("test", 0);
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (mut id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        manager.commit_header(id, &header).expect("commit failed");
+        for i in 1..4 {
+            let (next_id, next_header) = manager.allocate_leaf().expect("page allocation failed");
+            assert_eq!(next_id, i);
+            assert!(manager.free_leaves.is_empty());
+            // the file grows by one leaf
+            assert_eq!(
+                std::fs::metadata(&leaf_file).expect("no file").len() as usize,
+                (i + 1) * config.get_leaf_size()
+            );
+            header.set_next(next_id);
+            manager.commit_header(id, &header).expect("commit failed");
+            manager
+                .commit_header(next_id, &next_header)
+                .expect("commit failed");
+            id = next_id;
+            header = next_header;
+        }
+        drop(manager);
+
+        // all leaves are recovered without any free leaf
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert_eq!(manager.get_leaf_id_chain(), vec![0, 1, 2, 3]);
+        assert!(manager.free_leaves.is_empty());
+        let (new_id, _) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(new_id, 4);
+        assert_eq!(
+            std::fs::metadata(&leaf_file).expect("no file").len() as usize,
+            5 * config.get_leaf_size()
+        );
+    }
+
+    #[test]
+    fn test_broken_leaf_chain() {
+        let config = Config::builder_for_testing().leaf_allocation(1).build();
+        let leaf_size = config.get_leaf_size();
+        let leaf_file = config.get_leaf_file_path("test", 0);
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        for i in 0..4 {
+            let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+            if i < 3 {
+                header.set_next(id + 1);
+            }
+            manager.commit_header(id, &header).expect("commit failed");
+        }
+        drop(manager);
+
+        // break both header slots of leaf 2 as if its write was torn
+        let mut bytes = std::fs::read(&leaf_file).expect("no file");
+        for offset in [
+            2 * leaf_size,
+            2 * leaf_size + get_end_tail_offset(leaf_size),
+        ] {
+            bytes[offset..offset + INITIAL_TAIL_OFFSET].fill(0);
+        }
+        std::fs::write(&leaf_file, &bytes).expect("write failed");
+
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let leaf_id_chain = manager.get_leaf_id_chain();
+        assert_eq!(leaf_id_chain, vec![0, 1]);
+        manager
+            .reclaim_pages(&leaf_id_chain)
+            .expect("reclaim failed");
+        // pages of the lost leaves are reused
+        assert!(manager.get_header(3).is_none());
+        assert_eq!(manager.free_leaves.len(), 2);
+        drop(manager);
+
+        // no leaf is recovered without the first leaf
+        bytes[..INITIAL_TAIL_OFFSET].fill(0);
+        bytes[get_end_tail_offset(leaf_size)..get_end_tail_offset(leaf_size) + INITIAL_TAIL_OFFSET]
+            .fill(0);
+        std::fs::write(&leaf_file, &bytes).expect("write failed");
+        let manager = LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert!(manager.get_leaf_id_chain().is_empty());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let config = Config::builder_for_testing().leaf_size(16 * 1024).build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        let chunk_size = get_overflow_chunk_size(16 * 1024);
+        let value: Vec<u8> = (0..(chunk_size * 2 + 100)).map(|i| i as u8).collect();
+        let overflow_ref = manager.write_overflow(&value).expect("write failed");
+        assert_eq!(
+            overflow_ref.len(),
+            OverflowRef::get_size(value.len(), 16 * 1024)
+        );
+        assert_eq!(
+            manager.read_overflow(&overflow_ref).expect("read failed"),
+            value
+        );
+        let page_ids = OverflowRef::from_bytes(&overflow_ref, 16 * 1024)
+            .expect("broken reference")
+            .get_page_ids()
+            .to_vec();
+        assert_eq!(page_ids, vec![1, 2, 3]);
+
+        // the overflow pages are used by the leaf
+        let tail_offset = manager
+            .write_data(id, INITIAL_TAIL_OFFSET, b"key", &overflow_ref)
+            .expect("write failed")
+            .expect("no space");
+        header.set_slot(0);
+        header.set_kv_info(0, id, INITIAL_TAIL_OFFSET, 3, overflow_ref.len());
+        header.set_overflow(0);
+        header.set_tail_offset(tail_offset);
+        manager.commit_header(id, &header).expect("commit failed");
+        drop(manager);
+
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        manager.reclaim_pages(&[id]).expect("reclaim failed");
+        assert!(page_ids
+            .iter()
+            .all(|page_id| !manager.free_leaves.contains(page_id)));
+        assert_eq!(
+            manager.read_overflow(&overflow_ref).expect("read failed"),
+            value
+        );
+
+        // a broken chunk is detected
+        let page = manager.mmap_page(2).expect("mmap failed");
+        page.write_or_recover()[INITIAL_TAIL_OFFSET + 100] ^= 0xff;
+        assert_eq!(
+            manager.read_overflow(&overflow_ref).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
     #[test]
     fn test_read_write_data() {
         let config = Config::new_for_testing();
@@ -316,4 +805,492 @@ mod tests {
         assert_eq!(ret_key, key);
         assert!(ret_value.is_empty());
     }
+
+    #[test]
+    fn test_allocate_page_in_memory() {
+        let config = Config::builder_for_testing()
+            .leaf_allocation(NUM_ALLOCATION)
+            .in_memory(true)
+            .build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(id, 0);
+        assert_eq!(manager.free_leaves.len(), NUM_ALLOCATION - 1);
+        let ext_id = manager
+            .allocate_ext_page()
+            .expect("extension page allocation failed");
+        assert_ne!(ext_id, id);
+        header.set_ext(ext_id);
+        manager.commit_header(id, &header).expect("commit failed");
+        assert_eq!(
+            manager.get_header(id).expect("no header").get_ext(),
+            Some(ext_id)
+        );
+
+        let mut header = manager.get_header(id).expect("no header");
+        let (next_id, next_header) = manager.allocate_leaf().expect("page allocation failed");
+        header.set_next(next_id);
+        manager.commit_header(id, &header).expect("commit failed");
+        manager
+            .commit_header(next_id, &next_header)
+            .expect("commit failed");
+        assert_eq!(manager.get_leaf_id_chain(), vec![id, next_id]);
+
+        // buffers are added when all pages are used
+        for _ in 0..(NUM_ALLOCATION - 3) {
+            manager.allocate_ext_page().expect("allocation failed");
+        }
+        assert_eq!(
+            manager.storage.num_pages().expect("no pages"),
+            NUM_ALLOCATION
+        );
+        let (new_id, _) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(new_id, NUM_ALLOCATION);
+        assert_eq!(
+            manager.storage.num_pages().expect("no pages"),
+            NUM_ALLOCATION * 2
+        );
+
+        // a freed page is reused
+        manager.free_page(ext_id);
+        assert_eq!(
+            manager.allocate_ext_page().expect("allocation failed"),
+            ext_id
+        );
+
+        // no file is created
+        assert!(!std::path::Path::new(&config.get_leaf_dir_path("test")).exists());
+        manager.set_obsolete().expect("cannot set obsolete");
+        assert!(manager.get_header(id).is_some());
+    }
+
+    #[test]
+    fn test_read_write_data_in_memory() {
+        let config = Config::builder_for_testing()
+            .leaf_allocation(NUM_ALLOCATION)
+            .in_memory(true)
+            .build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, _) = manager.allocate_leaf().expect("page allocation failed");
+        let ext_id = manager.allocate_ext_page().expect("allocation failed");
+
+        for page_id in [id, ext_id] {
+            let tail = manager
+                .write_data(page_id, INITIAL_TAIL_OFFSET, b"key", b"value")
+                .expect("write failed")
+                .expect("no space");
+            manager
+                .write_data(page_id, tail, b"tombstone", &[])
+                .expect("write failed")
+                .expect("no space");
+            let (key, value) = manager
+                .read_data(page_id, INITIAL_TAIL_OFFSET, 3, 5)
+                .expect("read failed");
+            assert_eq!(
+                (key.as_slice(), value.as_slice()),
+                (&b"key"[..], &b"value"[..])
+            );
+            let (key, value) = manager.read_data(page_id, tail, 9, 0).expect("read failed");
+            assert_eq!(key, b"tombstone");
+            assert!(value.is_empty());
+        }
+
+        // a broken pair is detected
+        let page = manager.mmap_page(ext_id).expect("no page");
+        page.write_or_recover()[INITIAL_TAIL_OFFSET + data_util::get_data_size(3, 5) - 1] ^= 0xff;
+        assert_eq!(
+            manager
+                .read_data(ext_id, INITIAL_TAIL_OFFSET, 3, 5)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        // a broken size of the value is detected without slicing beyond the pair
+        let page = manager.mmap_page(id).expect("no page");
+        let size_offset = INITIAL_TAIL_OFFSET + data_util::get_bound_offset(3);
+        page.write_or_recover()[size_offset..size_offset + data_util::LEN_SIZE]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            manager
+                .read_data(id, INITIAL_TAIL_OFFSET, 3, 5)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        // the page hasn't been allocated
+        assert!(manager
+            .read_data(NUM_ALLOCATION, INITIAL_TAIL_OFFSET, 3, 5)
+            .is_err());
+    }
+
+    #[test]
+    fn test_data_alignment() {
+        let count_pairs = |alignment| {
+            let config = Config::builder_for_testing()
+                .leaf_size(16 * 1024)
+                .data_alignment(alignment)
+                .in_memory(true)
+                .build();
+            let mut manager =
+                LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+            assert_eq!(manager.get_data_alignment(), alignment);
+            let (id, _) = manager.allocate_leaf().expect("page allocation failed");
+            let mut offsets = Vec::new();
+            let mut tail = INITIAL_TAIL_OFFSET;
+            while let Some(next) = manager
+                .write_data(id, tail, b"key", &[offsets.len() as u8])
+                .expect("write failed")
+            {
+                assert_eq!(
+                    next - tail,
+                    data_util::round_up_size(data_util::get_data_size(3, 1), alignment)
+                );
+                offsets.push(tail);
+                tail = next;
+            }
+            for (i, offset) in offsets.iter().enumerate() {
+                let (_, value) = manager.read_data(id, *offset, 3, 1).expect("read failed");
+                assert_eq!(value, vec![i as u8]);
+            }
+            offsets.len()
+        };
+
+        // a page of 16 KiB has 8 KiB for key-value pairs
+        assert_eq!(count_pairs(data_util::DEFAULT_DATA_ALIGNMENT), 2);
+        assert_eq!(count_pairs(64), 128);
+        assert!(count_pairs(8) > 128);
+    }
+
+    #[test]
+    fn test_overflow_in_memory() {
+        let config = Config::builder_for_testing()
+            .leaf_size(16 * 1024)
+            .in_memory(true)
+            .build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        let chunk_size = get_overflow_chunk_size(16 * 1024);
+        let value: Vec<u8> = (0..(chunk_size * 2 + 100)).map(|i| i as u8).collect();
+        let overflow_ref = manager.write_overflow(&value).expect("write failed");
+        assert_eq!(
+            manager.read_overflow(&overflow_ref).expect("read failed"),
+            value
+        );
+        let page_ids = OverflowRef::from_bytes(&overflow_ref, 16 * 1024)
+            .expect("broken reference")
+            .get_page_ids()
+            .to_vec();
+        assert_eq!(page_ids, vec![1, 2, 3]);
+
+        let tail_offset = manager
+            .write_data(id, INITIAL_TAIL_OFFSET, b"key", &overflow_ref)
+            .expect("write failed")
+            .expect("no space");
+        header.set_slot(0);
+        header.set_kv_info(0, id, INITIAL_TAIL_OFFSET, 3, overflow_ref.len());
+        header.set_overflow(0);
+        header.set_tail_offset(tail_offset);
+        manager.commit_header(id, &header).expect("commit failed");
+
+        // the overflow pages are used by the leaf
+        manager.reclaim_pages(&[id]).expect("reclaim failed");
+        assert!(page_ids
+            .iter()
+            .all(|page_id| !manager.free_leaves.contains(page_id)));
+        assert_eq!(
+            manager.read_overflow(&overflow_ref).expect("read failed"),
+            value
+        );
+    }
+
+    #[test]
+    fn test_mmap_cache() {
+        const NUM_ACCESSES: usize = 1000;
+        for (cache_pages, expected_maps) in [(0, NUM_ACCESSES * 2), (DEFAULT_MMAP_CACHE_PAGES, 2)] {
+            let config = Config::builder_for_testing()
+                .mmap_cache_pages(cache_pages)
+                .durability(Durability::OnFlushOnly)
+                .build();
+            let mut manager =
+                LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+            let (id, _) = manager.allocate_leaf().expect("page allocation failed");
+            let ext_id = manager.allocate_ext_page().expect("allocation failed");
+
+            let start = Instant::now();
+            for i in 0..NUM_ACCESSES {
+                // overwrite the same region of the leaf and the extension page alternately
+                let page_id = if i % 2 == 0 { id } else { ext_id };
+                let value = i.to_be_bytes();
+                manager
+                    .write_data(page_id, INITIAL_TAIL_OFFSET, b"key", &value)
+                    .expect("write failed");
+                let (key, ret_value) = manager
+                    .read_data(page_id, INITIAL_TAIL_OFFSET, 3, value.len())
+                    .expect("read failed");
+                assert_eq!(key, b"key");
+                assert_eq!(ret_value, value);
+            }
+            debug!(
+                "{} accesses with {} cached pages took {:?}",
+                NUM_ACCESSES * 2,
+                cache_pages,
+                start.elapsed()
+            );
+            // each page is mapped only once with the cache
+            assert_eq!(manager.map_count.load(Ordering::Relaxed), expected_maps);
+        }
+    }
+
+    #[test]
+    fn test_format_version() {
+        let config = Config::new_for_testing();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert_eq!(manager.get_format_version(), data_util::FORMAT_VERSION);
+
+        // a leaf written by the version 0
+        let (id, _) = manager.allocate_leaf().expect("page allocation failed");
+        manager
+            .commit_header(id, &LeafHeader::new_v0())
+            .expect("commit failed");
+        drop(manager);
+
+        let manager = LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert_eq!(manager.get_format_version(), 0);
+        assert_eq!(manager.get_leaf_id_chain(), vec![id]);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let builder = Config::builder_for_testing();
+        // a leaf file written with CRC-32
+        let config = builder.clone().build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, header) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(header.get_checksum(), Checksum::Crc32);
+        manager
+            .write_data(id, INITIAL_TAIL_OFFSET, b"key", b"value")
+            .expect("write failed");
+        manager.commit_header(id, &header).expect("commit failed");
+        drop(manager);
+
+        // still verified after CRC-32C is configured
+        let config = builder.checksum(Checksum::Crc32c).build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (key, value) = manager
+            .read_data(id, INITIAL_TAIL_OFFSET, 3, 5)
+            .expect("read failed");
+        assert_eq!(
+            (key.as_slice(), value.as_slice()),
+            (&b"key"[..], &b"value"[..])
+        );
+        let (_, header) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(header.get_checksum(), Checksum::Crc32);
+
+        // a new leaf file is written with CRC-32C
+        let mut manager =
+            LeafManager::new("test", 1, &config).expect("cannot create a leaf manager");
+        let (id, header) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(header.get_checksum(), Checksum::Crc32c);
+        manager
+            .write_data(id, INITIAL_TAIL_OFFSET, b"key", b"value")
+            .expect("write failed");
+        manager.commit_header(id, &header).expect("commit failed");
+        let page = manager.mmap_page(id).expect("mmap failed");
+        let page = page.read_or_recover();
+        let data = &page[INITIAL_TAIL_OFFSET..];
+        let key = &data[..data_util::get_bound_offset(3)];
+        assert!(data_util::check_slot_crc(key, Checksum::Crc32c).is_ok());
+        assert!(data_util::check_slot_crc(key, Checksum::Crc32).is_err());
+        drop(page);
+        drop(manager);
+
+        let manager = LeafManager::new("test", 1, &config).expect("cannot reopen");
+        assert_eq!(
+            manager.get_header(id).map(|h| h.get_checksum()),
+            Some(Checksum::Crc32c)
+        );
+        assert!(manager.read_data(id, INITIAL_TAIL_OFFSET, 3, 5).is_ok());
+    }
+
+    #[test]
+    fn test_set_obsolete() {
+        let config = Config::new_for_testing();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, header) = manager.allocate_leaf().expect("page allocation failed");
+        manager.commit_header(id, &header).expect("commit failed");
+
+        manager.set_obsolete().expect("cannot set obsolete");
+        let obsolete_file_path = config.get_obsolete_leaf_file_path("test", 0);
+        assert!(!std::path::Path::new(&config.get_leaf_file_path("test", 0)).exists());
+        assert!(std::path::Path::new(&obsolete_file_path).exists());
+        // the leaf is still readable
+        assert!(manager.get_header(id).is_some());
+
+        drop(manager);
+        assert!(!std::path::Path::new(&obsolete_file_path).exists());
+    }
+
+    #[test]
+    fn test_durability() {
+        for durability in [
+            Durability::PerWrite,
+            Durability::Batched(Duration::from_millis(1)),
+            Durability::OnFlushOnly,
+        ] {
+            let config = Config::builder_for_testing().durability(durability).build();
+            let mut manager =
+                LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+            let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+            let last_sync = *manager.last_sync.lock().unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+
+            let tail = manager
+                .write_data(id, INITIAL_TAIL_OFFSET, b"key", b"value")
+                .expect("write failed")
+                .unwrap();
+            header.set_tail_offset(tail);
+            manager.commit_header(id, &header).expect("commit failed");
+            // only a batched write syncs the file after the interval
+            let synced = *manager.last_sync.lock().unwrap() != last_sync;
+            assert_eq!(synced, matches!(durability, Durability::Batched(_)));
+
+            // the unsynced writes are synced when closing the file
+            drop(manager);
+            let manager = LeafManager::new("test", 0, &config).expect("cannot reopen");
+            assert_eq!(manager.get_header(id).unwrap().get_tail_offset(), tail);
+            let (key, value) = manager
+                .read_data(id, INITIAL_TAIL_OFFSET, 3, 5)
+                .expect("read failed");
+            assert_eq!(key, b"key");
+            assert_eq!(value, b"value");
+        }
+    }
+
+    #[test]
+    fn test_torn_header() {
+        let config = Config::builder_for_testing().build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        header.set_slot(0);
+        manager.commit_header(id, &header).expect("commit failed");
+        let old_header = manager.get_header(id).unwrap();
+        header.set_slot(1);
+        manager.commit_header(id, &header).expect("commit failed");
+        drop(manager);
+
+        let corrupt = |slot_offset: usize| {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(config.get_leaf_file_path("test", 0))
+                .unwrap();
+            let offset = id * config.get_leaf_size() + slot_offset;
+            std::os::unix::fs::FileExt::write_all_at(&file, &[0xFF; 16], offset as u64 + 8)
+                .unwrap();
+        };
+        let tail_slot_offset = get_end_tail_offset(config.get_leaf_size());
+
+        // the newest header in the tail slot was torn
+        corrupt(tail_slot_offset);
+        let manager = LeafManager::new("test", 0, &config).expect("cannot reopen");
+        assert_eq!(manager.get_header(id).unwrap(), old_header);
+
+        // the torn slot is overwritten by the next commit
+        let mut header = manager.get_header(id).unwrap();
+        header.set_slot(2);
+        manager.commit_header(id, &header).expect("commit failed");
+        drop(manager);
+        let manager = LeafManager::new("test", 0, &config).expect("cannot reopen");
+        assert_eq!(manager.get_header(id).unwrap(), header);
+        drop(manager);
+
+        // the stale header in the head slot was torn
+        corrupt(0);
+        let manager = LeafManager::new("test", 0, &config).expect("cannot reopen");
+        assert_eq!(manager.get_header(id).unwrap(), header);
+    }
+
+    #[test]
+    fn test_fault_after_header_commit() {
+        let injector = Arc::new(FaultInjector::default());
+        let config = Config::builder_for_testing()
+            .fault_injector(injector.clone())
+            .build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        header.set_slot(0);
+        injector.arm(FaultPoint::LeafHeaderCommitted, 0);
+        assert!(manager.commit_header(id, &header).is_err());
+        drop(manager);
+
+        // the header has been committed before the fault
+        let manager = LeafManager::new("test", 0, &config).expect("cannot reopen");
+        assert_eq!(manager.get_header(id).unwrap(), header);
+    }
+
+    #[test]
+    fn test_recover_num_slot() {
+        let builder = Config::builder_for_testing();
+        let config = builder.clone().num_slot(128).build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert_eq!(manager.num_slot, 128);
+        let (id, mut header) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(header.get_num_slot(), 128);
+        header.set_slot(127);
+        manager.commit_header(id, &header).expect("commit failed");
+        drop(manager);
+
+        // the number of slots in the file is used regardless of the config
+        let config = builder.num_slot(DEFAULT_NUM_SLOT).build();
+        let manager = LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert_eq!(manager.num_slot, 128);
+        let recovered = manager.get_header(id).expect("no header");
+        assert_eq!(recovered, header);
+        assert!(recovered.is_slot_set(127));
+    }
+
+    #[test]
+    fn test_invalid_num_slot() {
+        let config = Config::builder_for_testing().num_slot(12).build();
+        assert!(LeafManager::new("test", 0, &config).is_err());
+    }
+
+    #[test]
+    fn test_recover_different_leaf_size() {
+        let builder = Config::builder_for_testing();
+        let config = builder.clone().leaf_size(64 * 1024).build();
+        let mut manager =
+            LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        let (id, header) = manager.allocate_leaf().expect("page allocation failed");
+        assert_eq!(header.get_leaf_size(), 64 * 1024);
+        manager.commit_header(id, &header).expect("commit failed");
+        let (next_id, next_header) = manager.allocate_leaf().expect("page allocation failed");
+        manager
+            .commit_header(next_id, &next_header)
+            .expect("commit failed");
+        drop(manager);
+
+        // the same leaf size
+        let manager = LeafManager::new("test", 0, &config).expect("cannot create a leaf manager");
+        assert!(manager.get_header(next_id).is_some());
+        drop(manager);
+
+        let config = builder.leaf_size(DEFAULT_LEAF_SIZE).build();
+        match LeafManager::new("test", 0, &config) {
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::InvalidData);
+                assert!(e.to_string().contains("leaf size 65536"));
+            }
+            Ok(_) => panic!("the different leaf size should be rejected"),
+        }
+    }
 }
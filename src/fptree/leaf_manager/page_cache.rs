@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::storage::Buffer;
+use crate::util::lock_util::MutexExt;
+
+pub const DEFAULT_MMAP_CACHE_PAGES: usize = 64;
+
+/// The mapping of a whole page
+/// Writes to a page are serialized by the lock of the leaf which owns it
+pub type PageMmap = Arc<RwLock<Buffer>>;
+
+/// LRU cache of page mappings evicted by the number of pages
+pub struct PageCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// Cached mappings with their last access
+    pages: HashMap<usize, (PageMmap, u64)>,
+    /// Page IDs from the least recently used one
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+}
+
+impl PageCache {
+    /// `capacity` is the number of cached pages
+    pub fn new(capacity: usize) -> Self {
+        PageCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Return the cached mapping of the page, or cache a new one made by `map`
+    /// The least recently used pages over the capacity are evicted, but their mappings are
+    /// kept until all readers release them
+    pub fn get_or_map(
+        &self,
+        page_id: usize,
+        map: impl FnOnce() -> Result<Buffer, std::io::Error>,
+    ) -> Result<PageMmap, std::io::Error> {
+        if self.capacity == 0 {
+            return Ok(Arc::new(RwLock::new(map()?)));
+        }
+
+        let mut inner = self.inner.lock_or_recover();
+        let tick = inner.next_tick();
+        if let Some((mmap, last_access)) = inner.pages.get_mut(&page_id) {
+            let mmap = mmap.clone();
+            let prev = std::mem::replace(last_access, tick);
+            inner.lru.remove(&prev);
+            inner.lru.insert(tick, page_id);
+            return Ok(mmap);
+        }
+
+        // mapped with the lock not to map the same page twice
+        let mmap = Arc::new(RwLock::new(map()?));
+        inner.pages.insert(page_id, (mmap.clone(), tick));
+        inner.lru.insert(tick, page_id);
+        while inner.pages.len() > self.capacity {
+            let (_, evicted) = inner.lru.pop_first().expect("no cached page");
+            inner.pages.remove(&evicted);
+        }
+
+        Ok(mmap)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock_or_recover().pages.len()
+    }
+}
+
+impl CacheInner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memmap::MmapMut;
+
+    fn map() -> Result<Buffer, std::io::Error> {
+        Ok(Buffer::Mmap(MmapMut::map_anon(4096)?))
+    }
+
+    #[test]
+    fn test_page_cache() {
+        let cache = PageCache::new(2);
+        let page0 = cache.get_or_map(0, map).unwrap();
+        page0.write().unwrap()[0] = 1;
+        cache.get_or_map(1, map).unwrap();
+
+        // the cached mapping is returned
+        let cached = cache
+            .get_or_map(0, || panic!("the page should be cached"))
+            .unwrap();
+        assert_eq!(cached.read().unwrap()[0], 1);
+
+        // page 1 is the least recently used one
+        cache.get_or_map(2, map).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_or_map(0, || panic!("evicted")).is_ok());
+        assert!(cache.get_or_map(2, || panic!("evicted")).is_ok());
+        let remapped = cache.get_or_map(1, map).unwrap();
+        assert_eq!(remapped.read().unwrap()[0], 0);
+        assert_eq!(cache.len(), 2);
+        // the evicted mapping is still readable
+        assert_eq!(page0.read().unwrap()[0], 1);
+
+        // no page is cached with no capacity
+        let cache = PageCache::new(0);
+        cache.get_or_map(0, map).unwrap();
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,248 @@
+use log::{debug, warn};
+use memmap::{MmapMut, MmapOptions};
+use std::fs::File;
+use std::ops::{Deref, DerefMut};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::page_cache::{PageCache, PageMmap};
+use super::types::INITIAL_TAIL_OFFSET;
+use crate::config::{Config, Durability};
+use crate::storage::Storage;
+
+/// Bytes of a header slot or a whole page
+pub enum Buffer {
+    Mmap(MmapMut),
+    Memory(Box<[u8]>),
+}
+
+impl Buffer {
+    /// Persist the region of the buffer
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<(), std::io::Error> {
+        match self {
+            Buffer::Mmap(mmap) => mmap.flush_range(offset, len),
+            Buffer::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Buffer::Mmap(mmap) => mmap,
+            Buffer::Memory(bytes) => bytes,
+        }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Buffer::Mmap(mmap) => mmap,
+            Buffer::Memory(bytes) => bytes,
+        }
+    }
+}
+
+/// Pages of leaves which `LeafManager` reads and writes
+///
+/// The manager keeps the header slots of allocated leaves, so a header region is
+/// mapped only when a leaf is allocated or recovered.
+pub trait LeafStorage: Send + Sync {
+    /// The number of allocated pages
+    fn num_pages(&self) -> Result<usize, std::io::Error>;
+
+    /// Append `num` pages
+    fn extend(&mut self, num: usize) -> Result<(), std::io::Error>;
+
+    /// Return the header region at `offset` of the storage
+    fn map_header(&self, offset: usize) -> Result<Buffer, std::io::Error>;
+
+    /// Return the whole page
+    fn map_page(&self, id: usize) -> Result<PageMmap, std::io::Error>;
+
+    /// Persist all writes
+    fn sync(&self) -> Result<(), std::io::Error>;
+
+    /// Mark the pages as obsolete not to be recovered
+    fn set_obsolete(&mut self) -> Result<(), std::io::Error>;
+}
+
+/// Pages in a leaf file mapped to the memory
+pub struct FileStorage {
+    file: File,
+    leaf_size: usize,
+    /// Mappings of pages reused by reads and writes of key-value pairs
+    page_mmaps: PageCache,
+    file_path: String,
+    obsolete_file_path: String,
+    storage: Arc<dyn Storage>,
+    is_obsolete: bool,
+    durability: Durability,
+    /// The number of mapped pages
+    #[cfg(test)]
+    map_count: Arc<AtomicUsize>,
+}
+
+impl FileStorage {
+    /// Open the leaf file of the FPTree, and return whether it has been created
+    pub fn open(name: &str, id: usize, config: &Config) -> Result<(Self, bool), std::io::Error> {
+        let data_dir = config.get_leaf_dir_path(name);
+        if let Err(e) = std::fs::create_dir_all(&data_dir) {
+            unreachable!("Creating {} failed: {}", data_dir, e);
+        }
+
+        let file_path = config.get_leaf_file_path(name, id);
+        let storage = config.get_storage().clone();
+        let (file, is_created) = storage.open_leaf_file(&file_path)?;
+        let storage = FileStorage {
+            file,
+            leaf_size: config.get_leaf_size(),
+            page_mmaps: PageCache::new(config.get_mmap_cache_pages()),
+            file_path,
+            obsolete_file_path: config.get_obsolete_leaf_file_path(name, id),
+            storage,
+            is_obsolete: false,
+            durability: config.get_durability(),
+            #[cfg(test)]
+            map_count: Arc::new(AtomicUsize::new(0)),
+        };
+
+        Ok((storage, is_created))
+    }
+
+    /// Count mappings of pages with the counter
+    #[cfg(test)]
+    pub fn with_map_count(mut self, map_count: Arc<AtomicUsize>) -> Self {
+        self.map_count = map_count;
+        self
+    }
+}
+
+impl LeafStorage for FileStorage {
+    fn num_pages(&self) -> Result<usize, std::io::Error> {
+        Ok(self.file.metadata()?.len() as usize / self.leaf_size)
+    }
+
+    fn extend(&mut self, num: usize) -> Result<(), std::io::Error> {
+        let new_size = (self.num_pages()? + num) * self.leaf_size;
+        self.file.set_len(new_size as u64)
+    }
+
+    fn map_header(&self, offset: usize) -> Result<Buffer, std::io::Error> {
+        // the whole header region is mapped since the header size depends on the number of slots
+        let mmap = unsafe {
+            MmapOptions::new()
+                .offset(offset as u64)
+                .len(INITIAL_TAIL_OFFSET)
+                .map_mut(&self.file)?
+        };
+
+        Ok(Buffer::Mmap(mmap))
+    }
+
+    /// Return the mapping of the whole page through the cache
+    fn map_page(&self, id: usize) -> Result<PageMmap, std::io::Error> {
+        self.page_mmaps.get_or_map(id, || {
+            #[cfg(test)]
+            self.map_count.fetch_add(1, Ordering::Relaxed);
+            let mmap = unsafe {
+                MmapOptions::new()
+                    .offset((id * self.leaf_size) as u64)
+                    .len(self.leaf_size)
+                    .map_mut(&self.file)?
+            };
+            Ok(Buffer::Mmap(mmap))
+        })
+    }
+
+    fn sync(&self) -> Result<(), std::io::Error> {
+        self.file.sync_data()
+    }
+
+    /// The file is renamed not to be recovered, and removed when the storage is dropped
+    fn set_obsolete(&mut self) -> Result<(), std::io::Error> {
+        self.storage
+            .rename(&self.file_path, &self.obsolete_file_path)?;
+        self.is_obsolete = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for FileStorage {
+    fn drop(&mut self) {
+        // the leaf file might be reopened on restart
+        if !self.is_obsolete && self.durability != Durability::PerWrite {
+            if let Err(e) = self.file.sync_data() {
+                warn!("Failed to sync {}: {}", self.file_path, e);
+            }
+        }
+        if self.is_obsolete {
+            debug!(
+                "Removing the obsolete leaf file {}",
+                self.obsolete_file_path
+            );
+            if let Err(e) = self.storage.remove(&self.obsolete_file_path) {
+                warn!("Failed to remove {}: {}", self.obsolete_file_path, e);
+            }
+        }
+    }
+}
+
+/// Pages in `Vec`-backed buffers which are lost when the storage is dropped
+pub struct MemoryStorage {
+    leaf_size: usize,
+    pages: Vec<PageMmap>,
+}
+
+impl MemoryStorage {
+    pub fn new(leaf_size: usize) -> Self {
+        MemoryStorage {
+            leaf_size,
+            pages: Vec::new(),
+        }
+    }
+}
+
+impl LeafStorage for MemoryStorage {
+    fn num_pages(&self) -> Result<usize, std::io::Error> {
+        Ok(self.pages.len())
+    }
+
+    fn extend(&mut self, num: usize) -> Result<(), std::io::Error> {
+        for _ in 0..num {
+            let page = vec![0; self.leaf_size].into_boxed_slice();
+            self.pages.push(Arc::new(RwLock::new(Buffer::Memory(page))));
+        }
+
+        Ok(())
+    }
+
+    /// Return a new buffer since headers of allocated leaves are kept by the manager
+    fn map_header(&self, _offset: usize) -> Result<Buffer, std::io::Error> {
+        Ok(Buffer::Memory(
+            vec![0; INITIAL_TAIL_OFFSET].into_boxed_slice(),
+        ))
+    }
+
+    fn map_page(&self, id: usize) -> Result<PageMmap, std::io::Error> {
+        self.pages.get(id).cloned().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("page {} isn't allocated", id),
+            )
+        })
+    }
+
+    fn sync(&self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn set_obsolete(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
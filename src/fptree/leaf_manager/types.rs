@@ -1,42 +1,109 @@
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::Hasher;
+use std::io::ErrorKind;
 
+use crate::amphis_error::CorruptionError;
+use crate::config::{Checksum, FingerprintHash};
 use crate::util::data_util;
 
-pub const NUM_SLOT: usize = 32;
-pub const NUM_ALLOCATION: usize = 16;
-pub const LEAF_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_NUM_SLOT: usize = 32;
+// the leaf file is extended by this size unless the number of leaves is configured
+pub const DEFAULT_ALLOCATION_BYTES: usize = 4 * 1024 * 1024;
+pub const DEFAULT_LEAF_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_FINGERPRINT_BITS: usize = 8;
 
 const INVALID_LEAF_ID: u32 = u32::MAX;
-pub const INITIAL_TAIL_OFFSET: usize = data_util::DATA_ALIGNMENT;
-pub const END_TAIL_OFFSET: usize = LEAF_SIZE - data_util::DATA_ALIGNMENT;
+// the top bit of the value size marks a value stored in overflow pages
+const OVERFLOW_FLAG: u32 = 1 << 31;
+const LEN_OVERFLOW_VALUE_SIZE: usize = 8;
+const LEN_OVERFLOW_PAGE_ID: usize = 4;
+// the header region is followed by key-value pairs
+pub const INITIAL_TAIL_OFFSET: usize = data_util::PAGE_SIZE;
 
 // for header format
-pub(super) const HEADER_MAGIC: u32 = 0x1234;
-pub(super) const LEN_HEADER_MAGIC: usize = 4;
-const LEN_BITMAP: usize = NUM_SLOT / 8;
+// the magic also identifies the format version of values in the leaf
+pub(super) const HEADER_MAGIC: u32 = 0x1239;
+// headers in the current layout whose CRCs and CRCs of key-value pairs are CRC-32C
+pub(super) const HEADER_MAGIC_CRC32C: u32 = 0x123A;
+// headers with 8-bit fingerprints by SipHash
+pub(super) const HEADER_MAGIC_V4: u32 = 0x1238;
+// headers without the sequence number
+pub(super) const HEADER_MAGIC_V3: u32 = 0x1237;
+// headers whose empty values are deletions
+pub(super) const HEADER_MAGIC_V2: u32 = 0x1236;
+// headers with the fixed number of slots
+pub(super) const HEADER_MAGIC_V1: u32 = 0x1235;
+pub(super) const HEADER_MAGIC_V0: u32 = 0x1234;
+const LEGACY_NUM_SLOT: usize = 32;
+const LEGACY_LEAF_SIZE: usize = 1024 * 1024;
+const LEN_HEADER_MAGIC: usize = 4;
+const LEN_NUM_SLOT: usize = 4;
+const LEN_LEAF_SIZE: usize = 4;
+const LEN_FINGERPRINT_HASH: usize = 1;
+const LEN_FINGERPRINT_BITS: usize = 1;
+// bincode prefixes a Vec with its length
+const LEN_VEC_LEN: usize = 8;
 const LEN_NEXT: usize = 4;
 const LEN_EXT: usize = 4;
 const LEN_TAIL_OFFSET: usize = 4;
-const LEN_FINGERPRINTS: usize = NUM_SLOT;
-const LEN_KV_INFO: usize = NUM_SLOT * std::mem::size_of::<KVInfo>();
-pub const LEAF_HEADER_SIZE: usize = LEN_HEADER_MAGIC
-    + LEN_BITMAP
+const LEN_SEQ: usize = 8;
+const LEN_KV_INFO: usize = std::mem::size_of::<KVInfo>();
+const LEGACY_HEADER_SIZE: usize = LEN_HEADER_MAGIC
+    + LEGACY_NUM_SLOT / 8
     + LEN_NEXT
     + LEN_EXT
     + LEN_TAIL_OFFSET
-    + LEN_FINGERPRINTS
-    + LEN_KV_INFO
+    + LEGACY_NUM_SLOT
+    + LEGACY_NUM_SLOT * LEN_KV_INFO
     + data_util::LEN_CRC;
 
+// IDs of fingerprint hash functions in headers
+const FINGERPRINT_HASH_SIP: u8 = 0;
+const FINGERPRINT_HASH_XXHASH: u8 = 1;
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct LeafHeader {
     magic: u32,
-    bitmap: [u8; NUM_SLOT / 8],
+    num_slot: u32,
+    leaf_size: u32,
+    fingerprint_hash: u8,
+    fingerprint_bits: u8,
+    bitmap: Vec<u8>,
+    next: u32,
+    ext: u32,
+    tail_offset: u32,
+    /// Little-endian fingerprints of `fingerprint_bits` for each slot
+    fingerprints: Vec<u8>,
+    kv_info: Vec<KVInfo>,
+}
+
+/// Header before the fingerprint became configurable
+/// Its fingerprints are 8-bit SipHash
+#[derive(Serialize, Deserialize)]
+struct LeafHeaderV4 {
+    magic: u32,
+    num_slot: u32,
+    leaf_size: u32,
+    bitmap: Vec<u8>,
     next: u32,
     ext: u32,
     tail_offset: u32,
-    fingerprints: [u8; NUM_SLOT],
-    kv_info: [KVInfo; NUM_SLOT],
+    fingerprints: Vec<u8>,
+    kv_info: Vec<KVInfo>,
+}
+
+/// Header before the number of slots became configurable
+#[derive(Serialize, Deserialize)]
+struct LegacyLeafHeader {
+    magic: u32,
+    bitmap: [u8; LEGACY_NUM_SLOT / 8],
+    next: u32,
+    ext: u32,
+    tail_offset: u32,
+    fingerprints: [u8; LEGACY_NUM_SLOT],
+    kv_info: [KVInfo; LEGACY_NUM_SLOT],
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
@@ -47,23 +114,329 @@ struct KVInfo {
     value_size: u32,
 }
 
+/// Return the size of an encoded header with `num_slot` slots and fingerprints of
+/// `fingerprint_bits` including the sequence number and the CRC
+pub fn get_header_size(num_slot: usize, fingerprint_bits: usize) -> usize {
+    get_header_size_without_seq(num_slot, fingerprint_bits / 8)
+        + LEN_FINGERPRINT_HASH
+        + LEN_FINGERPRINT_BITS
+        + LEN_SEQ
+}
+
+/// Return the size of a header in the layout of `LeafHeaderV4` without the sequence number
+fn get_header_size_without_seq(num_slot: usize, fingerprint_bytes: usize) -> usize {
+    LEN_HEADER_MAGIC
+        + LEN_NUM_SLOT
+        + LEN_LEAF_SIZE
+        + LEN_VEC_LEN
+        + num_slot / 8
+        + LEN_NEXT
+        + LEN_EXT
+        + LEN_TAIL_OFFSET
+        + LEN_VEC_LEN
+        + num_slot * fingerprint_bytes
+        + LEN_VEC_LEN
+        + num_slot * LEN_KV_INFO
+        + data_util::LEN_CRC
+}
+
+fn get_checksum(magic: u32) -> Checksum {
+    if magic == HEADER_MAGIC_CRC32C {
+        Checksum::Crc32c
+    } else {
+        Checksum::Crc32
+    }
+}
+
+/// Check that fingerprints can have `fingerprint_bits`
+pub fn validate_fingerprint_bits(fingerprint_bits: usize) -> Result<(), std::io::Error> {
+    if fingerprint_bits != 8 && fingerprint_bits != 16 {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid fingerprint bits: {}", fingerprint_bits),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that a leaf can have `num_slot` slots with fingerprints of `fingerprint_bits`
+/// The number has to be a multiple of 8 and the header has to fit in the header region
+pub fn validate_num_slot(num_slot: usize, fingerprint_bits: usize) -> Result<(), std::io::Error> {
+    if num_slot == 0
+        || !num_slot.is_multiple_of(8)
+        || get_header_size(num_slot, fingerprint_bits) > INITIAL_TAIL_OFFSET
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid number of slots in a leaf: {}", num_slot),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Return the end of the data region in a leaf of `leaf_size` bytes
+pub fn get_end_tail_offset(leaf_size: usize) -> usize {
+    leaf_size - data_util::PAGE_SIZE
+}
+
+/// The largest size of a key-value pair which fits in a page of `leaf_size` bytes
+pub fn get_max_data_size(leaf_size: usize) -> usize {
+    get_end_tail_offset(leaf_size) - INITIAL_TAIL_OFFSET
+}
+
+/// The size of a part of a large value written to each overflow page
+pub fn get_overflow_chunk_size(leaf_size: usize) -> usize {
+    get_max_data_size(leaf_size) - data_util::get_data_size(0, 0)
+}
+
+/// Return the largest size of a value stored with a key of `key_size` bytes
+/// A value which doesn't fit in the page is stored in overflow pages
+pub fn get_max_value_size(leaf_size: usize, key_size: usize) -> usize {
+    let max_data_size = get_max_data_size(leaf_size);
+    let in_page = max_data_size.saturating_sub(data_util::get_data_size(key_size, 0));
+    let num_pages = max_data_size
+        .saturating_sub(data_util::get_data_size(key_size, LEN_OVERFLOW_VALUE_SIZE))
+        / LEN_OVERFLOW_PAGE_ID;
+
+    in_page.max(num_pages * get_overflow_chunk_size(leaf_size))
+}
+
+/// Check that key-value pairs can start at multiples of `alignment` bytes
+/// It has to be a power of two not to break the alignment of pages
+pub fn validate_data_alignment(alignment: usize) -> Result<(), std::io::Error> {
+    if !alignment.is_power_of_two() || alignment > data_util::PAGE_SIZE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid data alignment: {}", alignment),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that a leaf of `leaf_size` bytes can be allocated
+/// The size has to be aligned and has to leave some space for key-value pairs
+pub fn validate_leaf_size(leaf_size: usize) -> Result<(), std::io::Error> {
+    if !leaf_size.is_multiple_of(data_util::PAGE_SIZE)
+        || leaf_size < INITIAL_TAIL_OFFSET + 2 * data_util::PAGE_SIZE
+        || leaf_size > OVERFLOW_FLAG as usize
+    {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid leaf size: {}", leaf_size),
+        ));
+    }
+
+    Ok(())
+}
+
 impl LeafHeader {
-    pub fn new() -> Self {
+    /// Make a header with the default fingerprints
+    #[cfg(test)]
+    pub fn new(num_slot: usize, leaf_size: usize) -> Self {
+        Self::with_fingerprint(
+            num_slot,
+            leaf_size,
+            FingerprintHash::default(),
+            DEFAULT_FINGERPRINT_BITS,
+        )
+    }
+
+    /// Make a header whose fingerprints are `fingerprint_bits` of the hash
+    pub fn with_fingerprint(
+        num_slot: usize,
+        leaf_size: usize,
+        fingerprint_hash: FingerprintHash,
+        fingerprint_bits: usize,
+    ) -> Self {
         LeafHeader {
             magic: HEADER_MAGIC,
-            bitmap: [0u8; NUM_SLOT / 8],
+            num_slot: num_slot as u32,
+            leaf_size: leaf_size as u32,
+            fingerprint_hash: match fingerprint_hash {
+                FingerprintHash::Sip => FINGERPRINT_HASH_SIP,
+                FingerprintHash::XxHash => FINGERPRINT_HASH_XXHASH,
+            },
+            fingerprint_bits: fingerprint_bits as u8,
+            bitmap: vec![0u8; num_slot / 8],
             next: INVALID_LEAF_ID,
             ext: INVALID_LEAF_ID,
-            fingerprints: [0u8; NUM_SLOT],
-            kv_info: [KVInfo::new(); NUM_SLOT],
+            fingerprints: vec![0u8; num_slot * fingerprint_bits / 8],
+            kv_info: vec![KVInfo::new(); num_slot],
             tail_offset: INITIAL_TAIL_OFFSET as u32,
         }
     }
 
+    /// Compute CRCs by the algorithm
+    /// It has to be set before the header is written
+    pub fn with_checksum(self, checksum: Checksum) -> Self {
+        let magic = match checksum {
+            Checksum::Crc32 => HEADER_MAGIC,
+            Checksum::Crc32c => HEADER_MAGIC_CRC32C,
+        };
+        LeafHeader { magic, ..self }
+    }
+
+    #[cfg(test)]
+    pub(super) fn new_v0() -> Self {
+        LeafHeader {
+            magic: HEADER_MAGIC_V0,
+            ..Self::new(LEGACY_NUM_SLOT, LEGACY_LEAF_SIZE)
+        }
+    }
+
+    /// Decode the header from the header region with validating its magic and CRC
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        Ok(Self::from_bytes_with_seq(bytes)?.0)
+    }
+
+    /// Decode the header and the sequence number of the write
+    /// A header written before the sequence number was introduced has 0
+    pub fn from_bytes_with_seq(bytes: &[u8]) -> Result<(Self, u64), std::io::Error> {
+        let magic = u32::from_le_bytes(bytes[0..LEN_HEADER_MAGIC].try_into().unwrap());
+        let header_size = match magic {
+            HEADER_MAGIC_V0 | HEADER_MAGIC_V1 => LEGACY_HEADER_SIZE,
+            HEADER_MAGIC | HEADER_MAGIC_CRC32C | HEADER_MAGIC_V4 | HEADER_MAGIC_V3
+            | HEADER_MAGIC_V2 => {
+                let num_slot = u32::from_le_bytes(
+                    bytes[LEN_HEADER_MAGIC..(LEN_HEADER_MAGIC + LEN_NUM_SLOT)]
+                        .try_into()
+                        .unwrap(),
+                ) as usize;
+                let fingerprint_bits = if magic == HEADER_MAGIC || magic == HEADER_MAGIC_CRC32C {
+                    // the hash ID precedes the number of bits
+                    bytes[LEN_HEADER_MAGIC + LEN_NUM_SLOT + LEN_LEAF_SIZE + LEN_FINGERPRINT_HASH]
+                        as usize
+                } else {
+                    DEFAULT_FINGERPRINT_BITS
+                };
+                let to_invalid_data =
+                    |e: std::io::Error| std::io::Error::new(ErrorKind::InvalidData, e.to_string());
+                validate_fingerprint_bits(fingerprint_bits).map_err(to_invalid_data)?;
+                validate_num_slot(num_slot, fingerprint_bits).map_err(to_invalid_data)?;
+                match magic {
+                    HEADER_MAGIC | HEADER_MAGIC_CRC32C => {
+                        get_header_size(num_slot, fingerprint_bits)
+                    }
+                    HEADER_MAGIC_V4 => get_header_size_without_seq(num_slot, 1) + LEN_SEQ,
+                    _ => get_header_size_without_seq(num_slot, 1),
+                }
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "header magic was not found",
+                ))
+            }
+        };
+        let bytes = &bytes.get(..header_size).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidData, "the header region is too small")
+        })?;
+        data_util::check_header_crc(bytes, get_checksum(magic))?;
+
+        let invalid = |e| std::io::Error::new(ErrorKind::InvalidData, e);
+        let (header, seq) = match magic {
+            HEADER_MAGIC | HEADER_MAGIC_CRC32C => {
+                let (header, seq): (LeafHeader, u64) =
+                    bincode::deserialize(bytes).map_err(invalid)?;
+                if header.fingerprint_hash != FINGERPRINT_HASH_SIP
+                    && header.fingerprint_hash != FINGERPRINT_HASH_XXHASH
+                {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown fingerprint hash: {}", header.fingerprint_hash),
+                    ));
+                }
+                (header, seq)
+            }
+            // the values are in the current format
+            HEADER_MAGIC_V4 => {
+                let (header, seq): (LeafHeaderV4, u64) =
+                    bincode::deserialize(bytes).map_err(invalid)?;
+                (
+                    LeafHeader {
+                        magic: HEADER_MAGIC,
+                        ..LeafHeader::from(header)
+                    },
+                    seq,
+                )
+            }
+            HEADER_MAGIC_V3 => {
+                let header: LeafHeaderV4 = bincode::deserialize(bytes).map_err(invalid)?;
+                (
+                    LeafHeader {
+                        magic: HEADER_MAGIC,
+                        ..LeafHeader::from(header)
+                    },
+                    0,
+                )
+            }
+            HEADER_MAGIC_V2 => {
+                let header: LeafHeaderV4 = bincode::deserialize(bytes).map_err(invalid)?;
+                (LeafHeader::from(header), 0)
+            }
+            _ => {
+                let legacy: LegacyLeafHeader = bincode::deserialize(bytes).map_err(invalid)?;
+                (LeafHeader::from(legacy), 0)
+            }
+        };
+
+        Ok((header, seq))
+    }
+
+    /// Encode the header with the sequence number of the write and the CRC
+    /// An old header is encoded in its layout, which might not have the sequence number
+    pub fn to_bytes(&self, seq: u64) -> Result<Vec<u8>, std::io::Error> {
+        let encoded = match self.magic {
+            HEADER_MAGIC | HEADER_MAGIC_CRC32C => bincode::serialize(&(self, seq)),
+            HEADER_MAGIC_V4 => bincode::serialize(&(LeafHeaderV4::from(self), seq)),
+            HEADER_MAGIC_V3 | HEADER_MAGIC_V2 => bincode::serialize(&LeafHeaderV4::from(self)),
+            _ => bincode::serialize(&LegacyLeafHeader::from(self)),
+        };
+        // the bincode error is converted to `CrudError::Serialization`
+        let mut encoded = encoded.map_err(|e| std::io::Error::other(*e))?;
+        encoded.extend(&data_util::calc_checksum(self.get_checksum(), &encoded).to_le_bytes());
+
+        Ok(encoded)
+    }
+
+    /// Return the format version of values written with this header
+    pub fn get_format_version(&self) -> u8 {
+        match self.magic {
+            HEADER_MAGIC_V0 => 0,
+            HEADER_MAGIC_V1 | HEADER_MAGIC_V2 => 1,
+            _ => data_util::FORMAT_VERSION,
+        }
+    }
+
+    /// Return the checksum algorithm of this header and key-value pairs written with it
+    pub fn get_checksum(&self) -> Checksum {
+        get_checksum(self.magic)
+    }
+
+    pub fn get_num_slot(&self) -> usize {
+        self.num_slot as usize
+    }
+
+    /// Return the size of each leaf in the file which this header was written to
+    pub fn get_leaf_size(&self) -> usize {
+        self.leaf_size as usize
+    }
+
     pub fn need_split(&self) -> bool {
         self.bitmap.iter().all(|&x| x == 0xFF)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.bitmap.iter().all(|&x| x == 0)
+    }
+
+    pub fn count_set_slots(&self) -> usize {
+        self.bitmap.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
     pub fn get_empty_slot(&self) -> Option<usize> {
         for (i, slots) in self.bitmap.iter().enumerate() {
             if *slots == 0xFF {
@@ -132,18 +505,65 @@ impl LeafHeader {
         self.tail_offset = data_offset as u32;
     }
 
-    pub fn get_fingerprints(&self) -> &[u8] {
-        &self.fingerprints
+    pub fn get_fingerprint_hash(&self) -> FingerprintHash {
+        match self.fingerprint_hash {
+            FINGERPRINT_HASH_XXHASH => FingerprintHash::XxHash,
+            _ => FingerprintHash::Sip,
+        }
     }
 
-    pub fn set_fingerprint(&mut self, slot: usize, hash: u8) {
-        self.fingerprints[slot] = hash;
+    pub fn get_fingerprint_bits(&self) -> usize {
+        self.fingerprint_bits as usize
     }
 
+    /// Return the fingerprint of the key computed in the way of this header
+    pub fn calc_fingerprint(&self, key: &[u8]) -> u16 {
+        let hash = match self.fingerprint_hash {
+            FINGERPRINT_HASH_XXHASH => xxhash_rust::xxh3::xxh3_64(key),
+            _ => {
+                // each byte is written to be compatible with existing leaves
+                let mut hasher = DefaultHasher::new();
+                for b in key {
+                    hasher.write_u8(*b);
+                }
+                hasher.finish()
+            }
+        };
+
+        (hash & ((1 << self.fingerprint_bits) - 1)) as u16
+    }
+
+    pub fn get_fingerprint(&self, slot: usize) -> u16 {
+        let width = self.fingerprint_bits as usize / 8;
+        self.fingerprints[(slot * width)..((slot + 1) * width)]
+            .iter()
+            .rev()
+            .fold(0, |fp, b| (fp << 8) | *b as u16)
+    }
+
+    pub fn set_fingerprint(&mut self, slot: usize, fingerprint: u16) {
+        let width = self.fingerprint_bits as usize / 8;
+        let bytes = fingerprint.to_le_bytes();
+        self.fingerprints[(slot * width)..((slot + 1) * width)].copy_from_slice(&bytes[..width]);
+    }
+
+    /// The value size is the size stored in the page, i.e. the size of the `OverflowRef`
+    /// for a value in overflow pages
     pub fn get_kv_info(&self, slot: usize) -> (usize, usize, usize, usize) {
         self.kv_info[slot].get()
     }
 
+    /// Return true if the value of the slot is stored in overflow pages
+    pub fn is_overflow(&self, slot: usize) -> bool {
+        self.kv_info[slot].value_size & OVERFLOW_FLAG != 0
+    }
+
+    /// Mark the value of the slot as an `OverflowRef`
+    /// This has to be called after `set_kv_info`
+    pub fn set_overflow(&mut self, slot: usize) {
+        self.kv_info[slot].value_size |= OVERFLOW_FLAG;
+    }
+
     pub fn set_kv_info(
         &mut self,
         slot: usize,
@@ -156,6 +576,78 @@ impl LeafHeader {
     }
 }
 
+impl From<LeafHeaderV4> for LeafHeader {
+    fn from(header: LeafHeaderV4) -> Self {
+        LeafHeader {
+            magic: header.magic,
+            num_slot: header.num_slot,
+            leaf_size: header.leaf_size,
+            fingerprint_hash: FINGERPRINT_HASH_SIP,
+            fingerprint_bits: DEFAULT_FINGERPRINT_BITS as u8,
+            bitmap: header.bitmap,
+            next: header.next,
+            ext: header.ext,
+            tail_offset: header.tail_offset,
+            fingerprints: header.fingerprints,
+            kv_info: header.kv_info,
+        }
+    }
+}
+
+impl From<&LeafHeader> for LeafHeaderV4 {
+    fn from(header: &LeafHeader) -> Self {
+        assert_eq!(
+            header.fingerprint_bits as usize, DEFAULT_FINGERPRINT_BITS,
+            "not an old header"
+        );
+        LeafHeaderV4 {
+            magic: header.magic,
+            num_slot: header.num_slot,
+            leaf_size: header.leaf_size,
+            bitmap: header.bitmap.clone(),
+            next: header.next,
+            ext: header.ext,
+            tail_offset: header.tail_offset,
+            fingerprints: header.fingerprints.clone(),
+            kv_info: header.kv_info.clone(),
+        }
+    }
+}
+
+impl From<LegacyLeafHeader> for LeafHeader {
+    fn from(legacy: LegacyLeafHeader) -> Self {
+        LeafHeader {
+            magic: legacy.magic,
+            num_slot: LEGACY_NUM_SLOT as u32,
+            leaf_size: LEGACY_LEAF_SIZE as u32,
+            fingerprint_hash: FINGERPRINT_HASH_SIP,
+            fingerprint_bits: DEFAULT_FINGERPRINT_BITS as u8,
+            bitmap: legacy.bitmap.to_vec(),
+            next: legacy.next,
+            ext: legacy.ext,
+            tail_offset: legacy.tail_offset,
+            fingerprints: legacy.fingerprints.to_vec(),
+            kv_info: legacy.kv_info.to_vec(),
+        }
+    }
+}
+
+impl From<&LeafHeader> for LegacyLeafHeader {
+    fn from(header: &LeafHeader) -> Self {
+        LegacyLeafHeader {
+            magic: header.magic,
+            bitmap: header.bitmap[..].try_into().expect("not a legacy header"),
+            next: header.next,
+            ext: header.ext,
+            tail_offset: header.tail_offset,
+            fingerprints: header.fingerprints[..]
+                .try_into()
+                .expect("not a legacy header"),
+            kv_info: header.kv_info[..].try_into().expect("not a legacy header"),
+        }
+    }
+}
+
 impl KVInfo {
     fn new() -> Self {
         KVInfo {
@@ -171,7 +663,7 @@ impl KVInfo {
             self.page_id as usize,
             self.offset as usize,
             self.key_size as usize,
-            self.value_size as usize,
+            (self.value_size & !OVERFLOW_FLAG) as usize,
         )
     }
 
@@ -183,6 +675,67 @@ impl KVInfo {
     }
 }
 
+/// The pages of a value which doesn't fit in a page
+/// The leaf page has this reference as the value, and each page has a part of the value
+#[derive(PartialEq, Debug)]
+pub struct OverflowRef {
+    value_size: usize,
+    page_ids: Vec<usize>,
+}
+
+impl OverflowRef {
+    pub fn new(value_size: usize, page_ids: Vec<usize>) -> Self {
+        OverflowRef {
+            value_size,
+            page_ids,
+        }
+    }
+
+    /// Return the size of the reference to a value of `value_size` bytes
+    pub fn get_size(value_size: usize, leaf_size: usize) -> usize {
+        LEN_OVERFLOW_VALUE_SIZE
+            + value_size.div_ceil(get_overflow_chunk_size(leaf_size)) * LEN_OVERFLOW_PAGE_ID
+    }
+
+    pub fn from_bytes(bytes: &[u8], leaf_size: usize) -> Result<Self, std::io::Error> {
+        let broken = || CorruptionError("the overflow reference is broken".to_owned());
+        if bytes.len() < LEN_OVERFLOW_VALUE_SIZE {
+            return Err(broken().into());
+        }
+        let (value_size, page_ids) = bytes.split_at(LEN_OVERFLOW_VALUE_SIZE);
+        let value_size = u64::from_le_bytes(value_size.try_into().unwrap()) as usize;
+        if Self::get_size(value_size, leaf_size) != bytes.len() {
+            return Err(broken().into());
+        }
+        let page_ids = page_ids
+            .chunks_exact(LEN_OVERFLOW_PAGE_ID)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()) as usize)
+            .collect();
+
+        Ok(Self::new(value_size, page_ids))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            LEN_OVERFLOW_VALUE_SIZE + self.page_ids.len() * LEN_OVERFLOW_PAGE_ID,
+        );
+        bytes.extend((self.value_size as u64).to_le_bytes());
+        for id in &self.page_ids {
+            bytes.extend((*id as u32).to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn get_value_size(&self) -> usize {
+        self.value_size
+    }
+
+    pub fn get_page_ids(&self) -> &[usize] {
+        &self.page_ids
+    }
+}
+
 impl std::fmt::Display for LeafHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -207,16 +760,10 @@ impl std::fmt::Display for KVInfo {
 mod tests {
     use super::*;
 
+    const NUM_SLOT: usize = DEFAULT_NUM_SLOT;
+
     fn make_header() -> LeafHeader {
-        LeafHeader {
-            magic: HEADER_MAGIC,
-            bitmap: [0u8; NUM_SLOT / 8],
-            next: INVALID_LEAF_ID,
-            ext: INVALID_LEAF_ID,
-            fingerprints: [0u8; NUM_SLOT],
-            kv_info: [KVInfo::new(); NUM_SLOT],
-            tail_offset: INITIAL_TAIL_OFFSET as u32,
-        }
+        LeafHeader::new(NUM_SLOT, DEFAULT_LEAF_SIZE)
     }
 
     #[test]
@@ -229,6 +776,28 @@ mod tests {
         assert!(header.need_split());
     }
 
+    #[test]
+    fn test_is_empty() {
+        let mut header = make_header();
+        assert!(header.is_empty());
+        header.set_slot(NUM_SLOT - 1);
+        assert!(!header.is_empty());
+        header.unset_slot(NUM_SLOT - 1);
+        assert!(header.is_empty());
+    }
+
+    #[test]
+    fn test_count_set_slots() {
+        let mut header = make_header();
+        assert_eq!(header.count_set_slots(), 0);
+        header.set_slot(0);
+        header.set_slot(9);
+        header.set_slot(NUM_SLOT - 1);
+        assert_eq!(header.count_set_slots(), 3);
+        header.unset_slot(9);
+        assert_eq!(header.count_set_slots(), 2);
+    }
+
     #[test]
     fn test_slot() {
         let mut header = make_header();
@@ -243,4 +812,205 @@ mod tests {
         header.unset_slot(2);
         assert_eq!(header.get_empty_slot().unwrap(), 2);
     }
+
+    #[test]
+    fn test_encode_decode() {
+        for (num_slot, fingerprint_bits) in [(8, 8), (DEFAULT_NUM_SLOT, 8), (128, 8), (128, 16)] {
+            let mut header = LeafHeader::with_fingerprint(
+                num_slot,
+                DEFAULT_LEAF_SIZE,
+                FingerprintHash::XxHash,
+                fingerprint_bits,
+            );
+            header.set_slot(num_slot - 1);
+            header.set_fingerprint(num_slot - 1, 0x1207);
+            header.set_kv_info(num_slot - 1, 1, 4096, 3, 5);
+
+            let encoded = header.to_bytes(7).unwrap();
+            assert_eq!(encoded.len(), get_header_size(num_slot, fingerprint_bits));
+
+            let mut region = encoded.clone();
+            region.resize(INITIAL_TAIL_OFFSET, 0);
+            let (decoded, seq) = LeafHeader::from_bytes_with_seq(&region).unwrap();
+            assert_eq!(decoded, header);
+            assert_eq!(seq, 7);
+            assert_eq!(decoded.get_num_slot(), num_slot);
+            assert_eq!(decoded.get_fingerprint_hash(), FingerprintHash::XxHash);
+            assert_eq!(decoded.get_fingerprint_bits(), fingerprint_bits);
+            // truncated to the width
+            let expected = if fingerprint_bits == 8 { 0x07 } else { 0x1207 };
+            assert_eq!(decoded.get_fingerprint(num_slot - 1), expected);
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        let header = LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE);
+        assert_eq!(header.get_checksum(), Checksum::Crc32);
+        let ieee_encoded = header.to_bytes(7).unwrap();
+
+        let header = header.with_checksum(Checksum::Crc32c);
+        let encoded = header.to_bytes(7).unwrap();
+        assert_eq!(encoded.len(), ieee_encoded.len());
+        let decoded = LeafHeader::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.get_checksum(), Checksum::Crc32c);
+        assert_eq!(decoded.get_format_version(), data_util::FORMAT_VERSION);
+
+        // the CRC is checked by the algorithm of the magic
+        let mut broken = ieee_encoded;
+        broken[..LEN_HEADER_MAGIC].copy_from_slice(&HEADER_MAGIC_CRC32C.to_le_bytes());
+        assert!(LeafHeader::from_bytes(&broken).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let sip = LeafHeader::new(NUM_SLOT, DEFAULT_LEAF_SIZE);
+        // compatible with fingerprints in existing leaves
+        assert_eq!(sip.calc_fingerprint(b"key"), 192);
+
+        let xxhash =
+            LeafHeader::with_fingerprint(NUM_SLOT, DEFAULT_LEAF_SIZE, FingerprintHash::XxHash, 16);
+        let fingerprints: std::collections::HashSet<u16> = (0..1000u32)
+            .map(|i| xxhash.calc_fingerprint(&i.to_be_bytes()))
+            .collect();
+        // 8-bit fingerprints can't distinguish them
+        assert!(fingerprints.len() > 900);
+    }
+
+    #[test]
+    fn test_format_version() {
+        assert_eq!(
+            LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE).get_format_version(),
+            data_util::FORMAT_VERSION
+        );
+
+        // a header written when empty values were deletions
+        let header = LeafHeader {
+            magic: HEADER_MAGIC_V2,
+            ..LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE)
+        };
+        let encoded = header.to_bytes(7).unwrap();
+        let decoded = LeafHeader::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.get_format_version(), 1);
+
+        // a header written without the sequence number is upgraded
+        let header = LeafHeader {
+            magic: HEADER_MAGIC_V3,
+            ..LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE)
+        };
+        let encoded = header.to_bytes(7).unwrap();
+        assert_eq!(
+            encoded.len(),
+            get_header_size_without_seq(DEFAULT_NUM_SLOT, 1)
+        );
+        let (decoded, seq) = LeafHeader::from_bytes_with_seq(&encoded).unwrap();
+        assert_eq!(
+            decoded,
+            LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE)
+        );
+        assert_eq!(seq, 0);
+        assert_eq!(decoded.get_format_version(), data_util::FORMAT_VERSION);
+
+        // a header written before the fingerprint became configurable is upgraded
+        let mut header = LeafHeader {
+            magic: HEADER_MAGIC_V4,
+            ..LeafHeader::new(DEFAULT_NUM_SLOT, DEFAULT_LEAF_SIZE)
+        };
+        header.set_slot(1);
+        header.set_fingerprint(1, 192);
+        let encoded = header.to_bytes(7).unwrap();
+        assert_eq!(
+            encoded.len(),
+            get_header_size_without_seq(DEFAULT_NUM_SLOT, 1) + LEN_SEQ
+        );
+        let (decoded, seq) = LeafHeader::from_bytes_with_seq(&encoded).unwrap();
+        assert_eq!(decoded.magic, HEADER_MAGIC);
+        assert_eq!(seq, 7);
+        assert_eq!(decoded.get_fingerprint_hash(), FingerprintHash::Sip);
+        assert_eq!(decoded.get_fingerprint_bits(), 8);
+        assert_eq!(decoded.get_fingerprint(1), 192);
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        let mut header = LeafHeader::new_v0();
+        header.set_slot(3);
+        let encoded = header.to_bytes(0).unwrap();
+        assert_eq!(encoded.len(), LEGACY_HEADER_SIZE);
+
+        let decoded = LeafHeader::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.get_num_slot(), LEGACY_NUM_SLOT);
+        assert_eq!(decoded.get_leaf_size(), LEGACY_LEAF_SIZE);
+        assert_eq!(decoded.get_format_version(), 0);
+    }
+
+    #[test]
+    fn test_validate_num_slot() {
+        assert!(validate_num_slot(8, 8).is_ok());
+        assert!(validate_num_slot(128, 8).is_ok());
+        assert!(validate_num_slot(128, 16).is_ok());
+        assert!(validate_num_slot(0, 8).is_err());
+        assert!(validate_num_slot(12, 8).is_err());
+        // the header doesn't fit in the header region
+        assert!(validate_num_slot(256, 8).is_err());
+        assert!(validate_num_slot(224, 8).is_ok());
+        assert!(validate_num_slot(224, 16).is_err());
+    }
+
+    #[test]
+    fn test_validate_leaf_size() {
+        assert!(validate_leaf_size(DEFAULT_LEAF_SIZE).is_ok());
+        assert!(validate_leaf_size(64 * 1024).is_ok());
+        assert!(validate_leaf_size(0).is_err());
+        assert!(validate_leaf_size(DEFAULT_LEAF_SIZE + 1).is_err());
+        assert!(validate_leaf_size(data_util::PAGE_SIZE * 2).is_err());
+        // the value size in a header can't reach the overflow flag
+        assert!(validate_leaf_size(1 << 31).is_ok());
+        assert!(validate_leaf_size((1 << 31) + data_util::PAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_validate_data_alignment() {
+        assert!(validate_data_alignment(1).is_ok());
+        assert!(validate_data_alignment(64).is_ok());
+        assert!(validate_data_alignment(data_util::PAGE_SIZE).is_ok());
+        assert!(validate_data_alignment(0).is_err());
+        assert!(validate_data_alignment(48).is_err());
+        assert!(validate_data_alignment(data_util::PAGE_SIZE * 2).is_err());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut header = LeafHeader::new(NUM_SLOT, DEFAULT_LEAF_SIZE);
+        header.set_kv_info(1, 2, 4096, 3, 28);
+        header.set_overflow(1);
+        assert!(header.is_overflow(1));
+        assert!(!header.is_overflow(0));
+        assert_eq!(header.get_kv_info(1), (2, 4096, 3, 28));
+        let bytes = header.to_bytes(1).unwrap();
+        let decoded = LeafHeader::from_bytes(&bytes).unwrap();
+        assert!(decoded.is_overflow(1));
+        assert_eq!(decoded.get_kv_info(1), (2, 4096, 3, 28));
+        // the flag is reset by a new pair
+        header.set_kv_info(1, 2, 8192, 3, 28);
+        assert!(!header.is_overflow(1));
+
+        let chunk_size = get_overflow_chunk_size(DEFAULT_LEAF_SIZE);
+        let overflow_ref = OverflowRef::new(chunk_size * 4 + 1, vec![3, 4, 5, 6, 9]);
+        let bytes = overflow_ref.to_bytes();
+        assert_eq!(bytes.len(), 28);
+        assert_eq!(
+            OverflowRef::get_size(chunk_size * 4 + 1, DEFAULT_LEAF_SIZE),
+            28
+        );
+        assert_eq!(
+            OverflowRef::from_bytes(&bytes, DEFAULT_LEAF_SIZE).unwrap(),
+            overflow_ref
+        );
+        assert!(OverflowRef::from_bytes(&bytes[..24], DEFAULT_LEAF_SIZE).is_err());
+        assert!(OverflowRef::from_bytes(&bytes, 64 * 1024).is_err());
+    }
 }
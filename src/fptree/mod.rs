@@ -3,13 +3,16 @@ mod leaf;
 pub mod leaf_manager;
 mod node;
 
-use log::debug;
+use log::{debug, warn};
+use std::cell::OnceCell;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
 use std::sync::RwLockWriteGuard;
 
-use inner::Inner;
+use inner::{Inner, KeyedNode};
 pub use leaf::Leaf;
 cfg_if::cfg_if! {
     if #[cfg(test)] {
@@ -19,35 +22,285 @@ cfg_if::cfg_if! {
     }
 }
 use crate::config::Config;
+use crate::range_tombstone::{self, RangeTombstone};
+use crate::util::data_util;
+use crate::util::lock_util::{MutexExt, RwLockExt};
+use crate::wal::Wal;
 use node::Node;
 
+pub type KvPair = (Vec<u8>, Vec<u8>);
+type NodeRef = Arc<RwLock<dyn Node + Send + Sync>>;
+type NodeWriteGuard<'a> = RwLockWriteGuard<'a, dyn Node + Send + Sync + 'static>;
+
+/// Iterator over key-value pairs of an FPTree in the key order
+///
+/// Each leaf is read under its read lock, and the FPTree isn't locked during the scan.
+/// A key inserted concurrently might be missed, and a key moved to the following leaf
+/// by a concurrent split is skipped, so the pairs are always sorted without duplicates.
+/// When writers are blocked during the scan, it returns a consistent view of the FPTree.
+pub struct LeafScan {
+    next_leaf: Option<Arc<RwLock<Leaf>>>,
+    kv_pairs: std::vec::IntoIter<KvPair>,
+    start: Vec<u8>,
+    last_key: Option<Vec<u8>>,
+}
+
+impl Iterator for LeafScan {
+    type Item = Result<KvPair, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for (key, value) in self.kv_pairs.by_ref() {
+                if key < self.start || self.last_key.as_ref().is_some_and(|last| key <= *last) {
+                    continue;
+                }
+                self.last_key = Some(key.clone());
+                return Some(Ok((key, value)));
+            }
+
+            let leaf = self.next_leaf.take()?;
+            let locked_leaf = leaf.read_or_recover();
+            let mut kv_pairs: Vec<KvPair> = match locked_leaf.get_kv_pairs() {
+                Ok(kv_pairs) => kv_pairs.into_iter().map(|(k, v, _)| (k, v)).collect(),
+                Err(e) => return Some(Err(e)),
+            };
+            kv_pairs.sort_by(|a, b| a.0.cmp(&b.0));
+            self.kv_pairs = kv_pairs.into_iter();
+            self.next_leaf = locked_leaf.get_next_leaf();
+        }
+    }
+}
+
 pub struct FPTree {
     root_ptr: Arc<RwLock<Arc<RwLock<dyn Node + Send + Sync>>>>,
     first_leaf: Arc<RwLock<Leaf>>,
-    mutex: Arc<Mutex<usize>>,
     root_split_count: Arc<Mutex<usize>>,
+    written_bytes: Arc<Mutex<usize>>,
+    /// The largest sequence of values in this FPTree
+    max_sequence: AtomicU64,
+    range_tombstones: Arc<RwLock<Vec<RangeTombstone>>>,
+    /// Range tombstones are kept only in memory without the file
+    range_tombstone_file: Option<String>,
+    wal: Option<Wal>,
 }
 
 impl FPTree {
     pub fn new(name: &str, id: usize, config: &Config) -> Result<Self, std::io::Error> {
         let leaf_manager = Arc::new(RwLock::new(LeafManager::new(name, id, config)?));
         let first_leaf = Arc::new(RwLock::new(Leaf::new(leaf_manager).unwrap()));
-        first_leaf.write().unwrap().set_root(true);
+        first_leaf.write_or_recover().set_root(true);
+        // the empty first leaf is needed to recover or flush an FPTree without keys
+        first_leaf.read_or_recover().commit()?;
+        let root = first_leaf.clone();
+
+        Self::with_root(name, id, config, root, first_leaf, 0, 0)
+    }
+
+    /// Reopen the FPTree persisted in the leaf file without flushing it
+    /// Inner nodes are rebuilt from the leaf chain and the minimum key of each leaf
+    /// Return `None` when values in the file have to be upgraded by a flush, or when the
+    /// first leaf is lost
+    pub fn open(name: &str, id: usize, config: &Config) -> Result<Option<Self>, std::io::Error> {
+        let leaf_manager = Arc::new(RwLock::new(LeafManager::new(name, id, config)?));
+        if leaf_manager.read_or_recover().get_format_version() != data_util::FORMAT_VERSION {
+            return Ok(None);
+        }
+        let leaf_id_chain = leaf_manager.read_or_recover().get_leaf_id_chain();
+        if leaf_id_chain.is_empty() {
+            return Ok(None);
+        }
+        leaf_manager
+            .write_or_recover()
+            .reclaim_pages(&leaf_id_chain)?;
+
+        // link leaves from the last one
+        let mut leaves = Vec::with_capacity(leaf_id_chain.len());
+        let mut next = None;
+        for leaf_id in leaf_id_chain.into_iter().rev() {
+            let leaf = Arc::new(RwLock::new(Leaf::open(
+                leaf_manager.clone(),
+                leaf_id,
+                next,
+            )?));
+            next = Some(leaf.clone());
+            leaves.push(leaf);
+        }
+        leaves.reverse();
+        let first_leaf = leaves[0].clone();
+
+        let mut written_bytes = 0;
+        let mut max_sequence = 0;
+        let mut nodes: Vec<KeyedNode> = Vec::new();
+        for (i, leaf) in leaves.into_iter().enumerate() {
+            let kv_pairs = leaf.read_or_recover().get_kv_pairs()?;
+            for (k, v, _) in &kv_pairs {
+                written_bytes += k.len() + v.len();
+                max_sequence = max_sequence.max(data_util::get_sequence(v)?.0);
+            }
+            match kv_pairs.into_iter().map(|(k, _, _)| k).min() {
+                Some(min_key) => nodes.push((min_key, leaf)),
+                // the first leaf has the smallest keys even if it is empty
+                None if i == 0 => nodes.push((Vec::new(), leaf)),
+                // an empty leaf isn't routed, but it is still in the leaf chain
+                None => {}
+            }
+        }
+        // each level corresponds to a root split
+        let mut root_split_count = 0;
+        while nodes.len() > 1 {
+            nodes = inner::build_parents(nodes);
+            root_split_count += 1;
+        }
+        let (_, root) = nodes.pop().expect("no leaf");
+        root.write_or_recover().set_root(true);
+        debug!(
+            "Reopened FPTree {} with {} levels of inner nodes",
+            id, root_split_count
+        );
+
+        let fptree = Self::with_root(
+            name,
+            id,
+            config,
+            root,
+            first_leaf,
+            root_split_count,
+            written_bytes,
+        )?;
+        fptree.max_sequence.store(max_sequence, Ordering::Release);
+
+        Ok(Some(fptree))
+    }
+
+    fn with_root(
+        name: &str,
+        id: usize,
+        config: &Config,
+        root: Arc<RwLock<dyn Node + Send + Sync>>,
+        first_leaf: Arc<RwLock<Leaf>>,
+        root_split_count: usize,
+        written_bytes: usize,
+    ) -> Result<Self, std::io::Error> {
+        let (range_tombstone_file, range_tombstones) = if config.get_in_memory() {
+            (None, Vec::new())
+        } else {
+            let file_path = config.get_range_tombstone_file_path(name, id);
+            let range_tombstones = range_tombstone::load(&file_path)?;
+            (Some(file_path), range_tombstones)
+        };
 
         Ok(FPTree {
-            root_ptr: Arc::new(RwLock::new(first_leaf.clone())),
-            mutex: Arc::new(Mutex::new(0)),
+            root_ptr: Arc::new(RwLock::new(root)),
             first_leaf,
-            root_split_count: Arc::new(Mutex::new(0)),
+            root_split_count: Arc::new(Mutex::new(root_split_count)),
+            written_bytes: Arc::new(Mutex::new(written_bytes)),
+            max_sequence: AtomicU64::new(0),
+            range_tombstones: Arc::new(RwLock::new(range_tombstones)),
+            range_tombstone_file,
+            wal: None,
         })
     }
 
+    /// Log each mutation to the WAL before applying it
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
     pub fn get_first_leaf(&self) -> Arc<RwLock<Leaf>> {
         self.first_leaf.clone()
     }
 
+    pub fn is_empty(&self) -> bool {
+        let first_leaf = self.first_leaf.read_or_recover();
+        first_leaf.is_empty()
+            && first_leaf.get_next_leaf().is_none()
+            && self.range_tombstones.read_or_recover().is_empty()
+    }
+
+    /// Mark the leaf file as obsolete
+    /// The file is removed after all references to the leaves are dropped
+    pub fn set_obsolete(&self) -> Result<(), std::io::Error> {
+        // removed first not to replay the WAL of the flushed FPTree into a new leaf file
+        if let Some(wal) = &self.wal {
+            wal.remove()?;
+        }
+        let leaf_manager = self.first_leaf.read_or_recover().get_leaf_manager();
+        leaf_manager.write_or_recover().set_obsolete()?;
+
+        // range tombstones are kept in memory
+        match self.range_tombstone_file.as_ref().map(std::fs::remove_file) {
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read_or_recover().clone()
+    }
+
+    /// Persist the range tombstone to hide keys in older FPTrees and SSTables
+    pub fn add_range_tombstone(
+        &self,
+        range_tombstone: RangeTombstone,
+    ) -> Result<(), std::io::Error> {
+        let mut range_tombstones = self.range_tombstones.write_or_recover();
+        if let Some(file_path) = &self.range_tombstone_file {
+            range_tombstone::append(file_path, &range_tombstone)?;
+        }
+        range_tombstones.push(range_tombstone);
+
+        Ok(())
+    }
+
+    /// The number of key-value pairs in all leaves including tombstones
+    pub fn approximate_len(&self) -> usize {
+        let mut len = 0;
+        let mut leaf = Some(self.first_leaf.clone());
+        while let Some(current) = leaf {
+            let locked_leaf = current.read_or_recover();
+            len += locked_leaf.len();
+            leaf = locked_leaf.get_next_leaf();
+        }
+
+        len
+    }
+
     pub fn get_root_split_count(&self) -> usize {
-        *self.root_split_count.lock().unwrap()
+        *self.root_split_count.lock_or_recover()
+    }
+
+    /// The total bytes of keys and values written to this FPTree
+    pub fn get_written_bytes(&self) -> usize {
+        *self.written_bytes.lock_or_recover()
+    }
+
+    /// The largest sequence of values written to this FPTree
+    pub fn get_max_sequence(&self) -> u64 {
+        self.max_sequence.load(Ordering::Acquire)
+    }
+
+    /// Print inner nodes from the root and the leaf chain with slot occupancy
+    pub fn debug_dump(&self, w: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(w, "root splits: {}", self.get_root_split_count())?;
+        self.root_ptr
+            .read_or_recover()
+            .read_or_recover()
+            .dump(w, 0)?;
+
+        writeln!(w, "leaf chain:")?;
+        let mut leaf = Some(self.first_leaf.clone());
+        while let Some(current) = leaf {
+            let locked_leaf = current.read_or_recover();
+            writeln!(
+                w,
+                "  leaf {}: {}",
+                locked_leaf.get_id(),
+                locked_leaf.get_slot_map()
+            )?;
+            leaf = locked_leaf.get_next_leaf();
+        }
+
+        Ok(())
     }
 
     fn split_root(
@@ -64,74 +317,176 @@ impl FPTree {
         new_root.add_child(locked_new_child.clone());
         *locked_root = Arc::new(RwLock::new(new_root));
 
-        let mut count = self.root_split_count.lock().unwrap();
+        let mut count = self.root_split_count.lock_or_recover();
         *count += 1;
     }
 
+    /// Writers lock nodes from the root by latch crabbing, so puts into different subtrees
+    /// run concurrently
+    /// Most puts don't split the root and hold only the read lock of the root pointer
+    /// When the root might be split, the put retries with the write lock of the pointer
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
-        // Lock the pointer to the root since it might be updated
-        let locked_root = self.root_ptr.write().unwrap();
+        self.insert(key, value, true)
+    }
 
-        // Phase1: Acquire locks of nodes atomically
-        let lock = self.mutex.lock().unwrap();
-        let mut nodes = Vec::new();
-        nodes.push(locked_root.clone());
-        loop {
-            let index = nodes.len() - 1;
-            if nodes[index].read().unwrap().is_leaf() {
-                break;
+    /// Apply all pairs or none of them
+    /// The pairs are logged as one WAL record after they are applied, and the record commits
+    /// them. When a write fails, the applied pairs are restored in the leaves. Pairs left
+    /// by a crash before the record is appended are removed by replaying the WAL.
+    /// The other writers of this FPTree have to be blocked by the caller
+    pub fn put_batch(&self, kv_pairs: &[KvPair]) -> Result<(), std::io::Error> {
+        // each pair keeps the floor of the version which it overwrites like a put
+        let mut merged: Vec<KvPair> = Vec::with_capacity(kv_pairs.len());
+        let mut olds: Vec<(&[u8], Option<Vec<u8>>)> = Vec::new();
+        for (key, value) in kv_pairs {
+            let older = match merged.iter().rev().find(|(k, _)| k == key) {
+                Some((_, v)) => Some(v.clone()),
+                None => {
+                    let old = self.get_entry(key)?;
+                    olds.push((key, old.clone()));
+                    old
+                }
+            };
+            let mut inserted = value.clone();
+            if let Some(older) = older {
+                data_util::merge_floor(&mut inserted, &older)?;
+            }
+            merged.push((key.clone(), inserted));
+        }
+
+        let mut result = merged
+            .iter()
+            .try_for_each(|(key, value)| self.insert(key, value, false));
+        if let (Ok(()), Some(wal)) = (&result, &self.wal) {
+            let record: Vec<(&[u8], &[u8])> = merged
+                .iter()
+                .map(|(k, v)| (k.as_slice(), v.as_slice()))
+                .collect();
+            result = wal.append(&record);
+        }
+        if result.is_err() {
+            // the error of the write is returned even if the restore fails
+            for (key, old) in olds {
+                if let Err(e) = self.restore(key, old.as_deref()) {
+                    warn!("Failed to restore {:?} of a failed batch: {}", key, e);
+                    break;
+                }
             }
+        }
+
+        result
+    }
 
-            let child = nodes[index].read().unwrap().get_child(key).unwrap();
-            nodes.push(child.clone());
+    /// Put back the stored data of the key without logging it, or remove the key
+    fn restore(&self, key: &[u8], old: Option<&[u8]>) -> Result<(), std::io::Error> {
+        self.remove(key)?;
+        match old {
+            Some(old) => self.insert(key, old, false),
+            None => Ok(()),
         }
+    }
+
+    /// Remove the key from the leaf without logging it
+    /// This is only for rolling back writes which aren't in the WAL
+    pub fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.root_ptr
+            .read_or_recover()
+            .write_or_recover()
+            .remove(key)
+    }
 
-        let mut locked_nodes = Vec::new();
-        let mut is_root_locked = true;
-        for locked_node in nodes.iter().map(|node| node.write().unwrap()) {
-            if !locked_node.may_need_split() {
-                is_root_locked = false;
-                locked_nodes.clear();
+    /// `log` is false when the pair has been already logged
+    fn insert(&self, key: &[u8], value: &[u8], log: bool) -> Result<(), std::io::Error> {
+        *self.written_bytes.lock_or_recover() += key.len() + value.len();
+
+        {
+            let root_ptr = self.root_ptr.read_or_recover();
+            let path = self.new_path(&root_ptr);
+            let locked_nodes = lock_path(&path, key);
+            if !may_split_root(&locked_nodes) {
+                // the root pointer isn't updated by this put
+                drop(root_ptr);
+                return self.insert_locked(key, value, log, locked_nodes, None);
             }
-            locked_nodes.push(locked_node);
         }
-        drop(lock);
 
-        // Phase2: Insert split keys and a value
+        let root_ptr = self.root_ptr.write_or_recover();
+        let path = self.new_path(&root_ptr);
+        let locked_nodes = lock_path(&path, key);
+        if may_split_root(&locked_nodes) {
+            self.insert_locked(key, value, log, locked_nodes, Some(root_ptr))
+        } else {
+            drop(root_ptr);
+            self.insert_locked(key, value, log, locked_nodes, None)
+        }
+    }
+
+    /// Return cells for the nodes from the root to a leaf
+    /// The height doesn't change while the root pointer is locked
+    fn new_path(&self, root: &NodeRef) -> Vec<OnceCell<NodeRef>> {
+        let path: Vec<OnceCell<NodeRef>> = (0..=self.get_root_split_count())
+            .map(|_| OnceCell::new())
+            .collect();
+        let _ = path[0].set(root.clone());
+        path
+    }
+
+    /// Insert the pair into the locked leaf, and then the split keys into the locked parents
+    /// `root_ptr` has to be given when the root might be split
+    fn insert_locked(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        log: bool,
+        mut locked_nodes: Vec<NodeWriteGuard>,
+        root_ptr: Option<RwLockWriteGuard<NodeRef>>,
+    ) -> Result<(), std::io::Error> {
+        // the entry keeps the floor of the version which it overwrites
         let mut inserted = value.to_vec();
-        if is_root_locked {
-            while let Some(mut locked_node) = locked_nodes.pop() {
-                if let Some(split_key) = locked_node.insert(key, &inserted)? {
-                    inserted = split_key;
-                    if locked_node.is_root() {
-                        locked_node.set_root(false);
-                        let new_child = locked_node.get_next().unwrap();
-                        self.split_root(&inserted, locked_root, new_child);
-                        return Ok(());
-                    }
-                } else {
-                    break;
-                }
+        let leaf = locked_nodes.last().expect("no leaf is locked");
+        if let Some(old) = leaf.get(key)? {
+            data_util::merge_floor(&mut inserted, &old)?;
+        }
+        self.max_sequence
+            .fetch_max(data_util::get_sequence(&inserted)?.0, Ordering::AcqRel);
+
+        // the leaf is locked to log mutations of the same key in the applied order
+        if let (Some(wal), true) = (&self.wal, log) {
+            wal.append(&[(key, &inserted)])?;
+        }
+
+        while let Some(mut locked_node) = locked_nodes.pop() {
+            match locked_node.insert(key, &inserted)? {
+                Some(split_key) => inserted = split_key,
+                None => break,
             }
-        } else {
-            drop(locked_root);
-            while let Some(mut locked_node) = locked_nodes.pop() {
-                if let Some(split_key) = locked_node.insert(key, &inserted)? {
-                    inserted = split_key.clone();
-                } else {
-                    break;
-                }
+            if locked_node.is_root() {
+                locked_node.set_root(false);
+                let new_child = locked_node.get_next().unwrap();
+                let root_ptr = root_ptr.expect("the root pointer isn't locked");
+                self.split_root(&inserted, root_ptr, new_child);
+                break;
             }
         }
 
         Ok(())
     }
 
+    /// A key covered by a range tombstone is returned as a tombstone
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
-        let mut node = self.root_ptr.read().unwrap().clone();
+        match self.get_entry(key)? {
+            Some(v) => Ok(Some(v)),
+            None if self.is_range_deleted(key) => Ok(Some(Vec::new())),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the stored data of the key regardless of range tombstones
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut node = self.root_ptr.read_or_recover().clone();
         loop {
             let n = node.clone();
-            let node_guard = n.read().unwrap();
+            let node_guard = n.read_or_recover();
             if node_guard.is_leaf() {
                 return node_guard.get(key);
             }
@@ -140,8 +495,85 @@ impl FPTree {
         }
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<(), std::io::Error> {
-        // just add a tombstone
-        self.put(key, &Vec::new())
+    pub fn is_range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones
+            .read_or_recover()
+            .iter()
+            .any(|r| r.covers(key))
+    }
+
+    /// Collect key-value pairs in `[start, end)`
+    /// Tombstones are included since they have to shadow older tables
+    pub fn range(&self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<KvPair>, std::io::Error> {
+        self.scan(start)
+            .take_while(|kv| match kv {
+                Ok((key, _)) => end.is_none_or(|end| key.as_slice() < end),
+                Err(_) => true,
+            })
+            .collect()
+    }
+
+    /// Return an iterator over key-value pairs from `start` in the key order
+    /// It walks the leaf chain from the leaf which has `start`
+    pub fn scan(&self, start: &[u8]) -> LeafScan {
+        let mut node = self.root_ptr.read_or_recover().clone();
+        loop {
+            let child = match node.read_or_recover().get_child(start) {
+                Some(child) => child,
+                None => break,
+            };
+            node = child;
+        }
+
+        // find the leaf in the chain to follow the next leaves
+        let mut leaf = self.first_leaf.clone();
+        let mut current = Some(leaf.clone());
+        while let Some(c) = current {
+            if std::ptr::addr_eq(Arc::as_ptr(&c), Arc::as_ptr(&node)) {
+                leaf = c;
+                break;
+            }
+            current = c.read_or_recover().get_next_leaf();
+        }
+
+        LeafScan {
+            next_leaf: Some(leaf),
+            kv_pairs: Vec::new().into_iter(),
+            start: start.to_vec(),
+            last_key: None,
+        }
+    }
+}
+
+/// Lock nodes from the root to the leaf of `key` by latch crabbing
+/// Locks of the ancestors are released when a child is locked and it won't be split, so
+/// the returned nodes are the lowest node which won't be split and its descendants
+fn lock_path<'a>(path: &'a [OnceCell<NodeRef>], key: &[u8]) -> Vec<NodeWriteGuard<'a>> {
+    let mut locked_nodes: Vec<NodeWriteGuard> = Vec::new();
+    for (depth, cell) in path.iter().enumerate() {
+        let locked_node = cell.get().expect("no node in the path").write_or_recover();
+        if !locked_node.may_need_split() {
+            locked_nodes.clear();
+        }
+        if locked_node.is_leaf() {
+            locked_nodes.push(locked_node);
+            return locked_nodes;
+        }
+
+        let child = locked_node.get_child(key).unwrap();
+        let _ = path
+            .get(depth + 1)
+            .expect("the path is shorter than the tree")
+            .set(child);
+        locked_nodes.push(locked_node);
     }
+
+    unreachable!("no leaf in the path");
+}
+
+/// Whether all locked nodes up to the root might be split
+fn may_split_root(locked_nodes: &[NodeWriteGuard]) -> bool {
+    locked_nodes
+        .first()
+        .is_some_and(|node| node.is_root() && node.may_need_split())
 }
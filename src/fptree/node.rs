@@ -1,3 +1,4 @@
+use std::io::Write;
 use std::sync::Arc;
 use std::sync::RwLock;
 
@@ -10,6 +11,10 @@ pub trait Node {
     fn may_need_split(&self) -> bool;
     fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error>;
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error>;
+    /// Remove the pair from the leaf without leaving a tombstone
+    fn remove(&mut self, key: &[u8]) -> Result<(), std::io::Error>;
     fn split(&mut self) -> Result<Vec<u8>, std::io::Error>;
     fn commit(&self) -> Result<(), std::io::Error>;
+    /// Print the subtree indented by `depth`
+    fn dump(&self, w: &mut dyn Write, depth: usize) -> Result<(), std::io::Error>;
 }
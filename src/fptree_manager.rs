@@ -1,8 +1,20 @@
-use log::info;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
-use crate::config::Config;
+use crate::amphis_error::CrudError;
+use crate::config::{Config, FlushTrigger};
+use crate::fptree::leaf_manager::{get_max_data_size, get_max_value_size};
 use crate::fptree::{FPTree, Leaf};
+use crate::range_tombstone::RangeTombstone;
+use crate::scan::{SortedRun, Source};
+use crate::util::data_util::{self, VersionAt};
+use crate::util::file_util;
+use crate::util::lock_util::RwLockExt;
+use crate::wal::{self, Wal};
 
 pub struct FPTreeManager {
     name: String,
@@ -11,126 +23,542 @@ pub struct FPTreeManager {
     new_fptree_ptr: Arc<RwLock<Option<Arc<RwLock<FPTree>>>>>,
     fptree_id: Arc<RwLock<usize>>,
     fptree_written: Arc<()>,
+    /// The sequence of the last write shared by all column families
+    sequence: Arc<AtomicU64>,
+}
+
+/// FPTrees locked by `FPTreeManager::write_exclusively`
+/// All writes through them are stamped with the same sequence
+pub struct LockedFPTrees<'a> {
+    target: &'a FPTree,
+    flushing: Option<&'a FPTree>,
+    sequence: u64,
+}
+
+impl LockedFPTrees<'_> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        match self.target.get(key)? {
+            Some(v) => Ok(Some(v)),
+            None => match self.flushing {
+                Some(f) => Ok(f.get(key)?),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// `value` is an encoded value or an empty tombstone
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        Ok(self
+            .target
+            .put(key, &data_util::stamp_sequence(value, self.sequence))?)
+    }
+
+    /// Put the pairs logged as one WAL record
+    /// `kv_pairs` have encoded values or empty tombstones
+    pub fn put_batch(&self, kv_pairs: &[(Vec<u8>, Vec<u8>)]) -> Result<(), CrudError> {
+        let stamped: Vec<_> = kv_pairs
+            .iter()
+            .map(|(k, v)| (k.clone(), data_util::stamp_sequence(v, self.sequence)))
+            .collect();
+        Ok(self.target.put_batch(&stamped)?)
+    }
+
+    /// Add a range tombstone and overwrite keys in the range with tombstones
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), CrudError> {
+        self.target
+            .add_range_tombstone(RangeTombstone::new(start, end))?;
+        // the range tombstone doesn't hide keys in the same FPTree
+        for (key, value) in self.target.range(start, Some(end))? {
+            if !data_util::is_tombstone(&value) {
+                self.put(&key, &[])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub type FlushTarget = (Arc<RwLock<Leaf>>, Vec<RangeTombstone>);
+
+/// FPTrees frozen at a point in time
+pub struct FrozenFPTrees {
+    /// pairs copied from the FPTree being written
+    pairs: BTreeMap<Vec<u8>, Vec<u8>>,
+    range_tombstones: Vec<RangeTombstone>,
+    /// the FPTree being flushed isn't written anymore
+    flushing: Option<Arc<RwLock<FPTree>>>,
+}
+
+impl FrozenFPTrees {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        match self.pairs.get(key) {
+            Some(v) => Ok(Some(v.clone())),
+            None if self.range_tombstones.iter().any(|r| r.covers(key)) => Ok(Some(Vec::new())),
+            None => match &self.flushing {
+                Some(f) => Ok(f.read_or_recover().get(key)?),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Return key-value pairs in `[start, end)` of each FPTree, the newest FPTree first
+    pub fn range(&self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<SortedRun>, CrudError> {
+        let pairs: Vec<_> = self
+            .pairs
+            .range(start.to_vec()..)
+            .take_while(|(k, _)| end.is_none_or(|end| k.as_slice() < end))
+            .map(|(k, v)| Ok((k.clone(), v.clone())))
+            .collect();
+        let mut results = vec![SortedRun::new(
+            pairs.into_iter(),
+            self.range_tombstones.clone(),
+            Source::Memtable,
+        )];
+        if let Some(f) = &self.flushing {
+            results.push(make_source(
+                &f.read_or_recover(),
+                start,
+                end,
+                Source::FlushingMemtable,
+            )?);
+        }
+
+        Ok(results)
+    }
 }
 
 impl FPTreeManager {
-    pub fn new(name: &str, config: Config) -> Result<Self, std::io::Error> {
-        let fptree_id = 0;
-        Ok(FPTreeManager {
+    pub fn new(name: &str, config: Config, sequence: Arc<AtomicU64>) -> Result<Self, CrudError> {
+        let fptree = create_fptree(name, 0, &config)?;
+        Ok(Self::with_fptree(name, config, 0, fptree, sequence))
+    }
+
+    /// Reopen the existing FPTree as the current FPTree
+    /// Return `None` when the FPTree has to be flushed instead
+    pub fn recover(
+        name: &str,
+        fptree_id: usize,
+        config: Config,
+        sequence: Arc<AtomicU64>,
+    ) -> Result<Option<Self>, CrudError> {
+        // only the WAL remains
+        if !Path::new(&config.get_leaf_file_path(name, fptree_id)).exists() {
+            return Ok(None);
+        }
+        let mut fptree = match FPTree::open(name, fptree_id, &config)? {
+            Some(fptree) => fptree,
+            None => return Ok(None),
+        };
+        let wal_file = config.get_wal_file_path(name, fptree_id);
+        replay_wal(&fptree, &wal_file)?;
+        fptree.set_wal(Wal::open(&wal_file, config.get_wal_sync())?);
+        sequence.fetch_max(fptree.get_max_sequence(), Ordering::AcqRel);
+
+        Ok(Some(Self::with_fptree(
+            name, config, fptree_id, fptree, sequence,
+        )))
+    }
+
+    /// Apply mutations in the WAL to the FPTree before the FPTree is flushed on startup
+    /// The FPTree is created when the leaf file has been lost
+    pub fn replay_wal(name: &str, fptree_id: usize, config: &Config) -> Result<(), CrudError> {
+        let wal_file = config.get_wal_file_path(name, fptree_id);
+        if !Path::new(&wal_file).exists() {
+            return Ok(());
+        }
+
+        let fptree = if Path::new(&config.get_leaf_file_path(name, fptree_id)).exists() {
+            match FPTree::open(name, fptree_id, config)? {
+                Some(fptree) => fptree,
+                None => {
+                    warn!("WAL {} is ignored for the unopenable leaf file", wal_file);
+                    return Ok(());
+                }
+            }
+        } else {
+            FPTree::new(name, fptree_id, config)?
+        };
+
+        Ok(replay_wal(&fptree, &wal_file)?)
+    }
+
+    fn with_fptree(
+        name: &str,
+        config: Config,
+        fptree_id: usize,
+        fptree: FPTree,
+        sequence: Arc<AtomicU64>,
+    ) -> Self {
+        FPTreeManager {
             name: name.to_string(),
-            config: config.clone(),
-            fptree_ptr: Arc::new(RwLock::new(Arc::new(RwLock::new(FPTree::new(
-                name, fptree_id, &config,
-            )?)))),
+            config,
+            fptree_ptr: Arc::new(RwLock::new(Arc::new(RwLock::new(fptree)))),
             new_fptree_ptr: Arc::new(RwLock::new(None)),
             fptree_id: Arc::new(RwLock::new(fptree_id)),
             fptree_written: Arc::new(()),
-        })
+            sequence,
+        }
+    }
+
+    /// Allocate the sequence of a new write
+    pub fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::AcqRel) + 1
     }
 
     pub fn need_flush(&self) -> bool {
         // Flush has been already started when the new FPTree exists
-        self.new_fptree_ptr.read().unwrap().is_none()
-            && self
-                .fptree_ptr
-                .read()
-                .unwrap()
-                .read()
-                .unwrap()
-                .get_root_split_count()
-                >= self.config.get_root_split_threshold()
-    }
-
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
-        let locked_new = self.new_fptree_ptr.read().unwrap();
+        !self.config.get_in_memory()
+            && self.new_fptree_ptr.read_or_recover().is_none()
+            && self.is_flush_triggered(&self.fptree_ptr.read_or_recover().read_or_recover())
+    }
+
+    fn is_flush_triggered(&self, fptree: &FPTree) -> bool {
+        match self.config.get_flush_trigger() {
+            FlushTrigger::RootSplits(threshold) => fptree.get_root_split_count() >= threshold,
+            FlushTrigger::Bytes(limit) => fptree.get_written_bytes() >= limit,
+        }
+    }
+
+    /// The number of root splits of the current FPTree
+    pub fn get_root_split_count(&self) -> usize {
+        self.fptree_ptr
+            .read_or_recover()
+            .read_or_recover()
+            .get_root_split_count()
+    }
+
+    /// Print the current FPTree, and the FPTree being flushed if any
+    pub fn debug_dump(&self, w: &mut dyn Write) -> Result<(), CrudError> {
+        let locked_new = self.new_fptree_ptr.read_or_recover();
+        let fptree = self.fptree_ptr.read_or_recover().clone();
         match &*locked_new {
-            Some(n) => n.read().unwrap().put(key, value),
+            // the new FPTree receives writes while the current one is flushed
+            Some(new_fptree) => {
+                writeln!(w, "FPTree (current):")?;
+                new_fptree.read_or_recover().debug_dump(w)?;
+                writeln!(w, "FPTree (flushing):")?;
+                fptree.read_or_recover().debug_dump(w)?;
+            }
             None => {
-                let _written = self.fptree_written.clone();
-                self.fptree_ptr
-                    .read()
-                    .unwrap()
-                    .read()
-                    .unwrap()
-                    .put(key, value)
+                writeln!(w, "FPTree (current):")?;
+                fptree.read_or_recover().debug_dump(w)?;
             }
         }
+
+        Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
-        let mut result = None;
-        // TODO: concurrenct read
-        let locked_new = self.new_fptree_ptr.read().unwrap();
+    /// The number of key-value pairs in FPTrees including tombstones
+    pub fn approximate_len(&self) -> usize {
+        let locked_new = self.new_fptree_ptr.read_or_recover();
+        let mut len = self
+            .fptree_ptr
+            .read_or_recover()
+            .read_or_recover()
+            .approximate_len();
         if let Some(n) = &*locked_new {
-            result = n.read().unwrap().get(key)?;
+            len += n.read_or_recover().approximate_len();
         }
 
-        if result.is_none() {
-            result = self.fptree_ptr.read().unwrap().read().unwrap().get(key)?;
+        len
+    }
+
+    /// The total size of leaf files
+    pub fn size_on_disk(&self) -> Result<u64, CrudError> {
+        if self.config.get_in_memory() {
+            return Ok(0);
         }
 
-        Ok(result)
+        let mut size = 0;
+        for entry in std::fs::read_dir(self.config.get_leaf_dir_path(&self.name))? {
+            let entry = entry?;
+            if file_util::get_tree_id(&entry.path()).is_some() {
+                size += entry.metadata()?.len();
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Check that the key-value pair can be written to a leaf
+    /// `value` is an encoded value or an empty tombstone, and `max_key_size` and
+    /// `max_value_size` are applied only to puts
+    /// The sequence stamped when the pair is written is included in the header
+    pub fn check_entry(&self, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        if key.is_empty() {
+            return Err(CrudError::InvalidInput("empty key".to_owned()));
+        }
+
+        let (user_value, max_key_size, max_value_size) = if value.is_empty() {
+            (value, None, None)
+        } else {
+            (
+                data_util::decode_value(value)?.1,
+                self.config.get_max_key_size(),
+                self.config.get_max_value_size(),
+            )
+        };
+        let header_size = data_util::get_stamped_size(value) - user_value.len();
+        let leaf_size = self.config.get_leaf_size();
+
+        // otherwise, the pair would never fit in a page
+        let key_limit = (get_max_data_size(leaf_size) - data_util::get_data_size(0, header_size))
+            .min(max_key_size.unwrap_or(usize::MAX));
+        if key.len() > key_limit {
+            return Err(CrudError::KeyTooLarge {
+                size: key.len(),
+                limit: key_limit,
+            });
+        }
+        // a large value is stored in overflow pages, and the leaf page has the reference
+        let value_limit = get_max_value_size(leaf_size, key.len())
+            .saturating_sub(header_size)
+            .min(max_value_size.unwrap_or(usize::MAX));
+        if user_value.len() > value_limit {
+            return Err(CrudError::ValueTooLarge {
+                size: user_value.len(),
+                limit: value_limit,
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<(), std::io::Error> {
-        let locked_new = self.new_fptree_ptr.read().unwrap();
+    /// `value` is an encoded value or an empty tombstone
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        let value = data_util::stamp_sequence(value, self.next_sequence());
+        let value = value.as_slice();
+        let locked_new = self.new_fptree_ptr.read_or_recover();
         match &*locked_new {
-            Some(n) => n.read().unwrap().delete(key),
+            Some(n) => n.read_or_recover().put(key, value)?,
             None => {
                 let _written = self.fptree_written.clone();
-                self.fptree_ptr.read().unwrap().read().unwrap().delete(key)
+                self.fptree_ptr
+                    .read_or_recover()
+                    .read_or_recover()
+                    .put(key, value)?;
             }
         }
+
+        Ok(())
     }
 
-    /// Check the triggered flush before starting flush and set the new FPTree
-    pub fn prepare_flush(&self) -> Result<Option<Arc<RwLock<Leaf>>>, std::io::Error> {
-        let locked_fptree_id = self.fptree_id.write().unwrap();
+    /// Apply all entries under `write_exclusively`
+    /// All entries are stamped with the same sequence, and readers see all of them or none of them
+    pub fn put_batch(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), CrudError> {
+        // reject the batch before applying any entry
+        for (key, value) in entries {
+            self.check_entry(key, value)?;
+        }
 
-        // re-check since another thread might have already flushed
-        if !self.need_flush() {
-            return Ok(None);
+        self.write_exclusively(|fptrees| fptrees.put_batch(entries))
+    }
+
+    /// Run `f` while blocking the other writers, the FPTree switch, and readers of the FPTree
+    /// being written
+    /// The FPTree being flushed is only read, so lookups of it and the flush proceed
+    pub fn write_exclusively<R>(
+        &self,
+        f: impl FnOnce(&LockedFPTrees) -> Result<R, CrudError>,
+    ) -> Result<R, CrudError> {
+        let locked_new = self.new_fptree_ptr.write_or_recover();
+        let locked_fptree = self.fptree_ptr.read_or_recover();
+        let sequence = self.next_sequence();
+        match &*locked_new {
+            Some(n) => f(&LockedFPTrees {
+                target: &n.write_or_recover(),
+                flushing: Some(&locked_fptree.read_or_recover()),
+                sequence,
+            }),
+            None => {
+                let _written = self.fptree_written.clone();
+                f(&LockedFPTrees {
+                    target: &locked_fptree.write_or_recover(),
+                    flushing: None,
+                    sequence,
+                })
+            }
         }
+    }
 
-        let mut locked_new = self.new_fptree_ptr.write().unwrap();
-        if (*locked_new).is_some() {
-            // The flush is in progress
-            return Ok(None);
+    /// Return the FPTree being written and the FPTree being flushed if it exists
+    /// The trees are read without the locks of the pointers not to block the switch.
+    /// The leaf file of a switched FPTree is removed when its last reader drops it.
+    fn capture_fptrees(&self) -> (Arc<RwLock<FPTree>>, Option<Arc<RwLock<FPTree>>>) {
+        let locked_new = self.new_fptree_ptr.read_or_recover();
+        let fptree = self.fptree_ptr.read_or_recover().clone();
+        match &*locked_new {
+            Some(n) => (n.clone(), Some(fptree)),
+            None => (fptree, None),
+        }
+    }
+
+    /// Look up the new FPTree and then the FPTree being flushed
+    /// Only their read locks are taken, so lookups proceed while the flush reads the leaves
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        let (target, flushing) = self.capture_fptrees();
+        let mut result = target.read_or_recover().get(key)?;
+        if let (None, Some(f)) = (&result, flushing) {
+            result = f.read_or_recover().get(key)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Return the newest version of the key at `sequence` in the FPTrees
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> Result<VersionAt, CrudError> {
+        let (target, flushing) = self.capture_fptrees();
+        for fptree in std::iter::once(target).chain(flushing) {
+            let fptree = fptree.read_or_recover();
+            let entry = fptree.get_entry(key)?;
+            match data_util::select_version(entry, fptree.is_range_deleted(key), sequence)? {
+                VersionAt::NotFound => continue,
+                version => return Ok(version),
+            }
+        }
+
+        Ok(VersionAt::NotFound)
+    }
+
+    /// Look up all keys with a single acquisition of the FPTree locks
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, CrudError> {
+        let (target, flushing) = self.capture_fptrees();
+        let target = target.read_or_recover();
+        let flushing = flushing.as_ref().map(|f| f.read_or_recover());
+
+        let mut results = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut result = target.get(key)?;
+            if let (None, Some(f)) = (&result, &flushing) {
+                result = f.get(key)?;
+            }
+            results.push(result);
         }
 
-        *locked_new = Some(Arc::new(RwLock::new(FPTree::new(
-            &self.name,
-            *locked_fptree_id + 1,
-            &self.config,
-        )?)));
+        Ok(results)
+    }
+
+    /// Return key-value pairs in `[start, end)` of each FPTree, the newest FPTree first
+    pub fn range(&self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<SortedRun>, CrudError> {
+        let (target, flushing) = self.capture_fptrees();
+        let mut results = vec![make_source(
+            &target.read_or_recover(),
+            start,
+            end,
+            Source::Memtable,
+        )?];
+        if let Some(f) = flushing {
+            results.push(make_source(
+                &f.read_or_recover(),
+                start,
+                end,
+                Source::FlushingMemtable,
+            )?);
+        }
+
+        Ok(results)
+    }
+
+    /// Freeze the current FPTrees and run `f` before the FPTree switch
+    pub fn freeze<R>(&self, f: impl FnOnce() -> R) -> Result<(FrozenFPTrees, R), CrudError> {
+        let locked_new = self.new_fptree_ptr.read_or_recover();
+        let locked_fptree = self.fptree_ptr.read_or_recover();
+        let (target, flushing) = match &*locked_new {
+            Some(n) => (n.clone(), Some(locked_fptree.clone())),
+            None => (locked_fptree.clone(), None),
+        };
+        let target = target.read_or_recover();
+        let frozen = FrozenFPTrees {
+            pairs: target.range(&[], None)?.into_iter().collect(),
+            range_tombstones: target.get_range_tombstones(),
+            flushing,
+        };
+
+        Ok((frozen, f()))
+    }
+
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), CrudError> {
+        self.write_exclusively(|fptrees| fptrees.delete_range(start, end))
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), CrudError> {
+        self.check_entry(key, &[])?;
+
+        // just add a tombstone
+        self.put(key, &[])
+    }
+
+    /// Remove the files of the current FPTree if it's empty, e.g. after the last flush
+    /// Return whether the files are removed
+    pub fn discard_empty_fptree(&self) -> Result<bool, CrudError> {
+        // no writer is in progress while the pointer is locked
+        let locked_new = self.new_fptree_ptr.write_or_recover();
+        if locked_new.is_some() {
+            return Ok(false);
+        }
+        let locked_fptree = self.fptree_ptr.read_or_recover();
+        let fptree = locked_fptree.read_or_recover();
+        if !fptree.is_empty() {
+            return Ok(false);
+        }
+        fptree.set_obsolete()?;
+
+        Ok(true)
+    }
+
+    /// Check the triggered flush before starting flush and set the new FPTree
+    /// A forced flush starts unless the current FPTree is empty
+    /// Return the first leaf and the range tombstones of the FPTree to be flushed
+    pub fn prepare_flush(&self, force: bool) -> Result<Option<FlushTarget>, CrudError> {
+        let locked_fptree_id = self.fptree_id.write_or_recover();
+
+        let mut locked_new = self.new_fptree_ptr.write_or_recover();
+        // The new FPTree exists when the previous attempt has waited for writers
+        if locked_new.is_none() {
+            let locked_fptree = self.fptree_ptr.read_or_recover();
+            let fptree = locked_fptree.read_or_recover();
+            // re-check since another thread might have already flushed
+            let triggered = if force {
+                !fptree.is_empty()
+            } else {
+                self.is_flush_triggered(&fptree)
+            };
+            if !triggered {
+                return Ok(None);
+            }
+
+            *locked_new = Some(Arc::new(RwLock::new(create_fptree(
+                &self.name,
+                *locked_fptree_id + 1,
+                &self.config,
+            )?)));
+        }
 
         // check if other threads write data to the current FPTree
         if Arc::strong_count(&self.fptree_written) != 1 {
             return Ok(None);
         }
 
-        let first_leaf = self
-            .fptree_ptr
-            .read()
-            .unwrap()
-            .read()
-            .unwrap()
-            .get_first_leaf();
-        Ok(Some(first_leaf))
+        let locked_fptree = self.fptree_ptr.read_or_recover();
+        let fptree = locked_fptree.read_or_recover();
+        Ok(Some((
+            fptree.get_first_leaf(),
+            fptree.get_range_tombstones(),
+        )))
     }
 
-    pub fn switch_fptree(&self) -> Result<(), std::io::Error> {
-        let mut locked_fptree_id = self.fptree_id.write().unwrap();
-        let mut locked_new = self.new_fptree_ptr.write().unwrap();
+    pub fn switch_fptree(&self) -> Result<(), CrudError> {
+        let mut locked_fptree_id = self.fptree_id.write_or_recover();
+        let mut locked_new = self.new_fptree_ptr.write_or_recover();
         match &*locked_new {
             Some(n) => {
-                *self.fptree_ptr.write().unwrap() = n.clone();
-                let deleted_id = *locked_fptree_id;
+                let old = std::mem::replace(&mut *self.fptree_ptr.write_or_recover(), n.clone());
                 *locked_fptree_id += 1;
                 *locked_new = None;
 
-                let leaf_file = self.config.get_leaf_file_path(&self.name, deleted_id);
-                std::fs::remove_file(leaf_file)?;
+                // readers and snapshots might still read the old FPTree, and its leaf file
+                // is renamed now and removed when the last of them drops the FPTree
+                old.read_or_recover().set_obsolete()?;
             }
             None => unreachable!("No new FPTree when flushing"),
         }
@@ -140,3 +568,72 @@ impl FPTreeManager {
         Ok(())
     }
 }
+
+/// Create a new FPTree which logs mutations to its WAL
+fn create_fptree(name: &str, fptree_id: usize, config: &Config) -> Result<FPTree, CrudError> {
+    let mut fptree = FPTree::new(name, fptree_id, config)?;
+    // nothing is recovered in memory
+    if config.get_in_memory() {
+        return Ok(fptree);
+    }
+    // a WAL left by the FPTree with the same ID has been already replayed and flushed
+    let wal_file = config.get_wal_file_path(name, fptree_id);
+    wal::remove(&wal_file)?;
+    fptree.set_wal(Wal::open(&wal_file, config.get_wal_sync())?);
+
+    Ok(fptree)
+}
+
+/// Apply the last value of each key in the WAL unless the FPTree has it
+/// The WAL has all mutations of the FPTree, so the last one is the latest value, and a key
+/// which isn't in the WAL is removed
+fn replay_wal(fptree: &FPTree, wal_file: &str) -> Result<(), std::io::Error> {
+    if !Path::new(wal_file).exists() {
+        return Ok(());
+    }
+    let latest: BTreeMap<Vec<u8>, Vec<u8>> = wal::replay(wal_file)?.into_iter().collect();
+    // a key not in the WAL is left by a batch which failed before its record was appended
+    let mut removed = 0;
+    for (key, _) in fptree.range(&[], None)? {
+        if !latest.contains_key(&key) {
+            fptree.remove(&key)?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        warn!(
+            "{} keys of an uncommitted batch have been removed by WAL {}",
+            removed, wal_file
+        );
+    }
+
+    let mut replayed = 0;
+    for (key, value) in latest {
+        if fptree.get(&key)?.as_ref() != Some(&value) {
+            fptree.put(&key, &value)?;
+            replayed += 1;
+        }
+    }
+    if replayed > 0 {
+        info!(
+            "{} keys have been recovered from WAL {}",
+            replayed, wal_file
+        );
+    }
+
+    Ok(())
+}
+
+fn make_source(
+    fptree: &FPTree,
+    start: &[u8],
+    end: Option<&[u8]>,
+    source: Source,
+) -> Result<SortedRun, CrudError> {
+    let kv_pairs = fptree.range(start, end)?;
+    Ok(SortedRun::new(
+        kv_pairs.into_iter().map(Ok),
+        fptree.get_range_tombstones(),
+        source,
+    ))
+}
@@ -0,0 +1,28 @@
+/// The level which `KVS::ingest_sorted_with` places the new SSTable in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IngestLevel {
+    /// Level 0 like a flushed table, where the ingested pairs overwrite older values
+    #[default]
+    Top,
+    /// The deepest level, so the table isn't merged by compactions of the upper levels
+    /// Tables in the upper levels shadow it, so the keys should be disjoint from existing ones
+    Bottom,
+}
+
+/// Options of `KVS::ingest_sorted_with`
+#[derive(Clone, Copy, Debug)]
+pub struct IngestOptions {
+    pub level: IngestLevel,
+    /// With `IngestLevel::Bottom`, return `CrudError::InvalidInput` and ingest nothing if an
+    /// existing SSTable overlaps the ingested keys
+    pub check_overlap: bool,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        IngestOptions {
+            level: IngestLevel::Top,
+            check_overlap: true,
+        }
+    }
+}
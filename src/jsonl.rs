@@ -0,0 +1,104 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+use crate::amphis_error::CrudError;
+
+/*
+ * JSON Lines format:
+ * {"key":"<base64>","value":"<base64>"}
+ * One line per key-value pair. Keys and values are arbitrary bytes, so they're encoded
+ * with the standard base64 alphabet.
+ */
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// Write each pair as a line
+pub fn export(
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), CrudError>>,
+    w: &mut dyn Write,
+) -> Result<(), CrudError> {
+    for pair in pairs {
+        let (key, value) = pair?;
+        let record = Record {
+            key: STANDARD.encode(key),
+            value: STANDARD.encode(value),
+        };
+        let line =
+            serde_json::to_string(&record).map_err(|e| CrudError::Serialization(e.to_string()))?;
+        writeln!(w, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+/// Iterate over pairs in the lines
+/// Empty lines are skipped, and a malformed line is `CrudError::InvalidInput`
+pub fn import(
+    r: &mut dyn BufRead,
+) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), CrudError>> + '_ {
+    r.lines().enumerate().filter_map(|(i, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if line.trim().is_empty() {
+            return None;
+        }
+        Some(decode(&line).map_err(|e| CrudError::InvalidInput(format!("line {}: {}", i + 1, e))))
+    })
+}
+
+fn decode(line: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let record: Record = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let key = STANDARD.decode(record.key).map_err(|e| e.to_string())?;
+    let value = STANDARD.decode(record.value).map_err(|e| e.to_string())?;
+
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import() {
+        let pairs = vec![
+            (b"\x00a".to_vec(), b"\xff\x00".to_vec()),
+            (b"b".to_vec(), Vec::new()),
+        ];
+        let mut out = Vec::new();
+        export(pairs.clone().into_iter().map(Ok), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "{\"key\":\"AGE=\",\"value\":\"/wA=\"}\n{\"key\":\"Yg==\",\"value\":\"\"}\n"
+        );
+
+        let imported: Vec<_> = import(&mut out.as_slice())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(imported, pairs);
+
+        // empty lines are skipped
+        let mut input = "\n{\"key\":\"Yg==\",\"value\":\"\"}\n\n".as_bytes();
+        assert_eq!(import(&mut input).count(), 1);
+
+        let mut input =
+            "{\"key\":\"Yg==\",\"value\":\"\"}\n{\"key\":\"not base64\",\"value\":\"\"}\n"
+                .as_bytes();
+        let results: Vec<_> = import(&mut input).collect();
+        assert!(results[0].is_ok());
+        assert!(matches!(&results[1], Err(CrudError::InvalidInput(m)) if m.starts_with("line 2:")));
+
+        let mut input = "{\"key\":\"Yg==\"}".as_bytes();
+        assert!(matches!(
+            import(&mut input).next(),
+            Some(Err(CrudError::InvalidInput(_)))
+        ));
+    }
+}
@@ -0,0 +1,82 @@
+//! Order-preserving encodings of integer keys
+//!
+//! Keys are compared as byte strings, so integers stored by `to_le_bytes()` aren't
+//! scanned in their order. These functions encode integers in big-endian and flip the
+//! sign bit of signed integers so that the byte order matches the numeric order.
+use std::convert::TryInto;
+
+use crate::amphis_error::CrudError;
+
+macro_rules! impl_int_codec {
+    ($(($t:ty, $encode:ident, $decode:ident)),*) => {
+        $(
+            #[doc = concat!("Encode `", stringify!($t), "` into bytes in the numeric order")]
+            pub fn $encode(value: $t) -> [u8; std::mem::size_of::<$t>()] {
+                // `MIN` is the sign bit of a signed integer and 0 of an unsigned one
+                (value ^ <$t>::MIN).to_be_bytes()
+            }
+
+            #[doc = concat!("Decode `", stringify!($t), "` encoded by `", stringify!($encode), "`")]
+            pub fn $decode(bytes: &[u8]) -> Result<$t, CrudError> {
+                let bytes = bytes.try_into().map_err(|_| {
+                    CrudError::Serialization(format!(
+                        "{} bytes can't be decoded as {}",
+                        bytes.len(),
+                        stringify!($t)
+                    ))
+                })?;
+                Ok(<$t>::from_be_bytes(bytes) ^ <$t>::MIN)
+            }
+        )*
+    };
+}
+
+impl_int_codec!(
+    (u8, encode_u8_be, decode_u8_be),
+    (u16, encode_u16_be, decode_u16_be),
+    (u32, encode_u32_be, decode_u32_be),
+    (u64, encode_u64_be, decode_u64_be),
+    (u128, encode_u128_be, decode_u128_be),
+    (i8, encode_i8_be, decode_i8_be),
+    (i16, encode_i16_be, decode_i16_be),
+    (i32, encode_i32_be, decode_i32_be),
+    (i64, encode_i64_be, decode_i64_be),
+    (i128, encode_i128_be, decode_i128_be)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let values = [i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX];
+        let encoded: Vec<[u8; 8]> = values.iter().map(|v| encode_i64_be(*v)).collect();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        for (value, bytes) in values.iter().zip(&encoded) {
+            assert_eq!(decode_i64_be(bytes).unwrap(), *value);
+        }
+
+        let values = [0u64, 1, 255, 256, u64::MAX];
+        let encoded: Vec<[u8; 8]> = values.iter().map(|v| encode_u64_be(*v)).collect();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        for (value, bytes) in values.iter().zip(&encoded) {
+            assert_eq!(decode_u64_be(bytes).unwrap(), *value);
+        }
+
+        assert!(encode_i8_be(-1) < encode_i8_be(0));
+        assert_eq!(decode_i128_be(&encode_i128_be(-42)).unwrap(), -42);
+    }
+
+    #[test]
+    fn test_invalid_length() {
+        assert!(matches!(
+            decode_u64_be(&[0; 4]),
+            Err(CrudError::Serialization(_))
+        ));
+        assert!(matches!(
+            decode_i32_be(&[]),
+            Err(CrudError::Serialization(_))
+        ));
+    }
+}
@@ -1,109 +1,501 @@
 use crossbeam_channel::Sender;
-use log::{debug, error, info, trace};
+use log::{error, info};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufRead, Write};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
-//use crate::amphis_error::CrudError;
+use crate::amphis_error::CrudError;
+use crate::column_family::ColumnFamilies;
+use crate::compaction_worker::{spawn_compaction_worker, CompactionSignal};
 use crate::config::Config;
-use crate::flush_writer::{spawn_flush_writer, FlushSignal, FlushWriter};
-use crate::fptree_manager::FPTreeManager;
-use crate::sstable_manager::SstableManager;
-use crate::util::file_util;
+use crate::flush_writer::{spawn_flush_writer, FlushSignal};
+use crate::util::lock_util::RwLockExt;
+
+pub use bytes::Bytes;
+
+pub use crate::column_family::ColumnFamily;
+pub use crate::compaction_filter::{CompactionFilter, FilterDecision};
+pub use crate::entry::Entry;
+#[cfg(feature = "fault-injection")]
+pub use crate::fault::{FaultInjector, FaultPoint};
+pub use crate::ingest::{IngestLevel, IngestOptions};
+pub use crate::listener::Listener;
+pub use crate::scan::{Scan, ScanWithSource, Source};
+/// Iterator over all key-value pairs
+pub type Iter = Scan;
+pub use crate::snapshot::Snapshot;
+pub use crate::stats::Stats;
+pub use crate::storage::{FsStorage, Storage, StorageFile};
+pub use crate::typed_kvs::{BincodeCodec, KeyCodec, OrderedIntCodec, TypedKvs, TypedScan};
+pub use crate::write_batch::WriteBatch;
 
 pub struct KVS {
-    fptree_manager: Arc<FPTreeManager>,
-    sstable_manager: Arc<SstableManager>,
+    name: String,
+    config: Config,
+    default_cf: Arc<ColumnFamily>,
+    column_families: ColumnFamilies,
+    /// The sequence of the last write to any column family
+    sequence: Arc<AtomicU64>,
     flush_writer_handle: Option<JoinHandle<()>>,
     sender: Sender<FlushSignal>,
+    compaction_worker_handle: Option<JoinHandle<()>>,
+    compaction_sender: Sender<CompactionSignal>,
+    /// The lock is released when the file is closed after the workers stop
+    /// No file is locked in memory
+    _lock_file: Option<File>,
 }
 
 impl KVS {
-    pub fn new(name: &str, config: Config) -> Result<Self, std::io::Error> {
-        let path = config.get_leaf_dir_path(name);
+    /// Open the existing database
+    /// Return `CrudError::NotFound` when no directory of the database exists
+    pub fn open(name: &str, config: Config) -> Result<Self, CrudError> {
+        if !Path::new(&config.get_leaf_dir_path(name)).exists()
+            && !Path::new(&config.get_table_dir_path(name)).exists()
+        {
+            return Err(CrudError::NotFound(name.to_string()));
+        }
+
+        Self::create(name, config)
+    }
+
+    /// Open the database, or create it if it doesn't exist
+    pub fn create(name: &str, config: Config) -> Result<Self, CrudError> {
+        Self::new(name, config)
+    }
+
+    /// Same as `create`
+    pub fn new(name: &str, config: Config) -> Result<Self, CrudError> {
+        config.validate()?;
+        let config = config.with_new_rate_limiter();
+        let lock_file = if config.get_in_memory() {
+            None
+        } else {
+            Some(lock(name, &config)?)
+        };
+
         let (tx, rx) = crossbeam_channel::unbounded::<FlushSignal>();
+        let (compaction_tx, compaction_rx) = crossbeam_channel::unbounded::<CompactionSignal>();
+
+        let sequence = Arc::new(AtomicU64::new(0));
+        let default_cf = Arc::new(ColumnFamily::open(
+            0,
+            name,
+            config.clone(),
+            sequence.clone(),
+            tx.clone(),
+            compaction_tx.clone(),
+        )?);
+        let column_families = Arc::new(RwLock::new(vec![default_cf.clone()]));
+        let flush_writer_handle = spawn_flush_writer(rx, column_families.clone());
+        let compaction_worker_handle =
+            spawn_compaction_worker(compaction_rx, column_families.clone());
+
+        let kvs = KVS {
+            name: name.to_string(),
+            config,
+            default_cf,
+            column_families,
+            sequence,
+            flush_writer_handle: Some(flush_writer_handle),
+            sender: tx,
+            compaction_worker_handle: Some(compaction_worker_handle),
+            compaction_sender: compaction_tx,
+            _lock_file: lock_file,
+        };
 
-        let (sstable_manager, next_table_id) = SstableManager::new(name, config.clone())?;
-        let sstable_manager = Arc::new(sstable_manager);
-
-        let mut flush_writer = FlushWriter::new(name, config.clone(), next_table_id);
-        if Path::new(&path).exists() {
-            // flush the exsting trees
-            for entry in std::fs::read_dir(path)? {
-                if let Some(fptree_id) = file_util::get_tree_id(&entry?.path()) {
-                    debug!("found FPTree ID: {}", fptree_id);
-                    let table_info = flush_writer.flush_with_file(name, fptree_id)?;
-                    sstable_manager.register(table_info)?;
-                    let leaf_file = config.get_leaf_file_path(name, fptree_id);
-                    std::fs::remove_file(leaf_file)?;
+        // recover all column families
+        let mut cf_names = BTreeSet::new();
+        for dir in [
+            kvs.config.get_leaf_dir_path(name),
+            kvs.config.get_table_dir_path(name),
+        ] {
+            // nothing remains of an in-memory database
+            if kvs.config.get_in_memory() || !Path::new(&dir).exists() {
+                continue;
+            }
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    cf_names.insert(entry.file_name().to_string_lossy().to_string());
                 }
             }
         }
+        for cf_name in cf_names {
+            kvs.open_cf(&cf_name)?;
+        }
 
-        let fptree_manager = Arc::new(FPTreeManager::new(name, config.clone())?);
-
-        let flush_writer_handle = spawn_flush_writer(
-            flush_writer,
-            rx,
-            fptree_manager.clone(),
-            sstable_manager.clone(),
-        );
         info!("Amphis KVS has started: table {}", name);
-        Ok(KVS {
-            fptree_manager,
-            sstable_manager,
-            flush_writer_handle: Some(flush_writer_handle),
-            sender: tx,
-        })
+        Ok(kvs)
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
-        trace!(
-            "Put K: {}, V: {}",
-            String::from_utf8(key.to_vec()).unwrap(),
-            String::from_utf8(value.to_vec()).unwrap()
-        );
-
-        self.fptree_manager.put(key, value)?;
+    /// Open the column family, or create it if it doesn't exist
+    pub fn open_cf(&self, cf: &str) -> Result<Arc<ColumnFamily>, CrudError> {
+        if cf.is_empty() || cf == "." || cf == ".." || cf.contains(['/', '\\']) {
+            return Err(CrudError::InvalidInput(format!(
+                "invalid column family name: {}",
+                cf
+            )));
+        }
 
-        if self.fptree_manager.need_flush() {
-            let _ = self.sender.send(FlushSignal::TryFlush);
+        // each column family is stored in a subdirectory
+        let cf_name = format!("{}/{}", self.name, cf);
+        let mut column_families = self.column_families.write_or_recover();
+        if let Some(column_family) = column_families.iter().find(|c| c.get_name() == cf_name) {
+            return Ok(column_family.clone());
         }
 
-        Ok(())
+        let column_family = Arc::new(ColumnFamily::open(
+            column_families.len(),
+            &cf_name,
+            self.config.clone(),
+            self.sequence.clone(),
+            self.sender.clone(),
+            self.compaction_sender.clone(),
+        )?);
+        column_families.push(column_family.clone());
+
+        Ok(column_family)
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
-        trace!(
-            "Getting from K: {}",
-            String::from_utf8(key.to_vec()).unwrap()
-        );
+    /// Return `CrudError::KeyTooLarge` or `CrudError::ValueTooLarge` if the key or the value
+    /// exceeds `max_key_size`, `max_value_size` or a leaf page, and `CrudError::InvalidInput`
+    /// if the key is empty
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        self.default_cf.put(key, value)
+    }
+
+    pub fn put_cf(&self, cf: &ColumnFamily, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        cf.put(key, value)
+    }
 
-        // TODO: concurrenct read
-        let result = match self.fptree_manager.get(key)? {
-            Some(r) => Some(r),
-            None => self.sstable_manager.get(key)?,
+    /// Put the key-value pair which expires after `ttl`
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), CrudError> {
+        self.default_cf.put_with_ttl(key, value, ttl)
+    }
+
+    /// Apply all mutations in the batch atomically
+    /// Readers see either all of them or none of them
+    /// When an error is returned, none of them is applied, even after a crash and a restart
+    pub fn write(&self, batch: WriteBatch) -> Result<(), CrudError> {
+        self.default_cf.write(batch)
+    }
+
+    /// Flush the current FPTree to a new SSTable
+    /// This blocks until the SSTable is persisted and registered
+    pub fn flush(&self) -> Result<(), CrudError> {
+        self.default_cf.flush()
+    }
+
+    /// Write key-value pairs directly to a new SSTable bypassing the FPTree
+    /// Keys have to be in the strictly ascending order, otherwise `CrudError::InvalidInput`
+    /// is returned and nothing is ingested
+    /// The ingested pairs overwrite values written before this call
+    pub fn ingest_sorted(
+        &self,
+        pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), CrudError> {
+        self.ingest_sorted_with(pairs, &IngestOptions::default())
+    }
+
+    /// Same as `ingest_sorted`, but the new SSTable is placed by `options`
+    /// With `IngestLevel::Bottom`, the table is placed in the deepest level not to be merged
+    /// by compactions of the upper levels, which is for keys disjoint from existing ones
+    pub fn ingest_sorted_with(
+        &self,
+        pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        options: &IngestOptions,
+    ) -> Result<(), CrudError> {
+        self.default_cf
+            .ingest_sorted(pairs.into_iter().map(Ok), options)
+    }
+
+    /// Ingest all live key-value pairs of the database at `other` into this database
+    /// The values of `other` win on conflicts, see `absorb_with` for details
+    pub fn absorb(&self, other: &Path) -> Result<(), CrudError> {
+        self.absorb_with(other, |_key, _ours, theirs| theirs.to_vec())
+    }
+
+    /// Ingest all live key-value pairs of the database at `other` into this database
+    /// `resolve(key, ours, theirs)` returns the value of a key existing in both databases
+    /// `other` is opened like a checkpoint, so its leaves are flushed to its SSTables, and
+    /// it's locked until the pairs are ingested
+    /// Only the default column family is absorbed, and keys deleted in `other` remain in
+    /// this database
+    /// Return `CrudError::NotFound` if `other` doesn't exist, and nothing is ingested on an error
+    pub fn absorb_with<F>(&self, other: &Path, resolve: F) -> Result<(), CrudError>
+    where
+        F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8>,
+    {
+        let (dir, name) = match (other.parent(), other.file_name()) {
+            // a relative path without any directory is in the current directory
+            (Some(dir), Some(name)) if dir.as_os_str().is_empty() => {
+                (".".into(), name.to_string_lossy())
+            }
+            (Some(dir), Some(name)) => (dir.to_string_lossy(), name.to_string_lossy()),
+            _ => {
+                return Err(CrudError::InvalidInput(format!(
+                    "invalid database path {:?}",
+                    other
+                )))
+            }
         };
+        let other_kvs = KVS::open(&name, self.config.with_directory(&dir))?;
 
-        match result {
-            Some(v) => {
-                if v.is_empty() {
-                    Ok(None)
-                } else {
-                    Ok(Some(v))
+        let pairs = other_kvs.iter()?.map(|pair| {
+            let (key, theirs) = pair?;
+            match self.get(&key)? {
+                Some(ours) => {
+                    let value = resolve(&key, &ours, &theirs);
+                    Ok((key, value))
                 }
+                None => Ok((key, theirs)),
             }
-            None => Ok(None),
-        }
+        });
+        self.default_cf
+            .ingest_sorted(pairs, &IngestOptions::default())?;
+
+        info!("{:?} has been absorbed into {}", other, self.name);
+        Ok(())
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<(), std::io::Error> {
-        trace!(
-            "Deleting from K: {}",
-            String::from_utf8(key.to_vec()).unwrap()
+    /// Save the flushed state to `dest_dir` as a standalone database without stopping writes
+    /// SSTable files are hard-linked if possible, and writes after the flush aren't included
+    /// The checkpoint is opened with the parent directory of `dest_dir` as the leaf and
+    /// table directories, and the name of `dest_dir` as the database name
+    /// Return `CrudError::InvalidInput` if `dest_dir` already exists
+    pub fn checkpoint(&self, dest_dir: &Path) -> Result<(), CrudError> {
+        if dest_dir.exists() {
+            return Err(CrudError::InvalidInput(format!(
+                "the checkpoint directory {:?} already exists",
+                dest_dir
+            )));
+        }
+
+        // not to block opening a column family during flushes
+        let column_families = self.column_families.read_or_recover().clone();
+        for column_family in column_families {
+            // a column family is stored in a subdirectory of the database
+            let sub_dir = column_family.get_name()[self.name.len()..].trim_start_matches('/');
+            column_family.checkpoint(&dest_dir.join(sub_dir))?;
+        }
+
+        info!(
+            "Checkpoint of {} has been saved to {:?}",
+            self.name, dest_dir
         );
+        Ok(())
+    }
 
-        self.fptree_manager.delete(key)
+    /// Merge all SSTables into a sorted run to reclaim space of overwritten and deleted keys
+    /// This blocks until the merged tables are persisted
+    /// Keys in the current FPTree are not included, so call `flush` before this if needed
+    pub fn compact(&self) -> Result<(), CrudError> {
+        self.default_cf.compact()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        self.default_cf.get(key)
+    }
+
+    /// Same as `get`, but the value is returned as `Bytes`
+    /// The encoded value is copied once from the leaf, the SSTable block or the read cache, and
+    /// the returned value is a slice of the copy, which saves only the last copy of `get`
+    /// It refers to neither the leaf mapping nor the cached block
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Bytes>, CrudError> {
+        self.default_cf.get_bytes(key)
+    }
+
+    /// Same as `get`, but return `CrudError::TimedOut` if the lookup takes longer than `timeout`
+    /// It's best-effort: the elapsed time is checked before locking the FPTrees and the
+    /// SSTables and before reading each SSTable, and a read in progress isn't interrupted
+    pub fn get_timeout(&self, key: &[u8], timeout: Duration) -> Result<Option<Vec<u8>>, CrudError> {
+        self.default_cf.get_timeout(key, timeout)
+    }
+
+    /// Get the value which the key had when the write of `sequence` was applied
+    /// Return `CrudError::VersionUnavailable` when the version can't be read anymore: it was
+    /// overwritten in the current FPTree or by a compaction, or a range deletion covers the key
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> Result<Option<Vec<u8>>, CrudError> {
+        self.default_cf.get_at(key, sequence)
+    }
+
+    /// The sequence of the last write
+    /// Each write, batch and ingestion gets a larger sequence than the previous ones, and
+    /// it's visible to `get_at` with its sequence after it returns
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::Acquire)
+    }
+
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        cf.get(key)
+    }
+
+    /// Return `false` only if the key definitely doesn't exist, e.g. for cache admission
+    ///
+    /// Only the FPTrees and the bloom filters of SSTables are checked, and no SSTable file
+    /// is read. `true` means that the key may exist: the bloom filter of a table can pass
+    /// a missing key at about `fp_rate`, and an older value deleted or expired in an SSTable
+    /// also passes. `false` is never returned for a key which `get` would return.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.default_cf.may_contain(key)
+    }
+
+    /// Get values of multiple keys in the same order as `keys`
+    pub fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, CrudError> {
+        self.default_cf.get_many(keys)
+    }
+
+    /// Return an iterator over key-value pairs in `[start, end)` in the key order
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<Scan, CrudError> {
+        self.default_cf.scan(start, end)
+    }
+
+    pub fn scan_cf(&self, cf: &ColumnFamily, start: &[u8], end: &[u8]) -> Result<Scan, CrudError> {
+        cf.scan(start, end)
+    }
+
+    /// Return an iterator over key-value pairs whose keys start with `prefix`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan, CrudError> {
+        self.default_cf.scan_prefix(prefix)
+    }
+
+    /// Return an iterator over all live key-value pairs in the key order
+    pub fn iter(&self) -> Result<Iter, CrudError> {
+        self.default_cf.iter()
+    }
+
+    /// Return an iterator over all live key-value pairs with the layers which served them
+    /// Use `Scan::with_source()` to get the layers of a range
+    pub fn iter_with_source(&self) -> Result<ScanWithSource, CrudError> {
+        Ok(self.default_cf.iter()?.with_source())
+    }
+
+    /// Write all live key-value pairs to `w` as JSON Lines in the key order
+    /// Each line is `{"key":<base64>,"value":<base64>}`
+    pub fn export_jsonl(&self, w: &mut dyn Write) -> Result<(), CrudError> {
+        self.default_cf.export_jsonl(w)
+    }
+
+    /// Write key-value pairs in `[start, end)` to `w` in the key order without collecting them
+    /// Each record is the key length (u32, little endian), the key, the value length and the
+    /// value. Return the number of written records
+    pub fn scan_to_writer(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        w: &mut dyn Write,
+    ) -> Result<u64, CrudError> {
+        self.default_cf.scan_to_writer(start, end, w)
+    }
+
+    /// Put all key-value pairs in JSON Lines written by `export_jsonl`
+    /// Pairs are put by batches, and a malformed line is `CrudError::InvalidInput`
+    /// Batches before the malformed line have been put, but the batch including it hasn't
+    pub fn import_jsonl(&self, r: &mut dyn BufRead) -> Result<(), CrudError> {
+        self.default_cf.import_jsonl(r)
+    }
+
+    /// Return the approximate number of keys
+    /// Overwritten keys and tombstones which haven't been compacted are also counted
+    pub fn approximate_len(&self) -> usize {
+        self.default_cf.approximate_len()
+    }
+
+    /// Return the total size of leaf files and SSTable files in bytes
+    pub fn size_on_disk(&self) -> Result<u64, CrudError> {
+        self.default_cf.size_on_disk()
+    }
+
+    /// Return counters for monitoring
+    pub fn stats(&self) -> Stats {
+        self.default_cf.stats()
+    }
+
+    /// Return `(level, the number of SSTables, the total size in bytes)` of each level
+    /// The number of tables in Level 0 is a rough measure of the read amplification
+    pub fn level_summary(&self) -> Vec<(usize, usize, u64)> {
+        self.default_cf.level_summary()
+    }
+
+    /// Return the total size of SSTables divided by the estimated size of the live data
+    /// It grows while overwritten values remain in older tables, and it's 1.0 without them
+    pub fn space_amplification(&self) -> f64 {
+        self.default_cf.space_amplification()
+    }
+
+    /// Print the inner nodes and the leaf chain of FPTrees, and SSTables of each level
+    /// This is for debugging, and the format isn't stable
+    pub fn debug_dump(&self, w: &mut dyn Write) -> Result<(), CrudError> {
+        self.default_cf.debug_dump(w)
+    }
+
+    /// Return a read-only view of the current state
+    /// Writes after this call are invisible in the snapshot
+    pub fn snapshot(&self) -> Result<Snapshot, CrudError> {
+        self.default_cf.snapshot()
+    }
+
+    /// Set `new` only when the current value is `expected`
+    /// `None` as `expected` means that the key doesn't exist, and `None` as `new` deletes the key
+    /// Return whether the value has been swapped
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, CrudError> {
+        self.default_cf.compare_and_swap(key, expected, new)
+    }
+
+    /// Return the entry to read and write the key atomically against other writers
+    /// e.g. `kvs.entry(b"counter").and_modify(|v| v[0] += 1).or_insert(&[1])`
+    pub fn entry(&self, key: &[u8]) -> Entry<'_> {
+        Entry::new(&self.default_cf, key)
+    }
+
+    /// Put the key-value pair and return the previous value
+    /// A deleted or expired value is returned as `None`
+    pub fn replace(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        self.default_cf.replace(key, value)
+    }
+
+    /// Delete all keys in `[start, end)`
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), CrudError> {
+        self.default_cf.delete_range(start, end)
+    }
+
+    /// Delete the key regardless of its existence
+    pub fn delete(&self, key: &[u8]) -> Result<(), CrudError> {
+        self.default_cf.delete(key)
+    }
+
+    /// Delete the key and return whether a live value existed
+    pub fn remove(&self, key: &[u8]) -> Result<bool, CrudError> {
+        self.default_cf.remove(key)
+    }
+
+    pub fn delete_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<(), CrudError> {
+        cf.delete(key)
+    }
+}
+
+/// Lock the database not to be opened by another instance or process
+fn lock(name: &str, config: &Config) -> Result<File, CrudError> {
+    std::fs::create_dir_all(config.get_leaf_dir_path(name))?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(config.get_lock_file_path(name))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(CrudError::AlreadyOpen(name.to_string())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
     }
 }
 
@@ -116,6 +508,13 @@ impl Drop for KVS {
                 error!("FlushWrite failed to shut down: {e:?}");
             }
         }
+        // the flush writer doesn't request compactions anymore
+        let _ = self.compaction_sender.send(CompactionSignal::Shutdown);
+        if let Some(handle) = self.compaction_worker_handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Compaction worker failed to shut down: {e:?}");
+            }
+        }
         info!("Shutdown gracefully");
     }
 }
@@ -1,9 +1,31 @@
+pub mod amphis_error;
 pub mod config;
+pub mod keycodec;
 pub mod kvs;
+#[cfg(feature = "server")]
+pub mod server;
 
+mod column_family;
+mod compaction_filter;
+mod compaction_worker;
+mod entry;
+mod fault;
 mod flush_writer;
 mod fptree;
 mod fptree_manager;
+mod ingest;
+mod jsonl;
+mod listener;
+mod range_tombstone;
+mod rate_limiter;
+mod read_cache;
+mod scan;
+mod snapshot;
 mod sparse_index;
 mod sstable_manager;
+mod stats;
+mod storage;
+mod typed_kvs;
 mod util;
+mod wal;
+mod write_batch;
@@ -0,0 +1,14 @@
+/// Callbacks of background events
+///
+/// Callbacks are invoked on the flush writer thread and the compaction worker
+/// thread, and the next flush or compaction waits for them. They should be
+/// cheap, e.g. updating counters or sending a message to another thread.
+pub trait Listener: Send + Sync {
+    /// Called when an FPTree has been flushed to the new SSTable `table_id`
+    /// `bytes` is the size of the table file
+    fn on_flush(&self, _table_id: usize, _entry_count: usize, _bytes: usize) {}
+
+    /// Called when a compaction has replaced the input tables with the output tables
+    /// `outputs` can be empty when all entries have been dropped
+    fn on_compaction(&self, _inputs: &[usize], _outputs: &[usize]) {}
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::util::data_util;
+use crate::util::file_util;
+
+/// Deletion of all keys in `[start, end)`
+///
+/// A range tombstone hides keys in older FPTrees and SSTables. Keys in the same
+/// FPTree or SSTable aren't hidden since the keys which existed when deleting
+/// were overwritten with tombstones.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeTombstone {
+    start: Vec<u8>,
+    end: Vec<u8>,
+}
+
+impl RangeTombstone {
+    pub fn new(start: &[u8], end: &[u8]) -> Self {
+        RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+        }
+    }
+
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    pub fn get_start(&self) -> &[u8] {
+        &self.start
+    }
+
+    pub fn get_end(&self) -> &[u8] {
+        &self.end
+    }
+
+    /// Return the part of the range tombstone in `[lower, upper)`
+    /// `None` bound means no limit, and `None` is returned when no part remains
+    pub fn clip(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Option<Self> {
+        let start = match lower {
+            Some(lower) if lower > self.start.as_slice() => lower,
+            _ => self.start.as_slice(),
+        };
+        let end = match upper {
+            Some(upper) if upper < self.end.as_slice() => upper,
+            _ => self.end.as_slice(),
+        };
+
+        (start < end).then(|| RangeTombstone::new(start, end))
+    }
+}
+
+/// Append the range tombstone to the file
+pub fn append(file_path: &str, range_tombstone: &RangeTombstone) -> Result<(), std::io::Error> {
+    let (file, _) = file_util::open_file(file_path)?;
+    let mut writer = BufWriter::new(&file);
+
+    let encoded =
+        bincode::serialize(range_tombstone).expect("serializing the range tombstone failed");
+    writer.write_all(&data_util::format_bytes_with_crc(&encoded))?;
+    writer.flush()?;
+    drop(writer);
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Load all range tombstones in the file
+/// Nothing is loaded when the file doesn't exist
+pub fn load(file_path: &str) -> Result<Vec<RangeTombstone>, std::io::Error> {
+    let mut range_tombstones = Vec::new();
+    if !Path::new(file_path).exists() {
+        return Ok(range_tombstones);
+    }
+
+    let (file, _) = file_util::open_file(file_path)?;
+    let mut reader = BufReader::new(file);
+    while let Some(bytes) = data_util::read_bytes_with_crc(&mut reader)? {
+        let range_tombstone = bincode::deserialize(&bytes)
+            .map_err(|_| std::io::Error::other("failed to deserialize a range tombstone"))?;
+        range_tombstones.push(range_tombstone);
+    }
+
+    Ok(range_tombstones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_covers() {
+        let range_tombstone = RangeTombstone::new(b"b", b"d");
+        assert!(!range_tombstone.covers(b"a"));
+        assert!(range_tombstone.covers(b"b"));
+        assert!(range_tombstone.covers(b"c0"));
+        assert!(!range_tombstone.covers(b"d"));
+    }
+
+    #[test]
+    fn test_clip() {
+        let range_tombstone = RangeTombstone::new(b"b", b"f");
+        assert_eq!(
+            range_tombstone.clip(None, None),
+            Some(range_tombstone.clone())
+        );
+        assert_eq!(
+            range_tombstone.clip(Some(b"c"), Some(b"e")),
+            Some(RangeTombstone::new(b"c", b"e"))
+        );
+        assert_eq!(
+            range_tombstone.clip(Some(b"a"), Some(b"d")),
+            Some(RangeTombstone::new(b"b", b"d"))
+        );
+        assert_eq!(range_tombstone.clip(Some(b"f"), None), None);
+        assert_eq!(range_tombstone.clip(None, Some(b"b")), None);
+    }
+
+    #[test]
+    fn test_append_and_load() {
+        let dir = tempfile::tempdir().expect("no temp directory");
+        let file_path = format!("{}/range-tombstones-0.amph", dir.path().to_str().unwrap());
+        assert!(load(&file_path).expect("load failed").is_empty());
+
+        let range_tombstones = vec![
+            RangeTombstone::new(b"a", b"c"),
+            RangeTombstone::new(b"x", b"z"),
+        ];
+        for range_tombstone in &range_tombstones {
+            append(&file_path, range_tombstone).expect("append failed");
+        }
+
+        assert_eq!(load(&file_path).expect("load failed"), range_tombstones);
+    }
+}
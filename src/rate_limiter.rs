@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::util::lock_util::MutexExt;
+
+/// Token bucket limiting the bytes written by flushes and compactions
+///
+/// Writers sharing the limiter draw from one budget. A write takes its bytes even if the
+/// bucket doesn't have enough, and it waits until the debt is refilled, so later writers
+/// wait for it too. Up to a second of bytes is accumulated while no table is written.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    bucket: Mutex<Bucket>,
+    /// The wait of the last write in microseconds
+    delay_micros: AtomicU64,
+    /// The total wait of writes in microseconds
+    throttle_micros: AtomicU64,
+}
+
+struct Bucket {
+    /// Negative when the written bytes haven't been refilled yet
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// `None` doesn't limit writes
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                last_refill: Instant::now(),
+            }),
+            delay_micros: AtomicU64::new(0),
+            throttle_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Take `bytes` from the bucket, and block until they are refilled if it's short
+    pub fn request(&self, bytes: usize) {
+        let rate = match self.bytes_per_sec {
+            Some(rate) => rate as f64,
+            None => return,
+        };
+
+        let delay = {
+            let mut bucket = self.bucket.lock_or_recover();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.available = (bucket.available + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            bucket.available -= bytes as f64;
+            if bucket.available < 0.0 {
+                Duration::from_secs_f64(-bucket.available / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        let micros = delay.as_micros() as u64;
+        self.delay_micros.store(micros, Ordering::Relaxed);
+        if micros > 0 {
+            self.throttle_micros.fetch_add(micros, Ordering::Relaxed);
+            std::thread::sleep(delay);
+        }
+    }
+
+    pub fn get_delay_micros(&self) -> u64 {
+        self.delay_micros.load(Ordering::Relaxed)
+    }
+
+    pub fn get_throttle_micros(&self) -> u64 {
+        self.throttle_micros.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        limiter.request(1 << 30);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(limiter.get_throttle_micros(), 0);
+    }
+
+    #[test]
+    fn test_shared_budget() {
+        const RATE: u64 = 100 * 1024;
+        let limiter = RateLimiter::new(Some(RATE));
+        let start = Instant::now();
+        // two writers draw from one budget
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        limiter.request(RATE as usize / 40);
+                    }
+                });
+            }
+        });
+
+        // half a second of bytes from the empty bucket
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        assert!(limiter.get_throttle_micros() > 0);
+    }
+}
@@ -0,0 +1,197 @@
+//! Cache of lookup results of hot keys
+//!
+//! `ColumnFamily::get` returns a cached result without reading the FPTrees and the
+//! SSTables. Each write removes the cached result of its key after the write is applied,
+//! and a result read concurrently with a write isn't cached since it can be the old one.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::util::lock_util::MutexExt;
+
+/// The result of a lookup in the cache
+pub enum Cached {
+    /// The encoded value or `None` for a missing key
+    Hit(Option<Vec<u8>>),
+    /// The generation to cache the result read after the miss
+    Miss(u64),
+}
+
+/// LRU cache of encoded values evicted by the total size of keys and values
+pub struct ReadCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// Cached results with their last access
+    entries: HashMap<Vec<u8>, (Option<Vec<u8>>, u64)>,
+    /// Keys from the least recently used one
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    size: usize,
+    /// Incremented by every invalidation
+    generation: u64,
+}
+
+impl ReadCache {
+    /// `capacity` is the total size of cached keys and values in bytes
+    /// 0 disables the cache
+    pub fn new(capacity: usize) -> Self {
+        ReadCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Cached {
+        if self.capacity == 0 {
+            return Cached::Miss(0);
+        }
+
+        let mut inner = self.inner.lock_or_recover();
+        let tick = inner.next_tick();
+        let generation = inner.generation;
+        let (value, last_access) = match inner.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return Cached::Miss(generation),
+        };
+        let value = value.clone();
+        let prev = std::mem::replace(last_access, tick);
+        inner.lru.remove(&prev);
+        inner.lru.insert(tick, key.to_vec());
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Cached::Hit(value)
+    }
+
+    /// Cache the result read after the miss of `generation`
+    /// The result isn't cached if any key has been invalidated since the miss
+    pub fn insert(&self, key: &[u8], value: Option<&[u8]>, generation: u64) {
+        let size = key.len() + value.map_or(0, |v| v.len());
+        if size > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock_or_recover();
+        if inner.generation != generation {
+            return;
+        }
+        let tick = inner.next_tick();
+        let value = value.map(|v| v.to_vec());
+        if let Some((old, last_access)) = inner.entries.insert(key.to_vec(), (value, tick)) {
+            inner.lru.remove(&last_access);
+            inner.size -= key.len() + old.map_or(0, |v| v.len());
+        }
+        inner.lru.insert(tick, key.to_vec());
+        inner.size += size;
+
+        while inner.size > self.capacity {
+            let (_, key) = inner.lru.pop_first().expect("no cached key");
+            let (evicted, _) = inner.entries.remove(&key).expect("no cached key");
+            inner.size -= key.len() + evicted.map_or(0, |v| v.len());
+        }
+    }
+
+    /// Remove the cached result of the key after it's written
+    pub fn invalidate(&self, key: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock_or_recover();
+        inner.generation += 1;
+        if let Some((value, last_access)) = inner.entries.remove(key) {
+            inner.lru.remove(&last_access);
+            inner.size -= key.len() + value.map_or(0, |v| v.len());
+        }
+    }
+
+    /// Remove all cached results after writes of many keys like a range deletion
+    pub fn clear(&self) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock_or_recover();
+        let generation = inner.generation + 1;
+        *inner = CacheInner {
+            generation,
+            ..CacheInner::default()
+        };
+    }
+
+    /// The number of lookups served by the cache
+    pub fn get_hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl CacheInner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(cache: &ReadCache, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        match cache.get(key) {
+            Cached::Hit(value) => Some(value),
+            Cached::Miss(_) => None,
+        }
+    }
+
+    fn miss(cache: &ReadCache, key: &[u8]) -> u64 {
+        match cache.get(key) {
+            Cached::Hit(_) => panic!("{:?} is cached", key),
+            Cached::Miss(generation) => generation,
+        }
+    }
+
+    #[test]
+    fn test_read_cache() {
+        // 3 entries of a 1-byte key and a 9-byte value
+        let cache = ReadCache::new(30);
+        for i in 0..3u8 {
+            let generation = miss(&cache, &[i]);
+            cache.insert(&[i], Some(&[i; 9]), generation);
+        }
+        assert_eq!(get(&cache, &[0]), Some(Some(vec![0; 9])));
+
+        // the keys 1 and 0 are evicted from the least recently used one
+        let generation = miss(&cache, &[3]);
+        cache.insert(&[3], None, generation);
+        assert_eq!(get(&cache, &[3]), Some(None));
+        assert_eq!(get(&cache, &[2]), Some(Some(vec![2; 9])));
+        let generation = miss(&cache, &[4]);
+        cache.insert(&[4], Some(&[4; 9]), generation);
+        assert_eq!(get(&cache, &[1]), None);
+        assert_eq!(get(&cache, &[0]), None);
+        assert_eq!(get(&cache, &[2]), Some(Some(vec![2; 9])));
+        assert_eq!(cache.get_hits(), 4);
+
+        // a result read before an invalidation isn't cached
+        let generation = miss(&cache, &[5]);
+        cache.invalidate(&[0]);
+        assert_eq!(get(&cache, &[0]), None);
+        cache.insert(&[5], Some(&[5]), generation);
+        assert_eq!(get(&cache, &[5]), None);
+
+        cache.clear();
+        assert_eq!(get(&cache, &[2]), None);
+        assert_eq!(cache.inner.lock_or_recover().size, 0);
+
+        // nothing is cached with no capacity
+        let cache = ReadCache::new(0);
+        let generation = miss(&cache, &[0]);
+        cache.insert(&[0], None, generation);
+        assert_eq!(get(&cache, &[0]), None);
+    }
+}
@@ -0,0 +1,385 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::amphis_error::CrudError;
+use crate::range_tombstone::RangeTombstone;
+use crate::util::data_util;
+
+type KvResult = Result<(Vec<u8>, Vec<u8>), std::io::Error>;
+// (key, value, source)
+type SourcedKv = (Vec<u8>, Vec<u8>, Source);
+// (key, source index, value)
+type Head = Reverse<(Vec<u8>, usize, Vec<u8>)>;
+
+/// The layer which a pair returned by `ScanWithSource` came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The FPTree being written
+    Memtable,
+    /// The FPTree being flushed
+    FlushingMemtable,
+    /// The SSTable with its ID and its level
+    Sstable { id: usize, level: usize },
+}
+
+/// Key-value pairs of an FPTree or an SSTable with its range tombstones
+pub(crate) struct SortedRun {
+    pairs: Box<dyn Iterator<Item = KvResult> + Send>,
+    range_tombstones: Vec<RangeTombstone>,
+    source: Source,
+}
+
+impl SortedRun {
+    pub(crate) fn new(
+        pairs: impl Iterator<Item = KvResult> + Send + 'static,
+        range_tombstones: Vec<RangeTombstone>,
+        source: Source,
+    ) -> Self {
+        SortedRun {
+            pairs: Box::new(pairs),
+            range_tombstones,
+            source,
+        }
+    }
+
+    fn is_range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones.iter().any(|r| r.covers(key))
+    }
+}
+
+/// Ordered iterator over the newest versions of keys in `[start, end)`, or from `start` when `end` is `None`
+///
+/// Sources are merged in the key order. When the same key exists in some
+/// sources, the source with the smallest index wins, so the sources have to be
+/// given from the newest one. Pairs covered by range tombstones of newer
+/// sources are skipped, but tombstones and expired pairs are returned as is.
+pub(crate) struct Merge {
+    sources: Vec<SortedRun>,
+    heads: BinaryHeap<Head>,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    error: Option<std::io::Error>,
+}
+
+impl Merge {
+    pub(crate) fn new(sources: Vec<SortedRun>, start: &[u8], end: Option<&[u8]>) -> Self {
+        let mut merge = Merge {
+            sources,
+            heads: BinaryHeap::new(),
+            start: start.to_vec(),
+            end: end.map(|e| e.to_vec()),
+            error: None,
+        };
+        for idx in 0..merge.sources.len() {
+            if let Err(e) = merge.advance(idx) {
+                merge.error = Some(e);
+                break;
+            }
+        }
+
+        merge
+    }
+
+    /// Push the next pair in the range of the source to the heap
+    fn advance(&mut self, idx: usize) -> Result<(), std::io::Error> {
+        for kv in self.sources[idx].pairs.by_ref() {
+            let (key, value) = kv?;
+            if key < self.start {
+                continue;
+            }
+            if self.end.as_ref().is_none_or(|end| key < *end) {
+                self.heads.push(Reverse((key, idx, value)));
+            }
+            break;
+        }
+
+        Ok(())
+    }
+
+    /// Return the next pair with the source which it came from
+    fn next_with_source(&mut self) -> Option<Result<SourcedKv, std::io::Error>> {
+        loop {
+            if let Some(e) = self.error.take() {
+                self.heads.clear();
+                return Some(Err(e));
+            }
+
+            let Reverse((key, idx, mut value)) = self.heads.pop()?;
+            // the popped pair is still the newest one even if the source fails
+            if let Err(e) = self.advance(idx) {
+                self.error = Some(e);
+            }
+
+            // skip older versions of the same key
+            while let Some(Reverse((next_key, _, _))) = self.heads.peek() {
+                if *next_key != key {
+                    break;
+                }
+                let Reverse((_, old_idx, old_value)) = self.heads.pop().unwrap();
+                if let Err(e) = self.advance(old_idx) {
+                    self.error = Some(e);
+                }
+                // the newest version keeps the floor of the skipped versions
+                if let Err(e) = data_util::merge_floor(&mut value, &old_value) {
+                    self.error = Some(e);
+                }
+            }
+
+            if self.sources[..idx].iter().any(|s| s.is_range_deleted(&key)) {
+                continue;
+            }
+
+            return Some(Ok((key, value, self.sources[idx].source)));
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = KvResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_with_source()?.map(|(key, value, _)| (key, value)))
+    }
+}
+
+/// Ordered iterator over live key-value pairs in `[start, end)`, or from `start` when `end` is `None`
+///
+/// Tombstones and expired pairs of the merged sources are skipped.
+pub struct Scan {
+    merge: Merge,
+    now: u64,
+    done: bool,
+}
+
+impl Scan {
+    /// Pairs expired at `now` are skipped
+    pub(crate) fn new(sources: Vec<SortedRun>, start: &[u8], end: Option<&[u8]>, now: u64) -> Self {
+        Scan {
+            merge: Merge::new(sources, start, end),
+            now,
+            done: false,
+        }
+    }
+
+    /// Return pairs with the layers which they came from, e.g. for debugging
+    pub fn with_source(self) -> ScanWithSource {
+        ScanWithSource { scan: self }
+    }
+
+    fn next_with_source(&mut self) -> Option<Result<SourcedKv, CrudError>> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let (key, value, source) = match self.merge.next_with_source()? {
+                Ok(kv) => kv,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            match data_util::get_live_value(&value, self.now) {
+                Ok(Some(v)) => return Some(Ok((key, v.to_vec(), source))),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>), CrudError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_with_source()?.map(|(key, value, _)| (key, value)))
+    }
+}
+
+/// `Scan` which returns each pair with the layer which served it
+pub struct ScanWithSource {
+    scan: Scan,
+}
+
+impl Iterator for ScanWithSource {
+    type Item = Result<SourcedKv, CrudError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.scan.next_with_source()
+    }
+}
+
+/// Return the smallest key which is larger than all keys with the prefix
+/// `None` means no upper bound, e.g. the prefix consists of only 0xFF
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|b| *b != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_source(kv_pairs: Vec<(u8, &str)>) -> SortedRun {
+        make_source_with_range_tombstones(kv_pairs, Vec::new())
+    }
+
+    fn make_source_with_range_tombstones(
+        kv_pairs: Vec<(u8, &str)>,
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> SortedRun {
+        let kv_pairs: Vec<KvResult> = kv_pairs
+            .into_iter()
+            .map(|(k, v)| {
+                if v.is_empty() {
+                    Ok((vec![k], Vec::new()))
+                } else {
+                    Ok((vec![k], data_util::encode_value(v.as_bytes(), None)))
+                }
+            })
+            .collect();
+        SortedRun::new(kv_pairs.into_iter(), range_tombstones, Source::Memtable)
+    }
+
+    #[test]
+    fn test_merge() {
+        let newer = make_source(vec![(1, "new1"), (3, ""), (5, "new5")]);
+        let older = make_source(vec![(0, "old0"), (1, "old1"), (3, "old3"), (4, "old4")]);
+
+        let result: Vec<(Vec<u8>, Vec<u8>)> = Scan::new(vec![newer, older], &[0], Some(&[9]), 0)
+            .map(|kv| kv.unwrap())
+            .collect();
+
+        assert_eq!(
+            result,
+            vec![
+                (vec![0], b"old0".to_vec()),
+                (vec![1], b"new1".to_vec()),
+                (vec![4], b"old4".to_vec()),
+                (vec![5], b"new5".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source() {
+        let newer = make_source(vec![(1, "new1"), (3, "")]);
+        let older: Vec<KvResult> = [(1, "old1"), (2, "old2"), (3, "old3")]
+            .iter()
+            .map(|(k, v)| Ok((vec![*k], data_util::encode_value(v.as_bytes(), None))))
+            .collect();
+        let older = SortedRun::new(
+            older.into_iter(),
+            Vec::new(),
+            Source::Sstable { id: 4, level: 1 },
+        );
+
+        let result: Vec<(Vec<u8>, Source)> = Scan::new(vec![newer, older], &[0], None, 0)
+            .with_source()
+            .map(|kv| kv.map(|(k, _, source)| (k, source)).unwrap())
+            .collect();
+
+        // the tombstone of the newer source hides the older value
+        assert_eq!(
+            result,
+            vec![
+                (vec![1], Source::Memtable),
+                (vec![2], Source::Sstable { id: 4, level: 1 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let newer = make_source(vec![(1, "new1"), (5, "new5")]);
+        let older = make_source(vec![(0, "old0"), (2, "old2"), (4, "old4"), (6, "old6")]);
+
+        let keys: Vec<Vec<u8>> = Scan::new(vec![newer, older], &[1], Some(&[5]), 0)
+            .map(|kv| kv.unwrap().0)
+            .collect();
+
+        assert_eq!(keys, vec![vec![1], vec![2], vec![4]]);
+    }
+
+    #[test]
+    fn test_range_tombstone() {
+        let newer = make_source_with_range_tombstones(
+            vec![(1, "new1"), (3, "new3")],
+            vec![RangeTombstone::new(&[1], &[4])],
+        );
+        let older = make_source(vec![(0, "old0"), (1, "old1"), (2, "old2"), (4, "old4")]);
+
+        let result: Vec<(Vec<u8>, Vec<u8>)> = Scan::new(vec![newer, older], &[0], None, 0)
+            .map(|kv| kv.unwrap())
+            .collect();
+
+        // the range tombstone doesn't hide pairs in the same source
+        assert_eq!(
+            result,
+            vec![
+                (vec![0], b"old0".to_vec()),
+                (vec![1], b"new1".to_vec()),
+                (vec![3], b"new3".to_vec()),
+                (vec![4], b"old4".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_tombstones() {
+        let newer = make_source_with_range_tombstones(
+            vec![(1, ""), (5, "new5")],
+            vec![RangeTombstone::new(&[3], &[5])],
+        );
+        let older = make_source(vec![(0, "old0"), (1, "old1"), (3, "old3")]);
+
+        let result: Vec<(Vec<u8>, Vec<u8>)> = Merge::new(vec![newer, older], &[0], None)
+            .map(|kv| kv.unwrap())
+            .collect();
+
+        // the tombstone is returned, but the range-deleted pair isn't
+        assert_eq!(
+            result,
+            vec![
+                (vec![0], data_util::encode_value(b"old0", None)),
+                (vec![1], Vec::new()),
+                (vec![5], data_util::encode_value(b"new5", None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let broken: Vec<KvResult> = vec![
+            Ok((vec![0], data_util::encode_value(b"v0", None))),
+            Err(std::io::Error::other("broken")),
+        ];
+        let mut scan = Scan::new(
+            vec![SortedRun::new(
+                broken.into_iter(),
+                Vec::new(),
+                Source::Memtable,
+            )],
+            &[0],
+            None,
+            0,
+        );
+
+        assert_eq!(scan.next().unwrap().unwrap().0, vec![0]);
+        assert!(scan.next().unwrap().is_err());
+        assert!(scan.next().is_none());
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"user:"), Some(b"user;".to_vec()));
+        assert_eq!(prefix_end(&[0x00, 0x00]), Some(vec![0x00, 0x01]));
+        assert_eq!(prefix_end(&[0x01, 0xFF, 0xFF]), Some(vec![0x02]));
+        assert_eq!(prefix_end(&[0xFF, 0xFF]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
+}
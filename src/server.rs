@@ -0,0 +1,264 @@
+//! TCP front-end serving a KVS with a length-prefixed protocol
+//!
+//! A connection sends requests one by one, and each request gets its response before
+//! the next one is read. A field is its length (u32, little endian) and its bytes.
+//!
+//! Request: an operation byte and its fields
+//! - `PUT` (1): key, value
+//! - `GET` (2): key
+//! - `DELETE` (3): key
+//! - `SCAN` (4): start, end (exclusive)
+//!
+//! Response: a status byte and its fields
+//! - `OK` (0): the value for `GET`, and the records for `SCAN`
+//! - `NOT_FOUND` (1): no field, only for `GET`
+//! - `ERROR` (2): the message
+//!
+//! Records of `SCAN` are written in the same way as `KVS::scan_to_writer`, and they end
+//! with `END_OF_SCAN` as a key length. The connection is closed when reading a record
+//! fails after `OK` was written, and when the operation is unknown after `ERROR`.
+use log::{debug, warn};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use threadpool::ThreadPool;
+
+use crate::amphis_error::CrudError;
+use crate::fptree::KvPair;
+use crate::kvs::{Scan, KVS};
+
+pub const OP_PUT: u8 = 1;
+pub const OP_GET: u8 = 2;
+pub const OP_DELETE: u8 = 3;
+pub const OP_SCAN: u8 = 4;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_NOT_FOUND: u8 = 1;
+pub const STATUS_ERROR: u8 = 2;
+
+/// The key length ending records of `SCAN`
+pub const END_OF_SCAN: u32 = u32::MAX;
+
+/// Server handling each connection in a thread of the pool
+pub struct Server {
+    listener: TcpListener,
+    kvs: Arc<KVS>,
+    pool: ThreadPool,
+}
+
+impl Server {
+    /// Listen on `addr` to serve `kvs` with `num_threads` threads
+    /// At most `num_threads` connections are served at the same time, and others wait
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        kvs: Arc<KVS>,
+        num_threads: usize,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            kvs,
+            pool: ThreadPool::new(num_threads),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections until the listener fails
+    pub fn serve(&self) -> Result<(), std::io::Error> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let kvs = self.kvs.clone();
+            self.pool.execute(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = handle_connection(&kvs, stream) {
+                    warn!("The connection from {:?} failed: {}", peer, e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_connection(kvs: &KVS, stream: TcpStream) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let mut op = [0u8; 1];
+        match reader.read_exact(&mut op) {
+            Ok(()) => {}
+            // the client closed the connection
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let result = match op[0] {
+            OP_PUT => {
+                let key = read_field(&mut reader)?;
+                let value = read_field(&mut reader)?;
+                kvs.put(&key, &value).map(|_| Response::Ok)
+            }
+            OP_GET => {
+                let key = read_field(&mut reader)?;
+                kvs.get(&key).map(|value| match value {
+                    Some(value) => Response::Value(value),
+                    None => Response::NotFound,
+                })
+            }
+            OP_DELETE => {
+                let key = read_field(&mut reader)?;
+                kvs.delete(&key).map(|_| Response::Ok)
+            }
+            OP_SCAN => {
+                let start = read_field(&mut reader)?;
+                let end = read_field(&mut reader)?;
+                kvs.scan(&start, &end).map(Response::Records)
+            }
+            op => {
+                debug!("Close the connection for the unknown operation {}", op);
+                write_status(&mut writer, STATUS_ERROR)?;
+                write_field(&mut writer, format!("unknown operation {}", op).as_bytes())?;
+                return writer.flush();
+            }
+        };
+
+        match result {
+            Ok(Response::Ok) => write_status(&mut writer, STATUS_OK)?,
+            Ok(Response::Value(value)) => {
+                write_status(&mut writer, STATUS_OK)?;
+                write_field(&mut writer, &value)?;
+            }
+            Ok(Response::NotFound) => write_status(&mut writer, STATUS_NOT_FOUND)?,
+            Ok(Response::Records(scan)) => {
+                write_status(&mut writer, STATUS_OK)?;
+                for pair in scan {
+                    // the connection is closed since the response has been partially written
+                    let (key, value) = pair.map_err(|e| std::io::Error::other(e.to_string()))?;
+                    write_field(&mut writer, &key)?;
+                    write_field(&mut writer, &value)?;
+                }
+                writer.write_all(&END_OF_SCAN.to_le_bytes())?;
+            }
+            Err(e) => {
+                write_status(&mut writer, STATUS_ERROR)?;
+                write_field(&mut writer, e.to_string().as_bytes())?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+enum Response {
+    Ok,
+    Value(Vec<u8>),
+    NotFound,
+    Records(Scan),
+}
+
+fn write_status(w: &mut dyn Write, status: u8) -> Result<(), std::io::Error> {
+    w.write_all(&[status])
+}
+
+fn write_field(w: &mut dyn Write, field: &[u8]) -> Result<(), std::io::Error> {
+    w.write_all(&(field.len() as u32).to_le_bytes())?;
+    w.write_all(field)
+}
+
+fn read_len(r: &mut dyn Read) -> Result<u32, std::io::Error> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    Ok(u32::from_le_bytes(len))
+}
+
+fn read_field(r: &mut dyn Read) -> Result<Vec<u8>, std::io::Error> {
+    let len = read_len(r)?;
+    read_bytes(r, len)
+}
+
+/// Read exactly `len` bytes
+/// The buffer grows with the read bytes, so a broken length doesn't allocate at once
+fn read_bytes(r: &mut dyn Read, len: u32) -> Result<Vec<u8>, std::io::Error> {
+    let len = len as u64;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(bytes)
+}
+
+/// Client of `Server`
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, std::io::Error> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), CrudError> {
+        self.request(OP_PUT, &[key, value])?;
+        self.read_status().map(|_| ())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        self.request(OP_GET, &[key])?;
+        match self.read_status()? {
+            STATUS_NOT_FOUND => Ok(None),
+            _ => Ok(Some(read_field(&mut self.reader)?)),
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), CrudError> {
+        self.request(OP_DELETE, &[key])?;
+        self.read_status().map(|_| ())
+    }
+
+    /// Return key-value pairs in `[start, end)` in the key order
+    pub fn scan(&mut self, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, CrudError> {
+        self.request(OP_SCAN, &[start, end])?;
+        self.read_status()?;
+        let mut pairs = Vec::new();
+        loop {
+            let len = read_len(&mut self.reader)?;
+            if len == END_OF_SCAN {
+                return Ok(pairs);
+            }
+            let key = read_bytes(&mut self.reader, len)?;
+            let value = read_field(&mut self.reader)?;
+            pairs.push((key, value));
+        }
+    }
+
+    fn request(&mut self, op: u8, fields: &[&[u8]]) -> Result<(), std::io::Error> {
+        self.writer.write_all(&[op])?;
+        for field in fields {
+            write_field(&mut self.writer, field)?;
+        }
+        self.writer.flush()
+    }
+
+    /// Return the status, or the error sent by the server
+    fn read_status(&mut self) -> Result<u8, CrudError> {
+        let mut status = [0u8; 1];
+        self.reader.read_exact(&mut status)?;
+        match status[0] {
+            STATUS_ERROR => {
+                let message = read_field(&mut self.reader)?;
+                Err(CrudError::Remote(
+                    String::from_utf8_lossy(&message).into_owned(),
+                ))
+            }
+            status => Ok(status),
+        }
+    }
+}
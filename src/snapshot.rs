@@ -0,0 +1,73 @@
+use log::trace;
+use std::sync::Arc;
+
+use crate::amphis_error::CrudError;
+use crate::fptree_manager::FrozenFPTrees;
+use crate::scan::{self, Scan};
+use crate::sstable_manager::{SstableManager, TableInfo};
+use crate::util::data_util;
+
+/// A read-only view of `KVS` at the point when `KVS::snapshot` is called
+///
+/// Writes after that point are invisible. The snapshot pins the FPTree being
+/// flushed and the SSTables, so their files remain until it's dropped.
+pub struct Snapshot {
+    fptrees: FrozenFPTrees,
+    tables: Vec<Arc<TableInfo>>,
+    sstable_manager: Arc<SstableManager>,
+    now: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        fptrees: FrozenFPTrees,
+        tables: Vec<Arc<TableInfo>>,
+        sstable_manager: Arc<SstableManager>,
+    ) -> Self {
+        Snapshot {
+            fptrees,
+            tables,
+            sstable_manager,
+            now: data_util::current_millis(),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        trace!(
+            "Getting from K: {} in a snapshot",
+            String::from_utf8_lossy(key)
+        );
+
+        let result = match self.fptrees.get(key)? {
+            Some(r) => Some(r),
+            None => self
+                .sstable_manager
+                .get_from_tables(key, self.tables.iter(), None)?,
+        };
+
+        match result {
+            Some(v) => Ok(data_util::get_live_value(&v, self.now)?.map(|v| v.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    /// Return an iterator over key-value pairs in `[start, end)` in the key order
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<Scan, CrudError> {
+        self.scan_range(start, Some(end))
+    }
+
+    /// Return an iterator over key-value pairs whose keys start with `prefix`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan, CrudError> {
+        self.scan_range(prefix, scan::prefix_end(prefix).as_deref())
+    }
+
+    fn scan_range(&self, start: &[u8], end: Option<&[u8]>) -> Result<Scan, CrudError> {
+        let mut sources = self.fptrees.range(start, end)?;
+        sources.extend(
+            self.sstable_manager
+                .scan_tables(start, end, self.tables.iter())?,
+        );
+
+        Ok(Scan::new(sources, start, end, self.now))
+    }
+}
@@ -1,35 +1,124 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
-// TODO: parameterize
-const LEAST_OFFSET: usize = 1 << 18;
+pub const DEFAULT_INTERVAL: usize = 1 << 18;
 
 #[derive(Serialize, Deserialize)]
 pub struct SparseIndex {
     prev_offset: usize,
     index: BTreeMap<Vec<u8>, usize>,
+    // only for building the index, the table info keeps it
+    #[serde(skip)]
+    interval: usize,
 }
 
 impl SparseIndex {
-    pub fn new() -> Self {
+    /// Keys are sampled at least every `interval` bytes of the table
+    pub fn new(interval: usize) -> Self {
         SparseIndex {
             prev_offset: usize::MAX,
             index: BTreeMap::new(),
+            interval,
         }
     }
 
+    /// Keys have to be inserted in the order of their offsets
+    /// The first key is always indexed, so `get` of the minimum key returns its offset
     pub fn insert(&mut self, key: &[u8], offset: usize) {
-        if self.prev_offset == usize::MAX || offset - self.prev_offset >= LEAST_OFFSET {
+        debug_assert!(
+            self.index.is_empty() || offset >= self.prev_offset,
+            "the key at {} is inserted after the offset {}",
+            offset,
+            self.prev_offset
+        );
+        if self.index.is_empty() || offset - self.prev_offset >= self.interval {
             self.prev_offset = offset;
             self.index.insert(key.to_owned(), offset);
         }
     }
 
-    // the offset should be always returned since the minimum key is inserted
+    /// Indexed keys in the key order
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = &[u8]> {
+        self.index.keys().map(|key| key.as_slice())
+    }
+
+    /// Return the indexed offset at or before the key
+    /// The head of the table is returned for a key smaller than the minimum key
     pub fn get(&self, key: &[u8]) -> usize {
         match self.index.get(key) {
             Some(offset) => *offset,
-            None => *self.index.range(..key.to_vec()).last().unwrap().1,
+            None => self
+                .index
+                .range(..key.to_vec())
+                .next_back()
+                .map_or(0, |(_, offset)| *offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_KEYS: usize = 2000;
+    const DATA_SIZE: usize = 100;
+
+    fn make_index(interval: usize) -> SparseIndex {
+        let mut index = SparseIndex::new(interval);
+        for i in 0..NUM_KEYS {
+            index.insert(format!("k{:05}", i).as_bytes(), i * DATA_SIZE);
+        }
+
+        index
+    }
+
+    // the bytes to be read from the indexed offset to reach each key
+    fn scanned_bytes(index: &SparseIndex) -> usize {
+        (0..NUM_KEYS)
+            .map(|i| i * DATA_SIZE - index.get(format!("k{:05}", i).as_bytes()))
+            .sum()
+    }
+
+    #[test]
+    fn test_get() {
+        let index = make_index(DATA_SIZE * 10);
+        assert_eq!(index.get(b"k00000"), 0);
+        assert_eq!(index.get(b"k00015"), DATA_SIZE * 10);
+        assert_eq!(index.get(b"k00020"), DATA_SIZE * 20);
+        // a key smaller than the minimum key
+        assert_eq!(index.get(b"a"), 0);
+        assert_eq!(index.get(b""), 0);
+        // an empty table
+        assert_eq!(SparseIndex::new(DEFAULT_INTERVAL).get(b"k00000"), 0);
+    }
+
+    #[test]
+    fn test_first_key() {
+        for interval in [0, DATA_SIZE, DEFAULT_INTERVAL] {
+            let index = make_index(interval);
+            let (first_key, offset) = index.index.iter().next().expect("no indexed key");
+            assert_eq!(first_key.as_slice(), b"k00000");
+            assert_eq!(*offset, 0);
+            assert_eq!(index.get(b"k00000"), 0);
         }
+
+        // the first key is indexed even if it isn't at the head
+        let mut index = SparseIndex::new(DEFAULT_INTERVAL);
+        index.insert(b"k1", 300);
+        index.insert(b"k2", 400);
+        assert_eq!(index.get(b"k1"), 300);
+        assert_eq!(index.get(b"k2"), 300);
+    }
+
+    #[test]
+    fn test_interval() {
+        let default_index = make_index(DEFAULT_INTERVAL);
+        // the table is smaller than the default interval
+        assert_eq!(default_index.index.len(), 1);
+
+        let small_index = make_index(4096);
+        // a key every 41 keys (4100 bytes)
+        assert_eq!(small_index.index.len(), NUM_KEYS.div_ceil(41));
+        assert!(scanned_bytes(&small_index) * 10 < scanned_bytes(&default_index));
     }
 }
@@ -0,0 +1,403 @@
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::amphis_error::CorruptionError;
+use crate::config::{Checksum, Compression};
+
+/*
+ * Block-based table format (TABLE_FORMAT_BLOCK, TABLE_FORMAT_COMPRESSED_BLOCK and
+ * TABLE_FORMAT_CRC32C_BLOCK):
+ * | Data block | ... | Data block | Filter block | Index block | Footer |
+ * Each block is formatted by `format_bytes_with_crc`.
+ * The filter block and the index block are the serialized bloom filter and sparse index.
+ *
+ * Data block:
+ * | Key size (4B) | Key | Value size (4B) | Value | ... | Entry offset (4B) | ... | Entry count (4B) |
+ * With TABLE_FORMAT_COMPRESSED_BLOCK, each data block is stored as:
+ * | Codec ID (1B) | Compressed data block |
+ * TABLE_FORMAT_CRC32C_BLOCK is TABLE_FORMAT_COMPRESSED_BLOCK whose CRCs are CRC-32C.
+ *
+ * Footer:
+ * | Checksum (4B) | Magic (8B) | Table format (1B) | Filter block offset (8B) | Index block offset (8B) |
+ * The checksum is the CRC of all bytes before the footer.
+ * A footer written before the checksum was introduced has FOOTER_MAGIC_V1 and no checksum.
+ *
+ * The flat format (TABLE_FORMAT_FLAT) is a sequence of `format_data_with_crc`.
+ */
+
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+pub const TABLE_FORMAT_FLAT: u8 = 0;
+pub const TABLE_FORMAT_BLOCK: u8 = 1;
+pub const TABLE_FORMAT_COMPRESSED_BLOCK: u8 = 2;
+pub const TABLE_FORMAT_CRC32C_BLOCK: u8 = 3;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+const FOOTER_MAGIC: u64 = 0x414d_5048_4953_5443;
+const FOOTER_MAGIC_V1: u64 = 0x414d_5048_4953_5442;
+const LEN_CHECKSUM: usize = 4;
+const LEGACY_FOOTER_SIZE: usize = 8 + 1 + 8 + 8;
+pub const FOOTER_SIZE: usize = LEN_CHECKSUM + LEGACY_FOOTER_SIZE;
+const LEN_U32: usize = 4;
+
+/// Builder of a data block
+#[derive(Default)]
+pub struct BlockBuilder {
+    data: Vec<u8>,
+    offsets: Vec<u32>,
+}
+
+impl BlockBuilder {
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        self.offsets.push(self.data.len() as u32);
+        self.data.extend(&(key.len() as u32).to_le_bytes());
+        self.data.extend(key);
+        self.data.extend(&(value.len() as u32).to_le_bytes());
+        self.data.extend(value);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// The size of the encoded block
+    pub fn size(&self) -> usize {
+        self.data.len() + LEN_U32 * (self.offsets.len() + 1)
+    }
+
+    /// Return the encoded block and clear the builder
+    pub fn take(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.data);
+        let count = self.offsets.len() as u32;
+        for offset in self.offsets.drain(..) {
+            block.extend(&offset.to_le_bytes());
+        }
+        block.extend(&count.to_le_bytes());
+
+        block
+    }
+}
+
+/// Decoded data block
+pub struct Block {
+    data: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl Block {
+    pub fn decode(mut bytes: Vec<u8>) -> Result<Self, std::io::Error> {
+        let invalid = || std::io::Error::from(CorruptionError("invalid data block".to_owned()));
+        let count_offset = bytes.len().checked_sub(LEN_U32).ok_or_else(invalid)?;
+        let count = read_u32(&bytes, count_offset) as usize;
+        let data_size = count
+            .checked_mul(LEN_U32)
+            .and_then(|size| count_offset.checked_sub(size))
+            .ok_or_else(invalid)?;
+        let offsets: Vec<usize> = (0..count)
+            .map(|i| read_u32(&bytes, data_size + i * LEN_U32) as usize)
+            .collect();
+        bytes.truncate(data_size);
+        let block = Block {
+            data: bytes,
+            offsets,
+        };
+        // check all entries not to panic when reading them
+        for i in 0..count {
+            block.try_entry(i).ok_or_else(invalid)?;
+        }
+
+        Ok(block)
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// The size of the decoded block in memory
+    pub fn size(&self) -> usize {
+        self.data.len() + self.offsets.len() * std::mem::size_of::<usize>()
+    }
+
+    /// Return the key and the value of the i-th entry
+    pub fn entry(&self, i: usize) -> (&[u8], &[u8]) {
+        self.try_entry(i).expect("the entry was checked")
+    }
+
+    fn try_entry(&self, i: usize) -> Option<(&[u8], &[u8])> {
+        let mut offset = *self.offsets.get(i)?;
+        let mut read_bytes = || {
+            let size =
+                u32::from_le_bytes(self.data.get(offset..offset + LEN_U32)?.try_into().unwrap())
+                    as usize;
+            let bytes = self.data.get(offset + LEN_U32..offset + LEN_U32 + size)?;
+            offset += LEN_U32 + size;
+            Some(bytes)
+        };
+        let key = read_bytes()?;
+        let value = read_bytes()?;
+
+        Some((key, value))
+    }
+
+    /// Find the value of the key by binary search
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let (cur_key, value) = self.entry(mid);
+            match cur_key.cmp(key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(value),
+            }
+        }
+
+        None
+    }
+}
+
+/// Compress the data block and prepend the codec ID
+pub fn compress(block: &[u8], compression: Compression) -> Result<Vec<u8>, std::io::Error> {
+    let (codec, compressed) = match compression {
+        Compression::None => (CODEC_NONE, block.to_vec()),
+        Compression::Lz4 => (CODEC_LZ4, lz4_flex::compress_prepend_size(block)),
+        Compression::Zstd => (
+            CODEC_ZSTD,
+            zstd::bulk::compress(block, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+        ),
+    };
+    let mut bytes = Vec::with_capacity(compressed.len() + 1);
+    bytes.push(codec);
+    bytes.extend(compressed);
+
+    Ok(bytes)
+}
+
+/// Decompress the data block with the codec of its ID
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let invalid = |e: String| std::io::Error::from(CorruptionError(e));
+    match bytes.split_first() {
+        Some((&CODEC_NONE, block)) => Ok(block.to_vec()),
+        Some((&CODEC_LZ4, compressed)) => {
+            lz4_flex::decompress_size_prepended(compressed).map_err(|e| invalid(e.to_string()))
+        }
+        Some((&CODEC_ZSTD, compressed)) => {
+            zstd::decode_all(compressed).map_err(|e| invalid(e.to_string()))
+        }
+        Some((codec, _)) => Err(invalid(format!("unsupported codec: {}", codec))),
+        None => Err(invalid("no codec ID".to_owned())),
+    }
+}
+
+/// Return the table format of new tables whose CRCs are computed by the algorithm
+pub fn get_table_format(checksum: Checksum) -> u8 {
+    match checksum {
+        Checksum::Crc32 => TABLE_FORMAT_COMPRESSED_BLOCK,
+        Checksum::Crc32c => TABLE_FORMAT_CRC32C_BLOCK,
+    }
+}
+
+/// Return the checksum algorithm of blocks and the footer in the table format
+pub fn get_checksum(table_format: u8) -> Checksum {
+    match table_format {
+        TABLE_FORMAT_CRC32C_BLOCK => Checksum::Crc32c,
+        _ => Checksum::Crc32,
+    }
+}
+
+/// Whether data blocks in the table format have codec IDs
+pub fn is_compressed(table_format: u8) -> bool {
+    table_format == TABLE_FORMAT_COMPRESSED_BLOCK || table_format == TABLE_FORMAT_CRC32C_BLOCK
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + LEN_U32].try_into().unwrap())
+}
+
+pub struct Footer {
+    pub table_format: u8,
+    pub filter_offset: usize,
+    pub index_offset: usize,
+    /// The CRC of all bytes before the footer by the algorithm of the table format
+    /// `None` for a table written before the checksum was introduced
+    pub checksum: Option<u32>,
+}
+
+impl Footer {
+    /// Encode the footer in the layout without the checksum when it's `None`
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FOOTER_SIZE);
+        match self.checksum {
+            Some(checksum) => {
+                bytes.extend(&checksum.to_le_bytes());
+                bytes.extend(&FOOTER_MAGIC.to_le_bytes());
+            }
+            None => bytes.extend(&FOOTER_MAGIC_V1.to_le_bytes()),
+        }
+        bytes.push(self.table_format);
+        bytes.extend(&(self.filter_offset as u64).to_le_bytes());
+        bytes.extend(&(self.index_offset as u64).to_le_bytes());
+
+        bytes
+    }
+
+    /// The size of the encoded footer
+    pub fn size(&self) -> usize {
+        match self.checksum {
+            Some(_) => FOOTER_SIZE,
+            None => LEGACY_FOOTER_SIZE,
+        }
+    }
+
+    /// Read the footer at the end of the file
+    pub fn read(file: &mut (impl Read + Seek)) -> Result<Self, std::io::Error> {
+        let file_size = file.seek(SeekFrom::End(0))? as usize;
+        let mut bytes = vec![0u8; FOOTER_SIZE.min(file_size)];
+        file.seek(SeekFrom::Start((file_size - bytes.len()) as u64))?;
+        file.read_exact(&mut bytes)?;
+
+        Self::decode(&bytes)
+    }
+
+    /// Decode the footer at the end of `bytes`
+    pub fn decode(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        let not_found = || CorruptionError("the table footer was not found".to_owned()).into();
+        // the magic is at the same position from the end in both layouts
+        let start = bytes
+            .len()
+            .checked_sub(LEGACY_FOOTER_SIZE)
+            .ok_or_else(not_found)?;
+        let (checksum, bytes) = bytes.split_at(start);
+        let checksum = match u64::from_le_bytes(bytes[0..8].try_into().unwrap()) {
+            FOOTER_MAGIC if checksum.len() >= LEN_CHECKSUM => Some(read_u32(checksum, 0)),
+            FOOTER_MAGIC_V1 => None,
+            _ => return Err(not_found()),
+        };
+        if bytes[8] != TABLE_FORMAT_BLOCK && !is_compressed(bytes[8]) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported table format: {}", bytes[8]),
+            ));
+        }
+
+        Ok(Footer {
+            table_format: bytes[8],
+            filter_offset: u64::from_le_bytes(bytes[9..17].try_into().unwrap()) as usize,
+            index_offset: u64::from_le_bytes(bytes[17..25].try_into().unwrap()) as usize,
+            checksum,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block() {
+        let mut builder = BlockBuilder::default();
+        for i in (0..100u8).step_by(2) {
+            builder.add(&[i], &[i; 3]);
+        }
+        builder.add(&[100], &[]);
+        let size = builder.size();
+        let bytes = builder.take();
+        assert_eq!(bytes.len(), size);
+        assert!(builder.is_empty());
+
+        let block = Block::decode(bytes).unwrap();
+        assert_eq!(block.len(), 51);
+        assert_eq!(block.entry(1), (&[2u8][..], &[2u8; 3][..]));
+        assert_eq!(block.get(&[0]), Some(&[0u8; 3][..]));
+        assert_eq!(block.get(&[98]), Some(&[98u8; 3][..]));
+        assert_eq!(block.get(&[100]), Some(&[][..]));
+        assert_eq!(block.get(&[1]), None);
+        assert_eq!(block.get(&[101]), None);
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        let mut builder = BlockBuilder::default();
+        builder.add(b"key", b"value");
+        let mut bytes = builder.take();
+        // a broken key size
+        bytes[0] = 0xFF;
+        assert!(Block::decode(bytes).is_err());
+        assert!(Block::decode(vec![1, 0]).is_err());
+        assert!(Block::decode(vec![0xFF, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut builder = BlockBuilder::default();
+        for i in 0..100u32 {
+            let value = format!("text-heavy value of the key {} repeated", i % 3);
+            builder.add(&i.to_be_bytes(), value.as_bytes());
+        }
+        let block = builder.take();
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let compressed = compress(&block, compression).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < block.len() / 2);
+            }
+            assert_eq!(decompress(&compressed).unwrap(), block);
+        }
+
+        assert!(decompress(&[]).is_err());
+        assert!(decompress(&[0xFF, 0]).is_err());
+        assert!(decompress(&[CODEC_LZ4, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_footer() {
+        let footer = Footer {
+            table_format: TABLE_FORMAT_COMPRESSED_BLOCK,
+            filter_offset: 4096,
+            index_offset: 8192,
+            checksum: Some(0x1234_5678),
+        };
+        let bytes = footer.encode();
+        assert_eq!(bytes.len(), FOOTER_SIZE);
+        assert_eq!(footer.size(), FOOTER_SIZE);
+        let decoded = Footer::decode(&bytes).unwrap();
+        assert_eq!(decoded.table_format, TABLE_FORMAT_COMPRESSED_BLOCK);
+        assert_eq!(decoded.filter_offset, 4096);
+        assert_eq!(decoded.index_offset, 8192);
+        assert_eq!(decoded.checksum, Some(0x1234_5678));
+
+        let mut broken = bytes.clone();
+        broken[LEN_CHECKSUM] = 0;
+        assert!(Footer::decode(&broken).is_err());
+        let mut unknown = bytes.clone();
+        unknown[LEN_CHECKSUM + 8] = 0xFF;
+        assert!(Footer::decode(&unknown).is_err());
+        // a truncated footer
+        assert!(Footer::decode(&bytes[LEN_CHECKSUM + 1..]).is_err());
+    }
+
+    #[test]
+    fn test_footer_without_checksum() {
+        let footer = Footer {
+            table_format: TABLE_FORMAT_BLOCK,
+            filter_offset: 4096,
+            index_offset: 8192,
+            checksum: None,
+        };
+        let bytes = footer.encode();
+        assert_eq!(bytes.len(), footer.size());
+        assert!(footer.size() < FOOTER_SIZE);
+
+        // the footer is read from the end of the file with the last bytes of data
+        let mut file = vec![0xFF; 100];
+        file.extend(bytes);
+        let decoded = Footer::read(&mut std::io::Cursor::new(file)).unwrap();
+        assert_eq!(decoded.table_format, TABLE_FORMAT_BLOCK);
+        assert_eq!(decoded.index_offset, 8192);
+        assert_eq!(decoded.checksum, None);
+    }
+}
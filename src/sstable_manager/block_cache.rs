@@ -0,0 +1,128 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use super::block::Block;
+use super::TableId;
+use crate::util::lock_util::MutexExt;
+
+pub const DEFAULT_BLOCK_CACHE_BYTES: usize = 8 * 1024 * 1024;
+
+/// The table ID and the offset of a data block
+type BlockKey = (TableId, usize);
+
+/// LRU cache of decoded data blocks evicted by the total size
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// Cached blocks with their last access
+    blocks: HashMap<BlockKey, (Arc<Block>, u64)>,
+    /// Keys from the least recently used one
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    size: usize,
+}
+
+impl BlockCache {
+    /// `capacity` is the total size of cached blocks in bytes
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    pub fn get(&self, table_id: TableId, offset: usize) -> Option<Arc<Block>> {
+        let mut inner = self.inner.lock_or_recover();
+        let tick = inner.next_tick();
+        let (block, last_access) = inner.blocks.get_mut(&(table_id, offset))?;
+        let block = block.clone();
+        let prev = std::mem::replace(last_access, tick);
+        inner.lru.remove(&prev);
+        inner.lru.insert(tick, (table_id, offset));
+
+        Some(block)
+    }
+
+    /// Cache the block and evict the least recently used blocks over the capacity
+    /// A block larger than the capacity isn't cached
+    pub fn insert(&self, table_id: TableId, offset: usize, block: Arc<Block>) {
+        let size = block.size();
+        if size > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock_or_recover();
+        let tick = inner.next_tick();
+        if let Some((old, last_access)) = inner.blocks.insert((table_id, offset), (block, tick)) {
+            inner.lru.remove(&last_access);
+            inner.size -= old.size();
+        }
+        inner.lru.insert(tick, (table_id, offset));
+        inner.size += size;
+
+        while inner.size > self.capacity {
+            let (_, key) = inner.lru.pop_first().expect("no cached block");
+            let (evicted, _) = inner.blocks.remove(&key).expect("no cached block");
+            inner.size -= evicted.size();
+        }
+    }
+
+    #[cfg(test)]
+    fn size(&self) -> usize {
+        self.inner.lock_or_recover().size
+    }
+}
+
+impl CacheInner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable_manager::block::BlockBuilder;
+
+    fn make_block(key: u8) -> Arc<Block> {
+        let mut builder = BlockBuilder::default();
+        builder.add(&[key], &[key; 100]);
+        Arc::new(Block::decode(builder.take()).unwrap())
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let block_size = make_block(0).size();
+        let cache = BlockCache::new(block_size * 3);
+        for i in 0..3 {
+            cache.insert(0, i, make_block(i as u8));
+        }
+        assert_eq!(cache.size(), block_size * 3);
+
+        // the block at 1 becomes the least recently used one
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(1, 0).is_none());
+        cache.insert(0, 3, make_block(3));
+        assert!(cache.get(0, 1).is_none());
+        assert_eq!(cache.get(0, 0).unwrap().entry(0).0, &[0]);
+        assert!(cache.get(0, 2).is_some());
+        assert!(cache.get(0, 3).is_some());
+        assert_eq!(cache.size(), block_size * 3);
+
+        // replace the cached block
+        cache.insert(0, 3, make_block(4));
+        assert_eq!(cache.get(0, 3).unwrap().entry(0).0, &[4]);
+        assert_eq!(cache.size(), block_size * 3);
+
+        // no block is cached with no capacity
+        let cache = BlockCache::new(0);
+        cache.insert(0, 0, make_block(0));
+        assert!(cache.get(0, 0).is_none());
+        assert_eq!(cache.size(), 0);
+    }
+}
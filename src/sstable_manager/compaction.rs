@@ -0,0 +1,818 @@
+use log::debug;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, PoisonError};
+use std::time::Instant;
+
+use super::{SstableManager, TableId, TableInfo, TableWriter};
+use crate::amphis_error::CrudError;
+use crate::compaction_filter::FilterDecision;
+use crate::range_tombstone::RangeTombstone;
+use crate::scan::{Merge, SortedRun, Source};
+use crate::util::data_util;
+use crate::util::lock_util::{MutexExt, RwLockExt};
+
+/// Tables merged into `output_level`
+struct CompactionTask {
+    output_level: usize,
+    /// Input tables from the newest one
+    inputs: Vec<Arc<TableInfo>>,
+    /// No table exists in levels deeper than `output_level`
+    is_bottom: bool,
+}
+
+impl SstableManager {
+    /// Merge tables until Level 0 has fewer tables than the trigger and each
+    /// deeper level is within its size limit
+    /// All tables are merged when the space amplification exceeds `max_space_amplification`
+    /// Return whether the compaction filter dropped any pair
+    pub fn compact(&self) -> Result<bool, CrudError> {
+        let _guard = self.compaction_lock.lock_or_recover();
+        let mut filtered = false;
+        while let Some(task) = self.pick_compaction() {
+            filtered |= self.run_compaction(task)?;
+        }
+        if let Some(max) = self.config.get_max_space_amplification() {
+            let space_amplification = self.space_amplification();
+            if space_amplification > max {
+                debug!(
+                    "Compact all tables of {} for the space amplification {:.2}",
+                    self.name, space_amplification
+                );
+                if let Some(task) = self.full_compaction() {
+                    filtered |= self.run_compaction(task)?;
+                }
+            }
+        }
+
+        Ok(filtered)
+    }
+
+    /// Merge all tables into a sorted run in the deepest level
+    /// Overwritten values and tombstones are dropped
+    /// Return whether the compaction filter dropped any pair
+    pub fn compact_all(&self) -> Result<bool, CrudError> {
+        let _guard = self.compaction_lock.lock_or_recover();
+        match self.full_compaction() {
+            Some(task) => self.run_compaction(task),
+            None => Ok(false),
+        }
+    }
+
+    /// The task merging all tables, or `None` when no table exists
+    fn full_compaction(&self) -> Option<CompactionTask> {
+        let tables = self.tables.read_or_recover();
+        let inputs: Vec<Arc<TableInfo>> = tables
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values().rev().cloned())
+            .collect();
+        if inputs.is_empty() {
+            return None;
+        }
+
+        Some(CompactionTask {
+            output_level: tables.len().saturating_sub(1).max(1),
+            inputs,
+            is_bottom: true,
+        })
+    }
+
+    /// Block a write while Level 0 has `max_l0_tables` or more tables
+    /// Return `CrudError::WriteStall` if compactions don't reduce them within the timeout
+    pub fn wait_for_l0_compaction(&self) -> Result<(), CrudError> {
+        let Some(max_l0_tables) = self.config.get_max_l0_tables() else {
+            return Ok(());
+        };
+        let l0_tables = || {
+            self.tables
+                .read_or_recover()
+                .first()
+                .map_or(0, |l0| l0.len())
+        };
+        if l0_tables() < max_l0_tables {
+            return Ok(());
+        }
+
+        debug!(
+            "Writes to {} stall since Level 0 has {} tables",
+            self.name,
+            l0_tables()
+        );
+        self.write_stalls.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        // the predicate is checked with the lock which the compaction takes to notify
+        let guard = self.stall_lock.lock_or_recover();
+        let (_guard, result) = self
+            .l0_compacted
+            .wait_timeout_while(guard, self.config.get_write_stall_timeout(), |_| {
+                l0_tables() >= max_l0_tables
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        self.write_stall_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if result.timed_out() {
+            return Err(CrudError::WriteStall(l0_tables()));
+        }
+
+        Ok(())
+    }
+
+    fn pick_compaction(&self) -> Option<CompactionTask> {
+        let tables = self.tables.read_or_recover();
+        let (level, mut inputs): (usize, Vec<Arc<TableInfo>>) = if tables
+            .first()
+            .is_some_and(|l0| l0.len() >= self.config.get_l0_compaction_trigger())
+        {
+            // Level 0 tables might overlap each other
+            (0, tables[0].values().rev().cloned().collect())
+        } else {
+            let level = (1..tables.len()).find(|level| {
+                let size: usize = tables[*level].values().map(|t| t.size).sum();
+                size > self.config.get_level_max_bytes(*level)
+            })?;
+            // the oldest table is pushed down first
+            (level, vec![tables[level].values().next()?.clone()])
+        };
+
+        let output_level = level + 1;
+        let key_range = inputs
+            .iter()
+            .filter_map(|t| t.key_range.clone())
+            .reduce(|(s1, l1), (s2, l2)| (s1.min(s2), l1.max(l2)));
+        if let (Some((smallest, largest)), Some(output_tables)) =
+            (key_range, tables.get(output_level))
+        {
+            inputs.extend(
+                output_tables
+                    .values()
+                    .filter(|t| t.overlaps(&smallest, &largest))
+                    .cloned(),
+            );
+        }
+        let is_bottom = tables
+            .iter()
+            .skip(output_level + 1)
+            .all(|leveled_tables| leveled_tables.is_empty());
+
+        Some(CompactionTask {
+            output_level,
+            inputs,
+            is_bottom,
+        })
+    }
+
+    /// Return whether the compaction filter dropped any pair
+    fn run_compaction(&self, task: CompactionTask) -> Result<bool, CrudError> {
+        debug!(
+            "Compact tables {:?} of {} into Level {}",
+            task.inputs.iter().map(|t| t.id).collect::<Vec<_>>(),
+            self.name,
+            task.output_level
+        );
+        // range tombstones are unnecessary when no older table can be hidden
+        let mut range_tombstones: Vec<RangeTombstone> = Vec::new();
+        if !task.is_bottom {
+            for range_tombstone in task.inputs.iter().flat_map(|t| t.range_tombstones.iter()) {
+                if !range_tombstones.contains(range_tombstone) {
+                    range_tombstones.push(range_tombstone.clone());
+                }
+            }
+        }
+        let clip = |lower: Option<&[u8]>, upper: Option<&[u8]>| -> Vec<RangeTombstone> {
+            range_tombstones
+                .iter()
+                .filter_map(|r| r.clip(lower, upper))
+                .collect()
+        };
+        // each output has about its share of the input entries by the target size
+        let entry_count = task.inputs.iter().map(|t| t.entry_count).sum::<usize>();
+        let input_bytes = task.inputs.iter().map(|t| t.size).sum::<usize>().max(1);
+        let items_count = entry_count
+            .min(self.config.get_target_table_bytes() * entry_count / input_bytes + 1)
+            .max(1);
+
+        let now = data_util::current_millis();
+        let sources = task
+            .inputs
+            .iter()
+            .map(|t| {
+                Ok(SortedRun::new(
+                    self.table_iter(t.id)?,
+                    t.range_tombstones.clone(),
+                    Source::Sstable {
+                        id: t.id,
+                        level: t.level,
+                    },
+                ))
+            })
+            .collect::<Result<Vec<_>, CrudError>>()?;
+        // outputs cover the sequences of the inputs even if their values are dropped
+        let max_sequence = task
+            .inputs
+            .iter()
+            .map(|t| t.max_sequence)
+            .max()
+            .unwrap_or(0);
+        let mut discarded_sequence = task
+            .inputs
+            .iter()
+            .map(|t| {
+                if task.is_bottom && !t.range_tombstones.is_empty() {
+                    // the range tombstones and the keys hidden by them are dropped
+                    t.max_sequence.max(t.discarded_sequence)
+                } else {
+                    t.discarded_sequence
+                }
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut filtered = false;
+        let mut outputs = Vec::new();
+        let mut writer: Option<TableWriter> = None;
+        // the smallest key of the current output
+        let mut lower: Option<Vec<u8>> = None;
+        for kv in Merge::new(sources, &[], None) {
+            let (key, mut value) = kv?;
+            if !data_util::is_tombstone(&value) && data_util::get_live_value(&value, now)?.is_none()
+            {
+                // keep the expired key as a tombstone to hide older values
+                value = data_util::to_tombstone(&value)?;
+            }
+            if let (Some(filter), Some(live_value)) = (
+                self.config.get_compaction_filter(),
+                data_util::get_live_value(&value, now)?,
+            ) {
+                if filter.decide(&key, live_value, task.output_level) == FilterDecision::Drop {
+                    value = data_util::to_tombstone(&value)?;
+                    filtered = true;
+                }
+            }
+            if data_util::is_tombstone(&value) && task.is_bottom {
+                // reads at older sequences can't see the versions deleted by the tombstone
+                discarded_sequence = discarded_sequence.max(data_util::get_sequence(&value)?.0);
+                continue;
+            }
+
+            if writer
+                .as_ref()
+                .is_some_and(|w| w.size() >= self.config.get_target_table_bytes())
+            {
+                // each output has range tombstones only in its own range
+                let table_info = writer
+                    .take()
+                    .unwrap()
+                    .finish(clip(lower.as_deref(), Some(&key)))?;
+                outputs.push(table_info);
+                lower = Some(key.clone());
+            }
+            let writer = match writer.as_mut() {
+                Some(writer) => writer,
+                None => writer.insert(self.create_table_writer(task.output_level, items_count)?),
+            };
+            writer.add(&key, &value)?;
+        }
+        let last_range_tombstones = clip(lower.as_deref(), None);
+        // an empty table keeps the sequences when all pairs are dropped
+        if writer.is_none()
+            && (!last_range_tombstones.is_empty()
+                || (outputs.is_empty() && max_sequence.max(discarded_sequence) > 0))
+        {
+            writer = Some(self.create_table_writer(task.output_level, items_count)?);
+        }
+        if let Some(writer) = writer {
+            outputs.push(writer.finish(last_range_tombstones)?);
+        }
+        for output in outputs.iter_mut() {
+            output.max_sequence = max_sequence;
+            output.discarded_sequence = discarded_sequence;
+        }
+
+        self.install_compaction(&task, outputs)?;
+
+        Ok(filtered)
+    }
+
+    fn create_table_writer(
+        &self,
+        level: usize,
+        items_count: usize,
+    ) -> Result<TableWriter, CrudError> {
+        let mut next_compaction_id = self.next_compaction_id.lock_or_recover();
+        let id = *next_compaction_id;
+        let writer = TableWriter::new(
+            id,
+            &self.config.get_table_file_path(&self.name, id),
+            level,
+            items_count,
+            &self.config,
+        )?;
+
+        // even ID used by flushes
+        *next_compaction_id += 2;
+
+        Ok(writer)
+    }
+
+    /// Replace the input tables with the outputs
+    ///
+    /// The rewritten metadata without the inputs records that they are
+    /// obsolete, and then the files are removed when the last readers release
+    /// them. Files left by a crash between the steps aren't in the metadata,
+    /// so they are removed on the next startup.
+    fn install_compaction(
+        &self,
+        task: &CompactionTask,
+        outputs: Vec<TableInfo>,
+    ) -> Result<(), CrudError> {
+        let mut tables = self.tables.write_or_recover();
+        for input in &task.inputs {
+            tables[input.level].remove(&input.id);
+        }
+        while tables.len() <= task.output_level {
+            tables.push(BTreeMap::new());
+        }
+        let output_ids: Vec<TableId> = outputs.iter().map(|t| t.id).collect();
+        for output in outputs {
+            tables[task.output_level].insert(output.id, Arc::new(output));
+        }
+        self.rewrite_table_info(&tables)?;
+
+        // readers might still read the inputs
+        for input in &task.inputs {
+            input.set_obsolete(
+                self.config.get_table_file_path(&self.name, input.id),
+                self.config.get_storage().clone(),
+            );
+        }
+        drop(tables);
+        debug!("Compaction of {} has finished", self.name);
+        if task.inputs.iter().any(|input| input.level == 0) {
+            let _guard = self.stall_lock.lock_or_recover();
+            self.l0_compacted.notify_all();
+        }
+
+        let input_ids: Vec<TableId> = task.inputs.iter().map(|t| t.id).collect();
+        for listener in self.config.get_listeners() {
+            listener.on_compaction(&input_ids, &output_ids);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction_filter::CompactionFilter;
+    use crate::config::Config;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn new_manager(config: Config) -> SstableManager {
+        SstableManager::new("test", config)
+            .expect("cannot create the manager")
+            .0
+    }
+
+    fn flush(
+        manager: &SstableManager,
+        table_id: usize,
+        kv_pairs: &[(&[u8], Option<&[u8]>)],
+        range_tombstones: Vec<RangeTombstone>,
+    ) {
+        let table_info = write_table(manager, table_id, 0, kv_pairs, range_tombstones);
+        manager.register(table_info).expect("register failed");
+    }
+
+    fn write_table(
+        manager: &SstableManager,
+        table_id: usize,
+        level: usize,
+        kv_pairs: &[(&[u8], Option<&[u8]>)],
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> TableInfo {
+        let path = manager.config.get_table_file_path(&manager.name, table_id);
+        let mut writer = TableWriter::new(table_id, &path, level, 1024, &manager.config)
+            .expect("cannot create a table");
+        for (key, value) in kv_pairs {
+            let value = value.map_or(Vec::new(), |v| data_util::encode_value(v, None));
+            writer.add(key, &value).expect("write failed");
+        }
+        writer.finish(range_tombstones).expect("finish failed")
+    }
+
+    fn get(manager: &SstableManager, key: &[u8]) -> Option<Vec<u8>> {
+        manager.get(key).expect("get failed").and_then(|v| {
+            data_util::get_live_value(&v, 0)
+                .unwrap()
+                .map(|v| v.to_vec())
+        })
+    }
+
+    fn get_table_ids(manager: &SstableManager) -> Vec<Vec<usize>> {
+        manager
+            .tables
+            .read()
+            .unwrap()
+            .iter()
+            .map(|leveled_tables| leveled_tables.keys().cloned().collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_compact_level_zero() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(3)
+            .build();
+        let manager = new_manager(config.clone());
+        flush(
+            &manager,
+            0,
+            &[
+                (b"a", Some(b"a0")),
+                (b"b", Some(b"b0")),
+                (b"c", Some(b"c0")),
+            ],
+            Vec::new(),
+        );
+        flush(
+            &manager,
+            2,
+            &[(b"a", Some(b"a2")), (b"b", None)],
+            Vec::new(),
+        );
+        manager.compact().expect("compaction failed");
+        // not triggered yet
+        assert_eq!(get_table_ids(&manager), vec![vec![0, 2]]);
+
+        flush(&manager, 4, &[(b"d", Some(b"d4"))], Vec::new());
+        manager.compact().expect("compaction failed");
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1]]);
+
+        assert_eq!(get(&manager, b"a"), Some(b"a2".to_vec()));
+        assert_eq!(get(&manager, b"b"), None);
+        assert_eq!(get(&manager, b"c"), Some(b"c0".to_vec()));
+        assert_eq!(get(&manager, b"d"), Some(b"d4".to_vec()));
+        // the tombstone was dropped in the bottom level
+        let tables = manager.get_tables();
+        assert_eq!(tables[0].entry_count, 3);
+        assert_eq!(tables[0].key_range, Some((b"a".to_vec(), b"d".to_vec())));
+        drop(tables);
+
+        // the inputs have been removed
+        for id in [0, 2, 4] {
+            assert!(!Path::new(&config.get_table_file_path("test", id)).exists());
+        }
+
+        // recover the compacted state
+        drop(manager);
+        let manager = new_manager(config);
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1]]);
+        assert_eq!(get(&manager, b"a"), Some(b"a2".to_vec()));
+        assert_eq!(*manager.next_compaction_id.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_compact_all() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .build();
+        let manager = new_manager(config);
+        // nothing to compact
+        manager.compact_all().expect("compaction failed");
+        assert!(get_table_ids(&manager).is_empty());
+
+        flush(
+            &manager,
+            0,
+            &[(b"a", Some(b"a0")), (b"b", Some(b"b0"))],
+            Vec::new(),
+        );
+        flush(&manager, 2, &[(b"a", Some(b"a2"))], Vec::new());
+        manager.compact().expect("compaction failed");
+        flush(
+            &manager,
+            4,
+            &[(b"b", None), (b"c", Some(b"c4"))],
+            Vec::new(),
+        );
+        flush(
+            &manager,
+            6,
+            &[(b"d", Some(b"d6"))],
+            vec![RangeTombstone::new(b"c", b"d")],
+        );
+        manager.compact_all().expect("compaction failed");
+
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![3]]);
+        let tables = manager.get_tables();
+        assert_eq!(tables[0].entry_count, 2);
+        assert!(tables[0].range_tombstones.is_empty());
+        drop(tables);
+        assert_eq!(get(&manager, b"a"), Some(b"a2".to_vec()));
+        assert_eq!(get(&manager, b"b"), None);
+        assert_eq!(get(&manager, b"c"), None);
+        assert_eq!(get(&manager, b"d"), Some(b"d6".to_vec()));
+    }
+
+    #[test]
+    fn test_level_filter_params() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .bloom_level_fp_rates(&[0.001, 0.1])
+            .build();
+        let manager = new_manager(config.clone());
+        let keys: Vec<Vec<u8>> = (0..200u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        for (table_id, chunk) in (0..).step_by(2).zip(keys.chunks(100)) {
+            let kv_pairs: Vec<(&[u8], Option<&[u8]>)> = chunk
+                .iter()
+                .map(|k| (k.as_slice(), Some(k.as_slice())))
+                .collect();
+            flush(&manager, table_id, &kv_pairs, Vec::new());
+        }
+        let bits_per_item = |t: &TableInfo| {
+            let params = t.filter_params.expect("no filter params");
+            t.filter.number_of_bits() as f64 / params.items_count as f64
+        };
+        let l0_table = manager.get_tables()[0].clone();
+        let l0_params = l0_table.filter_params.unwrap();
+        assert_eq!(l0_params.fp_rate, 0.001);
+
+        manager.compact_all().expect("compaction failed");
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1]]);
+        let l1_table = manager.get_tables()[0].clone();
+        let l1_params = l1_table.filter_params.unwrap();
+        assert_eq!(l1_params.fp_rate, 0.1);
+        // the filter is made for the compacted entries
+        assert_eq!(l1_params.items_count, 200);
+        assert!(bits_per_item(&l0_table) > 2.0 * bits_per_item(&l1_table));
+
+        // the parameters are recovered with the table
+        drop((l0_table, l1_table, manager));
+        let manager = new_manager(config);
+        assert_eq!(manager.get_tables()[0].filter_params, Some(l1_params));
+    }
+
+    #[test]
+    fn test_metadata_size() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .build();
+        let manager = new_manager(config.clone());
+        let metadata_path = config.get_metadata_path("test");
+        let mut sizes = Vec::new();
+        for table_id in (0..40).step_by(2) {
+            flush(
+                &manager,
+                table_id,
+                &[(b"a", Some(b"value")), (b"b", Some(b"value"))],
+                Vec::new(),
+            );
+            manager.compact().expect("compaction failed");
+            sizes.push(std::fs::metadata(&metadata_path).unwrap().len());
+        }
+
+        // only the current tables are in the metadata
+        let max_size = *sizes[..4].iter().max().unwrap();
+        assert!(sizes.iter().all(|size| *size <= max_size));
+
+        let table_ids = get_table_ids(&manager);
+        drop(manager);
+        let manager = new_manager(config.clone());
+        assert_eq!(get_table_ids(&manager), table_ids);
+        for table_info in manager.get_tables() {
+            assert!(Path::new(&config.get_table_file_path("test", table_info.id)).exists());
+        }
+        assert_eq!(get(&manager, b"a"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_crash_before_install() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .build();
+        let manager = new_manager(config.clone());
+        flush(&manager, 0, &[(b"a", Some(b"a0"))], Vec::new());
+        flush(&manager, 2, &[(b"a", Some(b"a2"))], Vec::new());
+        // crash after an output was written
+        let mut writer = manager.create_table_writer(1, 1024).unwrap();
+        writer
+            .add(b"a", &data_util::encode_value(b"a2", None))
+            .unwrap();
+        writer.finish(Vec::new()).unwrap();
+        drop(manager);
+        assert!(Path::new(&config.get_table_file_path("test", 1)).exists());
+
+        // the output is discarded and the inputs are still valid
+        let manager = new_manager(config.clone());
+        assert!(!Path::new(&config.get_table_file_path("test", 1)).exists());
+        assert_eq!(get_table_ids(&manager), vec![vec![0, 2]]);
+        assert_eq!(get(&manager, b"a"), Some(b"a2".to_vec()));
+        // the ID of the discarded output isn't reused
+        assert_eq!(*manager.next_compaction_id.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_crash_before_removal() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .build();
+        let manager = new_manager(config.clone());
+        flush(&manager, 0, &[(b"a", Some(b"a0"))], Vec::new());
+        let input = manager.get_tables().pop().unwrap();
+        flush(&manager, 2, &[(b"a", Some(b"a2"))], Vec::new());
+        manager.compact().expect("compaction failed");
+        assert!(!Path::new(&config.get_table_file_path("test", 2)).exists());
+        // crash before the reader releases the input
+        std::mem::forget(input);
+        drop(manager);
+        assert!(Path::new(&config.get_table_file_path("test", 0)).exists());
+
+        // the obsolete file is removed and isn't loaded
+        let manager = new_manager(config.clone());
+        assert!(!Path::new(&config.get_table_file_path("test", 0)).exists());
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1]]);
+        assert_eq!(get(&manager, b"a"), Some(b"a2".to_vec()));
+    }
+
+    #[test]
+    fn test_cascade() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .level_base_bytes(100)
+            .target_table_bytes(60)
+            .build();
+        let manager = new_manager(config);
+        let value = [0u8; 16];
+        let mut table_id = 0;
+        for i in 0..8u8 {
+            let keys = [[i], [i + 8]];
+            let kv_pairs: Vec<(&[u8], Option<&[u8]>)> = keys
+                .iter()
+                .map(|k| (k.as_slice(), Some(value.as_slice())))
+                .collect();
+            flush(&manager, table_id, &kv_pairs, Vec::new());
+            table_id += 2;
+            manager.compact().expect("compaction failed");
+        }
+
+        let tables = manager.tables.read().unwrap();
+        assert!(tables.len() > 2);
+        for level in 1..tables.len() {
+            let size: usize = tables[level].values().map(|t| t.size).sum();
+            assert!(size <= manager.config.get_level_max_bytes(level));
+            // tables in the same level don't overlap
+            let ranges: Vec<_> = tables[level]
+                .values()
+                .filter_map(|t| t.key_range.clone())
+                .collect();
+            for (i, (smallest, largest)) in ranges.iter().enumerate() {
+                for (s, l) in &ranges[i + 1..] {
+                    assert!(largest < s || l < smallest);
+                }
+            }
+        }
+        drop(tables);
+
+        for i in 0..16u8 {
+            assert_eq!(get(&manager, &[i]), Some(value.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_keep_tombstones() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .build();
+        let manager = new_manager(config);
+        // an old table in Level 2
+        let table_info = write_table(
+            &manager,
+            101,
+            2,
+            &[(b"a", Some(b"a1")), (b"b", Some(b"b1"))],
+            Vec::new(),
+        );
+        manager.tables.write().unwrap().extend([
+            BTreeMap::new(),
+            BTreeMap::new(),
+            BTreeMap::from([(101, Arc::new(table_info))]),
+        ]);
+
+        flush(&manager, 4, &[(b"a", None)], Vec::new());
+        flush(
+            &manager,
+            6,
+            &[(b"c", Some(b"c6"))],
+            vec![RangeTombstone::new(b"b", b"c")],
+        );
+        manager.compact().expect("compaction failed");
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1], vec![101]]);
+
+        // the tombstone and the range tombstone still hide older values
+        let tables = manager.get_tables();
+        assert_eq!(tables[0].entry_count, 2);
+        assert_eq!(
+            tables[0].range_tombstones,
+            vec![RangeTombstone::new(b"b", b"c")]
+        );
+        drop(tables);
+        assert_eq!(get(&manager, b"a"), None);
+        assert_eq!(get(&manager, b"b"), None);
+        assert_eq!(get(&manager, b"c"), Some(b"c6".to_vec()));
+    }
+
+    struct PrefixFilter(&'static [u8]);
+
+    impl CompactionFilter for PrefixFilter {
+        fn decide(&self, key: &[u8], _value: &[u8], _level: usize) -> FilterDecision {
+            if key.starts_with(self.0) {
+                FilterDecision::Drop
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .compaction_filter(Arc::new(PrefixFilter(b"tmp/")))
+            .build();
+        let manager = new_manager(config);
+        // an old table in Level 2
+        let table_info = write_table(&manager, 101, 2, &[(b"tmp/a", Some(b"a1"))], Vec::new());
+        manager.tables.write().unwrap().extend([
+            BTreeMap::new(),
+            BTreeMap::new(),
+            BTreeMap::from([(101, Arc::new(table_info))]),
+        ]);
+
+        flush(&manager, 4, &[(b"b", Some(b"b4"))], Vec::new());
+        flush(
+            &manager,
+            6,
+            &[(b"c", Some(b"c6")), (b"tmp/a", Some(b"a6"))],
+            Vec::new(),
+        );
+        manager.compact().expect("compaction failed");
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1], vec![101]]);
+        // the dropped pair is kept as a tombstone to hide the old value
+        assert_eq!(manager.get_tables()[0].entry_count, 3);
+        assert_eq!(get(&manager, b"tmp/a"), None);
+        assert_eq!(get(&manager, b"b"), Some(b"b4".to_vec()));
+
+        // the bottom level doesn't have it
+        manager.compact_all().expect("compaction failed");
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![], vec![3]]);
+        assert_eq!(manager.get_tables()[0].entry_count, 2);
+        assert_eq!(get(&manager, b"tmp/a"), None);
+        assert_eq!(get(&manager, b"c"), Some(b"c6".to_vec()));
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let config = Config::builder_for_testing()
+            .l0_compaction_trigger(2)
+            .max_l0_tables(2)
+            .write_stall_timeout(Duration::from_millis(10))
+            .build();
+        let manager = new_manager(config);
+        flush(&manager, 0, &[(b"a", Some(b"a0"))], Vec::new());
+        manager.wait_for_l0_compaction().expect("should not stall");
+        assert_eq!(manager.stats().write_stalls, 0);
+
+        // no compaction reduces Level 0
+        flush(&manager, 2, &[(b"b", Some(b"b2"))], Vec::new());
+        assert!(matches!(
+            manager.wait_for_l0_compaction(),
+            Err(CrudError::WriteStall(2))
+        ));
+        let stats = manager.stats();
+        assert_eq!(stats.write_stalls, 1);
+        assert!(stats.write_stall_micros >= 10_000);
+
+        // the stalled write resumes after the compaction
+        let manager = Arc::new(new_manager(
+            Config::builder_for_testing()
+                .l0_compaction_trigger(2)
+                .max_l0_tables(2)
+                .build(),
+        ));
+        flush(&manager, 0, &[(b"a", Some(b"a0"))], Vec::new());
+        flush(&manager, 2, &[(b"b", Some(b"b2"))], Vec::new());
+        let compactor = {
+            let manager = manager.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                manager.compact().expect("compaction failed");
+            })
+        };
+        manager.wait_for_l0_compaction().expect("should resume");
+        assert_eq!(get_table_ids(&manager), vec![vec![], vec![1]]);
+        assert_eq!(manager.stats().write_stalls, 1);
+        compactor.join().unwrap();
+    }
+}
@@ -0,0 +1,2406 @@
+use bloomfilter::Bloom;
+use log::{debug, error, trace, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Instant;
+
+use super::sparse_index::{self, SparseIndex};
+use crate::amphis_error::{CorruptionError, CrudError};
+use crate::config::{Checksum, Compression, Config};
+#[cfg(test)]
+use crate::fault::FaultInjector;
+use crate::fault::FaultPoint;
+use crate::range_tombstone::RangeTombstone;
+use crate::scan::{SortedRun, Source};
+use crate::stats::Stats;
+use crate::storage::{Storage, StorageFile};
+use crate::util::data_util::{self, VersionAt};
+use crate::util::file_util;
+use crate::util::lock_util::{MutexExt, RwLockExt};
+
+mod block;
+mod block_cache;
+mod compaction;
+mod table_writer;
+
+pub use block::DEFAULT_BLOCK_SIZE;
+use block::{
+    Block, Footer, TABLE_FORMAT_BLOCK, TABLE_FORMAT_COMPRESSED_BLOCK, TABLE_FORMAT_CRC32C_BLOCK,
+    TABLE_FORMAT_FLAT,
+};
+use block_cache::BlockCache;
+pub use block_cache::DEFAULT_BLOCK_CACHE_BYTES;
+pub use table_writer::{Segment, TableWriter};
+
+const READ_BUFFER_SIZE: usize = 1 << 16;
+/// The maximum number of keys sampled from each table to estimate the live data
+const MAX_SAMPLED_KEYS: usize = 64;
+
+pub struct SstableManager {
+    name: String,
+    config: Config,
+    tables: Arc<RwLock<Vec<LeveledTables>>>,
+    /// Odd ID for the next compaction output
+    next_compaction_id: Mutex<TableId>,
+    compaction_lock: Mutex<()>,
+    /// Notified when a compaction has reduced Level 0 tables
+    l0_compacted: Condvar,
+    stall_lock: Mutex<()>,
+    write_stalls: AtomicU64,
+    write_stall_micros: AtomicU64,
+    block_cache: BlockCache,
+    /// Counters of bloom filter checks by lookups
+    bloom_negatives: AtomicU64,
+    bloom_true_positives: AtomicU64,
+    bloom_false_positives: AtomicU64,
+    /// The number of opened table files
+    #[cfg(test)]
+    open_count: AtomicUsize,
+}
+
+pub type TableId = usize;
+type LeveledTables = BTreeMap<TableId, Arc<TableInfo>>;
+
+/// Parameters which the bloom filter of a table was made with
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FilterParams {
+    pub items_count: usize,
+    pub fp_rate: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TableInfo {
+    pub id: TableId,
+    pub size: usize,
+    pub level: usize,
+    pub filter: Bloom<Vec<u8>>,
+    pub index: SparseIndex,
+    pub format_version: u8,
+    pub range_tombstones: Vec<RangeTombstone>,
+    /// The number of key-value pairs including tombstones
+    pub entry_count: usize,
+    /// The sampling interval in bytes which the index was built with
+    pub index_interval: usize,
+    /// The smallest and the largest keys including range tombstones
+    /// `None` when the table is empty
+    pub key_range: Option<(Vec<u8>, Vec<u8>)>,
+    /// The layout of the table file
+    pub table_format: u8,
+    /// The codec which new data blocks of the table were compressed with
+    pub compression: Compression,
+    /// The largest sequence of the values, or of the compacted tables
+    pub max_sequence: u64,
+    /// The largest sequence of the tombstones which compactions have dropped with the
+    /// versions deleted by them
+    pub discarded_sequence: u64,
+    /// `None` for a table written before the parameters were recorded
+    pub filter_params: Option<FilterParams>,
+    /// The file is removed when the table is dropped after it was compacted
+    #[serde(skip)]
+    obsolete_path: Mutex<Option<(String, Arc<dyn Storage>)>>,
+}
+
+impl TableInfo {
+    fn is_range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones.iter().any(|r| r.covers(key))
+    }
+
+    fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        match &self.key_range {
+            Some((s, l)) => s.as_slice() <= largest && smallest <= l.as_slice(),
+            None => false,
+        }
+    }
+
+    /// Whether the table can have the key or a range tombstone covering it
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.overlaps(key, key)
+    }
+
+    /// Whether the table can have keys in `[start, end)`
+    fn overlaps_range(&self, start: &[u8], end: Option<&[u8]>) -> bool {
+        match &self.key_range {
+            Some((s, l)) => start <= l.as_slice() && end.is_none_or(|end| s.as_slice() < end),
+            None => false,
+        }
+    }
+
+    /// Remove the file after all readers release the table
+    fn set_obsolete(&self, path: String, storage: Arc<dyn Storage>) {
+        *self.obsolete_path.lock_or_recover() = Some((path, storage));
+    }
+}
+
+impl Drop for TableInfo {
+    fn drop(&mut self) {
+        if let Some((path, storage)) = self.obsolete_path.get_mut().unwrap().take() {
+            debug!("Remove the compacted table {}", path);
+            if let Err(e) = storage.remove(&path) {
+                warn!("Failed to remove the compacted table {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Extend the key range to include `key`
+fn extend_key_range(key_range: &mut Option<(Vec<u8>, Vec<u8>)>, key: &[u8]) {
+    match key_range {
+        Some((smallest, largest)) => {
+            if key < smallest.as_slice() {
+                *smallest = key.to_vec();
+            }
+            if key > largest.as_slice() {
+                *largest = key.to_vec();
+            }
+        }
+        None => *key_range = Some((key.to_vec(), key.to_vec())),
+    }
+}
+
+/// TableInfo written before the filter parameters were recorded
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutFilterParams {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: usize,
+    index_interval: usize,
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    table_format: u8,
+    compression: Compression,
+    max_sequence: u64,
+    discarded_sequence: u64,
+}
+
+impl From<TableInfoWithoutFilterParams> for TableInfo {
+    fn from(old: TableInfoWithoutFilterParams) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: old.entry_count,
+            index_interval: old.index_interval,
+            key_range: old.key_range,
+            table_format: old.table_format,
+            compression: old.compression,
+            max_sequence: old.max_sequence,
+            discarded_sequence: old.discarded_sequence,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before sequences were introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutSequence {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: usize,
+    index_interval: usize,
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    table_format: u8,
+    compression: Compression,
+}
+
+impl From<TableInfoWithoutSequence> for TableInfo {
+    fn from(old: TableInfoWithoutSequence) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: old.entry_count,
+            index_interval: old.index_interval,
+            key_range: old.key_range,
+            table_format: old.table_format,
+            compression: old.compression,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before the compression was introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutCompression {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: usize,
+    index_interval: usize,
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    table_format: u8,
+}
+
+impl From<TableInfoWithoutCompression> for TableInfo {
+    fn from(old: TableInfoWithoutCompression) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: old.entry_count,
+            index_interval: old.index_interval,
+            key_range: old.key_range,
+            table_format: old.table_format,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before the block format was introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutTableFormat {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: usize,
+    index_interval: usize,
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl From<TableInfoWithoutTableFormat> for TableInfo {
+    fn from(old: TableInfoWithoutTableFormat) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: old.entry_count,
+            index_interval: old.index_interval,
+            key_range: old.key_range,
+            table_format: TABLE_FORMAT_FLAT,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before the key range was introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutKeyRange {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: usize,
+    index_interval: usize,
+}
+
+impl From<TableInfoWithoutKeyRange> for TableInfo {
+    fn from(old: TableInfoWithoutKeyRange) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: old.entry_count,
+            index_interval: old.index_interval,
+            key_range: None,
+            table_format: TABLE_FORMAT_FLAT,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before the index interval was introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutIndexInterval {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+    entry_count: usize,
+}
+
+impl From<TableInfoWithoutIndexInterval> for TableInfo {
+    fn from(old: TableInfoWithoutIndexInterval) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: old.entry_count,
+            index_interval: sparse_index::DEFAULT_INTERVAL,
+            key_range: None,
+            table_format: TABLE_FORMAT_FLAT,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before the entry count was introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutEntryCount {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl From<TableInfoWithoutEntryCount> for TableInfo {
+    fn from(old: TableInfoWithoutEntryCount) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: old.range_tombstones,
+            entry_count: 0,
+            index_interval: sparse_index::DEFAULT_INTERVAL,
+            key_range: None,
+            table_format: TABLE_FORMAT_FLAT,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before range tombstones were introduced
+#[derive(Serialize, Deserialize)]
+struct TableInfoWithoutRangeTombstones {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+    format_version: u8,
+}
+
+impl From<TableInfoWithoutRangeTombstones> for TableInfo {
+    fn from(old: TableInfoWithoutRangeTombstones) -> Self {
+        TableInfo {
+            id: old.id,
+            size: old.size,
+            level: old.level,
+            filter: old.filter,
+            index: old.index,
+            format_version: old.format_version,
+            range_tombstones: Vec::new(),
+            entry_count: 0,
+            index_interval: sparse_index::DEFAULT_INTERVAL,
+            key_range: None,
+            table_format: TABLE_FORMAT_FLAT,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+/// TableInfo written before the format version was introduced
+#[derive(Serialize, Deserialize)]
+struct LegacyTableInfo {
+    id: TableId,
+    size: usize,
+    level: usize,
+    filter: Bloom<Vec<u8>>,
+    index: SparseIndex,
+}
+
+impl From<LegacyTableInfo> for TableInfo {
+    fn from(legacy: LegacyTableInfo) -> Self {
+        TableInfo {
+            id: legacy.id,
+            size: legacy.size,
+            level: legacy.level,
+            filter: legacy.filter,
+            index: legacy.index,
+            format_version: 0,
+            range_tombstones: Vec::new(),
+            entry_count: 0,
+            index_interval: sparse_index::DEFAULT_INTERVAL,
+            key_range: None,
+            table_format: TABLE_FORMAT_FLAT,
+            compression: Compression::None,
+            max_sequence: 0,
+            discarded_sequence: 0,
+            filter_params: None,
+            obsolete_path: Mutex::new(None),
+        }
+    }
+}
+
+impl SstableManager {
+    pub fn new(name: &str, config: Config) -> Result<(Self, usize), CrudError> {
+        let path = config.get_table_dir_path(name);
+        let manager = SstableManager {
+            name: name.to_string(),
+            block_cache: BlockCache::new(config.get_block_cache_bytes()),
+            config,
+            tables: Arc::new(RwLock::new(Vec::new())),
+            next_compaction_id: Mutex::new(1),
+            compaction_lock: Mutex::new(()),
+            l0_compacted: Condvar::new(),
+            stall_lock: Mutex::new(()),
+            write_stalls: AtomicU64::new(0),
+            write_stall_micros: AtomicU64::new(0),
+            bloom_negatives: AtomicU64::new(0),
+            bloom_true_positives: AtomicU64::new(0),
+            bloom_false_positives: AtomicU64::new(0),
+            #[cfg(test)]
+            open_count: AtomicUsize::new(0),
+        };
+
+        // no table is written in memory
+        if manager.config.get_in_memory() {
+            return Ok((manager, 0));
+        }
+
+        // recovery the current state
+        let mut next_flush_id = 0;
+        if Path::new(&path).exists() {
+            manager.load_table_info()?;
+            next_flush_id = manager.recover_next_ids()?;
+            manager.remove_unregistered_tables()?;
+            if manager.config.get_verify_tables() {
+                manager.quarantine_corrupted_tables()?;
+            }
+        } else {
+            // the table directory might be different from the leaf directory
+            std::fs::create_dir_all(&path)?;
+        }
+
+        Ok((manager, next_flush_id))
+    }
+
+    pub fn register(&self, table_info: TableInfo) -> Result<(), CrudError> {
+        // the lock is held not to be lost by rewriting the metadata in a compaction
+        let mut tables = self.tables.write_or_recover();
+        self.write_table_info(&table_info)?;
+
+        // Register the new table to Level 0
+        let table_info = Arc::new(table_info);
+        match tables.get_mut(0) {
+            Some(level_zero) => {
+                level_zero.insert(table_info.id, table_info);
+            }
+            None => {
+                let mut level_zero = BTreeMap::new();
+                level_zero.insert(table_info.id, table_info);
+                tables.push(level_zero);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register the ingested table to the deepest level, and Level 1 if no table is deeper
+    /// With `check_overlap`, return `CrudError::InvalidInput` and remove the table if an
+    /// existing table overlaps it
+    pub fn register_to_bottom(
+        &self,
+        mut table_info: TableInfo,
+        check_overlap: bool,
+    ) -> Result<(), CrudError> {
+        let mut tables = self.tables.write_or_recover();
+        if let (true, Some((smallest, largest))) = (check_overlap, &table_info.key_range) {
+            let overlapped = tables
+                .iter()
+                .flat_map(|leveled_tables| leveled_tables.values())
+                .find(|t| t.overlaps(smallest, largest));
+            if let Some(overlapped) = overlapped {
+                let err = CrudError::InvalidInput(format!(
+                    "the ingested keys overlap SSTable {} in Level {}",
+                    overlapped.id, overlapped.level
+                ));
+                table_info.set_obsolete(
+                    self.config.get_table_file_path(&self.name, table_info.id),
+                    self.config.get_storage().clone(),
+                );
+                return Err(err);
+            }
+        }
+
+        let level = tables.len().saturating_sub(1).max(1);
+        table_info.level = level;
+        self.write_table_info(&table_info)?;
+        while tables.len() <= level {
+            tables.push(BTreeMap::new());
+        }
+        tables[level].insert(table_info.id, Arc::new(table_info));
+
+        Ok(())
+    }
+
+    /// Return all tables from the newest one
+    /// The returned tables can be read even after they are replaced
+    pub fn get_tables(&self) -> Vec<Arc<TableInfo>> {
+        self.tables
+            .read_or_recover()
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values().rev().cloned())
+            .collect()
+    }
+
+    /// The number of key-value pairs in all tables
+    /// Overwritten keys and tombstones are also counted
+    pub fn approximate_len(&self) -> usize {
+        self.tables
+            .read_or_recover()
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values())
+            .map(|table_info| table_info.entry_count)
+            .sum()
+    }
+
+    /// The total size of tables divided by the estimated size of the live data in them
+    ///
+    /// Keys are sampled from the index of each table, and the part of the table is estimated
+    /// to be overwritten by the sampled keys which newer tables may have or delete. It is 1.0
+    /// when no table exists.
+    pub fn space_amplification(&self) -> f64 {
+        let tables = self.get_tables();
+        let mut total_bytes = 0.0;
+        let mut live_bytes = 0.0;
+        for (i, table_info) in tables.iter().enumerate() {
+            let keys = table_info.index.keys();
+            let step = keys.len().div_ceil(MAX_SAMPLED_KEYS).max(1);
+            let sampled: Vec<&[u8]> = keys.step_by(step).collect();
+            let overwritten = sampled
+                .iter()
+                .filter(|key| {
+                    tables[..i].iter().any(|newer| {
+                        newer.is_range_deleted(key)
+                            || (newer.may_contain(key) && newer.filter.check(&key.to_vec()))
+                    })
+                })
+                .count();
+            let size = table_info.size as f64;
+            total_bytes += size;
+            // a table without keys has only range tombstones
+            live_bytes += match sampled.len() {
+                0 => size,
+                n => size * (n - overwritten) as f64 / n as f64,
+            };
+        }
+        if total_bytes == 0.0 {
+            return 1.0;
+        }
+
+        // the newest table is always live
+        total_bytes / live_bytes
+    }
+
+    /// Return `(level, the number of tables, the total size in bytes)` of each level
+    /// Only the counts are computed under the read lock, so it's cheap to poll
+    pub fn level_summary(&self) -> Vec<(usize, usize, u64)> {
+        let tables = self.tables.read_or_recover();
+        tables
+            .iter()
+            .enumerate()
+            .map(|(level, leveled_tables)| {
+                let bytes = leveled_tables
+                    .values()
+                    .map(|table_info| table_info.size as u64)
+                    .sum();
+                (level, leveled_tables.len(), bytes)
+            })
+            .collect()
+    }
+
+    /// Return the table counts, the total size, the bloom filter counters and the write stalls
+    pub fn stats(&self) -> Stats {
+        let summary = self.level_summary();
+        Stats {
+            tables_per_level: summary.iter().map(|(_, count, _)| *count).collect(),
+            total_table_bytes: summary.iter().map(|(_, _, bytes)| bytes).sum(),
+            bloom_negatives: self.bloom_negatives.load(Ordering::Relaxed),
+            bloom_true_positives: self.bloom_true_positives.load(Ordering::Relaxed),
+            bloom_false_positives: self.bloom_false_positives.load(Ordering::Relaxed),
+            write_stalls: self.write_stalls.load(Ordering::Relaxed),
+            write_stall_micros: self.write_stall_micros.load(Ordering::Relaxed),
+            throttle_delay_micros: self.config.get_rate_limiter().get_delay_micros(),
+            throttle_micros: self.config.get_rate_limiter().get_throttle_micros(),
+            ..Stats::default()
+        }
+    }
+
+    /// Print SSTables of each level with their key ranges and sizes
+    pub fn debug_dump(&self, w: &mut dyn Write) -> Result<(), CrudError> {
+        let tables = self.tables.read_or_recover();
+        for (level, leveled_tables) in tables.iter().enumerate() {
+            writeln!(w, "level {}: {} tables", level, leveled_tables.len())?;
+            for table_info in leveled_tables.values() {
+                let key_range = match &table_info.key_range {
+                    Some((first, last)) => format!("{:?} - {:?}", first, last),
+                    None => "empty".to_string(),
+                };
+                writeln!(
+                    w,
+                    "  table {}: {} bytes, {} entries, keys {}",
+                    table_info.id, table_info.size, table_info.entry_count, key_range
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The total size of SSTable files
+    pub fn size_on_disk(&self) -> Result<u64, CrudError> {
+        let path = self.config.get_table_dir_path(&self.name);
+        if !Path::new(&path).exists() {
+            return Ok(0);
+        }
+
+        let mut size = 0;
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            if file_util::get_table_id(&entry.path()).is_some() {
+                size += entry.metadata()?.len();
+            }
+        }
+
+        Ok(size)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CrudError> {
+        self.get_until(key, None)
+    }
+
+    /// Same as `get`, but return `CrudError::TimedOut` if the deadline passes before locking
+    /// the tables or reading a table
+    pub fn get_until(
+        &self,
+        key: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        data_util::check_deadline(deadline)?;
+        let tables = self.tables.read_or_recover();
+        self.get_from_tables(
+            key,
+            tables
+                .iter()
+                .flat_map(|leveled_tables| leveled_tables.values().rev()),
+            deadline,
+        )
+    }
+
+    /// Whether any table may have a live value of the key by the bloom filters
+    /// No table file is read, so a false positive is possible but a false negative isn't
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let tables = self.tables.read_or_recover();
+        for table_info in tables
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values().rev())
+        {
+            if !table_info.may_contain(key) {
+                continue;
+            }
+            if table_info.filter.check(&key.to_vec()) {
+                return true;
+            }
+            // the range tombstone hides the key in older tables
+            if table_info.is_range_deleted(key) {
+                return false;
+            }
+        }
+
+        false
+    }
+
+    /// Return the newest version of the key at `sequence` in the tables
+    /// Versions deleted by tombstones which compactions dropped are unavailable
+    pub fn get_at(&self, key: &[u8], sequence: u64) -> Result<VersionAt, CrudError> {
+        let tables = self.tables.read_or_recover();
+        for table_info in tables
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values().rev())
+        {
+            if !table_info.may_contain(key) {
+                continue;
+            }
+            let entry = if self.check_filter(table_info, key) {
+                let offset = table_info.index.get(key);
+                let value = self.get_from_table(key, table_info, offset)?;
+                self.count_filter_positive(value.is_some());
+                value
+            } else {
+                None
+            };
+            match data_util::select_version(entry, table_info.is_range_deleted(key), sequence)? {
+                VersionAt::NotFound => continue,
+                version => return Ok(version),
+            }
+        }
+
+        let discarded_sequence = tables
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values())
+            .map(|table_info| table_info.discarded_sequence)
+            .max()
+            .unwrap_or(0);
+        if sequence < discarded_sequence {
+            return Ok(VersionAt::Unavailable);
+        }
+
+        Ok(VersionAt::NotFound)
+    }
+
+    /// The largest sequence which the tables have
+    pub fn get_max_sequence(&self) -> u64 {
+        self.tables
+            .read_or_recover()
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values())
+            .map(|table_info| table_info.max_sequence)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Get the value of the key from the newest table which has the key
+    /// A key covered by a range tombstone is returned as a tombstone
+    /// `tables` should be given from the newest one, so the newest tombstone wins over
+    /// older values and `KVS::get` returns `None` for it
+    pub fn get_from_tables<'a>(
+        &self,
+        key: &[u8],
+        tables: impl Iterator<Item = &'a Arc<TableInfo>>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        if self.config.get_parallel_lookup() {
+            return self.get_from_tables_in_parallel(key, tables.collect(), deadline);
+        }
+
+        for table_info in tables {
+            data_util::check_deadline(deadline)?;
+            // the table has neither the key nor range tombstones covering it
+            if !table_info.may_contain(key) {
+                continue;
+            }
+            trace!(
+                "Check the bloom filter of SSTable {} with {:?}",
+                table_info.id,
+                key
+            );
+            if self.check_filter(table_info, key) {
+                trace!("Read from SSTable {} with {:?}", table_info.id, key);
+                let offset = table_info.index.get(key);
+                let value = self.get_from_table(key, table_info, offset)?;
+                self.count_filter_positive(value.is_some());
+                if let Some(r) = value {
+                    return Ok(Some(r));
+                }
+            }
+
+            if table_info.is_range_deleted(key) {
+                return Ok(Some(Vec::new()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read candidate tables concurrently, and then return the value of the newest one
+    fn get_from_tables_in_parallel(
+        &self,
+        key: &[u8],
+        tables: Vec<&Arc<TableInfo>>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        let results: Vec<Result<Option<Vec<u8>>, CrudError>> = tables
+            .par_iter()
+            .map(|table_info| {
+                if table_info.may_contain(key) && self.check_filter(table_info, key) {
+                    data_util::check_deadline(deadline)?;
+                    trace!("Read from SSTable {} with {:?}", table_info.id, key);
+                    let offset = table_info.index.get(key);
+                    let value = self.get_from_table(key, table_info, offset)?;
+                    self.count_filter_positive(value.is_some());
+                    Ok(value)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect();
+
+        for (table_info, result) in tables.iter().zip(results) {
+            if let Some(r) = result? {
+                return Ok(Some(r));
+            }
+            if table_info.is_range_deleted(key) {
+                return Ok(Some(Vec::new()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Check the bloom filter of the table with counting a negative result
+    fn check_filter(&self, table_info: &TableInfo, key: &[u8]) -> bool {
+        let maybe = table_info.filter.check(&key.to_vec());
+        if !maybe {
+            self.bloom_negatives.fetch_add(1, Ordering::Relaxed);
+        }
+
+        maybe
+    }
+
+    /// Count whether the key passed by the bloom filter was in the table
+    /// A tombstone is also found in the table
+    fn count_filter_positive(&self, found: bool) {
+        let counter = if found {
+            &self.bloom_true_positives
+        } else {
+            &self.bloom_false_positives
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fill `results` of keys which haven't been found yet
+    /// `keys` should be sorted to read each table forward with a single reader
+    pub fn get_many(
+        &self,
+        keys: &[&[u8]],
+        results: &mut [Option<Vec<u8>>],
+    ) -> Result<(), CrudError> {
+        for leveled_tables in self.tables.read_or_recover().iter() {
+            for (table_id, table_info) in leveled_tables.iter().rev() {
+                let candidates: Vec<usize> = (0..keys.len())
+                    .filter(|i| results[*i].is_none())
+                    .filter(|i| table_info.may_contain(keys[*i]))
+                    .filter(|i| self.check_filter(table_info, keys[*i]))
+                    .collect();
+                if !candidates.is_empty() {
+                    trace!("Read {} keys from SSTable {}", candidates.len(), table_id);
+                    self.get_many_from_table(keys, &candidates, table_info, results)?;
+                    for i in candidates {
+                        self.count_filter_positive(results[i].is_some());
+                    }
+                }
+
+                // keys which are not found in the table might be deleted by its range tombstones
+                for (key, result) in keys.iter().zip(results.iter_mut()) {
+                    if result.is_none() && table_info.is_range_deleted(key) {
+                        *result = Some(Vec::new());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_many_from_table(
+        &self,
+        keys: &[&[u8]],
+        candidates: &[usize],
+        table_info: &TableInfo,
+        results: &mut [Option<Vec<u8>>],
+    ) -> Result<(), CrudError> {
+        let mut table_iter = self.open_table(table_info, 0)?;
+        let mut current_offset = table_iter.offset();
+        let mut current = None;
+        for &i in candidates {
+            let key = keys[i];
+            // skip to the indexed offset if it's ahead of the current pair
+            let offset = table_info.index.get(key);
+            if current.is_none() || current_offset < offset {
+                table_iter.seek(offset)?;
+                current_offset = offset;
+                current = table_iter.next().transpose()?;
+            }
+            while let Some((cur_key, _)) = &current {
+                if cur_key.as_slice() >= key {
+                    break;
+                }
+                current_offset = table_iter.offset();
+                current = table_iter.next().transpose()?;
+            }
+            match &current {
+                Some((cur_key, value)) if cur_key.as_slice() == key => {
+                    results[i] = Some(value.clone());
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return an iterator over all pairs of the table in the stored order
+    /// A broken record is returned as `CorruptionError`, and then the iterator stops
+    pub fn table_iter(&self, table_id: TableId) -> Result<TableIter, CrudError> {
+        let table_info = self.find_table(table_id)?;
+        let mut table_iter = self.open_table(&table_info, 0)?;
+        table_iter.pinned = Some(table_info);
+
+        Ok(table_iter)
+    }
+
+    fn find_table(&self, table_id: TableId) -> Result<Arc<TableInfo>, std::io::Error> {
+        self.tables
+            .read_or_recover()
+            .iter()
+            .find_map(|leveled_tables| leveled_tables.get(&table_id).cloned())
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::NotFound, format!("no table {}", table_id))
+            })
+    }
+
+    /// Verify the whole file of the table
+    /// A broken, truncated or replaced file is returned as `CrudError::Corruption`
+    /// A table written without the checksum is verified by reading all its records
+    pub fn verify_table(&self, table_id: TableId) -> Result<(), CrudError> {
+        let table_info = self.find_table(table_id)?;
+        let to_corruption = |e: std::io::Error| -> CrudError {
+            match e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<CorruptionError>())
+            {
+                Some(corruption) => {
+                    CrudError::Corruption(format!("SSTable {}: {}", table_id, corruption.0))
+                }
+                None if e.kind() == ErrorKind::UnexpectedEof => {
+                    CrudError::Corruption(format!("SSTable {} is truncated: {}", table_id, e))
+                }
+                None => e.into(),
+            }
+        };
+
+        let path = self.config.get_table_file_path(&self.name, table_id);
+        let mut file = self.config.get_storage().open(&path)?;
+        let footer = match table_info.table_format {
+            TABLE_FORMAT_FLAT => None,
+            _ => Some(Footer::read(&mut file).map_err(to_corruption)?),
+        };
+        match footer.and_then(|footer| Some((footer.checksum?, footer.size()))) {
+            Some((checksum, footer_size)) => verify_checksum(
+                file,
+                table_info.size,
+                footer_size,
+                block::get_checksum(table_info.table_format),
+                checksum,
+            )
+            .map_err(to_corruption),
+            None => {
+                for kv in self.open_table(&table_info, 0)? {
+                    kv.map_err(to_corruption)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Verify all tables, and stop reading tables which fail the verification
+    /// The file of a quarantined table is renamed to be kept for investigation
+    fn quarantine_corrupted_tables(&self) -> Result<(), CrudError> {
+        let mut corrupted = Vec::new();
+        for table_info in self.get_tables() {
+            if let Err(e) = self.verify_table(table_info.id) {
+                error!(
+                    "SSTable {} of {} is quarantined: {}",
+                    table_info.id, self.name, e
+                );
+                corrupted.push(table_info);
+            }
+        }
+        if corrupted.is_empty() {
+            return Ok(());
+        }
+
+        // the metadata is rewritten first not to load the table on the next startup
+        let mut tables = self.tables.write_or_recover();
+        for table_info in &corrupted {
+            tables[table_info.level].remove(&table_info.id);
+        }
+        self.rewrite_table_info(&tables)?;
+        for table_info in &corrupted {
+            let path = self.config.get_table_file_path(&self.name, table_info.id);
+            if let Err(e) = self
+                .config
+                .get_storage()
+                .rename(&path, &get_quarantined_table_path(&path))
+            {
+                warn!("Failed to quarantine {}: {}", path, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return iterators of all tables which overlap `[start, end)` from the newest one
+    /// Each iterator starts from the indexed offset at or before `start`
+    pub fn scan(&self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<SortedRun>, CrudError> {
+        let tables = self.tables.read_or_recover();
+        self.scan_tables(
+            start,
+            end,
+            tables
+                .iter()
+                .flat_map(|leveled_tables| leveled_tables.values().rev()),
+        )
+    }
+
+    /// Return iterators of the given tables which overlap `[start, end)` in the same order
+    pub fn scan_tables<'a>(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        tables: impl Iterator<Item = &'a Arc<TableInfo>>,
+    ) -> Result<Vec<SortedRun>, CrudError> {
+        let mut iters = Vec::new();
+        for table_info in tables {
+            if !table_info.overlaps_range(start, end) {
+                continue;
+            }
+            let offset = table_info.index.get(start);
+            trace!("Scan SSTable {} from offset {}", table_info.id, offset);
+            let mut table_iter = self.open_table(table_info, offset)?;
+            table_iter.pinned = Some(table_info.clone());
+            iters.push(SortedRun::new(
+                table_iter,
+                table_info.range_tombstones.clone(),
+                Source::Sstable {
+                    id: table_info.id,
+                    level: table_info.level,
+                },
+            ));
+        }
+
+        Ok(iters)
+    }
+
+    fn open_table(&self, table_info: &TableInfo, offset: usize) -> Result<TableIter, CrudError> {
+        let path = self.config.get_table_file_path(&self.name, table_info.id);
+        let mut file = self.config.get_storage().open(&path)?;
+        #[cfg(test)]
+        self.open_count.fetch_add(1, Ordering::Relaxed);
+        let data_end = match table_info.table_format {
+            TABLE_FORMAT_FLAT => None,
+            TABLE_FORMAT_BLOCK | TABLE_FORMAT_COMPRESSED_BLOCK | TABLE_FORMAT_CRC32C_BLOCK => {
+                Some(Footer::read(&mut file)?.filter_offset)
+            }
+            table_format => {
+                return Err(CrudError::Corruption(format!(
+                    "unsupported table format: {}",
+                    table_format
+                )))
+            }
+        };
+        let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+        reader.seek(SeekFrom::Start(offset as u64))?;
+
+        Ok(TableIter {
+            reader,
+            offset,
+            format_version: table_info.format_version,
+            table_format: table_info.table_format,
+            data_end,
+            block: None,
+            pinned: None,
+            failed: false,
+        })
+    }
+
+    fn get_from_table(
+        &self,
+        key: &[u8],
+        table_info: &TableInfo,
+        offset: usize,
+    ) -> Result<Option<Vec<u8>>, CrudError> {
+        if table_info.table_format == TABLE_FORMAT_FLAT {
+            return Ok(find_value(key, self.open_table(table_info, offset)?)?);
+        }
+
+        // only the indexed block can have the key since every block is indexed
+        match self.read_block(table_info, offset)? {
+            Some(block) => Ok(block
+                .get(key)
+                .map(|value| data_util::upgrade_value(table_info.format_version, value.to_vec()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the data block at the offset through the block cache
+    fn read_block(
+        &self,
+        table_info: &TableInfo,
+        offset: usize,
+    ) -> Result<Option<Arc<Block>>, CrudError> {
+        if let Some(block) = self.block_cache.get(table_info.id, offset) {
+            return Ok(Some(block));
+        }
+
+        let mut table_iter = self.open_table(table_info, offset)?;
+        if table_iter.load_block()?.is_none() {
+            return Ok(None);
+        }
+        let (block, _, _) = table_iter.block.take().expect("the block was loaded");
+        let block = Arc::new(block);
+        self.block_cache
+            .insert(table_info.id, offset, block.clone());
+
+        Ok(Some(block))
+    }
+
+    fn write_table_info(&self, table_info: &TableInfo) -> Result<(), CrudError> {
+        let file_path = self.config.get_metadata_path(&self.name);
+        let (mut file, _) = self.config.get_storage().open_or_create(&file_path)?;
+        let mut writer = BufWriter::new(&mut file);
+
+        let encoded = bincode::serialize(table_info).expect("serializing the table info failed");
+        writer.write_all(&data_util::format_bytes_with_crc(&encoded))?;
+        writer.flush()?;
+        drop(writer);
+        self.config
+            .get_fault_injector()
+            .check(FaultPoint::MetadataAppended)?;
+        file.sync()?;
+
+        Ok(())
+    }
+
+    /// Replace the metadata with the info of all current tables
+    fn rewrite_table_info(&self, tables: &[LeveledTables]) -> Result<(), CrudError> {
+        let file_path = self.config.get_metadata_path(&self.name);
+        let tmp_path = self.get_tmp_metadata_path();
+        let storage = self.config.get_storage();
+        write_metadata(storage.as_ref(), &tmp_path, tables)?;
+
+        // the old metadata is valid until the new one replaces it
+        storage.rename(&tmp_path, &file_path)?;
+        storage.sync_dir(&self.config.get_table_dir_path(&self.name))?;
+
+        Ok(())
+    }
+
+    /// Link files of the current tables into `dir` with the metadata of them
+    /// A file is copied when it can't be linked, e.g. `dir` is on another file system
+    pub fn checkpoint(&self, dir: &Path) -> Result<(), CrudError> {
+        // the captured tables aren't removed by compactions until they are dropped
+        let tables = self.tables.read_or_recover().clone();
+        std::fs::create_dir_all(dir)?;
+        for table_info in tables
+            .iter()
+            .flat_map(|leveled_tables| leveled_tables.values())
+        {
+            let src = self.config.get_table_file_path(&self.name, table_info.id);
+            let dest = dir.join(Path::new(&src).file_name().expect("no table file name"));
+            if let Err(e) = std::fs::hard_link(&src, &dest) {
+                debug!("Copy SSTable {} since linking failed: {}", table_info.id, e);
+                std::fs::copy(&src, &dest)?;
+                File::open(&dest)?.sync_all()?;
+            }
+        }
+        let metadata_path = self.config.get_metadata_path(&self.name);
+        let metadata_file = Path::new(&metadata_path)
+            .file_name()
+            .expect("no metadata file name");
+        write_metadata(
+            self.config.get_storage().as_ref(),
+            &dir.join(metadata_file).to_string_lossy(),
+            &tables,
+        )?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn get_tmp_metadata_path(&self) -> String {
+        format!("{}.tmp", self.config.get_metadata_path(&self.name))
+    }
+
+    /// Set the next ID of compacted tables (odd), and return the next ID of flushed tables (even)
+    /// Each follows the IDs of table files and registered tables of its parity not to overwrite them
+    fn recover_next_ids(&self) -> Result<TableId, CrudError> {
+        let mut table_ids: Vec<TableId> = self.get_tables().iter().map(|t| t.id).collect();
+        for entry in std::fs::read_dir(self.config.get_table_dir_path(&self.name))? {
+            let path = entry?.path();
+            // a quarantined table's ID isn't reused not to overwrite its file
+            if let Some(table_id) = file_util::get_table_id(&path)
+                .or_else(|| file_util::get_quarantined_table_id(&path))
+            {
+                table_ids.push(table_id);
+            }
+        }
+
+        let mut next_flush_id = 0;
+        let mut next_compaction_id = 1;
+        for table_id in table_ids {
+            if table_id % 2 == 0 {
+                next_flush_id = next_flush_id.max(table_id + 2);
+            } else {
+                next_compaction_id = next_compaction_id.max(table_id + 2);
+            }
+        }
+        debug!(
+            "next table IDs: {} for flushes, {} for compactions",
+            next_flush_id, next_compaction_id
+        );
+        *self.next_compaction_id.lock_or_recover() = next_compaction_id;
+
+        Ok(next_flush_id)
+    }
+
+    /// Remove table files which aren't in the metadata
+    /// e.g. an output of an interrupted compaction or an input of a finished compaction
+    fn remove_unregistered_tables(&self) -> Result<(), CrudError> {
+        // the metadata being rewritten when crashed
+        let tmp_path = self.get_tmp_metadata_path();
+        if Path::new(&tmp_path).exists() {
+            self.config.get_storage().remove(&tmp_path)?;
+        }
+
+        let registered: HashSet<TableId> = self.get_tables().iter().map(|t| t.id).collect();
+        for entry in std::fs::read_dir(self.config.get_table_dir_path(&self.name))? {
+            let path = entry?.path();
+            if let Some(table_id) = file_util::get_table_id(&path) {
+                if !registered.contains(&table_id) {
+                    debug!("Remove the unregistered table {}", table_id);
+                    self.config.get_storage().remove(&path.to_string_lossy())?;
+                }
+            } else if let Some(table_id) = file_util::get_tmp_table_id(&path)
+                .or_else(|| file_util::get_segment_table_id(&path))
+            {
+                // the table being written when crashed
+                debug!("Remove the unfinished table {}", table_id);
+                self.config.get_storage().remove(&path.to_string_lossy())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_table_info(&self) -> Result<(), CrudError> {
+        let file_path = self.config.get_metadata_path(&self.name);
+        let (file, _) = self.config.get_storage().open_or_create(&file_path)?;
+        let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+
+        let mut is_torn = false;
+        loop {
+            let bytes = match data_util::read_bytes_with_crc(&mut reader) {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => break,
+                // the info being appended when crashed, whose table is flushed again
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("The last table info of {} is ignored: {}", self.name, e);
+                    is_torn = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let table_info = self.decode_table_info(&bytes)?;
+            debug!("load table info for ID: {}", table_info.id);
+            let table_info = Arc::new(table_info);
+            let mut tables = self.tables.write_or_recover();
+            match tables.get_mut(table_info.level) {
+                Some(tables) => {
+                    tables.insert(table_info.id, table_info);
+                }
+                None => {
+                    while tables.len() < table_info.level {
+                        tables.push(BTreeMap::new());
+                    }
+                    let mut leveled_tables = BTreeMap::new();
+                    leveled_tables.insert(table_info.id, table_info);
+                    tables.push(leveled_tables);
+                }
+            }
+        }
+        if is_torn {
+            // new info isn't appended after the torn bytes
+            self.rewrite_table_info(&self.tables.read_or_recover())?;
+        }
+
+        Ok(())
+    }
+
+    /// Decode the table info in any layout
+    fn decode_table_info(&self, bytes: &[u8]) -> Result<TableInfo, CrudError> {
+        // try from the newest layout since an older one can be read from newer bytes
+        if let Ok(table_info) = bincode::deserialize::<TableInfo>(bytes) {
+            return Ok(table_info);
+        }
+        if let Ok(old) = bincode::deserialize::<TableInfoWithoutFilterParams>(bytes) {
+            return Ok(old.into());
+        }
+        if let Ok(old) = bincode::deserialize::<TableInfoWithoutSequence>(bytes) {
+            return Ok(old.into());
+        }
+        if let Ok(old) = bincode::deserialize::<TableInfoWithoutCompression>(bytes) {
+            return Ok(old.into());
+        }
+        if let Ok(old) = bincode::deserialize::<TableInfoWithoutTableFormat>(bytes) {
+            return Ok(old.into());
+        }
+        let (mut table_info, has_entry_count): (TableInfo, bool) =
+            if let Ok(old) = bincode::deserialize::<TableInfoWithoutKeyRange>(bytes) {
+                (old.into(), true)
+            } else if let Ok(old) = bincode::deserialize::<TableInfoWithoutIndexInterval>(bytes) {
+                (old.into(), true)
+            } else if let Ok(old) = bincode::deserialize::<TableInfoWithoutEntryCount>(bytes) {
+                (old.into(), false)
+            } else if let Ok(old) = bincode::deserialize::<TableInfoWithoutRangeTombstones>(bytes) {
+                (old.into(), false)
+            } else {
+                let legacy = bincode::deserialize::<LegacyTableInfo>(bytes)
+                    .map_err(|_| std::io::Error::other("failed to deserialize the table info"))?;
+                (legacy.into(), false)
+            };
+        // compute the key range and the entry count which the old layout doesn't have
+        let mut key_range = None;
+        let mut entry_count = 0;
+        for kv in self.open_table(&table_info, 0)? {
+            let (key, _) = kv?;
+            extend_key_range(&mut key_range, &key);
+            entry_count += 1;
+        }
+        for range_tombstone in &table_info.range_tombstones {
+            extend_key_range(&mut key_range, range_tombstone.get_start());
+            extend_key_range(&mut key_range, range_tombstone.get_end());
+        }
+        table_info.key_range = key_range;
+        if !has_entry_count {
+            table_info.entry_count = entry_count;
+        }
+        Ok(table_info)
+    }
+}
+
+/// Write the info of all tables to a new metadata file
+fn write_metadata(
+    storage: &dyn Storage,
+    file_path: &str,
+    tables: &[LeveledTables],
+) -> Result<(), std::io::Error> {
+    let mut file = storage.create(file_path)?;
+    let mut writer = BufWriter::new(&mut file);
+    for table_info in tables
+        .iter()
+        .flat_map(|leveled_tables| leveled_tables.values())
+    {
+        let encoded =
+            bincode::serialize(table_info.as_ref()).expect("serializing the table info failed");
+        writer.write_all(&data_util::format_bytes_with_crc(&encoded))?;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync()
+}
+
+/// Check the CRC of all bytes before the footer
+fn verify_checksum(
+    mut file: Box<dyn StorageFile>,
+    expected_size: usize,
+    footer_size: usize,
+    algorithm: Checksum,
+    checksum: u32,
+) -> Result<(), std::io::Error> {
+    let file_size = file.size()? as usize;
+    if file_size != expected_size {
+        return Err(CorruptionError(format!(
+            "the file has {} bytes, but {} bytes were written",
+            file_size, expected_size
+        ))
+        .into());
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader =
+        BufReader::with_capacity(READ_BUFFER_SIZE, file).take((file_size - footer_size) as u64);
+    let mut crc = 0;
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc = data_util::update_checksum(algorithm, crc, &buf[..n]);
+    }
+    if crc != checksum {
+        return Err(CorruptionError("the checksum doesn't match".to_owned()).into());
+    }
+
+    Ok(())
+}
+
+/// The path which the file of a corrupted table is moved to
+pub fn get_quarantined_table_path(file_path: &str) -> String {
+    format!("{}.quarantine", file_path)
+}
+
+/// Find the value of the key from sorted key-value pairs
+/// Pairs after a larger key are not read
+fn find_value(
+    key: &[u8],
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), std::io::Error>>,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    for kv in pairs {
+        let (cur_key, value) = kv?;
+        if cur_key == *key {
+            return Ok(Some(value));
+        }
+        // the following keys are larger since the table is sorted
+        if cur_key.as_slice() > key {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Iterator over key-value pairs of an SSTable in the stored order
+/// Values are converted to the current format
+pub struct TableIter {
+    reader: BufReader<Box<dyn StorageFile>>,
+    /// The offset of the next pair in the flat format, or the next block in the block format
+    offset: usize,
+    format_version: u8,
+    table_format: u8,
+    /// The end of data blocks in the block format
+    data_end: Option<usize>,
+    /// The current block with its offset and the position of the next entry
+    block: Option<(Block, usize, usize)>,
+    /// A compacted table isn't removed until the iterator is dropped
+    pinned: Option<Arc<TableInfo>>,
+    /// No pair is returned after an error
+    failed: bool,
+}
+
+impl TableIter {
+    /// The offset of the next pair, or the block which has the next pair in the block format
+    pub fn offset(&self) -> usize {
+        match &self.block {
+            Some((block, block_offset, pos)) if *pos < block.len() => *block_offset,
+            _ => self.offset,
+        }
+    }
+
+    /// `offset` should be an offset of a pair, or a block in the block format
+    pub fn seek(&mut self, offset: usize) -> Result<(), std::io::Error> {
+        self.reader.seek(SeekFrom::Start(offset as u64))?;
+        self.offset = offset;
+        self.block = None;
+
+        Ok(())
+    }
+
+    /// Read the next data block
+    /// Return `None` at the end of data blocks
+    fn load_block(&mut self) -> Result<Option<&Block>, std::io::Error> {
+        if self.data_end.is_none_or(|end| self.offset >= end) {
+            return Ok(None);
+        }
+
+        let checksum = block::get_checksum(self.table_format);
+        let mut bytes = data_util::read_bytes_with_checksum(&mut self.reader, checksum)?
+            .ok_or_else(|| CorruptionError("no data block".to_owned()))?;
+        let block_offset = self.offset;
+        self.offset += bytes.len() + data_util::LEN_SIZE + data_util::LEN_CRC;
+        if block::is_compressed(self.table_format) {
+            bytes = block::decompress(&bytes)?;
+        }
+        self.block = Some((Block::decode(bytes)?, block_offset, 0));
+
+        Ok(self.block.as_ref().map(|(block, _, _)| block))
+    }
+
+    fn next_in_blocks(&mut self) -> Option<<Self as Iterator>::Item> {
+        loop {
+            if let Some((block, _, pos)) = &mut self.block {
+                if *pos < block.len() {
+                    let (key, value) = block.entry(*pos);
+                    *pos += 1;
+                    return Some(Ok((
+                        key.to_vec(),
+                        data_util::upgrade_value(self.format_version, value.to_vec()),
+                    )));
+                }
+            }
+
+            match self.load_block() {
+                Ok(Some(_)) => {}
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn next_in_flat(&mut self) -> Option<<Self as Iterator>::Item> {
+        let key = match data_util::read_bytes_with_crc(&mut self.reader) {
+            Ok(Some(k)) => k,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        match data_util::read_bytes_with_crc(&mut self.reader) {
+            Ok(Some(value)) => {
+                self.offset += data_util::get_data_size(key.len(), value.len());
+                Some(Ok((
+                    key,
+                    data_util::upgrade_value(self.format_version, value),
+                )))
+            }
+            Ok(None) => Some(Err(
+                CorruptionError("no value for the key".to_owned()).into()
+            )),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Vec<u8>), std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = if self.data_end.is_some() {
+            self.next_in_blocks()
+        } else {
+            self.next_in_flat()
+        };
+        self.failed = matches!(next, Some(Err(_)));
+
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a table of even keys in 0..1000
+    fn write_table(config: &Config) -> TableInfo {
+        let path = config.get_table_file_path("test", 0);
+        let mut writer =
+            TableWriter::new(0, &path, 0, 1024, config).expect("cannot create a table");
+        for i in (0..1000u32).step_by(2) {
+            writer
+                .add(&i.to_be_bytes(), &data_util::encode_value(b"value", None))
+                .expect("write failed");
+        }
+
+        writer.finish(Vec::new()).expect("finish failed")
+    }
+
+    #[test]
+    fn test_find_value_stops_early() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+
+        // a key between two stored keys
+        let key = 101u32.to_be_bytes();
+        let offset = table_info.index.get(&key);
+        let mut read_count = 0;
+        let pairs = manager
+            .open_table(&table_info, offset)
+            .expect("cannot open")
+            .inspect(|_| read_count += 1);
+        assert_eq!(find_value(&key, pairs).expect("read failed"), None);
+        // only the pairs from the indexed block to the next larger key are read
+        assert!(read_count > 0);
+        assert!(read_count <= 256 / (4 + 6) + 1);
+
+        // a key smaller than the first key of the table
+        let offset = table_info.index.get(&[0]);
+        assert_eq!(offset, 0);
+        let value = manager
+            .get_from_table(&[0], &table_info, offset)
+            .expect("read failed");
+        assert_eq!(value, None);
+
+        let offset = table_info.index.get(&102u32.to_be_bytes());
+        let value = manager
+            .get_from_table(&102u32.to_be_bytes(), &table_info, offset)
+            .expect("read failed");
+        assert_eq!(value, Some(data_util::encode_value(b"value", None)));
+    }
+
+    #[test]
+    fn test_block_table() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        assert_eq!(table_info.table_format, TABLE_FORMAT_COMPRESSED_BLOCK);
+        assert_eq!(table_info.entry_count, 500);
+        // the minimum key is indexed at the head
+        assert_eq!(table_info.index.get(&0u32.to_be_bytes()), 0);
+
+        // the file has the filter and the index
+        let mut file = File::open(config.get_table_file_path("test", 0)).expect("no table");
+        let footer = Footer::read(&mut file).expect("no footer");
+        file.seek(SeekFrom::Start(footer.index_offset as u64))
+            .expect("seek failed");
+        let bytes = data_util::read_bytes_with_crc(&mut BufReader::new(file))
+            .expect("read failed")
+            .expect("no index");
+        let index: SparseIndex = bincode::deserialize(&bytes).expect("invalid index");
+        assert_eq!(
+            index.get(&500u32.to_be_bytes()),
+            table_info.index.get(&500u32.to_be_bytes())
+        );
+        assert!(index.get(&500u32.to_be_bytes()) > 0);
+
+        let value = data_util::encode_value(b"value", None);
+        for i in 0..1000u32 {
+            let key = i.to_be_bytes();
+            let offset = table_info.index.get(&key);
+            let expected = (i % 2 == 0).then(|| value.clone());
+            assert_eq!(
+                manager
+                    .get_from_table(&key, &table_info, offset)
+                    .expect("read failed"),
+                expected
+            );
+        }
+
+        // iterate from a block in the middle
+        let offset = table_info.index.get(&501u32.to_be_bytes());
+        let keys: Vec<Vec<u8>> = manager
+            .open_table(&table_info, offset)
+            .expect("cannot open")
+            .map(|kv| kv.expect("read failed").0)
+            .skip_while(|key| key.as_slice() < 501u32.to_be_bytes().as_slice())
+            .collect();
+        assert_eq!(keys.len(), 249);
+        assert_eq!(keys[0], 502u32.to_be_bytes().to_vec());
+
+        // read multiple keys with a single iterator
+        let keys: Vec<[u8; 4]> = (0..1000u32).step_by(3).map(|i| i.to_be_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let mut results = vec![None; keys.len()];
+        let candidates: Vec<usize> = (0..keys.len()).collect();
+        manager
+            .get_many_from_table(&keys, &candidates, &table_info, &mut results)
+            .expect("read failed");
+        for (i, result) in results.iter().enumerate() {
+            let expected = (i % 2 == 0).then(|| value.clone());
+            assert_eq!(*result, expected);
+        }
+    }
+
+    #[test]
+    fn test_compressed_table() {
+        let value = data_util::encode_value(&b"a text-heavy value ".repeat(8), None);
+        let mut sizes = Vec::new();
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let config = Config::builder_for_testing()
+                .compression(compression)
+                .build();
+            let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+            let path = config.get_table_file_path("test", 0);
+            let mut writer =
+                TableWriter::new(0, &path, 0, 1024, &config).expect("cannot create a table");
+            for i in 0..1000u32 {
+                writer.add(&i.to_be_bytes(), &value).expect("write failed");
+            }
+            let table_info = writer.finish(Vec::new()).expect("finish failed");
+            assert_eq!(table_info.compression, compression);
+            sizes.push(table_info.size);
+
+            let key = 777u32.to_be_bytes();
+            let offset = table_info.index.get(&key);
+            assert_eq!(
+                manager
+                    .get_from_table(&key, &table_info, offset)
+                    .expect("read failed"),
+                Some(value.clone())
+            );
+            let count = manager
+                .open_table(&table_info, 0)
+                .expect("cannot open")
+                .map(|kv| assert_eq!(kv.expect("read failed").1, value))
+                .count();
+            assert_eq!(count, 1000);
+        }
+        assert!(sizes[1] < sizes[0] / 2);
+        assert!(sizes[2] < sizes[0] / 2);
+    }
+
+    #[test]
+    fn test_read_flat_table() {
+        let config = Config::new_for_testing();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let value = data_util::encode_value(b"value", None);
+        let mut data = Vec::new();
+        let mut index = SparseIndex::new(64);
+        for i in 0..10u8 {
+            index.insert(&[i], data.len());
+            data.extend(data_util::format_data_with_crc(
+                &[i],
+                &value,
+                Checksum::Crc32,
+            ));
+        }
+        std::fs::write(config.get_table_file_path("test", 2), &data).expect("write failed");
+
+        let old = TableInfoWithoutTableFormat {
+            id: 2,
+            size: data.len(),
+            level: 0,
+            filter: Bloom::new_for_fp_rate(1024, 0.01),
+            index,
+            format_version: data_util::FORMAT_VERSION,
+            range_tombstones: Vec::new(),
+            entry_count: 10,
+            index_interval: 64,
+            key_range: Some((vec![0], vec![9])),
+        };
+        let encoded = bincode::serialize(&old).expect("serializing failed");
+        let table_info = manager
+            .decode_table_info(&encoded)
+            .expect("decoding failed");
+        assert_eq!(table_info.table_format, TABLE_FORMAT_FLAT);
+
+        for i in 0..10u8 {
+            let offset = table_info.index.get(&[i]);
+            assert_eq!(
+                manager
+                    .get_from_table(&[i], &table_info, offset)
+                    .expect("read failed"),
+                Some(value.clone())
+            );
+        }
+        assert_eq!(manager.open_table(&table_info, 0).unwrap().count(), 10);
+
+        // a table without the checksum is verified by its records
+        manager
+            .tables
+            .write()
+            .unwrap()
+            .push(BTreeMap::from([(2, Arc::new(table_info))]));
+        manager.verify_table(2).expect("verification failed");
+        std::fs::write(
+            config.get_table_file_path("test", 2),
+            &data[..data.len() - 3],
+        )
+        .expect("write failed");
+        assert!(matches!(
+            manager.verify_table(2),
+            Err(CrudError::Corruption(_))
+        ));
+
+        // the truncated last record isn't returned as a valid pair
+        let table_info = manager.get_tables().pop().expect("no table");
+        let mut table_iter = manager.open_table(&table_info, 0).expect("cannot open");
+        for _ in 0..9 {
+            table_iter.next().expect("no pair").expect("read failed");
+        }
+        let err = table_iter.next().expect("no error").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(matches!(
+            CrudError::from(err),
+            CrudError::Corruption(m) if m.contains("truncated")
+        ));
+        let offset = table_info.index.get(&[9]);
+        assert!(matches!(
+            manager.get_from_table(&[9], &table_info, offset),
+            Err(CrudError::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn test_torn_table_info() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        manager.register(table_info).expect("register failed");
+        drop(manager);
+
+        // the info of the next table was partially appended when crashed
+        let metadata_path = config.get_metadata_path("test");
+        let size = std::fs::metadata(&metadata_path)
+            .expect("no metadata")
+            .len();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&metadata_path)
+            .expect("cannot open");
+        file.write_all(&[0xFF, 0x01, 0x00, 0x00, 0x01, 0x02])
+            .expect("write failed");
+        drop(file);
+
+        // the complete info is loaded, and the torn bytes are removed
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot reopen");
+        assert_eq!(manager.get_tables().len(), 1);
+        assert_eq!(
+            std::fs::metadata(&metadata_path)
+                .expect("no metadata")
+                .len(),
+            size
+        );
+        assert_eq!(
+            manager.get(&500u32.to_be_bytes()).expect("read failed"),
+            Some(data_util::encode_value(b"value", None))
+        );
+    }
+
+    #[test]
+    fn test_load_legacy_table_info() {
+        let config = Config::new_for_testing();
+        std::fs::create_dir_all(config.get_table_dir_path("test")).expect("cannot create a dir");
+
+        let mut data = Vec::new();
+        for key in [b"k1", b"k2"] {
+            data.extend(data_util::format_data_with_crc(
+                key,
+                b"value",
+                Checksum::Crc32,
+            ));
+        }
+        std::fs::write(config.get_table_file_path("test", 2), &data).expect("write failed");
+
+        let legacy = LegacyTableInfo {
+            id: 2,
+            size: data.len(),
+            level: 0,
+            filter: Bloom::new_for_fp_rate(
+                config.get_filter_items_count(),
+                config.get_filter_fp_rate(),
+            ),
+            index: SparseIndex::new(sparse_index::DEFAULT_INTERVAL),
+        };
+        let encoded = bincode::serialize(&legacy).expect("serializing failed");
+        std::fs::write(
+            config.get_metadata_path("test"),
+            data_util::format_bytes_with_crc(&encoded),
+        )
+        .expect("write failed");
+
+        let (manager, _) = SstableManager::new("test", config).expect("cannot load tables");
+        let tables = manager.tables.read().unwrap();
+        let table_info = tables[0].get(&2).expect("no table info");
+        assert_eq!(table_info.format_version, 0);
+        assert_eq!(table_info.entry_count, 2);
+        assert_eq!(table_info.key_range, Some((b"k1".to_vec(), b"k2".to_vec())));
+        assert_eq!(table_info.max_sequence, 0);
+        assert_eq!(table_info.filter_params, None);
+        assert_eq!(manager.approximate_len(), 2);
+    }
+
+    #[test]
+    fn test_skip_disjoint_tables() {
+        let config = Config::new_for_testing();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let value = data_util::encode_value(b"value", None);
+        // tables of keys in [0, 100), [100, 200) and [200, 300)
+        for id in [0, 2, 4] {
+            let path = config.get_table_file_path("test", id);
+            let mut writer =
+                TableWriter::new(id, &path, 0, 1024, &config).expect("cannot create a table");
+            let first = id as u32 * 50;
+            for i in first..first + 100 {
+                writer.add(&i.to_be_bytes(), &value).expect("write failed");
+            }
+            let mut table_info = writer.finish(Vec::new()).expect("finish failed");
+            // the bloom filter passes all keys
+            for i in 0..300u32 {
+                table_info.filter.set(&i.to_be_bytes().to_vec());
+            }
+            manager.register(table_info).expect("register failed");
+        }
+        // reading the other tables would fail
+        for id in [0, 4] {
+            std::fs::remove_file(config.get_table_file_path("test", id)).expect("remove failed");
+        }
+
+        let key = 150u32.to_be_bytes();
+        assert_eq!(manager.get(&key).expect("read failed"), Some(value.clone()));
+        assert_eq!(
+            manager.get(&300u32.to_be_bytes()).expect("read failed"),
+            None
+        );
+
+        let keys: Vec<[u8; 4]> = (100..200u32).step_by(9).map(|i| i.to_be_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let mut results = vec![None; keys.len()];
+        manager.get_many(&keys, &mut results).expect("read failed");
+        assert!(results.iter().all(|r| r.as_ref() == Some(&value)));
+
+        let sources = manager
+            .scan(&120u32.to_be_bytes(), Some(&200u32.to_be_bytes()))
+            .expect("cannot scan");
+        assert_eq!(sources.len(), 1);
+        // the end is exclusive
+        assert!(manager
+            .scan(&50u32.to_be_bytes(), Some(&100u32.to_be_bytes()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        let value = data_util::encode_value(b"value", None);
+
+        let key = 500u32.to_be_bytes();
+        let offset = table_info.index.get(&key);
+        for _ in 0..10 {
+            let result = manager
+                .get_from_table(&key, &table_info, offset)
+                .expect("read failed");
+            assert_eq!(result, Some(value.clone()));
+        }
+        // the file is opened only for the first read
+        assert_eq!(manager.open_count.load(Ordering::Relaxed), 1);
+
+        // another key in the same block
+        let result = manager
+            .get_from_table(&502u32.to_be_bytes(), &table_info, offset)
+            .expect("read failed");
+        assert_eq!(result, Some(value.clone()));
+        assert_eq!(manager.open_count.load(Ordering::Relaxed), 1);
+
+        // every read opens the file without the cache
+        let config = Config::builder_for_testing()
+            .block_size(256)
+            .block_cache_bytes(0)
+            .build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        for _ in 0..10 {
+            let result = manager
+                .get_from_table(&key, &table_info, offset)
+                .expect("read failed");
+            assert_eq!(result, Some(value.clone()));
+        }
+        assert_eq!(manager.open_count.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn test_parallel_lookup() {
+        let config = Config::builder_for_testing().parallel_lookup(true).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let old = data_util::encode_value(b"old", None);
+        let new = data_util::encode_value(b"new", None);
+        // the oldest table has all keys, and each newer table overwrites one key
+        for id in (0..16).step_by(2) {
+            let path = config.get_table_file_path("test", id);
+            let mut writer =
+                TableWriter::new(id, &path, 0, 1024, &config).expect("cannot create a table");
+            let mut range_tombstones = Vec::new();
+            if id == 0 {
+                for i in 0..100u32 {
+                    writer.add(&i.to_be_bytes(), &old).expect("write failed");
+                }
+            } else if id == 14 {
+                // the newest table deletes keys in [90, 100)
+                range_tombstones.push(RangeTombstone::new(
+                    &90u32.to_be_bytes(),
+                    &100u32.to_be_bytes(),
+                ));
+            } else {
+                writer
+                    .add(&(id as u32).to_be_bytes(), &new)
+                    .expect("write failed");
+            }
+            let table_info = writer.finish(range_tombstones).expect("finish failed");
+            manager.register(table_info).expect("register failed");
+        }
+
+        for i in 0..100u32 {
+            let expected = if i >= 90 {
+                Some(Vec::new())
+            } else if i > 0 && i < 14 && i % 2 == 0 {
+                Some(new.clone())
+            } else {
+                Some(old.clone())
+            };
+            assert_eq!(
+                manager.get(&i.to_be_bytes()).expect("read failed"),
+                expected
+            );
+        }
+        assert_eq!(
+            manager.get(&100u32.to_be_bytes()).expect("read failed"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_corrupted_table() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        let second_block = table_info.index.get(&100u32.to_be_bytes());
+        manager.register(table_info).expect("register failed");
+
+        // break a value in the block of the key 100
+        let path = config.get_table_file_path("test", 0);
+        let mut bytes = std::fs::read(&path).expect("read failed");
+        bytes[second_block + 20] ^= 0xFF;
+        std::fs::write(&path, bytes).expect("write failed");
+
+        let mut table_iter = manager.table_iter(0).expect("cannot open");
+        let mut count = 0;
+        let err = loop {
+            match table_iter.next().expect("no corruption was detected") {
+                Ok(_) => count += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(count > 0 && count < 100 / 2);
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err
+            .get_ref()
+            .is_some_and(|e| e.downcast_ref::<CorruptionError>().is_some()));
+        // no pair after the corruption
+        assert!(table_iter.next().is_none());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let builder = Config::builder_for_testing().block_size(256);
+        let config = builder.clone().checksum(Checksum::Crc32c).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        // a table written with CRC-32
+        let old_table = write_table(&builder.build());
+        assert_eq!(old_table.table_format, TABLE_FORMAT_COMPRESSED_BLOCK);
+        manager.register(old_table).expect("register failed");
+        let path = config.get_table_file_path("test", 2);
+        let mut writer =
+            TableWriter::new(2, &path, 0, 1024, &config).expect("cannot create a table");
+        for i in (1..1000u32).step_by(2) {
+            writer
+                .add(&i.to_be_bytes(), &data_util::encode_value(b"value", None))
+                .expect("write failed");
+        }
+        let new_table = writer.finish(Vec::new()).expect("finish failed");
+        assert_eq!(new_table.table_format, TABLE_FORMAT_CRC32C_BLOCK);
+        manager.register(new_table).expect("register failed");
+
+        // the data block and the whole table are checked by CRC-32C
+        let mut file = File::open(&path).expect("no table");
+        let footer = Footer::read(&mut file).expect("no footer");
+        assert_eq!(footer.table_format, TABLE_FORMAT_CRC32C_BLOCK);
+        file.seek(SeekFrom::Start(0)).expect("seek failed");
+        let mut reader = BufReader::new(file);
+        assert!(data_util::read_bytes_with_checksum(&mut reader, Checksum::Crc32c).is_ok());
+        let bytes = std::fs::read(&path).expect("read failed");
+        let crc = data_util::calc_checksum(Checksum::Crc32c, &bytes[..bytes.len() - footer.size()]);
+        assert_eq!(footer.checksum, Some(crc));
+
+        for id in [0, 2] {
+            manager.verify_table(id).expect("verification failed");
+        }
+        for i in (0..1000u32).step_by(7) {
+            assert_eq!(
+                manager.get(&i.to_be_bytes()).expect("read failed"),
+                Some(data_util::encode_value(b"value", None))
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_table() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        manager.register(table_info).expect("register failed");
+        manager.verify_table(0).expect("verification failed");
+        assert!(matches!(manager.verify_table(2), Err(CrudError::Io(_))));
+
+        let path = config.get_table_file_path("test", 0);
+        let bytes = std::fs::read(&path).expect("read failed");
+        // a bit flip in a data block
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() / 3] ^= 0x01;
+        std::fs::write(&path, &flipped).expect("write failed");
+        match manager.verify_table(0) {
+            Err(CrudError::Corruption(e)) => assert!(e.contains("checksum")),
+            _ => panic!("the bit flip should be detected"),
+        }
+
+        // a truncated table
+        for size in [bytes.len() - 1, bytes.len() / 2, 10] {
+            std::fs::write(&path, &bytes[..size]).expect("write failed");
+            assert!(matches!(
+                manager.verify_table(0),
+                Err(CrudError::Corruption(_))
+            ));
+        }
+
+        std::fs::write(&path, &bytes).expect("write failed");
+        manager.verify_table(0).expect("verification failed");
+    }
+
+    #[test]
+    fn test_recover_next_ids() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, next_flush_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+        assert_eq!(next_flush_id, 0);
+        assert_eq!(*manager.next_compaction_id.lock().unwrap(), 1);
+
+        // flushed tables have even IDs and compacted ones have odd IDs
+        for (id, level) in [(0, 0), (2, 0), (5, 1), (7, 2)] {
+            let path = config.get_table_file_path("test", id);
+            let mut writer =
+                TableWriter::new(id, &path, level, 1024, &config).expect("cannot create a table");
+            writer
+                .add(
+                    &(id as u32).to_be_bytes(),
+                    &data_util::encode_value(b"value", None),
+                )
+                .expect("write failed");
+            manager
+                .register(writer.finish(Vec::new()).expect("finish failed"))
+                .expect("register failed");
+        }
+        drop(manager);
+
+        let assert_no_collision = |manager: &SstableManager, next_flush_id: TableId| {
+            let next_compaction_id = *manager.next_compaction_id.lock().unwrap();
+            assert_eq!(next_flush_id % 2, 0);
+            assert_eq!(next_compaction_id % 2, 1);
+            for table in manager.get_tables() {
+                assert_ne!(table.id, next_flush_id);
+                assert_ne!(table.id, next_compaction_id);
+            }
+            for id in [next_flush_id, next_compaction_id] {
+                assert!(!Path::new(&config.get_table_file_path("test", id)).exists());
+            }
+        };
+
+        let (manager, next_flush_id) =
+            SstableManager::new("test", config.clone()).expect("cannot open");
+        assert_eq!(next_flush_id, 4);
+        assert_eq!(*manager.next_compaction_id.lock().unwrap(), 9);
+        assert_no_collision(&manager, next_flush_id);
+        drop(manager);
+
+        // the ID of a registered table is reserved even when its file is lost
+        std::fs::remove_file(config.get_table_file_path("test", 7)).expect("remove failed");
+        let (manager, next_flush_id) =
+            SstableManager::new("test", config.clone()).expect("cannot open");
+        assert_eq!(next_flush_id, 4);
+        assert_eq!(*manager.next_compaction_id.lock().unwrap(), 9);
+        assert_no_collision(&manager, next_flush_id);
+    }
+
+    #[test]
+    fn test_quarantine_corrupted_tables() {
+        let builder = Config::builder_for_testing().block_size(256);
+        let config = builder.clone().verify_tables(true).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        manager
+            .register(write_table(&config))
+            .expect("register failed");
+        let path = config.get_table_file_path("test", 2);
+        let mut writer =
+            TableWriter::new(2, &path, 0, 1024, &config).expect("cannot create a table");
+        writer
+            .add(&1u32.to_be_bytes(), &data_util::encode_value(b"new", None))
+            .expect("write failed");
+        manager
+            .register(writer.finish(Vec::new()).expect("finish failed"))
+            .expect("register failed");
+        drop(manager);
+
+        let mut bytes = std::fs::read(&path).expect("read failed");
+        bytes[10] ^= 0x01;
+        std::fs::write(&path, bytes).expect("write failed");
+
+        // the corrupted table is skipped
+        let (manager, next_table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot open");
+        assert_eq!(next_table_id, 4);
+        let table_ids: Vec<TableId> = manager.get_tables().iter().map(|t| t.id).collect();
+        assert_eq!(table_ids, vec![0]);
+        assert!(manager
+            .get(&1u32.to_be_bytes())
+            .expect("get failed")
+            .is_none());
+        assert!(!Path::new(&path).exists());
+        assert!(Path::new(&get_quarantined_table_path(&path)).exists());
+        drop(manager);
+
+        // the quarantined table isn't loaded even without the verification
+        let (manager, next_table_id) =
+            SstableManager::new("test", builder.build()).expect("cannot open");
+        assert_eq!(next_table_id, 4);
+        assert_eq!(manager.get_tables().len(), 1);
+        assert!(Path::new(&get_quarantined_table_path(&path)).exists());
+    }
+
+    #[test]
+    fn test_bloom_false_positives() {
+        let config = Config::builder_for_testing()
+            .block_size(256)
+            .bloom_fp_rate(0.5)
+            .build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        // the filter for a single key is too small for 500 keys
+        let path = config.get_table_file_path("test", 0);
+        let mut writer = TableWriter::new(0, &path, 0, 1, &config).expect("cannot create a table");
+        for i in (0..1000u32).step_by(2) {
+            writer
+                .add(&i.to_be_bytes(), &data_util::encode_value(b"value", None))
+                .expect("write failed");
+        }
+        manager
+            .register(writer.finish(Vec::new()).expect("finish failed"))
+            .expect("register failed");
+
+        for i in 0..1000u32 {
+            let found = manager.get(&i.to_be_bytes()).expect("get failed").is_some();
+            assert_eq!(found, i % 2 == 0);
+        }
+        let stats = manager.stats();
+        assert_eq!(stats.bloom_true_positives, 500);
+        // 999 is out of the key range
+        assert_eq!(stats.bloom_negatives + stats.bloom_false_positives, 499);
+        assert!(stats.bloom_false_positives > 250);
+        assert!(stats.bloom_false_positive_rate() > 0.5);
+
+        // keys of a batch are also counted
+        let keys: Vec<[u8; 4]> = (1000..1010u32).map(|i| i.to_be_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let mut results = vec![None; keys.len()];
+        manager.get_many(&keys, &mut results).expect("get failed");
+        assert_eq!(manager.stats().bloom_checks(), stats.bloom_checks());
+        let keys = [0u32.to_be_bytes(), 1u32.to_be_bytes()];
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let mut results = vec![None; keys.len()];
+        manager.get_many(&keys, &mut results).expect("get failed");
+        let new_stats = manager.stats();
+        assert_eq!(new_stats.bloom_checks(), stats.bloom_checks() + 2);
+        assert_eq!(
+            new_stats.bloom_true_positives,
+            stats.bloom_true_positives + 1
+        );
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let table_info = write_table(&config);
+        manager.register(table_info).expect("register failed");
+
+        // a thread panics while holding the locks
+        std::thread::scope(|s| {
+            let result = s
+                .spawn(|| {
+                    let _tables = manager.tables.write_or_recover();
+                    let _id = manager.next_compaction_id.lock_or_recover();
+                    panic!("panic in the middle of an operation");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+        assert!(manager.tables.is_poisoned());
+
+        // the manager still serves reads and writes
+        let value = data_util::encode_value(b"value", None);
+        assert_eq!(
+            manager.get(&500u32.to_be_bytes()).expect("read failed"),
+            Some(value.clone())
+        );
+        manager.compact_all().expect("compaction failed");
+        assert_eq!(manager.get_tables().len(), 1);
+        assert_eq!(
+            manager.get(&500u32.to_be_bytes()).expect("read failed"),
+            Some(value)
+        );
+    }
+
+    #[test]
+    fn test_fault_before_metadata_sync() {
+        let injector = Arc::new(FaultInjector::default());
+        let config = Config::builder_for_testing()
+            .block_size(256)
+            .fault_injector(injector.clone())
+            .build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        injector.arm(FaultPoint::MetadataAppended, 0);
+        let table_info = write_table(&config);
+        assert!(manager.register(table_info).is_err());
+        assert!(manager.get_tables().is_empty());
+        drop(manager);
+
+        // the appended info is loaded since it has been written
+        let (manager, _) = SstableManager::new("test", config).expect("cannot reopen");
+        assert_eq!(manager.get_tables().len(), 1);
+        assert_eq!(
+            manager.get(&500u32.to_be_bytes()).expect("read failed"),
+            Some(data_util::encode_value(b"value", None))
+        );
+    }
+}
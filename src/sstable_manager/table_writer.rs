@@ -0,0 +1,418 @@
+use bloomfilter::Bloom;
+use log::warn;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::block::{self, BlockBuilder, Footer};
+use super::{extend_key_range, FilterParams, TableId, TableInfo};
+use crate::config::{Checksum, Compression, Config};
+use crate::fault::{FaultInjector, FaultPoint};
+use crate::range_tombstone::RangeTombstone;
+use crate::rate_limiter::RateLimiter;
+use crate::sparse_index::SparseIndex;
+use crate::storage::{Storage, StorageFile};
+use crate::util::data_util;
+
+const WRITE_BUFFER_SIZE: usize = 1 << 18;
+
+/// Writer of a new SSTable in the block format
+/// Key-value pairs have to be added in the key order
+///
+/// The table is written to a temporary file, which is renamed to the table file
+/// when finished. A crash while writing leaves only the temporary file, which
+/// is removed on the next startup.
+pub struct TableWriter {
+    id: TableId,
+    level: usize,
+    file_path: String,
+    tmp_path: String,
+    writer: BufWriter<Box<dyn StorageFile>>,
+    storage: Arc<dyn Storage>,
+    fault_injector: Arc<FaultInjector>,
+    rate_limiter: Arc<RateLimiter>,
+    checksum: Checksum,
+    /// The CRC of all bytes written so far
+    /// It's combined from the CRCs of blocks not to read the blocks twice
+    crc: u32,
+    /// The offset of the current block
+    offset: usize,
+    block: BlockBuilder,
+    block_size: usize,
+    compression: Compression,
+    filter: Bloom<Vec<u8>>,
+    filter_params: FilterParams,
+    index: SparseIndex,
+    entry_count: usize,
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    max_sequence: u64,
+}
+
+impl TableWriter {
+    /// Create the temporary file of the table file at `file_path` in the level
+    /// The bloom filter is made for `items_count` keys with the rate of the level
+    pub fn new(
+        id: TableId,
+        file_path: &str,
+        level: usize,
+        items_count: usize,
+        config: &Config,
+    ) -> Result<Self, std::io::Error> {
+        let tmp_path = get_tmp_table_path(file_path);
+        let storage = config.get_storage().clone();
+        let file = storage.create(&tmp_path)?;
+        let block_size = config.get_block_size();
+        let filter_params = FilterParams {
+            items_count,
+            fp_rate: config.get_level_filter_fp_rate(level),
+        };
+        Ok(TableWriter {
+            id,
+            level,
+            file_path: file_path.to_string(),
+            tmp_path,
+            writer: BufWriter::with_capacity(WRITE_BUFFER_SIZE, file),
+            storage,
+            fault_injector: config.get_fault_injector().clone(),
+            rate_limiter: config.get_rate_limiter().clone(),
+            checksum: config.get_checksum(),
+            crc: 0,
+            offset: 0,
+            block: BlockBuilder::default(),
+            block_size,
+            compression: config.get_compression(),
+            filter: Bloom::new_for_fp_rate(items_count, filter_params.fp_rate),
+            filter_params,
+            // every block is indexed even if a compressed block is small
+            index: SparseIndex::new(0),
+            entry_count: 0,
+            key_range: None,
+            max_sequence: 0,
+        })
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        if self.block.is_empty() {
+            self.index.insert(key, self.offset);
+        }
+        self.block.add(key, value);
+        self.filter.set(&key.to_vec());
+        self.entry_count += 1;
+        extend_key_range(&mut self.key_range, key);
+        self.max_sequence = self.max_sequence.max(data_util::get_sequence(value)?.0);
+
+        if self.block.size() >= self.block_size {
+            self.write_block()?;
+        }
+
+        Ok(())
+    }
+
+    /// The number of bytes written so far
+    pub fn size(&self) -> usize {
+        self.offset + self.block.size()
+    }
+
+    fn write_block(&mut self) -> Result<(), std::io::Error> {
+        self.fault_injector.check(FaultPoint::TableBlockWrite)?;
+        let block = block::compress(&self.block.take(), self.compression)?;
+        self.write_bytes(&block)
+    }
+
+    /// Create a writer of data blocks which will be appended to this table
+    /// `segment_id` distinguishes segments written concurrently
+    pub fn new_segment(&self, segment_id: usize) -> Result<SegmentWriter, std::io::Error> {
+        let path = get_segment_path(&self.file_path, segment_id);
+        let file = self.storage.create(&path)?;
+        Ok(SegmentWriter {
+            writer: BufWriter::with_capacity(WRITE_BUFFER_SIZE, file),
+            block: BlockBuilder::default(),
+            block_size: self.block_size,
+            compression: self.compression,
+            checksum: self.checksum,
+            fault_injector: self.fault_injector.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            segment: Segment {
+                path,
+                storage: self.storage.clone(),
+                size: 0,
+                crc: 0,
+                block_offsets: Vec::new(),
+                keys: Vec::new(),
+                max_sequence: 0,
+            },
+        })
+    }
+
+    /// Append data blocks of the segment after the pairs added so far
+    /// Keys of the segment have to be larger than them
+    pub fn append_segment(&mut self, segment: Segment) -> Result<(), std::io::Error> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+
+        // the CRC of the segment was computed while writing it
+        // the bytes were already limited when the segment was written
+        std::io::copy(&mut self.storage.open(&segment.path)?, &mut self.writer)?;
+        self.crc = data_util::combine_checksum(self.checksum, self.crc, segment.crc, segment.size);
+
+        for (key, offset) in &segment.block_offsets {
+            self.index.insert(key, self.offset + offset);
+        }
+        for key in &segment.keys {
+            self.filter.set(key);
+            extend_key_range(&mut self.key_range, key);
+        }
+        self.entry_count += segment.keys.len();
+        self.max_sequence = self.max_sequence.max(segment.max_sequence);
+        self.offset += segment.size;
+
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        let (formatted, crc) = data_util::format_bytes_with_checksums(bytes, self.checksum);
+        self.rate_limiter.request(formatted.len());
+        self.writer.write_all(&formatted)?;
+        self.crc = data_util::combine_checksum(self.checksum, self.crc, crc, formatted.len());
+        self.offset += formatted.len();
+
+        Ok(())
+    }
+
+    /// The number of key-value pairs added so far
+    pub fn entry_count(&self) -> usize {
+        self.entry_count
+    }
+
+    /// Discard the unfinished table
+    pub fn abort(self) -> Result<(), std::io::Error> {
+        drop(self.writer);
+        self.storage.remove(&self.tmp_path)
+    }
+
+    /// Persist the table, publish it with the table file name, and return its info
+    pub fn finish(
+        mut self,
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> Result<TableInfo, std::io::Error> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        let filter_offset = self.offset;
+        let filter = bincode::serialize(&self.filter).expect("serializing the filter failed");
+        self.write_bytes(&filter)?;
+        let index_offset = self.offset;
+        let index = bincode::serialize(&self.index).expect("serializing the index failed");
+        self.write_bytes(&index)?;
+        let table_format = block::get_table_format(self.checksum);
+        let footer = Footer {
+            table_format,
+            filter_offset,
+            index_offset,
+            checksum: Some(self.crc),
+        };
+        self.writer.write_all(&footer.encode())?;
+
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync()?;
+        // the table file is always complete
+        self.storage.rename(&self.tmp_path, &self.file_path)?;
+        if let Some(dir) = Path::new(&self.file_path).parent() {
+            self.storage.sync_dir(&dir.to_string_lossy())?;
+        }
+
+        for range_tombstone in &range_tombstones {
+            extend_key_range(&mut self.key_range, range_tombstone.get_start());
+            extend_key_range(&mut self.key_range, range_tombstone.get_end());
+        }
+
+        Ok(TableInfo {
+            id: self.id,
+            size: file.size()? as _,
+            level: self.level,
+            filter: self.filter,
+            index: self.index,
+            format_version: data_util::FORMAT_VERSION,
+            range_tombstones,
+            entry_count: self.entry_count,
+            index_interval: self.block_size,
+            key_range: self.key_range,
+            table_format,
+            compression: self.compression,
+            max_sequence: self.max_sequence,
+            discarded_sequence: 0,
+            filter_params: Some(self.filter_params),
+            obsolete_path: Mutex::new(None),
+        })
+    }
+}
+
+pub fn get_tmp_table_path(file_path: &str) -> String {
+    format!("{}.tmp", file_path)
+}
+
+pub fn get_segment_path(file_path: &str, segment_id: usize) -> String {
+    format!("{}.{}.segment", file_path, segment_id)
+}
+
+/// Data blocks of a part of a table in a temporary file
+/// The file is removed when the segment is dropped
+pub struct Segment {
+    path: String,
+    storage: Arc<dyn Storage>,
+    size: usize,
+    /// The CRC of all bytes of the segment
+    crc: u32,
+    /// The first key of each block with its offset in the segment
+    block_offsets: Vec<(Vec<u8>, usize)>,
+    /// All keys in the order for the bloom filter of the table
+    keys: Vec<Vec<u8>>,
+    max_sequence: u64,
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        if let Err(e) = self.storage.remove(&self.path) {
+            warn!("Failed to remove the segment {}: {}", self.path, e);
+        }
+    }
+}
+
+/// Writer of data blocks of a part of a table
+/// Segments of consecutive key ranges can be written concurrently, and then they are
+/// appended to the table in the key order
+pub struct SegmentWriter {
+    writer: BufWriter<Box<dyn StorageFile>>,
+    block: BlockBuilder,
+    block_size: usize,
+    compression: Compression,
+    checksum: Checksum,
+    fault_injector: Arc<FaultInjector>,
+    rate_limiter: Arc<RateLimiter>,
+    segment: Segment,
+}
+
+impl SegmentWriter {
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        if self.block.is_empty() {
+            self.segment
+                .block_offsets
+                .push((key.to_vec(), self.segment.size));
+        }
+        self.block.add(key, value);
+        self.segment.keys.push(key.to_vec());
+        self.segment.max_sequence = self
+            .segment
+            .max_sequence
+            .max(data_util::get_sequence(value)?.0);
+
+        if self.block.size() >= self.block_size {
+            self.write_block()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<(), std::io::Error> {
+        self.fault_injector.check(FaultPoint::TableBlockWrite)?;
+        let block = block::compress(&self.block.take(), self.compression)?;
+        let (formatted, crc) = data_util::format_bytes_with_checksums(&block, self.checksum);
+        self.rate_limiter.request(formatted.len());
+        self.writer.write_all(&formatted)?;
+        self.segment.crc =
+            data_util::combine_checksum(self.checksum, self.segment.crc, crc, formatted.len());
+        self.segment.size += formatted.len();
+
+        Ok(())
+    }
+
+    /// Write the last block and return the segment
+    /// The segment doesn't need to be synced since the table is synced after appending it
+    pub fn finish(mut self) -> Result<Segment, std::io::Error> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.writer.flush()?;
+
+        Ok(self.segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable_manager::SstableManager;
+
+    #[test]
+    fn test_mixed_compression() {
+        let config = Config::builder_for_testing().block_size(256).build();
+        let (manager, _) = SstableManager::new("test", config.clone()).expect("cannot create");
+        let path = config.get_table_file_path("test", 0);
+        let mut writer =
+            TableWriter::new(0, &path, 0, 1024, &config).expect("cannot create a table");
+        let value = data_util::encode_value(b"value", None);
+        // each block is decompressed with its own codec
+        let codecs = [Compression::None, Compression::Lz4, Compression::Zstd];
+        for i in 0..900u32 {
+            writer.compression = codecs[i as usize / 300];
+            writer.add(&i.to_be_bytes(), &value).expect("write failed");
+        }
+        let table_info = writer.finish(Vec::new()).expect("finish failed");
+
+        for i in (0..900u32).step_by(7) {
+            let key = i.to_be_bytes();
+            let offset = table_info.index.get(&key);
+            assert_eq!(
+                manager
+                    .get_from_table(&key, &table_info, offset)
+                    .expect("read failed"),
+                Some(value.clone())
+            );
+        }
+        let keys: Vec<Vec<u8>> = manager
+            .open_table(&table_info, 0)
+            .expect("cannot open")
+            .map(|kv| kv.expect("read failed").0)
+            .collect();
+        assert_eq!(keys.len(), 900);
+        assert_eq!(keys[899], 899u32.to_be_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_crash_before_rename() {
+        let config = Config::new_for_testing();
+        let (manager, table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+        let path = config.get_table_file_path("test", table_id);
+        let value = data_util::encode_value(b"value", None);
+        let mut writer =
+            TableWriter::new(table_id, &path, 0, 1024, &config).expect("cannot create a table");
+        for i in 0..100u32 {
+            writer.add(&i.to_be_bytes(), &value).expect("write failed");
+        }
+        // the partial table isn't visible with the table file name
+        assert!(!Path::new(&path).exists());
+        drop(writer);
+        drop(manager);
+        let tmp_path = get_tmp_table_path(&path);
+        assert!(Path::new(&tmp_path).exists());
+
+        // the partial table is ignored and removed
+        let (manager, next_table_id) =
+            SstableManager::new("test", config.clone()).expect("cannot create");
+        assert!(manager.get_tables().is_empty());
+        assert_eq!(next_table_id, table_id);
+        assert!(!Path::new(&tmp_path).exists());
+
+        // the finished table is published
+        let mut writer =
+            TableWriter::new(table_id, &path, 0, 1024, &config).expect("cannot create a table");
+        writer.add(b"key", &value).expect("write failed");
+        let table_info = writer.finish(Vec::new()).expect("finish failed");
+        assert!(Path::new(&path).exists());
+        assert!(!Path::new(&tmp_path).exists());
+        manager.register(table_info).expect("register failed");
+        assert_eq!(manager.get_tables().len(), 1);
+    }
+}
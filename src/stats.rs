@@ -0,0 +1,50 @@
+use serde::Serialize;
+
+/// Runtime statistics of a column family
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    /// The number of SSTables in each level from Level 0
+    pub tables_per_level: Vec<usize>,
+    /// The total size of SSTables in bytes
+    pub total_table_bytes: u64,
+    /// The number of FPTrees flushed to SSTables since the database was opened
+    pub flush_count: u64,
+    /// The number of lookups which a bloom filter rejected
+    pub bloom_negatives: u64,
+    /// The number of lookups which a bloom filter passed and found the key in the table
+    pub bloom_true_positives: u64,
+    /// The number of lookups which a bloom filter passed but didn't find the key in the table
+    /// Many false positives mean that the filter is too small for the table
+    pub bloom_false_positives: u64,
+    /// The number of root splits of the current FPTree
+    pub root_split_count: usize,
+    /// The number of writes which waited for compactions of Level 0
+    pub write_stalls: u64,
+    /// The total time of write stalls in microseconds
+    pub write_stall_micros: u64,
+    /// The number of lookups served by the read cache
+    pub read_cache_hits: u64,
+    /// The wait of the last write to an SSTable by `compaction_bytes_per_sec` in microseconds
+    /// It's shared by all column families
+    pub throttle_delay_micros: u64,
+    /// The total wait of writes to SSTables by `compaction_bytes_per_sec` in microseconds
+    pub throttle_micros: u64,
+}
+
+impl Stats {
+    /// The number of bloom filter checks by lookups
+    pub fn bloom_checks(&self) -> u64 {
+        self.bloom_negatives + self.bloom_true_positives + self.bloom_false_positives
+    }
+
+    /// The rate of false positives among lookups of keys which weren't in tables
+    /// This can be compared with `bloom_filter.fp_rate`
+    pub fn bloom_false_positive_rate(&self) -> f64 {
+        let absent = self.bloom_negatives + self.bloom_false_positives;
+        if absent == 0 {
+            0.0
+        } else {
+            self.bloom_false_positives as f64 / absent as f64
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! File operations of the engine
+//!
+//! SSTables, their metadata and leaf files are opened, renamed and removed through
+//! `Storage` of `Config`, so the engine can run over another backend like a wrapper
+//! injecting faults. `FsStorage` is the default implementation by `std::fs`.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+
+use crate::util::file_util;
+
+/// A file opened by `Storage`
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    /// Persist the written data
+    fn sync(&self) -> Result<(), std::io::Error>;
+
+    /// The size of the file in bytes
+    fn size(&self) -> Result<u64, std::io::Error>;
+}
+
+impl StorageFile for File {
+    fn sync(&self) -> Result<(), std::io::Error> {
+        self.sync_all()
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+pub trait Storage: Send + Sync {
+    /// Open the existing file to read it
+    fn open(&self, path: &str) -> Result<Box<dyn StorageFile>, std::io::Error>;
+
+    /// Create a new file to write it, or truncate the existing file
+    fn create(&self, path: &str) -> Result<Box<dyn StorageFile>, std::io::Error>;
+
+    /// Open the file to read and append to it, and create it if it doesn't exist
+    /// Return whether the file has been created
+    fn open_or_create(&self, path: &str) -> Result<(Box<dyn StorageFile>, bool), std::io::Error>;
+
+    /// Open the leaf file to map it to the memory, and create it if it doesn't exist
+    /// Return whether the file has been created
+    /// A leaf file is always a local file since its pages are mapped
+    fn open_leaf_file(&self, path: &str) -> Result<(File, bool), std::io::Error> {
+        file_util::open_file(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), std::io::Error>;
+
+    fn remove(&self, path: &str) -> Result<(), std::io::Error>;
+
+    /// Persist entries of the directory like renamed files
+    fn sync_dir(&self, path: &str) -> Result<(), std::io::Error>;
+}
+
+/// The default `Storage` by `std::fs`
+pub struct FsStorage;
+
+impl Storage for FsStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn StorageFile>, std::io::Error> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn StorageFile>, std::io::Error> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open_or_create(&self, path: &str) -> Result<(Box<dyn StorageFile>, bool), std::io::Error> {
+        let (file, is_created) = file_util::open_file(path)?;
+        Ok((Box::new(file), is_created))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), std::io::Error> {
+        std::fs::remove_file(path)
+    }
+
+    fn sync_dir(&self, path: &str) -> Result<(), std::io::Error> {
+        OpenOptions::new().read(true).open(path)?.sync_all()
+    }
+}
@@ -0,0 +1,201 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+
+use crate::amphis_error::CrudError;
+use crate::keycodec;
+use crate::kvs::{Scan, KVS};
+
+/// Conversion between typed keys and the bytes stored in `KVS`
+///
+/// Keys are ordered by their encoded bytes, so a codec has to preserve the order
+/// of keys for range scans to return the keys in their order.
+pub trait KeyCodec<K> {
+    fn encode(key: &K) -> Result<Vec<u8>, CrudError>;
+    fn decode(bytes: &[u8]) -> Result<K, CrudError>;
+}
+
+/// Encode keys by bincode
+///
+/// Any serializable key can be encoded, but the encoding doesn't preserve the order:
+/// integers are little-endian, e.g. 256 is encoded before 1, and strings are prefixed
+/// with their lengths. Scans with this codec return keys in an arbitrary order.
+pub struct BincodeCodec;
+
+impl<K: Serialize + DeserializeOwned> KeyCodec<K> for BincodeCodec {
+    fn encode(key: &K) -> Result<Vec<u8>, CrudError> {
+        Ok(bincode::serialize(key)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<K, CrudError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Encode integer keys in the order-preserving way by the functions of `keycodec`
+pub struct OrderedIntCodec;
+
+macro_rules! impl_ordered_int_codec {
+    ($(($t:ty, $encode:ident, $decode:ident)),*) => {
+        $(
+            impl KeyCodec<$t> for OrderedIntCodec {
+                fn encode(key: &$t) -> Result<Vec<u8>, CrudError> {
+                    Ok(keycodec::$encode(*key).to_vec())
+                }
+
+                fn decode(bytes: &[u8]) -> Result<$t, CrudError> {
+                    keycodec::$decode(bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_ordered_int_codec!(
+    (u8, encode_u8_be, decode_u8_be),
+    (u16, encode_u16_be, decode_u16_be),
+    (u32, encode_u32_be, decode_u32_be),
+    (u64, encode_u64_be, decode_u64_be),
+    (u128, encode_u128_be, decode_u128_be),
+    (i8, encode_i8_be, decode_i8_be),
+    (i16, encode_i16_be, decode_i16_be),
+    (i32, encode_i32_be, decode_i32_be),
+    (i64, encode_i64_be, decode_i64_be),
+    (i128, encode_i128_be, decode_i128_be)
+);
+
+/// Marker of the types without owning them, which keeps `TypedKvs` `Send` and `Sync`
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// `KVS` with typed keys and values
+///
+/// Values are encoded by bincode, and keys are encoded by the codec `C`.
+/// Use `OrderedIntCodec` for integer keys to scan them in their order.
+pub struct TypedKvs<K, V, C = BincodeCodec> {
+    kvs: KVS,
+    _types: Types<K, V, C>,
+}
+
+impl<K, V, C> TypedKvs<K, V, C>
+where
+    V: Serialize + DeserializeOwned,
+    C: KeyCodec<K>,
+{
+    pub fn new(kvs: KVS) -> Self {
+        TypedKvs {
+            kvs,
+            _types: PhantomData,
+        }
+    }
+
+    /// The underlying store with encoded keys and values
+    pub fn get_kvs(&self) -> &KVS {
+        &self.kvs
+    }
+
+    pub fn into_inner(self) -> KVS {
+        self.kvs
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<(), CrudError> {
+        self.kvs.put(&C::encode(key)?, &bincode::serialize(value)?)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, CrudError> {
+        match self.kvs.get(&C::encode(key)?)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, key: &K) -> Result<(), CrudError> {
+        self.kvs.delete(&C::encode(key)?)
+    }
+
+    /// Scan pairs whose encoded keys are in `[start, end)`
+    pub fn scan(&self, start: &K, end: &K) -> Result<TypedScan<K, V, C>, CrudError> {
+        let scan = self.kvs.scan(&C::encode(start)?, &C::encode(end)?)?;
+
+        Ok(TypedScan {
+            scan,
+            _types: PhantomData,
+        })
+    }
+}
+
+/// Iterator over decoded pairs of `TypedKvs::scan`
+pub struct TypedScan<K, V, C> {
+    scan: Scan,
+    _types: Types<K, V, C>,
+}
+
+impl<K, V, C> Iterator for TypedScan<K, V, C>
+where
+    V: DeserializeOwned,
+    C: KeyCodec<K>,
+{
+    type Item = Result<(K, V), CrudError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let decode = |(key, value): (Vec<u8>, Vec<u8>)| {
+            Ok((C::decode(&key)?, bincode::deserialize(&value)?))
+        };
+
+        Some(self.scan.next()?.and_then(decode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_ordered_int_codec() {
+        let keys = [i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX];
+        let encoded: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|k| OrderedIntCodec::encode(k).unwrap())
+            .collect();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        for (key, bytes) in keys.iter().zip(&encoded) {
+            assert_eq!(
+                <OrderedIntCodec as KeyCodec<i64>>::decode(bytes).unwrap(),
+                *key
+            );
+        }
+
+        let keys = [0u32, 1, 255, 256, u32::MAX];
+        let encoded: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|k| OrderedIntCodec::encode(k).unwrap())
+            .collect();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            <OrderedIntCodec as KeyCodec<u32>>::decode(&encoded[3]).unwrap(),
+            256
+        );
+
+        assert!(matches!(
+            <OrderedIntCodec as KeyCodec<u32>>::decode(&[0, 1]),
+            Err(CrudError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_bincode_codec() {
+        let key = ("user".to_owned(), 42u32);
+        let encoded = BincodeCodec::encode(&key).unwrap();
+        assert_eq!(
+            <BincodeCodec as KeyCodec<(String, u32)>>::decode(&encoded).unwrap(),
+            key
+        );
+
+        // the order isn't preserved
+        let one = BincodeCodec::encode(&1u32).unwrap();
+        let large = BincodeCodec::encode(&256u32).unwrap();
+        assert!(large < one);
+        let bytes: [u8; 4] = one.as_slice().try_into().unwrap();
+        assert_eq!(u32::from_le_bytes(bytes), 1);
+    }
+}
@@ -1,28 +1,70 @@
-use crc::{crc32, Hasher32};
+use crc::crc32;
 use std::convert::TryInto;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-// TODO: parameterize them
-pub const DATA_ALIGNMENT: usize = 1 << 12;
+use crate::amphis_error::CorruptionError;
+use crate::config::Checksum;
+
+/// The unit of leaf sizes and the header regions of leaves
+pub const PAGE_SIZE: usize = 1 << 12;
+/// Key-value pairs in a leaf start at multiples of this size by default
+pub const DEFAULT_DATA_ALIGNMENT: usize = PAGE_SIZE;
 pub const LEN_SIZE: usize = 4;
 pub const LEN_CRC: usize = 4;
 const LEN_REDUNDANCY: usize = LEN_SIZE + LEN_CRC;
 
+pub const FORMAT_VERSION: u8 = 3;
+const LEN_FLAGS: usize = 1;
+const LEN_EXPIRY: usize = 8;
+/// The sequence and the floor
+const LEN_SEQUENCE: usize = 16;
+/// The flags, the expiry and the sequence encoded before a value
+pub const MAX_VALUE_HEADER_SIZE: usize = LEN_FLAGS + LEN_EXPIRY + LEN_SEQUENCE;
+const FLAG_EXPIRY: u8 = 0x01;
+const FLAG_SEQUENCE: u8 = 0x02;
+const FLAG_TOMBSTONE: u8 = 0x04;
+
+// the reversed polynomials
+const CRC32_POLY: u32 = 0xedb8_8320;
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+/// A linear operator on CRCs over GF(2)
+type Gf2Matrix = [u32; 32];
+
+#[cfg(test)]
+thread_local! {
+    /// The number of bytes whose checksums have been computed by this thread
+    pub static CHECKSUM_BYTES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 /*
  * Common data format:
  * | Size (4B) | Data | CRC (4B) |
+ * The CRC is CRC-32 (IEEE) unless the file records `Checksum::Crc32c`.
+ *
+ * Value format (since FORMAT_VERSION 1):
+ * | Flags (1B) | Expiry in milliseconds (8B, when FLAG_EXPIRY is set) |
+ * | Sequence (8B) | Floor (8B) (when FLAG_SEQUENCE is set) | Value |
+ * A tombstone is an empty data without flags in any version.
+ * Since version 3, a tombstone stamped with its sequence has FLAG_TOMBSTONE instead.
+ * The floor is the smallest sequence of the versions of the key which the entry
+ * overwrote in the same FPTree or table. A value without the sequence has the sequence 0.
+ * An empty value has the flags, but version 1 handled it as a deletion.
+ * Version 0 stores only the value, and an empty value is a tombstone.
  */
 
-pub fn format_data_with_crc(key: &[u8], value: &[u8]) -> Vec<u8> {
+pub fn format_data_with_crc(key: &[u8], value: &[u8], checksum: Checksum) -> Vec<u8> {
     let data_size = get_data_size(key.len(), value.len());
     let mut data: Vec<u8> = Vec::with_capacity(data_size);
 
-    let crc = calc_crc(key).to_le_bytes();
+    let crc = calc_checksum(checksum, key).to_le_bytes();
     data.extend(&(key.len() as u32).to_le_bytes());
     data.extend(key);
     data.extend(crc);
 
-    let crc = calc_crc(value).to_le_bytes();
+    let crc = calc_checksum(checksum, value).to_le_bytes();
     data.extend(&(value.len() as u32).to_le_bytes());
     data.extend(value);
     data.extend(crc);
@@ -30,8 +72,88 @@ pub fn format_data_with_crc(key: &[u8], value: &[u8]) -> Vec<u8> {
     data
 }
 
-pub fn round_up_size(size: usize) -> usize {
-    ((size + DATA_ALIGNMENT - 1) / DATA_ALIGNMENT) * DATA_ALIGNMENT
+pub fn format_bytes_with_crc(bytes: &[u8]) -> Vec<u8> {
+    format_bytes_with_checksum(bytes, Checksum::Crc32)
+}
+
+pub fn format_bytes_with_checksum(bytes: &[u8], checksum: Checksum) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::with_capacity(bytes.len() + LEN_REDUNDANCY);
+    data.extend(&(bytes.len() as u32).to_le_bytes());
+    data.extend(bytes);
+    data.extend(&calc_checksum(checksum, bytes).to_le_bytes());
+
+    data
+}
+
+/// Format the bytes like `format_bytes_with_checksum` and return them with the checksum of the
+/// formatted bytes
+/// The checksum is derived from the CRC of `bytes` without reading them again
+pub fn format_bytes_with_checksums(bytes: &[u8], checksum: Checksum) -> (Vec<u8>, u32) {
+    let size = (bytes.len() as u32).to_le_bytes();
+    let crc = calc_checksum(checksum, bytes).to_le_bytes();
+    let mut data: Vec<u8> = Vec::with_capacity(bytes.len() + LEN_REDUNDANCY);
+    data.extend(&size);
+    data.extend(bytes);
+    data.extend(&crc);
+
+    let formatted_crc = combine_checksum(
+        checksum,
+        calc_checksum(checksum, &size),
+        u32::from_le_bytes(crc),
+        bytes.len(),
+    );
+    let formatted_crc = update_checksum(checksum, formatted_crc, &crc);
+
+    (data, formatted_crc)
+}
+
+/// Read the bytes formatted by `format_bytes_with_crc`
+/// Return `None` at the end of the reader
+pub fn read_bytes_with_crc(reader: &mut impl Read) -> Result<Option<Vec<u8>>, std::io::Error> {
+    read_bytes_with_checksum(reader, Checksum::Crc32)
+}
+
+/// Read the bytes formatted by `format_bytes_with_checksum` with the same algorithm
+pub fn read_bytes_with_checksum(
+    reader: &mut impl Read,
+    checksum: Checksum,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut size_buf = [0_u8; LEN_SIZE];
+    let len = reader.read(&mut size_buf)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    // the record was partially written, e.g. the file was truncated by a crash
+    // `ErrorKind::UnexpectedEof` is kept to stop reading at the last complete record
+    let truncated = |e: std::io::Error| match e.kind() {
+        ErrorKind::UnexpectedEof => std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            CorruptionError("the last record is truncated".to_owned()),
+        ),
+        _ => e,
+    };
+    // the reader might return a part of the size
+    reader.read_exact(&mut size_buf[len..]).map_err(truncated)?;
+    let size = u32::from_le_bytes(size_buf) as usize;
+
+    // the buffer grows with the read bytes, so a broken size doesn't allocate at once
+    let mut data = Vec::new();
+    reader.take(size as u64).read_to_end(&mut data)?;
+    if data.len() != size {
+        return Err(truncated(ErrorKind::UnexpectedEof.into()));
+    }
+
+    let mut crc_buf = [0_u8; LEN_CRC];
+    reader.read_exact(&mut crc_buf).map_err(truncated)?;
+    let crc = u32::from_le_bytes(crc_buf);
+
+    check_checksum(checksum, data.as_slice(), crc)?;
+
+    Ok(Some(data))
+}
+
+pub fn round_up_size(size: usize, alignment: usize) -> usize {
+    size.div_ceil(alignment) * alignment
 }
 
 pub fn get_key_offset(key_size: usize) -> (usize, usize) {
@@ -56,34 +178,551 @@ pub fn get_bound_offset(key_size: usize) -> usize {
     key_size + LEN_REDUNDANCY
 }
 
-pub fn calc_crc(data: &[u8]) -> u32 {
-    let mut digest = crc32::Digest::new(crc32::IEEE);
-    digest.write(data);
+pub fn calc_checksum(checksum: Checksum, data: &[u8]) -> u32 {
+    update_checksum(checksum, 0, data)
+}
+
+/// Extend the checksum `crc` of the preceding bytes with `data`
+pub fn update_checksum(checksum: Checksum, crc: u32, data: &[u8]) -> u32 {
+    #[cfg(test)]
+    CHECKSUM_BYTES.with(|bytes| bytes.set(bytes.get() + data.len()));
+    match checksum {
+        Checksum::Crc32 => crc32::update(crc, &crc32::IEEE_TABLE, data),
+        Checksum::Crc32c => crc32c::crc32c_append(crc, data),
+    }
+}
 
-    digest.sum32()
+/// Return the checksum of bytes A followed by bytes B from `crc1` of A, `crc2` of B and the
+/// length of B, without reading the bytes
+pub fn combine_checksum(checksum: Checksum, crc1: u32, crc2: u32, len2: usize) -> u32 {
+    static CRC32_ZEROS: OnceLock<Vec<Gf2Matrix>> = OnceLock::new();
+    static CRC32C_ZEROS: OnceLock<Vec<Gf2Matrix>> = OnceLock::new();
+    let zeros = match checksum {
+        Checksum::Crc32 => CRC32_ZEROS.get_or_init(|| make_zeros_operators(CRC32_POLY)),
+        Checksum::Crc32c => CRC32C_ZEROS.get_or_init(|| make_zeros_operators(CRC32C_POLY)),
+    };
+
+    // append zeros of the length of B to A
+    let mut crc = crc1;
+    let mut len = len2;
+    for operator in zeros {
+        if len == 0 {
+            break;
+        }
+        if len & 1 != 0 {
+            crc = gf2_matrix_times(operator, crc);
+        }
+        len >>= 1;
+    }
+
+    crc ^ crc2
 }
 
-pub fn check_crc(data: &[u8], crc: u32) -> Result<(), std::io::Error> {
-    if calc_crc(data) == crc {
+/// Return the operators which append 2^i zero bytes to a CRC for each i
+fn make_zeros_operators(poly: u32) -> Vec<Gf2Matrix> {
+    // the operator for a zero bit
+    let mut operator = [0u32; 32];
+    operator[0] = poly;
+    for (n, row) in operator.iter_mut().enumerate().skip(1) {
+        *row = 1 << (n - 1);
+    }
+    // 8 bits
+    for _ in 0..3 {
+        operator = gf2_matrix_square(&operator);
+    }
+
+    let mut operators = Vec::with_capacity(usize::BITS as usize);
+    for _ in 0..usize::BITS {
+        operators.push(operator);
+        operator = gf2_matrix_square(&operator);
+    }
+
+    operators
+}
+
+fn gf2_matrix_times(matrix: &Gf2Matrix, mut vec: u32) -> u32 {
+    let mut sum = 0;
+    for row in matrix {
+        if vec == 0 {
+            break;
+        }
+        if vec & 1 != 0 {
+            sum ^= row;
+        }
+        vec >>= 1;
+    }
+
+    sum
+}
+
+fn gf2_matrix_square(matrix: &Gf2Matrix) -> Gf2Matrix {
+    let mut square = [0u32; 32];
+    for (row, &vec) in square.iter_mut().zip(matrix) {
+        *row = gf2_matrix_times(matrix, vec);
+    }
+
+    square
+}
+
+pub fn check_checksum(checksum: Checksum, data: &[u8], crc: u32) -> Result<(), std::io::Error> {
+    if calc_checksum(checksum, data) == crc {
         Ok(())
     } else {
-        // TODO: replace with an amphis error
-        Err(std::io::Error::new(ErrorKind::Other, "CRC check failed!"))
+        Err(CorruptionError("CRC check failed".to_owned()).into())
     }
 }
 
-pub fn check_slot_crc(bytes: &[u8]) -> Result<(), std::io::Error> {
+/// Check the CRC of a key or a value formatted by `format_data_with_crc`
+/// A size which doesn't fit in `bytes` is reported as a corruption
+pub fn check_slot_crc(bytes: &[u8], checksum: Checksum) -> Result<(), std::io::Error> {
     let len = bytes.len();
-    let size = u32::from_le_bytes(bytes[0..LEN_SIZE].try_into().unwrap());
+    if len < LEN_REDUNDANCY {
+        return Err(CorruptionError(format!("the slot of {} bytes is too short", len)).into());
+    }
+    let size = u32::from_le_bytes(bytes[0..LEN_SIZE].try_into().unwrap()) as usize;
+    if size > len - LEN_REDUNDANCY {
+        return Err(CorruptionError(format!(
+            "the size {} exceeds the slot of {} bytes",
+            size, len
+        ))
+        .into());
+    }
     let crc = u32::from_le_bytes(bytes[(len - LEN_CRC)..].try_into().unwrap());
 
-    check_crc(&bytes[LEN_SIZE..(LEN_SIZE + size as usize)], crc)
+    check_checksum(checksum, &bytes[LEN_SIZE..(LEN_SIZE + size)], crc)
 }
 
-pub fn check_header_crc(bytes: &[u8]) -> Result<(), std::io::Error> {
+pub fn check_header_crc(bytes: &[u8], checksum: Checksum) -> Result<(), std::io::Error> {
     let len = bytes.len();
     let (data, crc_buf) = bytes.split_at(len - LEN_CRC);
     let crc = u32::from_le_bytes(crc_buf.try_into().unwrap());
 
-    check_crc(data, crc)
+    check_checksum(checksum, data, crc)
+}
+
+pub fn encode_value(value: &[u8], expiry: Option<u64>) -> Vec<u8> {
+    let mut data = Vec::with_capacity(LEN_FLAGS + LEN_EXPIRY + value.len());
+    match expiry {
+        Some(expiry) => {
+            data.push(FLAG_EXPIRY);
+            data.extend(&expiry.to_le_bytes());
+        }
+        None => data.push(0),
+    }
+    data.extend(value);
+
+    data
+}
+
+/// Return the expiry and the value of an encoded value
+/// The value of a tombstone stamped with its sequence is empty
+pub fn decode_value(data: &[u8]) -> Result<(Option<u64>, &[u8]), std::io::Error> {
+    let (flags, rest) = data
+        .split_first()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "no value flags"))?;
+    let (expiry, rest) = if flags & FLAG_EXPIRY == 0 {
+        (None, rest)
+    } else {
+        if rest.len() < LEN_EXPIRY {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "the value expiry is broken",
+            ));
+        }
+        let (expiry, rest) = rest.split_at(LEN_EXPIRY);
+        (Some(u64::from_le_bytes(expiry.try_into().unwrap())), rest)
+    };
+    if flags & FLAG_SEQUENCE == 0 {
+        return Ok((expiry, rest));
+    }
+    if rest.len() < LEN_SEQUENCE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "the value sequence is broken",
+        ));
+    }
+
+    Ok((expiry, &rest[LEN_SEQUENCE..]))
+}
+
+/// Whether the stored data is a tombstone
+pub fn is_tombstone(data: &[u8]) -> bool {
+    data.first().is_none_or(|flags| flags & FLAG_TOMBSTONE != 0)
+}
+
+/// The size of the data after it's stamped by `stamp_sequence`
+pub fn get_stamped_size(data: &[u8]) -> usize {
+    match data.first() {
+        Some(flags) if flags & FLAG_SEQUENCE != 0 => data.len(),
+        Some(_) => data.len() + LEN_SEQUENCE,
+        None => LEN_FLAGS + LEN_SEQUENCE,
+    }
+}
+
+/// Stamp the encoded value or the empty tombstone with the sequence of the write
+/// The floor is the sequence itself until the entry overwrites another version
+pub fn stamp_sequence(data: &[u8], sequence: u64) -> Vec<u8> {
+    let mut stamped = Vec::with_capacity(get_stamped_size(data));
+    let (flags, rest) = match data.split_first() {
+        Some((flags, rest)) => (*flags & !FLAG_SEQUENCE, rest),
+        None => (FLAG_TOMBSTONE, data),
+    };
+    stamped.push(flags | FLAG_SEQUENCE);
+    let (expiry, rest) = if flags & FLAG_EXPIRY == 0 {
+        (&rest[..0], rest)
+    } else {
+        rest.split_at(LEN_EXPIRY.min(rest.len()))
+    };
+    stamped.extend(expiry);
+    stamped.extend(&sequence.to_le_bytes());
+    stamped.extend(&sequence.to_le_bytes());
+    stamped.extend(rest);
+
+    stamped
+}
+
+/// Return the offset of the sequence in the stamped data
+fn get_sequence_offset(data: &[u8]) -> Result<Option<usize>, std::io::Error> {
+    let flags = match data.first() {
+        Some(flags) if flags & FLAG_SEQUENCE != 0 => flags,
+        _ => return Ok(None),
+    };
+    let offset = if flags & FLAG_EXPIRY == 0 {
+        LEN_FLAGS
+    } else {
+        LEN_FLAGS + LEN_EXPIRY
+    };
+    if data.len() < offset + LEN_SEQUENCE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "the value sequence is broken",
+        ));
+    }
+
+    Ok(Some(offset))
+}
+
+/// Return the sequence and the floor of the stored data
+/// Data written without the sequence has the sequence 0
+pub fn get_sequence(data: &[u8]) -> Result<(u64, u64), std::io::Error> {
+    match get_sequence_offset(data)? {
+        Some(offset) => {
+            let sequence = &data[offset..(offset + LEN_SEQUENCE)];
+            let (sequence, floor) = sequence.split_at(LEN_SEQUENCE / 2);
+            Ok((
+                u64::from_le_bytes(sequence.try_into().unwrap()),
+                u64::from_le_bytes(floor.try_into().unwrap()),
+            ))
+        }
+        None => Ok((0, 0)),
+    }
+}
+
+/// Lower the floor of the stamped data to the floor of the older version which it overwrites
+pub fn merge_floor(data: &mut [u8], older: &[u8]) -> Result<(), std::io::Error> {
+    let Some(offset) = get_sequence_offset(data)? else {
+        return Ok(());
+    };
+    let (_, older_floor) = get_sequence(older)?;
+    let floor = &mut data[(offset + LEN_SEQUENCE / 2)..(offset + LEN_SEQUENCE)];
+    if older_floor < u64::from_le_bytes((&*floor).try_into().unwrap()) {
+        floor.copy_from_slice(&older_floor.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+/// Convert the stored data to a tombstone keeping its sequence
+pub fn to_tombstone(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    match get_sequence_offset(data)? {
+        Some(offset) => {
+            let mut tombstone = Vec::with_capacity(LEN_FLAGS + LEN_SEQUENCE);
+            tombstone.push(FLAG_SEQUENCE | FLAG_TOMBSTONE);
+            tombstone.extend(&data[offset..(offset + LEN_SEQUENCE)]);
+            Ok(tombstone)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The version of a key which an FPTree or a table has at a sequence
+#[derive(Debug, PartialEq)]
+pub enum VersionAt {
+    /// The stored data, which might be a tombstone
+    Found(Vec<u8>),
+    /// No version at the sequence, so older FPTrees and tables are read
+    NotFound,
+    /// The version at the sequence has been overwritten or deleted by a range tombstone
+    Unavailable,
+}
+
+/// Select the version at `sequence` from the entry of the key in an FPTree or a table
+/// A range tombstone has no sequence, so a covered key is unavailable unless the entry
+/// is old enough
+pub fn select_version(
+    entry: Option<Vec<u8>>,
+    range_deleted: bool,
+    sequence: u64,
+) -> Result<VersionAt, std::io::Error> {
+    let Some(data) = entry else {
+        return Ok(if range_deleted {
+            VersionAt::Unavailable
+        } else {
+            VersionAt::NotFound
+        });
+    };
+    let (entry_sequence, floor) = get_sequence(&data)?;
+    if entry_sequence <= sequence {
+        Ok(VersionAt::Found(data))
+    } else if floor <= sequence || range_deleted {
+        Ok(VersionAt::Unavailable)
+    } else {
+        Ok(VersionAt::NotFound)
+    }
+}
+
+/// Convert a stored value written in `version` to the current format
+pub fn upgrade_value(version: u8, data: Vec<u8>) -> Vec<u8> {
+    // version 2 differs only in values stamped with their sequences
+    if version >= 2 || data.is_empty() {
+        return data;
+    }
+    if version == 0 {
+        return encode_value(&data, None);
+    }
+
+    match decode_value(&data) {
+        // the empty value was a deletion
+        Ok((_, [])) => Vec::new(),
+        _ => data,
+    }
+}
+
+/// Return the value unless the stored data is a tombstone or expired at `now`
+pub fn get_live_value(data: &[u8], now: u64) -> Result<Option<&[u8]>, std::io::Error> {
+    if is_tombstone(data) {
+        return Ok(None);
+    }
+
+    let (expiry, value) = decode_value(data)?;
+    match expiry {
+        Some(expiry) if expiry <= now => Ok(None),
+        _ => Ok(Some(value)),
+    }
+}
+
+/// Return `ErrorKind::TimedOut` if the deadline has passed
+/// It's converted to `CrudError::TimedOut`
+pub fn check_deadline(deadline: Option<Instant>) -> Result<(), std::io::Error> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            "the deadline has passed",
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn current_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the system time is before UNIX epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // the check values of the algorithms
+        assert_eq!(calc_checksum(Checksum::Crc32, b"123456789"), 0xcbf4_3926);
+        assert_eq!(calc_checksum(Checksum::Crc32c, b"123456789"), 0xe306_9283);
+        for checksum in [Checksum::Crc32, Checksum::Crc32c] {
+            let crc = update_checksum(checksum, calc_checksum(checksum, b"1234"), b"56789");
+            assert_eq!(crc, calc_checksum(checksum, b"123456789"));
+        }
+
+        let data = format_data_with_crc(b"key", b"value", Checksum::Crc32c);
+        let bound_offset = get_bound_offset(3);
+        assert!(check_slot_crc(&data[..bound_offset], Checksum::Crc32c).is_ok());
+        assert!(check_slot_crc(&data[bound_offset..], Checksum::Crc32c).is_ok());
+        assert!(check_slot_crc(&data[..bound_offset], Checksum::Crc32).is_err());
+
+        let data = format_bytes_with_crc(b"bytes");
+        assert!(read_bytes_with_checksum(&mut data.as_slice(), Checksum::Crc32c).is_err());
+        let data = format_bytes_with_checksum(b"bytes", Checksum::Crc32c);
+        assert_eq!(
+            read_bytes_with_checksum(&mut data.as_slice(), Checksum::Crc32c).unwrap(),
+            Some(b"bytes".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_corrupt_size() {
+        let data = format_data_with_crc(b"key", b"value", Checksum::Crc32);
+        let bound_offset = get_bound_offset(3);
+        for size in [4u32, 100, u32::MAX] {
+            let mut key = data[..bound_offset].to_vec();
+            key[..LEN_SIZE].copy_from_slice(&size.to_le_bytes());
+            let err = check_slot_crc(&key, Checksum::Crc32).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        // a smaller size is detected by the CRC
+        let mut key = data[..bound_offset].to_vec();
+        key[..LEN_SIZE].copy_from_slice(&2u32.to_le_bytes());
+        assert!(check_slot_crc(&key, Checksum::Crc32).is_err());
+        // no room for the size and the CRC
+        for len in [0, LEN_SIZE, LEN_REDUNDANCY - 1] {
+            let err = check_slot_crc(&data[..len], Checksum::Crc32).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        // a huge size is read as a truncated record without allocating its buffer
+        let mut data = format_bytes_with_crc(b"bytes");
+        data[..LEN_SIZE].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_bytes_with_crc(&mut data.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn test_combine_checksum() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for checksum in [Checksum::Crc32, Checksum::Crc32c] {
+            let expected = calc_checksum(checksum, &data);
+            for split in [0, 1, 7, 4096, 9999, 10000] {
+                let (a, b) = data.split_at(split);
+                let crc = combine_checksum(
+                    checksum,
+                    calc_checksum(checksum, a),
+                    calc_checksum(checksum, b),
+                    b.len(),
+                );
+                assert_eq!(crc, expected);
+            }
+
+            let (formatted, crc) = format_bytes_with_checksums(&data, checksum);
+            assert_eq!(formatted, format_bytes_with_checksum(&data, checksum));
+            assert_eq!(crc, calc_checksum(checksum, &formatted));
+        }
+    }
+
+    #[test]
+    fn test_encode_value() {
+        let data = encode_value(b"value", None);
+        assert_eq!(decode_value(&data).unwrap(), (None, &b"value"[..]));
+
+        let data = encode_value(b"value", Some(1234));
+        assert_eq!(decode_value(&data).unwrap(), (Some(1234), &b"value"[..]));
+
+        assert!(decode_value(&[]).is_err());
+        assert!(decode_value(&[FLAG_EXPIRY, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_value() {
+        let data = upgrade_value(0, b"value".to_vec());
+        assert_eq!(decode_value(&data).unwrap(), (None, &b"value"[..]));
+
+        // a tombstone is kept
+        assert!(upgrade_value(0, Vec::new()).is_empty());
+        assert!(upgrade_value(1, Vec::new()).is_empty());
+
+        // an empty value of the version 1 is a tombstone
+        assert!(upgrade_value(1, encode_value(b"", None)).is_empty());
+        assert!(upgrade_value(1, encode_value(b"", Some(1234))).is_empty());
+        let data = encode_value(b"value", Some(1234));
+        assert_eq!(upgrade_value(1, data.clone()), data);
+        let data = encode_value(b"", None);
+        assert_eq!(upgrade_value(FORMAT_VERSION, data.clone()), data);
+
+        let data = encode_value(b"value", Some(1234));
+        assert_eq!(upgrade_value(FORMAT_VERSION, data.clone()), data);
+    }
+
+    #[test]
+    fn test_get_live_value() {
+        let data = encode_value(b"value", Some(1000));
+        assert_eq!(get_live_value(&data, 999).unwrap(), Some(&b"value"[..]));
+        assert_eq!(get_live_value(&data, 1000).unwrap(), None);
+
+        let data = encode_value(b"value", None);
+        assert_eq!(
+            get_live_value(&data, u64::MAX).unwrap(),
+            Some(&b"value"[..])
+        );
+
+        assert_eq!(get_live_value(&[], 0).unwrap(), None);
+        assert_eq!(get_live_value(&stamp_sequence(&[], 1), 0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_stamp_sequence() {
+        let data = stamp_sequence(&encode_value(b"value", Some(1234)), 7);
+        assert_eq!(
+            data.len(),
+            get_stamped_size(&encode_value(b"value", Some(1234)))
+        );
+        assert_eq!(decode_value(&data).unwrap(), (Some(1234), &b"value"[..]));
+        assert_eq!(get_sequence(&data).unwrap(), (7, 7));
+        assert!(!is_tombstone(&data));
+
+        // the floor is lowered only by an older floor
+        let mut data = stamp_sequence(&encode_value(b"value", None), 7);
+        merge_floor(&mut data, &stamp_sequence(&[], 3)).unwrap();
+        assert_eq!(get_sequence(&data).unwrap(), (7, 3));
+        merge_floor(&mut data, &stamp_sequence(&[], 5)).unwrap();
+        assert_eq!(get_sequence(&data).unwrap(), (7, 3));
+        assert_eq!(decode_value(&data).unwrap(), (None, &b"value"[..]));
+
+        let tombstone = to_tombstone(&data).unwrap();
+        assert!(is_tombstone(&tombstone));
+        assert_eq!(get_sequence(&tombstone).unwrap(), (7, 3));
+        assert_eq!(tombstone.len(), get_stamped_size(&[]));
+
+        // a value written without the sequence
+        let data = encode_value(b"value", None);
+        assert_eq!(get_sequence(&data).unwrap(), (0, 0));
+        assert_eq!(get_sequence(&[]).unwrap(), (0, 0));
+        assert!(to_tombstone(&data).unwrap().is_empty());
+        assert!(get_sequence(&[FLAG_SEQUENCE, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_select_version() {
+        let mut data = stamp_sequence(&encode_value(b"new", None), 5);
+        merge_floor(&mut data, &stamp_sequence(&encode_value(b"old", None), 3)).unwrap();
+        assert_eq!(
+            select_version(Some(data.clone()), false, 5).unwrap(),
+            VersionAt::Found(data.clone())
+        );
+        // the version at 3 or 4 has been overwritten
+        assert_eq!(
+            select_version(Some(data.clone()), false, 4).unwrap(),
+            VersionAt::Unavailable
+        );
+        // the key didn't exist in this layer at 2
+        assert_eq!(
+            select_version(Some(data.clone()), false, 2).unwrap(),
+            VersionAt::NotFound
+        );
+        assert_eq!(
+            select_version(Some(data), true, 2).unwrap(),
+            VersionAt::Unavailable
+        );
+
+        // an old value has the sequence 0
+        let data = encode_value(b"value", None);
+        assert_eq!(
+            select_version(Some(data.clone()), false, 0).unwrap(),
+            VersionAt::Found(data)
+        );
+        assert_eq!(select_version(None, false, 1).unwrap(), VersionAt::NotFound);
+        assert_eq!(
+            select_version(None, true, 1).unwrap(),
+            VersionAt::Unavailable
+        );
+    }
 }
@@ -36,10 +36,47 @@ pub fn get_table_id(path: &Path) -> Option<usize> {
     get_id(path, "sstable-")
 }
 
+/// The ID of the temporary file of a table being written
+pub fn get_tmp_table_id(path: &Path) -> Option<usize> {
+    if path.extension()? != "tmp" {
+        return None;
+    }
+    get_table_id(&path.with_extension(""))
+}
+
+/// The ID of the table which a segment being written concurrently belongs to
+pub fn get_segment_table_id(path: &Path) -> Option<usize> {
+    if path.extension()? != "segment" {
+        return None;
+    }
+    // the segment ID is between the table file name and the extension
+    get_table_id(&path.with_extension("").with_extension(""))
+}
+
+/// The ID of a table quarantined because of its corruption
+pub fn get_quarantined_table_id(path: &Path) -> Option<usize> {
+    if path.extension()? != "quarantine" {
+        return None;
+    }
+    get_table_id(&path.with_extension(""))
+}
+
 pub fn get_tree_id(path: &Path) -> Option<usize> {
     get_id(path, "leaves-")
 }
 
+pub fn get_obsolete_tree_id(path: &Path) -> Option<usize> {
+    get_id(path, "obsolete-leaves-")
+}
+
+pub fn get_range_tombstone_tree_id(path: &Path) -> Option<usize> {
+    get_id(path, "range-tombstones-")
+}
+
+pub fn get_wal_tree_id(path: &Path) -> Option<usize> {
+    get_id(path, "wal-")
+}
+
 fn get_id(path: &Path, prefix: &str) -> Option<usize> {
     let file = path
         .file_stem()
@@ -0,0 +1,65 @@
+use log::warn;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/*
+ * Locks are acquired even if another thread panicked while holding them.
+ * A panic in one operation, e.g. for a broken leaf, shouldn't make
+ * all subsequent operations panic.
+ */
+
+pub trait MutexExt<T: ?Sized> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+pub trait RwLockExt<T: ?Sized> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T: ?Sized> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(recover)
+    }
+}
+
+impl<T: ?Sized> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(recover)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(recover)
+    }
+}
+
+fn recover<G>(e: PoisonError<G>) -> G {
+    warn!("recovered a lock poisoned by a panicked thread");
+    e.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_recover_lock() {
+        let lock = Arc::new(RwLock::new(1));
+        let mutex = Arc::new(Mutex::new(1));
+        let (l, m) = (lock.clone(), mutex.clone());
+        let result = std::thread::spawn(move || {
+            let _r = l.write().unwrap();
+            let _m = m.lock().unwrap();
+            panic!("panic while holding locks");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert!(mutex.is_poisoned());
+
+        *lock.write_or_recover() += 1;
+        assert_eq!(*lock.read_or_recover(), 2);
+        *mutex.lock_or_recover() += 1;
+        assert_eq!(*mutex.lock_or_recover(), 2);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod data_util;
 pub mod file_util;
+pub mod lock_util;
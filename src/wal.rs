@@ -0,0 +1,179 @@
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::WalSync;
+use crate::fptree::KvPair;
+use crate::util::data_util::{self, LEN_CRC, LEN_SIZE};
+use crate::util::file_util;
+use crate::util::lock_util::MutexExt;
+
+/*
+ * WAL format:
+ * | Record | Record | ... |
+ * Each record is a bincode-serialized `Vec<(key, value)>` formatted by `format_bytes_with_crc`.
+ * A put is a record of one pair, and a write batch is a record of all its pairs.
+ * A record written partially by a crash ends the log, so a batch is replayed entirely or not at all.
+ */
+
+/// Write-ahead log of mutations to an FPTree
+///
+/// Each mutation is appended before it's applied to the FPTree, and the log is
+/// removed after the FPTree has been flushed.
+pub struct Wal {
+    file_path: String,
+    writer: Mutex<WalWriter>,
+    sync: WalSync,
+}
+
+struct WalWriter {
+    file: File,
+    last_sync: Instant,
+}
+
+impl Wal {
+    /// Open the log to append mutations, or create it if it doesn't exist
+    pub fn open(file_path: &str, sync: WalSync) -> Result<Self, std::io::Error> {
+        let (file, _) = file_util::open_file(file_path)?;
+
+        Ok(Wal {
+            file_path: file_path.to_string(),
+            writer: Mutex::new(WalWriter {
+                file,
+                last_sync: Instant::now(),
+            }),
+            sync,
+        })
+    }
+
+    /// Append the pairs as one record
+    pub fn append(&self, kv_pairs: &[(&[u8], &[u8])]) -> Result<(), std::io::Error> {
+        let encoded = bincode::serialize(kv_pairs).expect("serializing the record failed");
+        let record = data_util::format_bytes_with_crc(&encoded);
+
+        let mut writer = self.writer.lock_or_recover();
+        // a record is written at once not to be lost by a process crash
+        writer.file.write_all(&record)?;
+        let need_sync = match self.sync {
+            WalSync::Always => true,
+            WalSync::Interval(ms) => writer.last_sync.elapsed() >= Duration::from_millis(ms),
+            WalSync::Never => false,
+        };
+        if need_sync {
+            writer.file.sync_data()?;
+            writer.last_sync = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Remove the log after the mutations have been persisted in an SSTable
+    pub fn remove(&self) -> Result<(), std::io::Error> {
+        remove(&self.file_path)
+    }
+}
+
+/// Load all mutations in the log in the written order
+/// The pairs of a record are loaded together, so a torn batch isn't loaded at all
+/// The broken record at the end is truncated to append new records after the valid ones
+pub fn replay(file_path: &str) -> Result<Vec<KvPair>, std::io::Error> {
+    let mut kv_pairs = Vec::new();
+    if !Path::new(file_path).exists() {
+        return Ok(kv_pairs);
+    }
+
+    let file = OpenOptions::new().read(true).write(true).open(file_path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(&file);
+    let mut valid_size = 0;
+    loop {
+        match data_util::read_bytes_with_crc(&mut reader) {
+            Ok(Some(bytes)) => {
+                let record: Vec<KvPair> = bincode::deserialize(&bytes)
+                    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+                kv_pairs.extend(record);
+                valid_size += (LEN_SIZE + bytes.len() + LEN_CRC) as u64;
+            }
+            Ok(None) => break,
+            // a crash while appending the record
+            Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData) => {
+                warn!(
+                    "WAL {} is truncated at {} of {} bytes: {}",
+                    file_path, valid_size, file_size, e
+                );
+                file.set_len(valid_size)?;
+                file.sync_all()?;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(kv_pairs)
+}
+
+/// Remove the log if it exists
+pub fn remove(file_path: &str) -> Result<(), std::io::Error> {
+    match std::fs::remove_file(file_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_replay() {
+        let config = Config::new_for_testing();
+        std::fs::create_dir_all(config.get_leaf_dir_path("test")).unwrap();
+        let path = config.get_wal_file_path("test", 0);
+        assert!(replay(&path).unwrap().is_empty());
+
+        let wal = Wal::open(&path, WalSync::Always).unwrap();
+        wal.append(&[(b"a", b"a0")]).unwrap();
+        wal.append(&[(b"b", b"")]).unwrap();
+        wal.append(&[(b"a", b"a1")]).unwrap();
+        drop(wal);
+        let expected = vec![
+            (b"a".to_vec(), b"a0".to_vec()),
+            (b"b".to_vec(), Vec::new()),
+            (b"a".to_vec(), b"a1".to_vec()),
+        ];
+        assert_eq!(replay(&path).unwrap(), expected);
+
+        // a crash while appending a batch
+        let valid_size = std::fs::metadata(&path).unwrap().len();
+        let batch: Vec<(&[u8], &[u8])> = vec![(b"c", b"c0"), (b"d", b"d0"), (b"e", b"")];
+        let encoded = bincode::serialize(&batch).unwrap();
+        let record = data_util::format_bytes_with_crc(&encoded);
+        let (mut file, _) = file_util::open_file(&path).unwrap();
+        file.write_all(&record[..record.len() - 3]).unwrap();
+        drop(file);
+        assert_eq!(replay(&path).unwrap(), expected);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_size);
+
+        // new records follow the valid ones
+        let wal = Wal::open(&path, WalSync::Interval(1000)).unwrap();
+        wal.append(&[(b"c", b"c1")]).unwrap();
+        wal.append(&batch).unwrap();
+        drop(wal);
+        let kv_pairs = replay(&path).unwrap();
+        assert_eq!(kv_pairs.len(), 7);
+        assert_eq!(kv_pairs[3], (b"c".to_vec(), b"c1".to_vec()));
+        assert_eq!(kv_pairs[4], (b"c".to_vec(), b"c0".to_vec()));
+        assert_eq!(kv_pairs[6], (b"e".to_vec(), Vec::new()));
+
+        let wal = Wal::open(&path, WalSync::Never).unwrap();
+        wal.remove().unwrap();
+        assert!(!Path::new(&path).exists());
+        assert!(replay(&path).unwrap().is_empty());
+        // removing twice is fine
+        remove(&path).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+use crate::util::data_util;
+
+/// A set of mutations applied atomically by `KVS::write`
+#[derive(Default)]
+pub struct WriteBatch {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.entries
+            .push((key.to_vec(), data_util::encode_value(value, None)));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        // just add a tombstone
+        self.entries.push((key.to_vec(), Vec::new()));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn entries(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.entries
+    }
+}
@@ -0,0 +1,153 @@
+//! Crash-recovery tests with injected faults
+//! Run with `cargo test --features fault-injection`
+use amphis::config::Config;
+use amphis::kvs::{FaultInjector, FaultPoint, WriteBatch, KVS};
+use std::sync::Arc;
+
+const NUM_INSERTION: u32 = 2000;
+
+fn value(i: u32) -> Vec<u8> {
+    format!("value{}", i).into_bytes()
+}
+
+fn open_with_faults(name: &str, dir: &std::path::Path) -> (KVS, Config, Arc<FaultInjector>) {
+    let injector = Arc::new(FaultInjector::default());
+    let config = Config::builder()
+        .leaf_dir(dir.to_str().unwrap())
+        .table_dir(dir.to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .block_size(256)
+        .fault_injector(injector.clone())
+        .build();
+    let kvs = KVS::new(name, config.clone()).unwrap();
+
+    (kvs, config, injector)
+}
+
+fn put_all(kvs: &KVS) {
+    for i in 0..NUM_INSERTION {
+        kvs.put(&i.to_be_bytes(), &value(i)).unwrap();
+    }
+}
+
+/// Stop the database without shutting down like a crashed process
+fn crash(kvs: KVS, name: &str, config: &Config) {
+    std::mem::forget(kvs);
+    std::fs::remove_file(config.get_lock_file_path(name)).unwrap();
+}
+
+/// Reopen the database without faults, and check that it has all acknowledged pairs
+fn reopen_and_check(name: &str, dir: &std::path::Path, num_acked: u32) {
+    let config = Config::builder()
+        .leaf_dir(dir.to_str().unwrap())
+        .table_dir(dir.to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = KVS::new(name, config.clone()).unwrap();
+    for i in 0..num_acked {
+        assert_eq!(kvs.get(&i.to_be_bytes()).unwrap(), Some(value(i)));
+    }
+    let keys: Vec<Vec<u8>> = kvs.iter().unwrap().map(|r| r.unwrap().0).collect();
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    // the failed write might have been logged before the fault
+    let num_keys = keys.len() as u32;
+    assert!(num_keys == num_acked || num_keys == num_acked + 1);
+
+    // no partial table remains
+    let has_tmp_file = std::fs::read_dir(config.get_table_dir_path(name))
+        .unwrap()
+        .any(|entry| entry.unwrap().path().extension().unwrap_or_default() == "tmp");
+    assert!(!has_tmp_file);
+
+    // the reopened database is writable and flushable
+    kvs.put(b"new", b"value").unwrap();
+    kvs.flush().unwrap();
+    assert_eq!(kvs.get(b"new").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(kvs.get(&0u32.to_be_bytes()).unwrap(), Some(value(0)));
+}
+
+#[test]
+fn test_fault_after_leaf_header_commit() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "leaf_header_fault_test";
+    let dir = tempfile::tempdir().unwrap();
+    let (kvs, config, injector) = open_with_faults(TABLE_NAME, dir.path());
+    injector.arm(FaultPoint::LeafHeaderCommitted, NUM_INSERTION as usize / 2);
+    let mut num_acked = 0;
+    for i in 0..NUM_INSERTION {
+        if kvs.put(&i.to_be_bytes(), &value(i)).is_err() {
+            break;
+        }
+        num_acked += 1;
+    }
+    assert!(num_acked < NUM_INSERTION);
+    crash(kvs, TABLE_NAME, &config);
+
+    reopen_and_check(TABLE_NAME, dir.path(), num_acked);
+}
+
+#[test]
+fn test_fault_in_write_batch() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "write_batch_fault_test";
+    const NUM_ENTRIES: u32 = 100;
+    let dir = tempfile::tempdir().unwrap();
+    let (kvs, config, injector) = open_with_faults(TABLE_NAME, dir.path());
+    put_all(&kvs);
+
+    // the batch overwrites half of its keys and adds the others
+    let batch_keys = (NUM_INSERTION - NUM_ENTRIES / 2)..(NUM_INSERTION + NUM_ENTRIES / 2);
+    let mut batch = WriteBatch::new();
+    for i in batch_keys.clone() {
+        batch.put(&i.to_be_bytes(), b"batch");
+    }
+    injector.arm(FaultPoint::LeafHeaderCommitted, NUM_ENTRIES as usize / 2);
+    assert!(kvs.write(batch).is_err());
+    let check = |kvs: &KVS| {
+        for i in batch_keys.clone() {
+            let expected = (i < NUM_INSERTION).then(|| value(i));
+            assert_eq!(kvs.get(&i.to_be_bytes()).unwrap(), expected);
+        }
+        assert_eq!(kvs.iter().unwrap().count(), NUM_INSERTION as usize);
+    };
+    // the applied entries have been restored
+    check(&kvs);
+    crash(kvs, TABLE_NAME, &config);
+
+    // none of the batch is recovered
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    check(&kvs);
+}
+
+#[test]
+fn test_fault_while_writing_table() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "table_fault_test";
+    let dir = tempfile::tempdir().unwrap();
+    let (kvs, config, injector) = open_with_faults(TABLE_NAME, dir.path());
+    put_all(&kvs);
+    injector.arm(FaultPoint::TableBlockWrite, 3);
+    assert!(kvs.flush().is_err());
+    crash(kvs, TABLE_NAME, &config);
+
+    reopen_and_check(TABLE_NAME, dir.path(), NUM_INSERTION);
+}
+
+#[test]
+fn test_fault_before_metadata_sync() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "metadata_fault_test";
+    let dir = tempfile::tempdir().unwrap();
+    let (kvs, config, injector) = open_with_faults(TABLE_NAME, dir.path());
+    put_all(&kvs);
+    injector.arm(FaultPoint::MetadataAppended, 0);
+    assert!(kvs.flush().is_err());
+    crash(kvs, TABLE_NAME, &config);
+
+    reopen_and_check(TABLE_NAME, dir.path(), NUM_INSERTION);
+}
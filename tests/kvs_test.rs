@@ -1,16 +1,46 @@
 extern crate amphis;
-use amphis::config::Config;
-use amphis::kvs::KVS;
-use env_logger;
-use std::sync::{mpsc, Arc};
+use amphis::amphis_error::CrudError;
+use amphis::config::{Config, Durability, FlushTrigger, WalSync};
+use amphis::keycodec;
+use amphis::kvs::{
+    CompactionFilter, FilterDecision, FsStorage, IngestLevel, IngestOptions, Listener,
+    OrderedIntCodec, Source, Storage, StorageFile, TypedKvs, WriteBatch, KVS,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 use threadpool::ThreadPool;
 
+fn count_files(table_name: &str, prefix: &str) -> usize {
+    std::fs::read_dir(format!("data/{}", table_name))
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(prefix)
+        })
+        .count()
+}
+
+/// Stop the database without shutting down
+/// The lock file is removed instead of being released by the OS as a crashed process
+fn crash(kvs: KVS, path: &std::path::Path) {
+    std::mem::forget(kvs);
+    std::fs::remove_file(path.join("LOCK")).unwrap();
+}
+
 #[test]
 fn test_mutations() {
     let _ = env_logger::builder().is_test(true).try_init();
     const NUM_INSERTION: usize = 1025;
     const TABLE_NAME: &str = "mutation_test";
-    let config = Config::new();
+    let config = Config::new().unwrap();
     let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
 
     // INSERT
@@ -53,6 +83,7 @@ fn test_mutations() {
         }
     }
 
+    drop(kvs);
     let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
 }
 
@@ -61,15 +92,14 @@ fn test_recovery() {
     let _ = env_logger::builder().is_test(true).try_init();
     const NUM_INSERTION: usize = 1025;
     const TABLE_NAME: &str = "recovery_test";
-    let config = Config::new();
+    let config = Config::new().unwrap();
     let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
 
     // INSERT
     for i in 0..NUM_INSERTION {
         let key = "k".to_string() + &i.to_string();
         let value = "v".to_string() + &i.to_string();
-        kvs.put(&key.as_bytes().to_vec(), &value.as_bytes().to_vec())
-            .unwrap();
+        kvs.put(key.as_bytes(), value.as_bytes()).unwrap();
     }
 
     // RESTART
@@ -82,13 +112,14 @@ fn test_recovery() {
         let expected = format!("{}{}", "v", (&*i.to_string())).as_bytes().to_vec();
 
         let actual = kvs
-            .get(&key.as_bytes().to_vec())
+            .get(key.as_bytes())
             .expect("read failed")
             .expect("no value");
 
         assert_eq!(actual, expected);
     }
 
+    drop(kvs);
     let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
 }
 
@@ -98,7 +129,7 @@ fn concurrent_insert() {
     const NUM_INSERTION: usize = 2345;
     const NUM_THREADS: usize = 8;
     const TABLE_NAME: &str = "concurrency_test";
-    let config = Config::new();
+    let config = Config::new().unwrap();
     let kvs = Arc::new(amphis::kvs::KVS::new(TABLE_NAME, config).expect("failed to start Amphis"));
 
     let (tx, rx) = mpsc::channel();
@@ -120,7 +151,7 @@ fn concurrent_insert() {
         });
     }
 
-    assert_eq!(rx.iter().take(NUM_THREADS).all(|r| r == 0), true);
+    assert!(rx.iter().take(NUM_THREADS).all(|r| r == 0));
 
     for i in 0..NUM_THREADS {
         let each = kvs.clone();
@@ -130,7 +161,7 @@ fn concurrent_insert() {
             for v in 0..NUM_INSERTION {
                 let key = format!("k{}:{}", v, i);
                 let expected = format!("v{}:{}", v, i);
-                match each.get(&key.as_bytes().to_vec()).unwrap() {
+                match each.get(key.as_bytes()).unwrap() {
                     Some(value) => {
                         let actual = String::from_utf8(value.to_vec()).unwrap();
                         assert_eq!(actual, expected);
@@ -144,7 +175,3727 @@ fn concurrent_insert() {
         });
     }
 
-    assert_eq!(rx.iter().take(NUM_THREADS).all(|r| r == 0), true);
+    assert!(rx.iter().take(NUM_THREADS).all(|r| r == 0));
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_scan() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 200;
+    const TABLE_NAME: &str = "scan_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:04}", i);
+        let value = format!("v{}", i);
+        kvs.put(key.as_bytes(), value.as_bytes()).unwrap();
+    }
+
+    // RESTART to flush the FPTree to an SSTable
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    // UPDATE or DELETE in the new FPTree
+    for i in (0..NUM_INSERTION).step_by(5) {
+        let key = format!("k{:04}", i);
+        if i % 2 == 0 {
+            kvs.delete(key.as_bytes()).unwrap();
+        } else {
+            let value = format!("new-v{}", i);
+            kvs.put(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+    }
+
+    // start from the middle of the SSTable
+    let (start, end) = (50, 150);
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = (start..end)
+        .filter(|i| i % 5 != 0 || i % 2 != 0)
+        .map(|i| {
+            let value = if i % 5 == 0 {
+                format!("new-v{}", i)
+            } else {
+                format!("v{}", i)
+            };
+            (format!("k{:04}", i).into_bytes(), value.into_bytes())
+        })
+        .collect();
+    let actual: Vec<(Vec<u8>, Vec<u8>)> = kvs
+        .scan(
+            format!("k{:04}", start).as_bytes(),
+            format!("k{:04}", end).as_bytes(),
+        )
+        .unwrap()
+        .map(|kv| kv.unwrap())
+        .collect();
+    assert_eq!(actual, expected);
+
+    // no key in the range
+    assert_eq!(kvs.scan(b"a", b"b").unwrap().count(), 0);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_scan_prefix() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "scan_prefix_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    kvs.put(b"user:1", b"alice").unwrap();
+    kvs.put(b"user:2", b"bob").unwrap();
+    kvs.put(b"users", b"all").unwrap();
+    kvs.put(b"user", b"none").unwrap();
+
+    let actual: Vec<(Vec<u8>, Vec<u8>)> = kvs
+        .scan_prefix(b"user:")
+        .unwrap()
+        .map(|kv| kv.unwrap())
+        .collect();
+    assert_eq!(
+        actual,
+        vec![
+            (b"user:1".to_vec(), b"alice".to_vec()),
+            (b"user:2".to_vec(), b"bob".to_vec()),
+        ]
+    );
+
+    // binary keys including NUL and 0xFF
+    kvs.put(&[0x00, 0x00, 0x01], b"a").unwrap();
+    kvs.put(&[0x00, 0x00, 0xFF], b"b").unwrap();
+    kvs.put(&[0x00, 0x01], b"c").unwrap();
+    kvs.put(&[0xFF, 0xFF, 0x00], b"d").unwrap();
+    let keys: Vec<Vec<u8>> = kvs
+        .scan_prefix(&[0x00, 0x00])
+        .unwrap()
+        .map(|kv| kv.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![vec![0x00, 0x00, 0x01], vec![0x00, 0x00, 0xFF]]);
+    let keys: Vec<Vec<u8>> = kvs
+        .scan_prefix(&[0xFF, 0xFF])
+        .unwrap()
+        .map(|kv| kv.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![vec![0xFF, 0xFF, 0x00]]);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_write_batch() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_ENTRIES: usize = 100;
+    const TABLE_NAME: &str = "write_batch_test";
+    let config = Config::new().unwrap();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+
+    // a reader checks that the batch is visible atomically
+    let reader = {
+        let kvs = kvs.clone();
+        std::thread::spawn(move || loop {
+            let count = kvs.scan_prefix(b"batch").unwrap().count();
+            assert!(
+                count == 0 || count == NUM_ENTRIES,
+                "partial batch: {}",
+                count
+            );
+            if count == NUM_ENTRIES {
+                break;
+            }
+        })
+    };
+
+    // the batch fails due to the empty key
+    let mut batch = WriteBatch::new();
+    for i in 0..NUM_ENTRIES {
+        batch.put(format!("batch{:03}", i).as_bytes(), b"value");
+    }
+    batch.put(b"", b"value");
+    assert!(kvs.write(batch).is_err());
+    for i in 0..NUM_ENTRIES {
+        assert_eq!(kvs.get(format!("batch{:03}", i).as_bytes()).unwrap(), None);
+    }
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"ignored", b"value");
+    batch.clear();
+    for i in 0..NUM_ENTRIES {
+        batch.put(format!("batch{:03}", i).as_bytes(), b"value");
+    }
+    batch.put(b"deleted", b"value");
+    batch.delete(b"deleted");
+    kvs.write(batch).unwrap();
+    reader.join().unwrap();
+
+    for i in 0..NUM_ENTRIES {
+        let actual = kvs.get(format!("batch{:03}", i).as_bytes()).unwrap();
+        assert_eq!(actual, Some(b"value".to_vec()));
+    }
+    assert_eq!(kvs.get(b"ignored").unwrap(), None);
+    assert_eq!(kvs.get(b"deleted").unwrap(), None);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_write_batch_concurrent_scan() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 50;
+    const NUM_ROUNDS: usize = 200;
+    const NUM_READERS: usize = 2;
+    const TABLE_NAME: &str = "write_batch_concurrent_scan_test";
+    let config = Config::new().unwrap();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+    let done = Arc::new(AtomicBool::new(false));
+
+    // readers see each batch and range deletion entirely or not at all
+    let readers: Vec<_> = (0..NUM_READERS)
+        .map(|_| {
+            let kvs = kvs.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut num_scans = 0;
+                while !done.load(Ordering::Acquire) {
+                    let pairs: Vec<_> = kvs
+                        .scan_prefix(b"key")
+                        .unwrap()
+                        .map(|kv| kv.unwrap())
+                        .collect();
+                    assert!(
+                        pairs.is_empty() || pairs.len() == NUM_KEYS,
+                        "partial batch: {}",
+                        pairs.len()
+                    );
+                    assert!(
+                        pairs.iter().all(|(_, v)| *v == pairs[0].1),
+                        "mixed rounds in a scan"
+                    );
+                    num_scans += 1;
+                }
+                num_scans
+            })
+        })
+        .collect();
+
+    for round in 0..NUM_ROUNDS {
+        if round % 4 == 3 {
+            kvs.delete_range(b"key", b"kez").unwrap();
+            continue;
+        }
+        let mut batch = WriteBatch::new();
+        for i in 0..NUM_KEYS {
+            batch.put(
+                format!("key{:03}", i).as_bytes(),
+                format!("value{}", round).as_bytes(),
+            );
+        }
+        kvs.write(batch).unwrap();
+    }
+    done.store(true, Ordering::Release);
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_get_many() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 300;
+    const TABLE_NAME: &str = "get_many_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:04}", i);
+        let value = format!("v{}", i);
+        kvs.put(key.as_bytes(), value.as_bytes()).unwrap();
+    }
+
+    // RESTART to flush the FPTree to an SSTable
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    kvs.put(b"k0010", b"new-v10").unwrap();
+    kvs.delete(b"k0020").unwrap();
+
+    let keys: Vec<Vec<u8>> = vec![
+        b"k0250".to_vec(),
+        b"k0010".to_vec(),
+        b"missing".to_vec(),
+        b"k0020".to_vec(),
+        b"k0001".to_vec(),
+        b"k0250".to_vec(),
+    ];
+    let actual = kvs.get_many(&keys).unwrap();
+    assert_eq!(
+        actual,
+        vec![
+            Some(b"v250".to_vec()),
+            Some(b"new-v10".to_vec()),
+            None,
+            None,
+            Some(b"v1".to_vec()),
+            Some(b"v250".to_vec()),
+        ]
+    );
+
+    let keys: Vec<Vec<u8>> = (0..NUM_INSERTION)
+        .rev()
+        .map(|i| format!("k{:04}", i).into_bytes())
+        .collect();
+    for (key, value) in keys.iter().zip(kvs.get_many(&keys).unwrap()) {
+        assert_eq!(value, kvs.get(key).unwrap());
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_compare_and_swap() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_THREADS: usize = 4;
+    const NUM_INCREMENTS: usize = 50;
+    const TABLE_NAME: &str = "compare_and_swap_test";
+    let config = Config::new().unwrap();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+
+    // the key doesn't exist
+    assert!(!kvs
+        .compare_and_swap(b"key", Some(b"v0"), Some(b"v1"))
+        .unwrap());
+    assert!(kvs.compare_and_swap(b"key", None, Some(b"v1")).unwrap());
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"v1".to_vec()));
+
+    // mismatch
+    assert!(!kvs.compare_and_swap(b"key", None, Some(b"v2")).unwrap());
+    assert!(!kvs
+        .compare_and_swap(b"key", Some(b"v0"), Some(b"v2"))
+        .unwrap());
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"v1".to_vec()));
+
+    // delete
+    assert!(kvs.compare_and_swap(b"key", Some(b"v1"), None).unwrap());
+    assert_eq!(kvs.get(b"key").unwrap(), None);
+
+    // concurrent increments
+    let handles: Vec<_> = (0..NUM_THREADS)
+        .map(|_| {
+            let kvs = kvs.clone();
+            std::thread::spawn(move || {
+                for _ in 0..NUM_INCREMENTS {
+                    loop {
+                        let current = kvs.get(b"counter").unwrap();
+                        let count = current
+                            .as_ref()
+                            .map_or(0, |v| String::from_utf8_lossy(v).parse().unwrap());
+                        let new = (count + 1).to_string();
+                        if kvs
+                            .compare_and_swap(b"counter", current.as_deref(), Some(new.as_bytes()))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let expected = (NUM_THREADS * NUM_INCREMENTS).to_string();
+    assert_eq!(
+        kvs.get(b"counter").unwrap(),
+        Some(expected.as_bytes().to_vec())
+    );
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_ttl() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "ttl_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    kvs.put(b"old", b"value").unwrap();
+    // RESTART to flush the old value to an SSTable
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    let ttl = Duration::from_millis(200);
+    kvs.put_with_ttl(b"old", b"short-lived", ttl).unwrap();
+    kvs.put_with_ttl(b"session", b"short-lived", ttl).unwrap();
+    kvs.put_with_ttl(b"long", b"long-lived", Duration::from_secs(3600))
+        .unwrap();
+    kvs.put_with_ttl(b"max", b"never-expired", Duration::MAX)
+        .unwrap();
+    assert_eq!(kvs.get(b"max").unwrap(), Some(b"never-expired".to_vec()));
+    assert_eq!(kvs.get(b"old").unwrap(), Some(b"short-lived".to_vec()));
+    assert_eq!(kvs.get(b"session").unwrap(), Some(b"short-lived".to_vec()));
+    assert_eq!(kvs.scan(b"a", b"z").unwrap().count(), 4);
+
+    std::thread::sleep(ttl);
+    assert_eq!(kvs.get(b"old").unwrap(), None);
+    assert_eq!(kvs.get(b"session").unwrap(), None);
+    assert_eq!(kvs.get(b"long").unwrap(), Some(b"long-lived".to_vec()));
+    let keys: Vec<Vec<u8>> = kvs
+        .scan(b"a", b"z")
+        .unwrap()
+        .map(|kv| kv.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![b"long".to_vec(), b"max".to_vec()]);
+
+    // the flushed expired key still hides the older value
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    assert_eq!(kvs.get(b"old").unwrap(), None);
+    assert_eq!(kvs.get(b"session").unwrap(), None);
+    assert_eq!(kvs.get(b"long").unwrap(), Some(b"long-lived".to_vec()));
+    assert_eq!(kvs.get(b"max").unwrap(), Some(b"never-expired".to_vec()));
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_flush() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "flush_test";
+    let config = Config::new().unwrap();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+
+    // nothing to flush
+    kvs.flush().unwrap();
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 0);
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+    }
+    kvs.flush().unwrap();
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+    assert_eq!(count_files(TABLE_NAME, "leaves-"), 1);
+
+    // the new FPTree is empty
+    kvs.flush().unwrap();
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+
+    // flush while another thread is writing
+    let writer = {
+        let kvs = kvs.clone();
+        std::thread::spawn(move || {
+            for i in NUM_INSERTION..(NUM_INSERTION * 2) {
+                let key = format!("k{}", i);
+                kvs.put(key.as_bytes(), b"value").unwrap();
+            }
+        })
+    };
+    for _ in 0..3 {
+        kvs.flush().unwrap();
+    }
+    writer.join().unwrap();
+    kvs.flush().unwrap();
+
+    for i in 0..(NUM_INSERTION * 2) {
+        let key = format!("k{}", i);
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(b"value".to_vec()));
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_flush_on_shutdown() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "flush_on_shutdown_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..10 {
+        let key = format!("k{}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+    }
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 0);
+    drop(kvs);
+
+    // only the SSTable remains
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+    assert_eq!(count_files(TABLE_NAME, "leaves-"), 0);
+    assert_eq!(count_files(TABLE_NAME, "wal-"), 0);
+
+    let kvs = KVS::open(TABLE_NAME, config.clone()).unwrap();
+    let pairs: Vec<_> = kvs
+        .iter_with_source()
+        .unwrap()
+        .map(|kv| kv.unwrap())
+        .collect();
+    assert_eq!(pairs.len(), 10);
+    assert!(pairs
+        .iter()
+        .all(|(_, value, source)| value == b"value" && matches!(source, Source::Sstable { .. })));
+    drop(kvs);
+
+    // no empty table is flushed by restarts without writes
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    drop(kvs);
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+    assert_eq!(count_files(TABLE_NAME, "leaves-"), 0);
+
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_snapshot() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "snapshot_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    // half of the pairs are in an SSTable, the others are in the FPTree
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), b"old").unwrap();
+        if i == NUM_INSERTION / 2 {
+            kvs.flush().unwrap();
+        }
+    }
+    let snapshot = kvs.snapshot().unwrap();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:03}", i);
+        if i % 2 == 0 {
+            kvs.put(key.as_bytes(), b"new").unwrap();
+        } else {
+            kvs.delete(key.as_bytes()).unwrap();
+        }
+    }
+    kvs.put(b"k999", b"new").unwrap();
+    kvs.flush().unwrap();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:03}", i);
+        assert_eq!(snapshot.get(key.as_bytes()).unwrap(), Some(b"old".to_vec()));
+    }
+    assert_eq!(snapshot.get(b"k999").unwrap(), None);
+    let values: Vec<Vec<u8>> = snapshot
+        .scan_prefix(b"k")
+        .unwrap()
+        .map(|kv| kv.unwrap().1)
+        .collect();
+    assert_eq!(values, vec![b"old".to_vec(); NUM_INSERTION]);
+
+    // the current state isn't affected
+    assert_eq!(kvs.get(b"k000").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(kvs.get(b"k001").unwrap(), None);
+
+    drop(snapshot);
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_iter() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 300;
+    const TABLE_NAME: &str = "iter_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    let mut expected = BTreeMap::new();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i).into_bytes();
+        let value = format!("v{}", i).into_bytes();
+        kvs.put(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+    kvs.flush().unwrap();
+
+    for i in (0..NUM_INSERTION).step_by(3) {
+        let key = format!("k{}", i).into_bytes();
+        let value = format!("updated{}", i).into_bytes();
+        kvs.put(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+    for i in (1..NUM_INSERTION).step_by(3) {
+        let key = format!("k{}", i).into_bytes();
+        kvs.delete(&key).unwrap();
+        expected.remove(&key);
+    }
+    for i in NUM_INSERTION..(NUM_INSERTION + 10) {
+        let key = format!("k{}", i).into_bytes();
+        let value = format!("v{}", i).into_bytes();
+        kvs.put(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+
+    let actual: BTreeMap<Vec<u8>, Vec<u8>> = kvs.iter().unwrap().map(|kv| kv.unwrap()).collect();
+    assert_eq!(actual, expected);
+
+    let keys: Vec<Vec<u8>> = kvs.iter().unwrap().map(|kv| kv.unwrap().0).collect();
+    assert_eq!(keys, expected.keys().cloned().collect::<Vec<_>>());
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_delete_range() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "delete_range_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    // k000-k049 are in an SSTable, k050-k099 are in the FPTree
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+        if i == NUM_INSERTION / 2 - 1 {
+            kvs.flush().unwrap();
+        }
+    }
+
+    kvs.delete_range(b"k030", b"k070").unwrap();
+    // written after the deletion
+    kvs.put(b"k040", b"new").unwrap();
+
+    let is_deleted = |i: usize| (30..70).contains(&i) && i != 40;
+    let check = |kvs: &KVS| {
+        for i in 0..NUM_INSERTION {
+            let key = format!("k{:03}", i);
+            let expected = match i {
+                40 => Some(b"new".to_vec()),
+                i if is_deleted(i) => None,
+                _ => Some(b"value".to_vec()),
+            };
+            assert_eq!(kvs.get(key.as_bytes()).unwrap(), expected);
+        }
+
+        let keys: Vec<Vec<u8>> = kvs.iter().unwrap().map(|kv| kv.unwrap().0).collect();
+        let expected: Vec<Vec<u8>> = (0..NUM_INSERTION)
+            .filter(|i| !is_deleted(*i))
+            .map(|i| format!("k{:03}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+
+        let results = kvs
+            .get_many(&[b"k029".to_vec(), b"k030".to_vec(), b"k069".to_vec()])
+            .unwrap();
+        assert_eq!(results, vec![Some(b"value".to_vec()), None, None]);
+    };
+    check(&kvs);
+
+    // the range tombstone is persisted with the FPTree
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, Config::new().unwrap()).unwrap();
+    check(&kvs);
+
+    // the range tombstone is flushed to an SSTable
+    kvs.delete_range(b"k090", b"k095").unwrap();
+    kvs.flush().unwrap();
+    assert_eq!(kvs.get(b"k092").unwrap(), None);
+    assert_eq!(kvs.get(b"k095").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(kvs.scan(b"k088", b"k097").unwrap().count(), 4);
+
+    // a new value hides the range tombstone in the older SSTable
+    kvs.put(b"k050", b"revived").unwrap();
+    assert_eq!(kvs.get(b"k050").unwrap(), Some(b"revived".to_vec()));
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_approximate_len() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "approximate_len_test";
+    // all leaves before the flush are in the first allocation of the leaf file
+    let config = Config::builder()
+        .root_split_threshold(4)
+        .leaf_allocation(16)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    assert_eq!(kvs.approximate_len(), 0);
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+    }
+    assert_eq!(kvs.approximate_len(), NUM_INSERTION);
+    let leaves_size = kvs.size_on_disk().unwrap();
+    assert!(leaves_size > 0);
+
+    kvs.flush().unwrap();
+    assert_eq!(kvs.approximate_len(), NUM_INSERTION);
+    assert!(kvs.size_on_disk().unwrap() > leaves_size);
+
+    // overwritten keys are counted until compaction
+    for i in 0..(NUM_INSERTION / 2) {
+        let key = format!("k{}", i);
+        kvs.put(key.as_bytes(), b"new_value").unwrap();
+    }
+    assert_eq!(kvs.approximate_len(), NUM_INSERTION + NUM_INSERTION / 2);
+
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    assert_eq!(kvs.approximate_len(), NUM_INSERTION + NUM_INSERTION / 2);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_column_family() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "column_family_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    let users = kvs.open_cf("users").unwrap();
+    let items = kvs.open_cf("items").unwrap();
+    assert!(kvs.open_cf("").is_err());
+    assert!(kvs.open_cf("a/b").is_err());
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), b"default").unwrap();
+        kvs.put_cf(&users, key.as_bytes(), b"user").unwrap();
+        if i % 2 == 0 {
+            kvs.put_cf(&items, key.as_bytes(), b"item").unwrap();
+        }
+    }
+    kvs.delete_cf(&users, b"k000").unwrap();
+
+    let check = |kvs: &KVS| {
+        let users = kvs.open_cf("users").unwrap();
+        let items = kvs.open_cf("items").unwrap();
+        assert_eq!(kvs.get(b"k000").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(kvs.get_cf(&users, b"k000").unwrap(), None);
+        assert_eq!(kvs.get_cf(&users, b"k001").unwrap(), Some(b"user".to_vec()));
+        assert_eq!(kvs.get_cf(&items, b"k000").unwrap(), Some(b"item".to_vec()));
+        assert_eq!(kvs.get_cf(&items, b"k001").unwrap(), None);
+        assert_eq!(kvs.scan(b"k", b"l").unwrap().count(), NUM_INSERTION);
+        assert_eq!(
+            kvs.scan_cf(&users, b"k", b"l").unwrap().count(),
+            NUM_INSERTION - 1
+        );
+        assert_eq!(
+            kvs.scan_cf(&items, b"k", b"l").unwrap().count(),
+            NUM_INSERTION / 2
+        );
+    };
+    check(&kvs);
+
+    // all column families are flushed and recovered
+    drop(users);
+    drop(items);
+    drop(kvs);
+    assert_eq!(count_files(&format!("{}/users", TABLE_NAME), "sstable-"), 1);
+    assert_eq!(count_files(&format!("{}/items", TABLE_NAME), "sstable-"), 1);
+    let kvs = KVS::new(TABLE_NAME, Config::new().unwrap()).unwrap();
+    check(&kvs);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_open() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "open_test";
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+
+    match KVS::open(TABLE_NAME, Config::new().unwrap()) {
+        Err(CrudError::NotFound(name)) => assert_eq!(name, TABLE_NAME),
+        _ => panic!("the database should not be found"),
+    }
+
+    let kvs = KVS::create(TABLE_NAME, Config::new().unwrap()).unwrap();
+    kvs.put(b"key", b"value").unwrap();
+    drop(kvs);
+
+    let kvs = KVS::open(TABLE_NAME, Config::new().unwrap()).unwrap();
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value".to_vec()));
 
+    drop(kvs);
     let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
 }
+
+#[test]
+fn test_replace() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "replace_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    assert_eq!(kvs.replace(b"key", b"v1").unwrap(), None);
+    assert_eq!(kvs.replace(b"key", b"v2").unwrap(), Some(b"v1".to_vec()));
+
+    // the key lives only in an SSTable
+    kvs.put(b"flushed", b"old").unwrap();
+    kvs.delete(b"deleted").unwrap();
+    kvs.flush().unwrap();
+    assert_eq!(
+        kvs.replace(b"flushed", b"new").unwrap(),
+        Some(b"old".to_vec())
+    );
+    assert_eq!(kvs.get(b"flushed").unwrap(), Some(b"new".to_vec()));
+
+    // a tombstone is returned as None
+    assert_eq!(kvs.replace(b"deleted", b"revived").unwrap(), None);
+    assert_eq!(kvs.get(b"deleted").unwrap(), Some(b"revived".to_vec()));
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_entry() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_THREADS: u64 = 4;
+    const NUM_INCREMENTS: u64 = 50;
+    const TABLE_NAME: &str = "entry_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .read_cache_bytes(1024)
+        .build();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config.clone()).unwrap());
+    let increment = |v: &mut Vec<u8>| {
+        let mut count = [0u8; 8];
+        count.copy_from_slice(v);
+        *v = (u64::from_be_bytes(count) + 1).to_be_bytes().to_vec();
+    };
+
+    // a missing key gets the default
+    assert_eq!(kvs.entry(b"new").or_insert(b"v0").unwrap(), b"v0".to_vec());
+    assert_eq!(kvs.get(b"new").unwrap(), Some(b"v0".to_vec()));
+    // the existing value is kept without `and_modify`
+    assert_eq!(kvs.entry(b"new").or_insert(b"v1").unwrap(), b"v0".to_vec());
+    assert_eq!(
+        kvs.entry(b"new")
+            .and_modify(|v| v.extend(b"-modified"))
+            .or_insert(b"v1")
+            .unwrap(),
+        b"v0-modified".to_vec()
+    );
+    assert_eq!(kvs.get(b"new").unwrap(), Some(b"v0-modified".to_vec()));
+    // `or_insert_with` isn't called for the existing key
+    assert_eq!(
+        kvs.entry(b"new")
+            .or_insert_with(|| panic!("the key exists"))
+            .unwrap(),
+        b"v0-modified".to_vec()
+    );
+
+    // keys in SSTables are modified, and a flushed tombstone is a missing key
+    kvs.put(b"counter", &1u64.to_be_bytes()).unwrap();
+    kvs.put(b"deleted", b"old").unwrap();
+    kvs.delete(b"deleted").unwrap();
+    kvs.flush().unwrap();
+    // the cached value is invalidated by the write
+    assert_eq!(
+        kvs.get(b"counter").unwrap(),
+        Some(1u64.to_be_bytes().to_vec())
+    );
+    assert_eq!(
+        kvs.entry(b"counter")
+            .and_modify(increment)
+            .and_modify(increment)
+            .or_insert(&0u64.to_be_bytes())
+            .unwrap(),
+        3u64.to_be_bytes().to_vec()
+    );
+    assert_eq!(
+        kvs.get(b"counter").unwrap(),
+        Some(3u64.to_be_bytes().to_vec())
+    );
+    assert_eq!(
+        kvs.entry(b"deleted")
+            .and_modify(|_| panic!("the key was deleted"))
+            .or_insert_with(|| b"revived".to_vec())
+            .unwrap(),
+        b"revived".to_vec()
+    );
+
+    // no increment is lost by concurrent writers
+    let handles: Vec<_> = (0..NUM_THREADS)
+        .map(|_| {
+            let kvs = kvs.clone();
+            std::thread::spawn(move || {
+                for _ in 0..NUM_INCREMENTS {
+                    kvs.entry(b"counter")
+                        .and_modify(increment)
+                        .or_insert(&1u64.to_be_bytes())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let expected = 3 + NUM_THREADS * NUM_INCREMENTS;
+    assert_eq!(
+        kvs.get(b"counter").unwrap(),
+        Some(expected.to_be_bytes().to_vec())
+    );
+
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    assert_eq!(
+        kvs.get(b"counter").unwrap(),
+        Some(expected.to_be_bytes().to_vec())
+    );
+    assert_eq!(kvs.get(b"deleted").unwrap(), Some(b"revived".to_vec()));
+}
+
+#[test]
+fn test_remove() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_THREADS: usize = 8;
+    const TABLE_NAME: &str = "remove_test";
+    let config = Config::new().unwrap();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+
+    assert!(!kvs.remove(b"none").unwrap());
+
+    kvs.put(b"flushed", b"value").unwrap();
+    kvs.flush().unwrap();
+    assert!(kvs.remove(b"flushed").unwrap());
+    assert!(!kvs.remove(b"flushed").unwrap());
+    assert_eq!(kvs.get(b"flushed").unwrap(), None);
+
+    // only one of concurrent removes reports the existence
+    kvs.put(b"key", b"value").unwrap();
+    let handles: Vec<_> = (0..NUM_THREADS)
+        .map(|_| {
+            let kvs = kvs.clone();
+            std::thread::spawn(move || kvs.remove(b"key").unwrap())
+        })
+        .collect();
+    let removed = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .filter(|r| *r)
+        .count();
+    assert_eq!(removed, 1);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_empty_value() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "empty_value_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    kvs.put(b"empty", b"").unwrap();
+    kvs.put(b"deleted", b"").unwrap();
+    kvs.delete(b"deleted").unwrap();
+    assert_eq!(kvs.get(b"empty").unwrap(), Some(Vec::new()));
+    assert_eq!(kvs.get(b"deleted").unwrap(), None);
+
+    // the empty value is kept in SSTables
+    kvs.flush().unwrap();
+    assert_eq!(kvs.get(b"empty").unwrap(), Some(Vec::new()));
+    assert_eq!(kvs.get(b"deleted").unwrap(), None);
+    let pairs: Vec<_> = kvs.iter().unwrap().map(|kv| kv.unwrap()).collect();
+    assert_eq!(pairs, vec![(b"empty".to_vec(), Vec::new())]);
+    kvs.compact().unwrap();
+    assert_eq!(kvs.get(b"empty").unwrap(), Some(Vec::new()));
+
+    assert!(kvs
+        .compare_and_swap(b"empty", Some(b""), Some(b"value"))
+        .unwrap());
+    assert_eq!(kvs.replace(b"empty", b"").unwrap(), Some(b"value".to_vec()));
+    assert!(kvs.remove(b"empty").unwrap());
+    assert_eq!(kvs.get(b"empty").unwrap(), None);
+
+    // after recovery
+    kvs.put(b"empty", b"").unwrap();
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    assert_eq!(kvs.get(b"empty").unwrap(), Some(Vec::new()));
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_config_builder() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 500;
+    const TABLE_NAME: &str = "config_builder_test";
+    let config = Config::builder()
+        .leaf_dir("data")
+        .table_dir("data")
+        .root_split_threshold(2)
+        .num_slot(128)
+        .leaf_size(64 * 1024)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        let value = format!("v{}", i);
+        kvs.put(key.as_bytes(), value.as_bytes()).unwrap();
+    }
+
+    // RESTART
+    drop(kvs);
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        let expected = format!("v{}", i).as_bytes().to_vec();
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(expected));
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_flush_by_bytes() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 200;
+    const TABLE_NAME: &str = "flush_by_bytes_test";
+    // keep flushed tables to count them
+    let config = Config::builder()
+        .flush_trigger(FlushTrigger::Bytes(1024))
+        .l0_compaction_trigger(usize::MAX)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    let value = vec![b'v'; 100];
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        kvs.put(key.as_bytes(), &value).unwrap();
+    }
+
+    // flushed without any root split
+    drop(kvs);
+    assert!(count_files(TABLE_NAME, "sstable-") > 1);
+
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(value.clone()));
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_flush_by_deletes() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_DELETION: usize = 200;
+    const TABLE_NAME: &str = "flush_by_deletes_test";
+    let config = Config::builder()
+        .flush_trigger(FlushTrigger::Bytes(1024))
+        .l0_compaction_trigger(usize::MAX)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    // tombstones trigger flushes without any put
+    for i in 0..NUM_DELETION {
+        let key = format!("k{}", i);
+        kvs.delete(key.as_bytes()).unwrap();
+    }
+    let start = std::time::Instant::now();
+    while count_files(TABLE_NAME, "sstable-") == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10), "not flushed");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    for i in 0..NUM_DELETION {
+        let key = format!("k{}", i);
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), None);
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_compaction() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 100;
+    const NUM_FLUSH: usize = 10;
+    const TABLE_NAME: &str = "compaction_test";
+    let config = Config::builder()
+        .l0_compaction_trigger(2)
+        .level_base_bytes(4096)
+        .level_multiplier(2)
+        .target_table_bytes(1024)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    for round in 0..NUM_FLUSH {
+        for i in 0..NUM_KEYS {
+            let key = format!("k{:03}", i);
+            kvs.put(key.as_bytes(), format!("v{}-{}", i, round).as_bytes())
+                .unwrap();
+        }
+        kvs.delete(format!("k{:03}", round).as_bytes()).unwrap();
+        kvs.flush().unwrap();
+    }
+    kvs.delete_range(b"k050", b"k060").unwrap();
+    kvs.flush().unwrap();
+
+    // compactions have merged tables
+    drop(kvs);
+    assert!(count_files(TABLE_NAME, "sstable-") < NUM_FLUSH);
+
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    for i in 0..NUM_KEYS {
+        let key = format!("k{:03}", i);
+        // keys deleted in the previous rounds were put again
+        let expected = if i == NUM_FLUSH - 1 || (50..60).contains(&i) {
+            None
+        } else {
+            Some(format!("v{}-{}", i, NUM_FLUSH - 1).into_bytes())
+        };
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), expected);
+    }
+    assert_eq!(kvs.iter().unwrap().count(), NUM_KEYS - 11);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_manual_compaction() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 100;
+    const TABLE_NAME: &str = "manual_compaction_test";
+    let config = Config::builder().l0_compaction_trigger(100).build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    for round in 0..3 {
+        for i in 0..NUM_KEYS {
+            let key = format!("k{:03}", i);
+            kvs.put(key.as_bytes(), format!("v{}-{}", i, round).as_bytes())
+                .unwrap();
+        }
+        kvs.flush().unwrap();
+    }
+    for i in 0..NUM_KEYS / 2 {
+        kvs.delete(format!("k{:03}", i).as_bytes()).unwrap();
+    }
+    kvs.flush().unwrap();
+    let size = kvs.size_on_disk().unwrap();
+    let len = kvs.approximate_len();
+
+    kvs.compact().unwrap();
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+    assert!(kvs.size_on_disk().unwrap() < size);
+    assert_eq!(kvs.approximate_len(), NUM_KEYS / 2);
+    assert!(kvs.approximate_len() < len);
+    for i in 0..NUM_KEYS {
+        let key = format!("k{:03}", i);
+        let expected = (i >= NUM_KEYS / 2).then(|| format!("v{}-2", i).into_bytes());
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), expected);
+    }
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_get_timeout() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_TABLES: usize = 20;
+    const TABLE_NAME: &str = "get_timeout_test";
+    let config = Config::builder().l0_compaction_trigger(100).build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    for i in 0..NUM_TABLES {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+        kvs.flush().unwrap();
+    }
+
+    // the oldest table has the key
+    assert!(matches!(
+        kvs.get_timeout(b"k000", Duration::ZERO),
+        Err(CrudError::TimedOut)
+    ));
+    assert!(matches!(
+        kvs.get_timeout(b"k000", Duration::from_nanos(1)),
+        Err(CrudError::TimedOut)
+    ));
+    assert_eq!(
+        kvs.get_timeout(b"k000", Duration::from_secs(10)).unwrap(),
+        Some(b"value".to_vec())
+    );
+    assert_eq!(kvs.get_timeout(b"k999", Duration::MAX).unwrap(), None);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_entry_size_limits() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "entry_size_limits_test";
+    let dir = tempfile::tempdir().unwrap();
+    let builder = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .leaf_size(16 * 1024);
+    let config = builder
+        .clone()
+        .max_key_size(16)
+        .max_value_size(1024)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    let long_key = [b'k'; 17];
+    let long_value = vec![0xab; 1025];
+    assert!(matches!(
+        kvs.put(&long_key, b"value"),
+        Err(CrudError::KeyTooLarge {
+            size: 17,
+            limit: 16
+        })
+    ));
+    assert!(matches!(
+        kvs.put(b"key", &long_value),
+        Err(CrudError::ValueTooLarge {
+            size: 1025,
+            limit: 1024
+        })
+    ));
+    assert!(matches!(
+        kvs.put(b"", b"value"),
+        Err(CrudError::InvalidInput(_))
+    ));
+    assert!(matches!(
+        kvs.put_with_ttl(b"key", &long_value, Duration::from_secs(60)),
+        Err(CrudError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        kvs.replace(&long_key, b"value"),
+        Err(CrudError::KeyTooLarge { .. })
+    ));
+    assert!(matches!(
+        kvs.compare_and_swap(b"key", None, Some(&long_value)),
+        Err(CrudError::ValueTooLarge { .. })
+    ));
+    let mut batch = WriteBatch::new();
+    batch.put(b"batch", b"value");
+    batch.put(b"", b"value");
+    assert!(matches!(kvs.write(batch), Err(CrudError::InvalidInput(_))));
+    assert!(matches!(kvs.delete(b""), Err(CrudError::InvalidInput(_))));
+
+    // nothing has been written, and the store is still usable
+    assert_eq!(kvs.get(b"key").unwrap(), None);
+    assert_eq!(kvs.get(b"batch").unwrap(), None);
+    kvs.put(&[b'k'; 16], &long_value[..1024]).unwrap();
+    kvs.put(b"key", b"value").unwrap();
+    kvs.delete(&long_key).unwrap();
+    assert_eq!(
+        kvs.get(&[b'k'; 16]).unwrap(),
+        Some(long_value[..1024].to_vec())
+    );
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value".to_vec()));
+    kvs.flush().unwrap();
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value".to_vec()));
+    drop(kvs);
+
+    // without the limits, a key has to fit in a leaf page, and a value is limited by
+    // the number of overflow pages referred from a leaf page
+    let kvs = KVS::new(TABLE_NAME, builder.build()).unwrap();
+    match kvs.put(b"key", &vec![0xab; 16 * 1024 * 1024]) {
+        Err(CrudError::ValueTooLarge { size, limit }) => {
+            assert_eq!(size, 16 * 1024 * 1024);
+            assert!(limit > 8 * 1024 * 1024);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(matches!(
+        kvs.put(&[b'k'; 16 * 1024], b""),
+        Err(CrudError::KeyTooLarge { .. })
+    ));
+    assert!(matches!(
+        kvs.delete(&[b'k'; 16 * 1024]),
+        Err(CrudError::KeyTooLarge { .. })
+    ));
+    kvs.put(b"key2", b"value").unwrap();
+    assert_eq!(kvs.get(b"key2").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_overflow_value() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const LEAF_SIZE: usize = 1024 * 1024;
+    const TABLE_NAME: &str = "overflow_value_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .leaf_size(LEAF_SIZE)
+        // the pages of a new value are allocated before the old ones are freed
+        .leaf_allocation(16)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    let large_value: Vec<u8> = (0..(LEAF_SIZE * 3 + 12345)).map(|i| i as u8).collect();
+    kvs.put(b"large", &large_value).unwrap();
+    kvs.put(b"small", b"value").unwrap();
+    assert_eq!(kvs.get(b"large").unwrap(), Some(large_value.clone()));
+    assert_eq!(kvs.get(b"small").unwrap(), Some(b"value".to_vec()));
+
+    // the pages of the old value are reused
+    let size = kvs.size_on_disk().unwrap();
+    for i in 0..10 {
+        let mut value = large_value.clone();
+        value[i] = 0xff;
+        kvs.put(b"large", &value).unwrap();
+    }
+    assert_eq!(kvs.size_on_disk().unwrap(), size);
+
+    kvs.put(b"large", &large_value).unwrap();
+    kvs.flush().unwrap();
+    // read from the SSTable
+    assert_eq!(kvs.get(b"large").unwrap(), Some(large_value.clone()));
+    assert_eq!(kvs.get(b"small").unwrap(), Some(b"value".to_vec()));
+    let scanned: Vec<(Vec<u8>, Vec<u8>)> = kvs
+        .scan(b"a", b"z")
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        scanned,
+        vec![
+            (b"large".to_vec(), large_value),
+            (b"small".to_vec(), b"value".to_vec())
+        ]
+    );
+}
+
+#[test]
+fn test_overflow_split() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 64;
+    const TABLE_NAME: &str = "overflow_split_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .leaf_size(16 * 1024)
+        .num_slot(8)
+        .root_split_threshold(100)
+        .recover_fptree(true)
+        .build();
+    let value = |i: usize| vec![i as u8; 4096 * (i % 8) + i];
+
+    // leaves are split and compacted with values in overflow pages
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_KEYS {
+        kvs.put(format!("k{:03}", i).as_bytes(), &value(i)).unwrap();
+    }
+    for i in (0..NUM_KEYS).step_by(3) {
+        kvs.put(format!("k{:03}", i).as_bytes(), &value(i + 1))
+            .unwrap();
+    }
+    for i in (0..NUM_KEYS).step_by(5) {
+        kvs.delete(format!("k{:03}", i).as_bytes()).unwrap();
+    }
+    let expected = |i: usize| match i {
+        i if i % 5 == 0 => None,
+        i if i % 3 == 0 => Some(value(i + 1)),
+        i => Some(value(i)),
+    };
+    for i in 0..NUM_KEYS {
+        assert_eq!(
+            kvs.get(format!("k{:03}", i).as_bytes()).unwrap(),
+            expected(i)
+        );
+    }
+    drop(kvs);
+
+    // the overflow pages aren't reused after the FPTree is reopened
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    for i in NUM_KEYS..(NUM_KEYS * 2) {
+        kvs.put(format!("k{:03}", i).as_bytes(), &value(i)).unwrap();
+    }
+    for i in 0..NUM_KEYS {
+        assert_eq!(
+            kvs.get(format!("k{:03}", i).as_bytes()).unwrap(),
+            expected(i)
+        );
+    }
+    kvs.flush().unwrap();
+    for i in 0..(NUM_KEYS * 2) {
+        let expected = if i < NUM_KEYS {
+            expected(i)
+        } else {
+            Some(value(i))
+        };
+        assert_eq!(kvs.get(format!("k{:03}", i).as_bytes()).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_in_memory() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "in_memory_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .root_split_threshold(1)
+        .in_memory(true)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+
+    // the FPTree isn't flushed even after the threshold
+    for i in 0..2000u32 {
+        kvs.put(&i.to_be_bytes(), format!("value{}", i).as_bytes())
+            .unwrap();
+    }
+    kvs.delete(&1u32.to_be_bytes()).unwrap();
+    kvs.delete_range(&10u32.to_be_bytes(), &20u32.to_be_bytes())
+        .unwrap();
+    kvs.flush().unwrap();
+    kvs.compact().unwrap();
+    assert_eq!(kvs.stats().flush_count, 0);
+    assert!(kvs.stats().root_split_count > 1);
+
+    assert_eq!(
+        kvs.get(&0u32.to_be_bytes()).unwrap(),
+        Some(b"value0".to_vec())
+    );
+    assert_eq!(kvs.get(&1u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(kvs.get(&15u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(
+        kvs.get(&1999u32.to_be_bytes()).unwrap(),
+        Some(b"value1999".to_vec())
+    );
+    let snapshot = kvs.snapshot().unwrap();
+    kvs.put(&0u32.to_be_bytes(), b"new").unwrap();
+    assert_eq!(
+        snapshot.get(&0u32.to_be_bytes()).unwrap(),
+        Some(b"value0".to_vec())
+    );
+    let keys: Vec<Vec<u8>> = kvs.iter().unwrap().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys.len(), 2000 - 1 - 10);
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+    // operations writing SSTables aren't supported
+    assert!(matches!(
+        kvs.ingest_sorted(vec![(b"key".to_vec(), b"value".to_vec())]),
+        Err(CrudError::InvalidInput(_))
+    ));
+    assert!(matches!(
+        kvs.checkpoint(&dir.path().join("checkpoint")),
+        Err(CrudError::InvalidInput(_))
+    ));
+
+    // nothing is written to the disk
+    assert_eq!(kvs.size_on_disk().unwrap(), 0);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    let cf = kvs.open_cf("cf").unwrap();
+    kvs.put_cf(&cf, b"key", b"value").unwrap();
+    assert_eq!(kvs.get_cf(&cf, b"key").unwrap(), Some(b"value".to_vec()));
+    drop(snapshot);
+    drop(kvs);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    // all data is lost
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    assert_eq!(kvs.get(&0u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(kvs.iter().unwrap().count(), 0);
+}
+
+#[test]
+fn test_integer_keys() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new("integer_keys_test", config).unwrap();
+
+    let mut expected = BTreeMap::new();
+    let mut x: u64 = 12345;
+    for i in 0..2000 {
+        // a linear congruential generator for random keys
+        x = x
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let key = x as i64 >> 16;
+        kvs.put(&keycodec::encode_i64_be(key), &key.to_le_bytes())
+            .unwrap();
+        expected.insert(key, key);
+        if i == 1000 {
+            kvs.flush().unwrap();
+        }
+    }
+
+    let scan = |start: i64, end: i64| -> Vec<i64> {
+        kvs.scan(
+            &keycodec::encode_i64_be(start),
+            &keycodec::encode_i64_be(end),
+        )
+        .unwrap()
+        .map(|r| keycodec::decode_i64_be(&r.unwrap().0).unwrap())
+        .collect()
+    };
+    let all: Vec<i64> = expected.keys().copied().collect();
+    assert_eq!(scan(i64::MIN, i64::MAX), all);
+    let range: Vec<i64> = expected
+        .range(-(1 << 40)..(1 << 40))
+        .map(|(k, _)| *k)
+        .collect();
+    assert!(!range.is_empty());
+    assert_eq!(scan(-(1 << 40), 1 << 40), range);
+
+    // unsigned keys
+    for key in [u64::MAX, 0, 256, 1, 255].iter() {
+        kvs.put(&keycodec::encode_u64_be(*key), b"unsigned")
+            .unwrap();
+    }
+    let keys: Vec<u64> = kvs
+        .scan(&keycodec::encode_u64_be(0), &keycodec::encode_u64_be(257))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .filter(|(_, value)| value == b"unsigned")
+        .map(|(key, _)| keycodec::decode_u64_be(&key).unwrap())
+        .collect();
+    assert_eq!(keys, vec![0, 1, 255, 256]);
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+}
+
+#[test]
+fn test_typed_kvs() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "typed_kvs_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let user = |i: i64| User {
+        name: format!("user{}", i),
+        age: i.unsigned_abs() as u32,
+        tags: vec!["a".to_owned(); i.unsigned_abs() as usize % 3],
+    };
+
+    // keys of any serializable type with the default codec
+    let kvs: TypedKvs<(String, u32), User> =
+        TypedKvs::new(KVS::new(TABLE_NAME, config.clone()).unwrap());
+    let key = ("users".to_owned(), 1);
+    kvs.put(&key, &user(1)).unwrap();
+    assert_eq!(kvs.get(&key).unwrap(), Some(user(1)));
+    assert_eq!(kvs.get(&("users".to_owned(), 2)).unwrap(), None);
+    kvs.delete(&key).unwrap();
+    assert_eq!(kvs.get(&key).unwrap(), None);
+    drop(kvs);
+
+    // integer keys are scanned in their order
+    let kvs: TypedKvs<i64, User, OrderedIntCodec> =
+        TypedKvs::new(KVS::new(TABLE_NAME, config).unwrap());
+    for i in -50..50 {
+        kvs.put(&(i * 7), &user(i)).unwrap();
+    }
+    kvs.get_kvs().flush().unwrap();
+    kvs.put(&0, &user(100)).unwrap();
+    let scanned: Vec<(i64, User)> = kvs
+        .scan(&-21, &14)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        scanned,
+        vec![
+            (-21, user(-3)),
+            (-14, user(-2)),
+            (-7, user(-1)),
+            (0, user(100)),
+            (7, user(1))
+        ]
+    );
+
+    // a value of another type isn't decoded
+    kvs.get_kvs().put(&0i64.to_be_bytes(), b"broken").unwrap();
+    assert!(matches!(
+        kvs.get(&i64::MIN),
+        Err(CrudError::Serialization(_))
+    ));
+}
+
+#[test]
+fn test_read_during_compaction() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 100;
+    const TABLE_NAME: &str = "read_during_compaction_test";
+    let config = Config::builder().l0_compaction_trigger(2).build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    for i in 0..NUM_KEYS {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+    kvs.flush().unwrap();
+    let snapshot = kvs.snapshot().unwrap();
+    let mut scan = kvs.scan(b"k000", b"k100").unwrap();
+    assert_eq!(scan.next().unwrap().unwrap().0, b"k000".to_vec());
+
+    // the worker compacts the tables in the background
+    for i in 0..NUM_KEYS {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), b"new").unwrap();
+    }
+    kvs.flush().unwrap();
+    kvs.compact().unwrap();
+
+    // tables being read are kept until the readers finish
+    assert_eq!(scan.count(), NUM_KEYS - 1);
+    for i in 0..NUM_KEYS {
+        let key = format!("k{:03}", i);
+        assert_eq!(
+            snapshot.get(key.as_bytes()).unwrap(),
+            Some(key.into_bytes())
+        );
+    }
+    drop(snapshot);
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+
+    // a scan also keeps the tables
+    let scan = kvs.scan(b"k000", b"k100").unwrap();
+    kvs.put(b"k000", b"newer").unwrap();
+    kvs.flush().unwrap();
+    kvs.compact().unwrap();
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 2);
+    assert_eq!(scan.count(), NUM_KEYS);
+    assert_eq!(count_files(TABLE_NAME, "sstable-"), 1);
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_reopen_fptree() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 1025;
+    const TABLE_NAME: &str = "reopen_fptree_test";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(TABLE_NAME);
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .recover_fptree(true)
+        .build();
+    let count_tables = || {
+        std::fs::read_dir(&path)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("sstable-")
+            })
+            .count()
+    };
+    let check = |kvs: &KVS, num_keys: usize| {
+        for i in 0..num_keys {
+            let key = format!("k{:05}", i);
+            let expected = if (100..200).contains(&i) || i % 7 == 0 {
+                None
+            } else {
+                Some(format!("v{}", i).into_bytes())
+            };
+            assert_eq!(kvs.get(key.as_bytes()).unwrap(), expected);
+        }
+    };
+
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        kvs.put(key.as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+        if i % 7 == 0 {
+            kvs.delete(key.as_bytes()).unwrap();
+        }
+    }
+    kvs.delete_range(b"k00100", b"k00200").unwrap();
+    let len = kvs.approximate_len();
+
+    // RESTART
+    drop(kvs);
+    let kvs = KVS::open(TABLE_NAME, config.clone()).unwrap();
+    // the FPTree is reopened without a flush
+    assert_eq!(count_tables(), 0);
+    assert_eq!(kvs.approximate_len(), len);
+    check(&kvs, NUM_INSERTION);
+
+    // the reopened FPTree is split by new keys
+    for i in NUM_INSERTION..NUM_INSERTION * 2 {
+        let key = format!("k{:05}", i);
+        kvs.put(key.as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+        if i % 7 == 0 {
+            kvs.delete(key.as_bytes()).unwrap();
+        }
+    }
+    check(&kvs, NUM_INSERTION * 2);
+
+    // RESTART
+    drop(kvs);
+    let kvs = KVS::open(TABLE_NAME, config.clone()).unwrap();
+    assert_eq!(count_tables(), 0);
+    check(&kvs, NUM_INSERTION * 2);
+    let live_count = (0..NUM_INSERTION * 2)
+        .filter(|i| !(100..200).contains(i) && i % 7 != 0)
+        .count();
+    assert_eq!(kvs.iter().unwrap().count(), live_count);
+
+    kvs.flush().unwrap();
+    assert_eq!(count_tables(), 1);
+    check(&kvs, NUM_INSERTION * 2);
+    kvs.put(b"unflushed", b"value").unwrap();
+
+    // the FPTree is flushed without the recovery
+    drop(kvs);
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    assert_eq!(count_tables(), 2);
+    check(&kvs, NUM_INSERTION * 2);
+    assert_eq!(kvs.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_broken_leaf_chain() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 40;
+    const LEAF_SIZE: usize = 4 * 4096;
+    const TABLE_NAME: &str = "broken_leaf_chain_test";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(TABLE_NAME);
+    // ascending keys are written to leaves linked in the order of pages
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .num_slot(8)
+        .leaf_size(LEAF_SIZE)
+        .leaf_allocation(1)
+        .recover_fptree(true)
+        .build();
+
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+    drop(kvs);
+
+    // break the header slots of a leaf in the middle of the chain without the WAL
+    std::fs::remove_file(path.join("wal-0.amph")).unwrap();
+    let leaf_file = path.join("leaves-0.amph");
+    let mut bytes = std::fs::read(&leaf_file).unwrap();
+    // a leaf page has the header at its head, but a page only for data doesn't
+    let leaf_pages: Vec<usize> = (0..bytes.len() / LEAF_SIZE)
+        .filter(|id| {
+            bytes[id * LEAF_SIZE..id * LEAF_SIZE + 4096]
+                .iter()
+                .any(|b| *b != 0)
+        })
+        .collect();
+    assert!(leaf_pages.len() > 3);
+    let broken = leaf_pages[leaf_pages.len() / 2];
+    bytes[broken * LEAF_SIZE..(broken + 1) * LEAF_SIZE].fill(0);
+    std::fs::write(&leaf_file, bytes).unwrap();
+
+    // the leaves before the broken one are served
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    let mut num_found = 0;
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        match kvs.get(key.as_bytes()).unwrap() {
+            Some(value) => {
+                assert_eq!(value, key.as_bytes());
+                num_found += 1;
+            }
+            None => assert!(i > 0),
+        }
+    }
+    assert!(num_found > 0 && num_found < NUM_INSERTION);
+    assert_eq!(kvs.iter().unwrap().count(), num_found);
+
+    // the lost keys can be written again
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+    assert_eq!(kvs.iter().unwrap().count(), NUM_INSERTION);
+}
+
+#[test]
+fn test_compaction_rate_limit() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "compaction_rate_limit_test";
+    const RATE: u64 = 256 * 1024;
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .compaction_bytes_per_sec(RATE)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    for i in 0..160u32 {
+        kvs.put(&i.to_be_bytes(), &[i as u8; 4096]).unwrap();
+    }
+
+    let start = std::time::Instant::now();
+    kvs.flush().unwrap();
+    let elapsed = start.elapsed();
+
+    // up to a second of bytes might have been accumulated before the flush
+    let stats = kvs.stats();
+    assert!(stats.total_table_bytes > 2 * RATE);
+    let min_secs = (stats.total_table_bytes - RATE) as f64 / RATE as f64;
+    assert!(
+        elapsed >= Duration::from_secs_f64(min_secs),
+        "the flush of {} bytes took {:?}",
+        stats.total_table_bytes,
+        elapsed
+    );
+    assert!(stats.throttle_delay_micros > 0);
+    assert!(stats.throttle_micros > 0);
+    assert_eq!(kvs.get(&7u32.to_be_bytes()).unwrap(), Some(vec![7; 4096]));
+}
+
+#[test]
+fn test_read_cache() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "read_cache_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .read_cache_bytes(1024)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    kvs.put(b"hot", b"v1").unwrap();
+    kvs.flush().unwrap();
+
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(kvs.stats().read_cache_hits, 1);
+
+    // writes invalidate the cached value
+    kvs.put(b"hot", b"v2").unwrap();
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(kvs.get_bytes(b"hot").unwrap().unwrap(), &b"v2"[..]);
+    let mut batch = WriteBatch::new();
+    batch.put(b"hot", b"v3");
+    kvs.write(batch).unwrap();
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v3".to_vec()));
+    assert!(kvs
+        .compare_and_swap(b"hot", Some(b"v3"), Some(b"v4"))
+        .unwrap());
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v4".to_vec()));
+    kvs.delete(b"hot").unwrap();
+    assert_eq!(kvs.get(b"hot").unwrap(), None);
+    assert_eq!(kvs.get(b"hot").unwrap(), None);
+    kvs.ingest_sorted(vec![(b"hot".to_vec(), b"v5".to_vec())])
+        .unwrap();
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v5".to_vec()));
+    kvs.delete_range(b"a", b"z").unwrap();
+    assert_eq!(kvs.get(b"hot").unwrap(), None);
+
+    // concurrent overwrites never leave a stale value in the cache
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..1000 {
+                kvs.put(b"hot", format!("v{}", i).as_bytes()).unwrap();
+            }
+        });
+        s.spawn(|| {
+            for _ in 0..1000 {
+                kvs.get(b"hot").unwrap();
+            }
+        });
+    });
+    assert_eq!(kvs.get(b"hot").unwrap(), Some(b"v999".to_vec()));
+    assert!(kvs.stats().read_cache_hits > 1);
+}
+
+#[test]
+fn test_reuse_leaf_pages() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "reuse_leaf_pages_test";
+    let dir = tempfile::tempdir().unwrap();
+    // a page has room for only one key-value pair
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .leaf_size(3 * 4096)
+        .memtable_bytes(1 << 30)
+        .build();
+    let leaf_file = dir.path().join(TABLE_NAME).join("leaves-0.amph");
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    let mut file_size = 0;
+    for round in 0..100 {
+        for i in 0..4 {
+            let key = format!("k{}", i);
+            kvs.put(key.as_bytes(), format!("v{}", round).as_bytes())
+                .unwrap();
+            kvs.delete(key.as_bytes()).unwrap();
+        }
+        kvs.put(b"k0", format!("v{}", round).as_bytes()).unwrap();
+        if round == 10 {
+            file_size = std::fs::metadata(&leaf_file).unwrap().len();
+        }
+    }
+    // pages of overwritten values are reused
+    assert_eq!(std::fs::metadata(&leaf_file).unwrap().len(), file_size);
+    assert_eq!(kvs.get(b"k0").unwrap(), Some(b"v99".to_vec()));
+    for i in 1..4 {
+        assert_eq!(kvs.get(format!("k{}", i).as_bytes()).unwrap(), None);
+    }
+}
+
+#[test]
+fn test_leaf_compaction() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_UPDATES: usize = 3000;
+    const TABLE_NAME: &str = "leaf_compaction_test";
+    let dir = tempfile::tempdir().unwrap();
+    // a page has room for 6 key-value pairs
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .leaf_size(8 * 4096)
+        .memtable_bytes(1 << 30)
+        .recover_fptree(true)
+        .build();
+    let leaf_file = dir.path().join(TABLE_NAME).join("leaves-0.amph");
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    kvs.put(b"k1", b"v1").unwrap();
+    kvs.put(b"k2", b"v2").unwrap();
+
+    let mut file_size = 0;
+    for i in 0..NUM_UPDATES {
+        kvs.put(b"k0", format!("v{}", i).as_bytes()).unwrap();
+        if i == 100 {
+            file_size = std::fs::metadata(&leaf_file).unwrap().len();
+        }
+    }
+    assert_eq!(std::fs::metadata(&leaf_file).unwrap().len(), file_size);
+    assert_eq!(kvs.approximate_len(), 3);
+
+    // RESTART
+    drop(kvs);
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    let expected = format!("v{}", NUM_UPDATES - 1).into_bytes();
+    assert_eq!(kvs.get(b"k0").unwrap(), Some(expected));
+    assert_eq!(kvs.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(kvs.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+}
+
+#[test]
+fn test_small_leaves() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 1000;
+    const TABLE_NAME: &str = "small_leaves_test";
+    let dir = tempfile::tempdir().unwrap();
+    // leaves with the minimum slots are split every 4 insertions
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .num_slot(8)
+        .leaf_size(4 * 4096)
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    // ascending keys always go to the new leaf of the last split
+    for i in 0..NUM_INSERTION {
+        let key = format!("a{:05}", i);
+        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+    // descending keys always stay in the split leaf
+    for i in (0..NUM_INSERTION).rev() {
+        let key = format!("d{:05}", i);
+        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+
+    for prefix in ["a", "d"] {
+        for i in 0..NUM_INSERTION {
+            let key = format!("{}{:05}", prefix, i);
+            assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(key.into_bytes()));
+        }
+    }
+    assert_eq!(kvs.iter().unwrap().count(), NUM_INSERTION * 2);
+}
+
+#[test]
+fn test_wal() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 500;
+    const TABLE_NAME: &str = "wal_test";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(TABLE_NAME);
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .wal_sync(WalSync::Always)
+        .build();
+    let check = |kvs: &KVS, num_keys: usize| {
+        for i in 0..num_keys {
+            let key = format!("k{:05}", i);
+            let expected = (i % 7 != 0).then(|| format!("v{}", i).into_bytes());
+            assert_eq!(kvs.get(key.as_bytes()).unwrap(), expected);
+        }
+    };
+    let write = |kvs: &KVS, keys: std::ops::Range<usize>| {
+        for i in keys {
+            let key = format!("k{:05}", i);
+            kvs.put(key.as_bytes(), format!("v{}", i).as_bytes())
+                .unwrap();
+            if i % 7 == 0 {
+                kvs.delete(key.as_bytes()).unwrap();
+            }
+        }
+    };
+
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    write(&kvs, 0..NUM_INSERTION);
+    // CRASH without the flush, and the leaves haven't been persisted
+    crash(kvs, &path);
+    std::fs::remove_file(path.join("leaves-0.amph")).unwrap();
+
+    // the FPTree is recovered from the WAL and flushed
+    let kvs = KVS::open(TABLE_NAME, config.clone()).unwrap();
+    check(&kvs, NUM_INSERTION);
+    assert!(path.join("sstable-0.amph").exists());
+    drop(kvs);
+
+    // the reopened FPTree keeps logging to the WAL
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .recover_fptree(true)
+        .build();
+    let kvs = KVS::open(TABLE_NAME, config.clone()).unwrap();
+    write(&kvs, NUM_INSERTION..NUM_INSERTION * 2);
+    crash(kvs, &path);
+    // a record is partially written by the crash
+    let wal_path = path.join("wal-0.amph");
+    let mut wal = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&wal_path)
+        .unwrap();
+    wal.write_all(&[0xFF, 0xFF, 0, 0, b'k']).unwrap();
+    drop(wal);
+
+    let kvs = KVS::open(TABLE_NAME, config.clone()).unwrap();
+    check(&kvs, NUM_INSERTION * 2);
+    write(&kvs, NUM_INSERTION * 2..NUM_INSERTION * 3);
+    crash(kvs, &path);
+    std::fs::remove_file(path.join("leaves-0.amph")).unwrap();
+
+    // the records after the broken one are also replayed
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    check(&kvs, NUM_INSERTION * 3);
+    assert!(path.join("sstable-2.amph").exists());
+    kvs.put(b"new", b"value").unwrap();
+    kvs.flush().unwrap();
+    // the WAL is removed with the flushed FPTree
+    assert!(!wal_path.exists());
+    assert!(path.join("wal-1.amph").exists());
+    check(&kvs, NUM_INSERTION * 3);
+}
+
+#[test]
+fn test_write_batch_torn_record() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "write_batch_torn_record_test";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(TABLE_NAME);
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .recover_fptree(true)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_INSERTION {
+        kvs.put(format!("k{:03}", i).as_bytes(), b"old").unwrap();
+    }
+    let wal_path = path.join("wal-0.amph");
+    let valid_size = std::fs::metadata(&wal_path).unwrap().len();
+
+    // the batch overwrites half of its keys and adds the others
+    let mut batch = WriteBatch::new();
+    for i in (NUM_INSERTION / 2)..(NUM_INSERTION * 3 / 2) {
+        batch.put(format!("k{:03}", i).as_bytes(), b"batch");
+    }
+    kvs.write(batch).unwrap();
+    // CRASH while appending the record of the applied batch
+    crash(kvs, &path);
+    let wal = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .unwrap();
+    wal.set_len(valid_size + 5).unwrap();
+    drop(wal);
+
+    // none of the batch is in the recovered FPTree
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    for i in 0..(NUM_INSERTION * 3 / 2) {
+        let expected = (i < NUM_INSERTION).then(|| b"old".to_vec());
+        assert_eq!(kvs.get(format!("k{:03}", i).as_bytes()).unwrap(), expected);
+    }
+    assert_eq!(kvs.iter().unwrap().count(), NUM_INSERTION);
+}
+
+#[test]
+fn test_durability_throughput() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 2000;
+    const TABLE_NAME: &str = "durability_throughput_test";
+    for durability in [
+        Durability::PerWrite,
+        Durability::Batched(Duration::from_millis(100)),
+        Durability::OnFlushOnly,
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .leaf_dir(dir.path().to_str().unwrap())
+            .table_dir(dir.path().to_str().unwrap())
+            .memtable_bytes(1 << 30)
+            .durability(durability)
+            .build();
+        let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+        let start = std::time::Instant::now();
+        for i in 0..NUM_INSERTION {
+            let key = format!("k{:05}", i);
+            kvs.put(key.as_bytes(), &[0u8; 100]).unwrap();
+        }
+        let elapsed = start.elapsed();
+        log::debug!(
+            "{:?}: {:.0} puts/s",
+            durability,
+            NUM_INSERTION as f64 / elapsed.as_secs_f64()
+        );
+
+        for i in 0..NUM_INSERTION {
+            let key = format!("k{:05}", i);
+            assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(vec![0u8; 100]));
+        }
+    }
+}
+
+#[test]
+fn test_disjoint_concurrent_insert_throughput() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 16000;
+    const TABLE_NAME: &str = "disjoint_concurrent_insert_test";
+    for num_threads in [1, 4] {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .leaf_dir(dir.path().to_str().unwrap())
+            .table_dir(dir.path().to_str().unwrap())
+            .memtable_bytes(1 << 30)
+            .durability(Durability::OnFlushOnly)
+            .build();
+        let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+
+        // each thread puts keys in its own range
+        let start = std::time::Instant::now();
+        let writers: Vec<_> = (0..num_threads)
+            .map(|t| {
+                let kvs = kvs.clone();
+                std::thread::spawn(move || {
+                    for i in 0..(NUM_INSERTION / num_threads) {
+                        let key = format!("t{}:k{:05}", t, i);
+                        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        log::debug!(
+            "{} threads: {:.0} puts/s",
+            num_threads,
+            NUM_INSERTION as f64 / elapsed.as_secs_f64()
+        );
+
+        // all keys are in the FPTree in the key order
+        assert_eq!(kvs.stats().flush_count, 0);
+        let mut expected: Vec<String> = (0..num_threads)
+            .flat_map(|t| {
+                (0..(NUM_INSERTION / num_threads)).map(move |i| format!("t{}:k{:05}", t, i))
+            })
+            .collect();
+        expected.sort();
+        let scanned: Vec<Vec<u8>> = kvs.iter().unwrap().map(|kv| kv.unwrap().0).collect();
+        assert_eq!(scanned.len(), NUM_INSERTION);
+        for (key, expected) in scanned.iter().zip(&expected) {
+            assert_eq!(key, expected.as_bytes());
+            assert_eq!(kvs.get(key).unwrap(), Some(key.clone()));
+        }
+    }
+}
+
+#[test]
+fn test_per_write_durability() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 500;
+    const TABLE_NAME: &str = "per_write_durability_test";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(TABLE_NAME);
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .durability(Durability::PerWrite)
+        .build();
+
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        kvs.put(key.as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+    }
+    // CRASH without the flush and the WAL
+    crash(kvs, &path);
+    std::fs::remove_file(path.join("wal-0.amph")).unwrap();
+
+    // all writes have been persisted in the leaves
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        assert_eq!(
+            kvs.get(key.as_bytes()).unwrap(),
+            Some(format!("v{}", i).into_bytes())
+        );
+    }
+}
+
+#[test]
+fn test_scan_during_insertion() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 1000;
+    const TABLE_NAME: &str = "scan_during_insertion_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .num_slot(8)
+        .leaf_size(4 * 4096)
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i * 2);
+        kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+
+    // insert keys between the existing keys in a scattered order to split leaves
+    let writer = {
+        let kvs = kvs.clone();
+        std::thread::spawn(move || {
+            for i in 0..NUM_INSERTION {
+                let key = format!("k{:05}", (i * 7919) % NUM_INSERTION * 2 + 1);
+                kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+            }
+        })
+    };
+    while !writer.is_finished() {
+        let keys: Vec<Vec<u8>> = kvs
+            .scan(b"k", b"l")
+            .unwrap()
+            .map(|kv| kv.unwrap().0)
+            .collect();
+        // a concurrent insertion might be missed, but the keys are never repeated
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        for i in 0..NUM_INSERTION {
+            let key = format!("k{:05}", i * 2);
+            assert!(keys.binary_search(&key.into_bytes()).is_ok());
+        }
+    }
+    writer.join().unwrap();
+
+    assert_eq!(kvs.scan(b"k", b"l").unwrap().count(), NUM_INSERTION * 2);
+}
+
+#[test]
+fn test_level_summary() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_FLUSHES: usize = 5;
+    const TABLE_NAME: &str = "level_summary_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .l0_compaction_trigger(NUM_FLUSHES + 1)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    assert!(kvs.level_summary().is_empty());
+
+    for i in 0..NUM_FLUSHES {
+        let key = format!("key{:05}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+        kvs.flush().unwrap();
+
+        let summary = kvs.level_summary();
+        assert_eq!(summary.len(), 1);
+        let (level, count, bytes) = summary[0];
+        assert_eq!((level, count), (0, i + 1));
+        let file_bytes: u64 = std::fs::read_dir(dir.path().join(TABLE_NAME))
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("sstable-"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum();
+        assert_eq!(bytes, file_bytes);
+    }
+
+    // all tables are merged out of Level 0
+    kvs.compact().unwrap();
+    let summary = kvs.level_summary();
+    assert_eq!(summary[0].1, 0);
+    assert!(
+        summary[1..]
+            .iter()
+            .map(|(_, count, _)| count)
+            .sum::<usize>()
+            > 0
+    );
+    assert_eq!(
+        summary.iter().map(|(_, _, bytes)| bytes).sum::<u64>(),
+        kvs.stats().total_table_bytes
+    );
+}
+
+#[test]
+fn test_stats() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "stats_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .num_slot(8)
+        .leaf_size(4 * 4096)
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("key{:05}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+    }
+    let stats = kvs.stats();
+    assert!(stats.tables_per_level.is_empty());
+    assert_eq!(stats.total_table_bytes, 0);
+    assert_eq!(stats.flush_count, 0);
+    assert_eq!(stats.bloom_checks(), 0);
+    assert!(stats.root_split_count > 0);
+
+    kvs.flush().unwrap();
+    let stats = kvs.stats();
+    assert_eq!(stats.tables_per_level, vec![1]);
+    let table_file = dir.path().join(TABLE_NAME).join("sstable-0.amph");
+    assert_eq!(
+        stats.total_table_bytes,
+        std::fs::metadata(table_file).unwrap().len()
+    );
+    assert_eq!(stats.flush_count, 1);
+    // the new FPTree hasn't been split
+    assert_eq!(stats.root_split_count, 0);
+
+    // the key is read from the table
+    assert_eq!(kvs.get(b"key00050").unwrap(), Some(b"value".to_vec()));
+    let stats = kvs.stats();
+    assert_eq!(stats.bloom_checks(), 1);
+    assert_eq!(stats.bloom_true_positives, 1);
+    // the filter rejects the key unless it's a false positive
+    assert_eq!(kvs.get(b"key00050x").unwrap(), None);
+    let stats = kvs.stats();
+    assert_eq!(stats.bloom_checks(), 2);
+    assert_eq!(stats.bloom_true_positives, 1);
+    assert_eq!(stats.bloom_negatives + stats.bloom_false_positives, 1);
+    // the key out of the key range of the table isn't checked
+    assert_eq!(kvs.get(b"zzz").unwrap(), None);
+    assert_eq!(kvs.stats().bloom_checks(), 2);
+
+    let dumped = format!("{:?}", stats.clone());
+    assert!(dumped.contains("flush_count: 1"));
+}
+
+#[derive(Default)]
+struct CountingListener {
+    flushes: AtomicUsize,
+    flushed_entries: AtomicUsize,
+    compactions: Mutex<Vec<(Vec<usize>, Vec<usize>)>>,
+}
+
+impl Listener for CountingListener {
+    fn on_flush(&self, _table_id: usize, entry_count: usize, bytes: usize) {
+        assert!(bytes > 0);
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flushed_entries
+            .fetch_add(entry_count, Ordering::Relaxed);
+    }
+
+    fn on_compaction(&self, inputs: &[usize], outputs: &[usize]) {
+        self.compactions
+            .lock()
+            .unwrap()
+            .push((inputs.to_vec(), outputs.to_vec()));
+    }
+}
+
+#[test]
+fn test_listener() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "listener_test";
+    let dir = tempfile::tempdir().unwrap();
+    let listener = Arc::new(CountingListener::default());
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .l0_compaction_trigger(10)
+        .listener(listener.clone())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    for i in 0..3 {
+        for j in 0..10 {
+            kvs.put(format!("k{}{}", i, j).as_bytes(), b"value")
+                .unwrap();
+        }
+        kvs.flush().unwrap();
+        assert_eq!(listener.flushes.load(Ordering::Relaxed), i + 1);
+    }
+    assert_eq!(listener.flushed_entries.load(Ordering::Relaxed), 30);
+    // nothing to flush
+    kvs.flush().unwrap();
+    assert_eq!(listener.flushes.load(Ordering::Relaxed), 3);
+    assert!(listener.compactions.lock().unwrap().is_empty());
+
+    kvs.compact().unwrap();
+    let compactions = listener.compactions.lock().unwrap().clone();
+    assert_eq!(compactions, vec![(vec![4, 2, 0], vec![1])]);
+}
+
+#[test]
+fn test_separate_dirs() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "separate_dirs_test";
+    let leaf_dir = tempfile::tempdir().unwrap();
+    let table_dir = tempfile::tempdir().unwrap();
+    let leaf_path = leaf_dir.path().join(TABLE_NAME);
+    let table_path = table_dir.path().join(TABLE_NAME);
+    let config = Config::builder()
+        .leaf_dir(leaf_dir.path().to_str().unwrap())
+        .table_dir(table_dir.path().to_str().unwrap())
+        .build();
+    let count = |path: &std::path::Path, prefix: &str| {
+        std::fs::read_dir(path)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(prefix)
+            })
+            .count()
+    };
+
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+    }
+    kvs.flush().unwrap();
+    kvs.put(b"unflushed", b"value").unwrap();
+
+    assert_eq!(count(&leaf_path, "leaves-"), 1);
+    assert_eq!(count(&leaf_path, "sstable-"), 0);
+    assert_eq!(count(&table_path, "sstable-"), 1);
+    assert_eq!(count(&table_path, "metadata"), 1);
+    assert_eq!(count(&table_path, "leaves-"), 0);
+
+    // RESTART
+    drop(kvs);
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{}", i);
+        assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(b"value".to_vec()));
+    }
+    assert_eq!(kvs.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_invalid_config() {
+    let config = Config::builder().bloom_fp_rate(0.0).build();
+    match KVS::new("invalid_config_test", config) {
+        Err(CrudError::InvalidConfig(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the invalid config should be rejected"),
+    }
+    assert!(!std::path::Path::new("data/invalid_config_test").exists());
+}
+
+#[test]
+fn test_corruption() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "corruption_test";
+    let config = Config::new().unwrap();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..10 {
+        kvs.put(format!("k{}", i).as_bytes(), b"value").unwrap();
+    }
+    kvs.flush().unwrap();
+    drop(kvs);
+
+    // break the first data block
+    let table_file = std::fs::read_dir(format!("data/{}", TABLE_NAME))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().contains("sstable-"))
+        .unwrap();
+    let mut bytes = std::fs::read(&table_file).unwrap();
+    bytes[20] ^= 0xFF;
+    std::fs::write(&table_file, bytes).unwrap();
+
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    assert!(matches!(kvs.get(b"k0"), Err(CrudError::Corruption(_))));
+    let mut iter = kvs.iter().unwrap();
+    assert!(matches!(iter.next(), Some(Err(CrudError::Corruption(_)))));
+    assert!(matches!(
+        kvs.open_cf("../invalid"),
+        Err(CrudError::InvalidInput(_))
+    ));
+
+    drop(kvs);
+    let _ = std::fs::remove_dir_all(format!("data/{}", TABLE_NAME));
+}
+
+#[test]
+fn test_debug_dump() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "debug_dump_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .num_slot(8)
+        .leaf_size(4 * 4096)
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("key{:05}", i);
+        kvs.put(key.as_bytes(), b"value").unwrap();
+    }
+
+    let mut out = Vec::new();
+    kvs.debug_dump(&mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    assert!(dump.contains("FPTree (current):"));
+    assert!(dump.lines().next().unwrap().starts_with("column family"));
+    // the root has been split
+    assert!(dump.contains("\ninner keys: "));
+    let leaves: Vec<&str> = dump
+        .lines()
+        .skip_while(|line| *line != "leaf chain:")
+        .skip(1)
+        .take_while(|line| line.starts_with("  leaf "))
+        .collect();
+    assert!(leaves.len() > 1);
+    let used_slots: usize = leaves.iter().map(|line| line.matches('#').count()).sum();
+    assert_eq!(used_slots, NUM_INSERTION);
+
+    kvs.flush().unwrap();
+    let mut out = Vec::new();
+    kvs.debug_dump(&mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    assert!(dump.contains("level 0: 1 tables"));
+    assert!(dump.contains("table 0: "));
+    assert!(dump.contains(&format!("{} entries", NUM_INSERTION)));
+}
+
+#[test]
+fn test_export_import_jsonl() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 2000;
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new("export_test", config.clone()).unwrap();
+    // binary keys and values with NUL bytes
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..NUM_INSERTION as u32)
+        .map(|i| {
+            let mut key = vec![0u8];
+            key.extend(i.to_be_bytes());
+            (key, vec![0, 0xff, (i % 256) as u8])
+        })
+        .collect();
+    for (key, value) in &pairs {
+        kvs.put(key, value).unwrap();
+    }
+    kvs.flush().unwrap();
+    kvs.put(b"\x00deleted", b"value").unwrap();
+    kvs.delete(b"\x00deleted").unwrap();
+
+    let mut out = Vec::new();
+    kvs.export_jsonl(&mut out).unwrap();
+    // tombstones are skipped
+    assert_eq!(out.iter().filter(|&&b| b == b'\n').count(), NUM_INSERTION);
+
+    let imported = KVS::new("import_test", config).unwrap();
+    imported.import_jsonl(&mut out.as_slice()).unwrap();
+    let actual: Vec<_> = imported.iter().unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(actual, pairs);
+
+    let mut input = "{\"key\":\"YQ==\",\"value\":\"\"}\nnot json\n".as_bytes();
+    assert!(matches!(
+        imported.import_jsonl(&mut input),
+        Err(CrudError::InvalidInput(_))
+    ));
+    // the batch including the malformed line isn't written
+    assert_eq!(imported.get(b"a").unwrap(), None);
+}
+
+#[test]
+fn test_scan_to_writer() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 5000;
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new("scan_to_writer_test", config).unwrap();
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:05}", i);
+        kvs.put(key.as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+        if i == NUM_INSERTION / 2 {
+            kvs.flush().unwrap();
+        }
+    }
+    kvs.delete(b"k01000").unwrap();
+
+    let mut out = Vec::new();
+    let count = kvs.scan_to_writer(b"k00100", b"k04100", &mut out).unwrap();
+    assert_eq!(count, 3999);
+
+    let mut records = Vec::new();
+    let mut rest = out.as_slice();
+    let read_field = |rest: &mut &[u8]| -> Vec<u8> {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&rest[..4]);
+        let (field, tail) = rest[4..].split_at(u32::from_le_bytes(len) as usize);
+        *rest = tail;
+        field.to_vec()
+    };
+    while !rest.is_empty() {
+        let key = read_field(&mut rest);
+        let value = read_field(&mut rest);
+        records.push((key, value));
+    }
+    let expected: Vec<_> = (100..4100)
+        .filter(|i| *i != 1000)
+        .map(|i| {
+            (
+                format!("k{:05}", i).into_bytes(),
+                format!("v{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    assert_eq!(records.len() as u64, count);
+    assert_eq!(records, expected);
+
+    // nothing is written for an empty range
+    let mut out = Vec::new();
+    assert_eq!(kvs.scan_to_writer(b"x", b"y", &mut out).unwrap(), 0);
+    assert!(out.is_empty());
+}
+
+#[test]
+fn test_ingest_sorted() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_PAIRS: u32 = 1_000_000;
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new("ingest_test", config).unwrap();
+    // older values are overwritten by the ingested ones
+    kvs.put(&7u32.to_be_bytes(), b"old").unwrap();
+
+    kvs.ingest_sorted(
+        (0..NUM_PAIRS).map(|i| (i.to_be_bytes().to_vec(), (i * 2).to_le_bytes().to_vec())),
+    )
+    .unwrap();
+    // the FPTree has been flushed before the ingested table
+    assert_eq!(kvs.stats().flush_count, 1);
+    assert_eq!(
+        kvs.get(&7u32.to_be_bytes()).unwrap(),
+        Some(14u32.to_le_bytes().to_vec())
+    );
+
+    let mut x: u32 = 12345;
+    for _ in 0..1000 {
+        // a linear congruential generator for random keys
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        let i = x % NUM_PAIRS;
+        assert_eq!(
+            kvs.get(&i.to_be_bytes()).unwrap(),
+            Some((i * 2).to_le_bytes().to_vec())
+        );
+    }
+    assert_eq!(kvs.get(&NUM_PAIRS.to_be_bytes()).unwrap(), None);
+
+    // newer writes overwrite the ingested values
+    kvs.put(&8u32.to_be_bytes(), b"new").unwrap();
+    assert_eq!(kvs.get(&8u32.to_be_bytes()).unwrap(), Some(b"new".to_vec()));
+
+    // keys out of order are rejected without any table
+    let pairs = vec![
+        (b"b".to_vec(), b"value".to_vec()),
+        (b"a".to_vec(), b"value".to_vec()),
+    ];
+    assert!(matches!(
+        kvs.ingest_sorted(pairs),
+        Err(CrudError::InvalidInput(_))
+    ));
+    let pairs = vec![
+        (b"a".to_vec(), b"value".to_vec()),
+        (b"a".to_vec(), b"value".to_vec()),
+    ];
+    assert!(matches!(
+        kvs.ingest_sorted(pairs),
+        Err(CrudError::InvalidInput(_))
+    ));
+    assert_eq!(kvs.get(b"b").unwrap(), None);
+    kvs.ingest_sorted(Vec::new()).unwrap();
+    // the FPTree with the new write has been flushed
+    assert_eq!(kvs.stats().flush_count, 2);
+    assert_eq!(kvs.get(&8u32.to_be_bytes()).unwrap(), Some(b"new".to_vec()));
+    assert!(!std::fs::read_dir(dir.path().join("ingest_test"))
+        .unwrap()
+        .any(|entry| entry.unwrap().path().to_string_lossy().ends_with(".tmp")));
+}
+
+#[test]
+fn test_ingest_to_bottom() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_RANGES: usize = 4;
+    const TABLE_NAME: &str = "ingest_to_bottom_test";
+    let dir = tempfile::tempdir().unwrap();
+    let listener = Arc::new(CountingListener::default());
+    // ingested tables in Level 0 would be compacted
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .l0_compaction_trigger(2)
+        .listener(listener.clone())
+        .build();
+    let count_tables = || {
+        std::fs::read_dir(dir.path().join(TABLE_NAME))
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("sstable-")
+            })
+            .count()
+    };
+    let options = IngestOptions {
+        level: IngestLevel::Bottom,
+        check_overlap: true,
+    };
+
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for r in 0..NUM_RANGES {
+        let pairs = (0..100).map(|i| {
+            let key = format!("r{}-k{:03}", r, i);
+            (key.clone().into_bytes(), key.into_bytes())
+        });
+        kvs.ingest_sorted_with(pairs, &options).unwrap();
+    }
+    assert_eq!(
+        kvs.level_summary()
+            .iter()
+            .map(|(level, count, _)| (*level, *count))
+            .collect::<Vec<_>>(),
+        vec![(0, 0), (1, NUM_RANGES)]
+    );
+    assert_eq!(kvs.get(b"r2-k050").unwrap(), Some(b"r2-k050".to_vec()));
+
+    // overlapping keys are rejected without any table
+    let pairs = vec![(b"r1-k050x".to_vec(), b"value".to_vec())];
+    assert!(matches!(
+        kvs.ingest_sorted_with(pairs, &options),
+        Err(CrudError::InvalidInput(_))
+    ));
+    assert_eq!(count_tables(), NUM_RANGES);
+    assert_eq!(kvs.get(b"r1-k050x").unwrap(), None);
+
+    // newer writes overwrite the ingested values
+    kvs.put(b"r0-k000", b"new").unwrap();
+    assert_eq!(kvs.get(b"r0-k000").unwrap(), Some(b"new".to_vec()));
+
+    // the compaction worker has handled all requests when the KVS is dropped
+    drop(kvs);
+    assert!(listener.compactions.lock().unwrap().is_empty());
+
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    assert_eq!(kvs.level_summary()[1].1, NUM_RANGES);
+    assert_eq!(kvs.get(b"r0-k000").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(kvs.get(b"r3-k099").unwrap(), Some(b"r3-k099".to_vec()));
+}
+
+#[test]
+fn test_checkpoint() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 5000;
+    const TABLE_NAME: &str = "checkpoint_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(16 * 1024)
+        .l0_compaction_trigger(2)
+        .build();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config.clone()).unwrap());
+    let cf = kvs.open_cf("cf").unwrap();
+    kvs.put_cf(&cf, b"cf_key", b"value").unwrap();
+
+    let committed = Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let kvs = kvs.clone();
+        let committed = committed.clone();
+        std::thread::spawn(move || {
+            for i in 0..NUM_INSERTION {
+                let key = format!("k{:05}", i);
+                kvs.put(key.as_bytes(), key.as_bytes()).unwrap();
+                committed.store(i + 1, Ordering::SeqCst);
+            }
+        })
+    };
+    while committed.load(Ordering::SeqCst) < NUM_INSERTION / 2 {
+        std::thread::yield_now();
+    }
+    let committed_before = committed.load(Ordering::SeqCst);
+    kvs.checkpoint(&dir.path().join("backup")).unwrap();
+    writer.join().unwrap();
+
+    // the existing directory isn't overwritten
+    assert!(matches!(
+        kvs.checkpoint(&dir.path().join("backup")),
+        Err(CrudError::InvalidInput(_))
+    ));
+
+    let backup = KVS::open("backup", config).unwrap();
+    let keys: Vec<Vec<u8>> = backup.iter().unwrap().map(|kv| kv.unwrap().0).collect();
+    // the checkpoint has all writes before it, and no write is skipped after them
+    assert!(keys.len() >= committed_before);
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(key, format!("k{:05}", i).as_bytes());
+    }
+    let backup_cf = backup.open_cf("cf").unwrap();
+    assert_eq!(
+        backup.get_cf(&backup_cf, b"cf_key").unwrap(),
+        Some(b"value".to_vec())
+    );
+
+    // the original database keeps all writes
+    assert_eq!(kvs.iter().unwrap().count(), NUM_INSERTION);
+}
+
+#[test]
+fn test_absorb() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new("absorb_test", config.clone()).unwrap();
+    for i in 0..100 {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), b"ours").unwrap();
+    }
+    kvs.delete(b"k000").unwrap();
+
+    for (name, start) in [("other1", 50), ("other2", 150)] {
+        let other = KVS::new(name, config.clone()).unwrap();
+        for i in start..(start + 100) {
+            let key = format!("k{:03}", i);
+            other.put(key.as_bytes(), b"theirs").unwrap();
+        }
+        // a part of pairs is in SSTables, and the others are in leaves
+        other.flush().unwrap();
+        other.put(b"k000", b"theirs").unwrap();
+        other.delete(b"k001").unwrap();
+    }
+
+    // the values of the other database win by default
+    kvs.absorb(&dir.path().join("other1")).unwrap();
+    assert_eq!(kvs.get(b"k000").unwrap(), Some(b"theirs".to_vec()));
+    // the deletion in the other database isn't applied
+    assert_eq!(kvs.get(b"k001").unwrap(), Some(b"ours".to_vec()));
+    assert_eq!(kvs.get(b"k049").unwrap(), Some(b"ours".to_vec()));
+    assert_eq!(kvs.get(b"k050").unwrap(), Some(b"theirs".to_vec()));
+    assert_eq!(kvs.get(b"k149").unwrap(), Some(b"theirs".to_vec()));
+    assert_eq!(kvs.iter().unwrap().count(), 150);
+
+    kvs.put(b"k150", b"ours").unwrap();
+    let resolved = Mutex::new(Vec::new());
+    kvs.absorb_with(&dir.path().join("other2"), |key, ours, theirs| {
+        resolved.lock().unwrap().push(key.to_vec());
+        [key, b":".as_slice(), ours, b"+".as_slice(), theirs].concat()
+    })
+    .unwrap();
+    // only k000 and k150 exist in both databases
+    assert_eq!(
+        resolved.into_inner().unwrap(),
+        vec![b"k000".to_vec(), b"k150".to_vec()]
+    );
+    assert_eq!(
+        kvs.get(b"k000").unwrap(),
+        Some(b"k000:theirs+theirs".to_vec())
+    );
+    assert_eq!(
+        kvs.get(b"k150").unwrap(),
+        Some(b"k150:ours+theirs".to_vec())
+    );
+    assert_eq!(kvs.get(b"k151").unwrap(), Some(b"theirs".to_vec()));
+    assert_eq!(kvs.iter().unwrap().count(), 250);
+
+    assert!(matches!(
+        kvs.absorb(&dir.path().join("missing")),
+        Err(CrudError::NotFound(_))
+    ));
+}
+
+#[test]
+fn test_already_open() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "already_open_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    kvs.put(b"key", b"value").unwrap();
+
+    match KVS::open(TABLE_NAME, config.clone()) {
+        Err(CrudError::AlreadyOpen(name)) => assert_eq!(name, TABLE_NAME),
+        _ => panic!("the database should be locked"),
+    }
+    // the failed open doesn't break the opened one
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+    // the lock is released on drop
+    drop(kvs);
+    let kvs = KVS::open(TABLE_NAME, config).unwrap();
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_get_bytes() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 100;
+    const TABLE_NAME: &str = "get_bytes_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    let large_value = vec![0xab; 64 * 1024];
+    for i in 0..NUM_INSERTION {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), &large_value[..i * 100]).unwrap();
+    }
+    kvs.delete(b"k000").unwrap();
+    // values in SSTables
+    kvs.flush().unwrap();
+    // values in the FPTree overwriting the flushed ones
+    for i in (0..NUM_INSERTION).step_by(2) {
+        let key = format!("k{:03}", i);
+        kvs.put(key.as_bytes(), format!("new{}", i).as_bytes())
+            .unwrap();
+    }
+    kvs.put(b"large", &large_value).unwrap();
+    kvs.put_with_ttl(b"expired", b"value", Duration::ZERO)
+        .unwrap();
+
+    let mut keys: Vec<Vec<u8>> = (0..NUM_INSERTION)
+        .map(|i| format!("k{:03}", i).into_bytes())
+        .collect();
+    keys.extend([b"large".to_vec(), b"expired".to_vec(), b"missing".to_vec()]);
+    for key in keys {
+        let bytes = kvs.get_bytes(&key).unwrap();
+        assert_eq!(bytes.as_deref(), kvs.get(&key).unwrap().as_deref());
+    }
+    assert_eq!(kvs.get_bytes(b"large").unwrap().unwrap().len(), 64 * 1024);
+    assert_eq!(kvs.get_bytes(b"k001").unwrap().unwrap().len(), 100);
+    assert_eq!(kvs.get_bytes(b"expired").unwrap(), None);
+    assert_eq!(kvs.get_bytes(b"missing").unwrap(), None);
+}
+
+/// Block the compaction worker after each compaction until it's released
+#[derive(Default)]
+struct BlockingListener {
+    released: std::sync::atomic::AtomicBool,
+}
+
+impl Listener for BlockingListener {
+    fn on_compaction(&self, _inputs: &[usize], _outputs: &[usize]) {
+        while !self.released.load(Ordering::Acquire) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+#[test]
+fn test_write_stall() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 5000;
+    const MAX_L0_TABLES: usize = 3;
+    const TABLE_NAME: &str = "write_stall_test";
+    let dir = tempfile::tempdir().unwrap();
+    let listener = Arc::new(BlockingListener::default());
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1024)
+        .l0_compaction_trigger(2)
+        .max_l0_tables(MAX_L0_TABLES)
+        .write_stall_timeout(Duration::from_secs(60))
+        .listener(listener.clone())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+
+    std::thread::scope(|s| {
+        let writer = s.spawn(|| {
+            for i in 0..NUM_INSERTION {
+                let key = format!("k{:05}", i);
+                kvs.put(key.as_bytes(), format!("v{}", i).as_bytes())
+                    .unwrap();
+            }
+        });
+
+        // puts stall instead of flushing more tables while the compaction is blocked
+        while kvs.stats().write_stalls == 0 {
+            assert!(!writer.is_finished());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        let stats = kvs.stats();
+        // a write which passed the check might flush one more table
+        assert!(stats.tables_per_level[0] <= MAX_L0_TABLES + 1);
+        assert!(!writer.is_finished());
+
+        listener.released.store(true, Ordering::Release);
+        writer.join().unwrap();
+    });
+
+    let stats = kvs.stats();
+    assert!(stats.write_stall_micros >= 100_000);
+    assert!(stats.tables_per_level.len() > 1);
+    for i in (0..NUM_INSERTION).step_by(97) {
+        let key = format!("k{:05}", i);
+        assert_eq!(
+            kvs.get(key.as_bytes()).unwrap(),
+            Some(format!("v{}", i).into_bytes())
+        );
+    }
+}
+
+#[test]
+fn test_iter_with_source() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const TABLE_NAME: &str = "iter_with_source_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    kvs.put(b"k1", b"old").unwrap();
+    kvs.put(b"k2", b"old").unwrap();
+    kvs.flush().unwrap();
+    kvs.put(b"k2", b"new").unwrap();
+    kvs.put(b"k3", b"new").unwrap();
+
+    let results: Vec<(Vec<u8>, Vec<u8>, Source)> = kvs
+        .iter_with_source()
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            (
+                b"k1".to_vec(),
+                b"old".to_vec(),
+                Source::Sstable { id: 0, level: 0 }
+            ),
+            (b"k2".to_vec(), b"new".to_vec(), Source::Memtable),
+            (b"k3".to_vec(), b"new".to_vec(), Source::Memtable),
+        ]
+    );
+
+    // a range scan also reports the sources
+    let sources: Vec<Source> = kvs
+        .scan(b"k1", b"k2")
+        .unwrap()
+        .with_source()
+        .map(|r| r.unwrap().2)
+        .collect();
+    assert_eq!(sources, vec![Source::Sstable { id: 0, level: 0 }]);
+}
+
+#[test]
+fn test_read_while_switching_fptrees() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: u32 = 500;
+    const NUM_FLUSHES: usize = 10;
+    const NUM_READERS: usize = 4;
+    const TABLE_NAME: &str = "switch_fptree_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .memtable_bytes(1 << 30)
+        .build();
+    let kvs = Arc::new(KVS::new(TABLE_NAME, config).unwrap());
+    let value = |i: u32| format!("value{}", i).into_bytes();
+    for i in 0..NUM_KEYS {
+        kvs.put(&i.to_be_bytes(), &value(i)).unwrap();
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..NUM_READERS)
+        .map(|r| {
+            let kvs = kvs.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut num_reads = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    for i in (r as u32..NUM_KEYS).step_by(7) {
+                        assert_eq!(kvs.get(&i.to_be_bytes()).unwrap(), Some(value(i)));
+                    }
+                    let keys: Vec<Vec<u8>> = (0..10u32).map(|i| i.to_be_bytes().to_vec()).collect();
+                    let values = kvs.get_many(&keys).unwrap();
+                    assert!(values
+                        .iter()
+                        .enumerate()
+                        .all(|(i, v)| *v == Some(value(i as u32))));
+                    num_reads += 1;
+                }
+                num_reads
+            })
+        })
+        .collect();
+
+    // each flush switches the FPTree while the readers read it
+    for _ in 0..NUM_FLUSHES {
+        for i in (0..NUM_KEYS).step_by(10) {
+            kvs.put(&i.to_be_bytes(), &value(i)).unwrap();
+        }
+        kvs.flush().unwrap();
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    // the leaf files of the switched FPTrees have been removed after the reads
+    let has_obsolete_file = std::fs::read_dir(dir.path().join(TABLE_NAME))
+        .unwrap()
+        .any(|entry| {
+            entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with("obsolete-leaves-")
+        });
+    assert!(!has_obsolete_file);
+}
+
+/// `FsStorage` which fails writes to SSTable files while it's armed
+struct FaultyStorage {
+    armed: Arc<AtomicBool>,
+}
+
+struct FaultyFile {
+    file: Box<dyn StorageFile>,
+    armed: Arc<AtomicBool>,
+}
+
+impl Storage for FaultyStorage {
+    fn open(&self, path: &str) -> Result<Box<dyn StorageFile>, std::io::Error> {
+        FsStorage.open(path)
+    }
+
+    fn create(&self, path: &str) -> Result<Box<dyn StorageFile>, std::io::Error> {
+        let file = FsStorage.create(path)?;
+        let file_name = std::path::Path::new(path).file_name().unwrap();
+        if file_name.to_string_lossy().starts_with("sstable-") {
+            Ok(Box::new(FaultyFile {
+                file,
+                armed: self.armed.clone(),
+            }))
+        } else {
+            Ok(file)
+        }
+    }
+
+    fn open_or_create(&self, path: &str) -> Result<(Box<dyn StorageFile>, bool), std::io::Error> {
+        FsStorage.open_or_create(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        FsStorage.rename(from, to)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), std::io::Error> {
+        FsStorage.remove(path)
+    }
+
+    fn sync_dir(&self, path: &str) -> Result<(), std::io::Error> {
+        FsStorage.sync_dir(path)
+    }
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.armed.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("injected write failure"));
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn sync(&self) -> Result<(), std::io::Error> {
+        self.file.sync()
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.file.size()
+    }
+}
+
+#[test]
+fn test_write_failure_while_flushing() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: u32 = 3000;
+    const TABLE_NAME: &str = "faulty_storage_test";
+    let dir = tempfile::tempdir().unwrap();
+    let armed = Arc::new(AtomicBool::new(false));
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .storage(Arc::new(FaultyStorage {
+            armed: armed.clone(),
+        }))
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config.clone()).unwrap();
+    for i in 0..NUM_INSERTION {
+        kvs.put(&i.to_be_bytes(), format!("value{}", i).as_bytes())
+            .unwrap();
+    }
+
+    // the failed flush doesn't lose any pair
+    armed.store(true, Ordering::SeqCst);
+    assert!(kvs.flush().is_err());
+    assert_eq!(kvs.stats().flush_count, 0);
+    for i in (0..NUM_INSERTION).step_by(7) {
+        assert_eq!(
+            kvs.get(&i.to_be_bytes()).unwrap(),
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    // the partial table is removed on restart
+    drop(kvs);
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    let table_dir = dir.path().join(TABLE_NAME);
+    let has_tmp_file = std::fs::read_dir(&table_dir)
+        .unwrap()
+        .any(|entry| entry.unwrap().path().extension().unwrap_or_default() == "tmp");
+    assert!(!has_tmp_file);
+    for i in 0..NUM_INSERTION {
+        assert_eq!(
+            kvs.get(&i.to_be_bytes()).unwrap(),
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    kvs.flush().unwrap();
+    assert!(kvs.stats().flush_count > 0);
+    assert_eq!(
+        kvs.get(&(NUM_INSERTION - 1).to_be_bytes()).unwrap(),
+        Some(format!("value{}", NUM_INSERTION - 1).into_bytes())
+    );
+}
+
+#[test]
+fn test_get_at() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new("get_at_test", config.clone()).unwrap();
+    assert_eq!(kvs.sequence(), 0);
+    kvs.put(b"k1", b"v1").unwrap();
+    let s1 = kvs.sequence();
+    kvs.put(b"k2", b"x").unwrap();
+    let s2 = kvs.sequence();
+    assert!(s1 < s2);
+    kvs.flush().unwrap();
+
+    // the older version is in the SSTable
+    kvs.put(b"k1", b"v2").unwrap();
+    let s3 = kvs.sequence();
+    assert_eq!(kvs.get_at(b"k1", s1).unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(kvs.get_at(b"k1", s3).unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(kvs.get_at(b"k1", 0).unwrap(), None);
+    // the version at s3 is overwritten in the same FPTree
+    kvs.put(b"k1", b"v3").unwrap();
+    assert!(matches!(
+        kvs.get_at(b"k1", s3),
+        Err(CrudError::VersionUnavailable(s)) if s == s3
+    ));
+    assert_eq!(kvs.get_at(b"k1", s1).unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(
+        kvs.get_at(b"k1", kvs.sequence()).unwrap(),
+        Some(b"v3".to_vec())
+    );
+
+    // a batch is applied with a single sequence
+    let mut batch = WriteBatch::new();
+    batch.delete(b"k2");
+    batch.put(b"k3", b"y");
+    kvs.write(batch).unwrap();
+    let s4 = kvs.sequence();
+    assert_eq!(kvs.get_at(b"k2", s4).unwrap(), None);
+    assert_eq!(kvs.get_at(b"k2", s4 - 1).unwrap(), Some(b"x".to_vec()));
+    assert_eq!(kvs.get_at(b"k3", s4).unwrap(), Some(b"y".to_vec()));
+    assert_eq!(kvs.get_at(b"k3", s4 - 1).unwrap(), None);
+    drop(kvs);
+
+    // sequences continue after reopening
+    let kvs = KVS::open("get_at_test", config).unwrap();
+    assert_eq!(kvs.sequence(), s4);
+    assert_eq!(kvs.get_at(b"k1", s1).unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(kvs.get_at(b"k2", s2).unwrap(), Some(b"x".to_vec()));
+    kvs.put(b"k4", b"z").unwrap();
+    assert_eq!(kvs.sequence(), s4 + 1);
+
+    // the compaction merges the versions and drops the tombstone
+    kvs.compact().unwrap();
+    assert_eq!(kvs.get(b"k1").unwrap(), Some(b"v3".to_vec()));
+    assert!(matches!(
+        kvs.get_at(b"k1", s1),
+        Err(CrudError::VersionUnavailable(_))
+    ));
+    assert!(matches!(
+        kvs.get_at(b"k2", s2),
+        Err(CrudError::VersionUnavailable(_))
+    ));
+    assert_eq!(kvs.get_at(b"k2", s4).unwrap(), None);
+    assert_eq!(kvs.get_at(b"k3", s4).unwrap(), Some(b"y".to_vec()));
+}
+
+#[test]
+fn test_space_amplification() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_FLUSH: usize = 10;
+    const TABLE_NAME: &str = "space_amplification_test";
+    let dir = tempfile::tempdir().unwrap();
+    let builder = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .l0_compaction_trigger(100);
+    let kvs = KVS::new(TABLE_NAME, builder.clone().build()).unwrap();
+    assert_eq!(kvs.space_amplification(), 1.0);
+
+    // each table has only the overwritten key
+    let mut amplifications = Vec::new();
+    for i in 0..NUM_FLUSH {
+        kvs.put(b"key", format!("value{}", i).as_bytes()).unwrap();
+        kvs.flush().unwrap();
+        amplifications.push(kvs.space_amplification());
+    }
+    assert_eq!(amplifications[0], 1.0);
+    assert!(amplifications.windows(2).all(|w| w[0] < w[1]));
+    assert!(amplifications[NUM_FLUSH - 1] > (NUM_FLUSH / 2) as f64);
+
+    kvs.compact().unwrap();
+    assert_eq!(kvs.space_amplification(), 1.0);
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value9".to_vec()));
+    drop(kvs);
+
+    // the amplification triggers the compaction of all tables
+    let kvs = KVS::new(TABLE_NAME, builder.max_space_amplification(4.0).build()).unwrap();
+    for i in 0..NUM_FLUSH {
+        kvs.put(b"key", format!("value{}", i).as_bytes()).unwrap();
+        kvs.flush().unwrap();
+    }
+    let start = std::time::Instant::now();
+    while kvs.space_amplification() > 4.0 {
+        assert!(start.elapsed() < Duration::from_secs(10), "not compacted");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let num_tables: usize = kvs.level_summary().iter().map(|(_, n, _)| n).sum();
+    assert!(num_tables < NUM_FLUSH);
+    assert_eq!(kvs.get(b"key").unwrap(), Some(b"value9".to_vec()));
+}
+
+/// Drop keys with the prefix and record the levels of the decisions
+struct PrefixFilter {
+    prefix: &'static [u8],
+    levels: Mutex<Vec<usize>>,
+}
+
+impl CompactionFilter for PrefixFilter {
+    fn decide(&self, key: &[u8], _value: &[u8], level: usize) -> FilterDecision {
+        self.levels.lock().unwrap().push(level);
+        if key.starts_with(self.prefix) {
+            FilterDecision::Drop
+        } else {
+            FilterDecision::Keep
+        }
+    }
+}
+
+#[test]
+fn test_compaction_filter() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 100;
+    let dir = tempfile::tempdir().unwrap();
+    let filter = Arc::new(PrefixFilter {
+        prefix: b"tmp/",
+        levels: Mutex::new(Vec::new()),
+    });
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .l0_compaction_trigger(100)
+        .compaction_filter(filter.clone())
+        .build();
+    let kvs = KVS::new("compaction_filter_test", config).unwrap();
+    for i in 0..NUM_KEYS {
+        kvs.put(format!("tmp/{:03}", i).as_bytes(), b"value")
+            .unwrap();
+        kvs.put(format!("user/{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    kvs.flush().unwrap();
+    // a tombstone isn't passed to the filter
+    kvs.delete(b"user/000").unwrap();
+    kvs.flush().unwrap();
+    // the filter isn't applied until the compaction
+    assert_eq!(kvs.get(b"tmp/000").unwrap(), Some(b"value".to_vec()));
+    assert!(filter.levels.lock().unwrap().is_empty());
+
+    kvs.compact().unwrap();
+    assert_eq!(kvs.get(b"tmp/000").unwrap(), None);
+    assert_eq!(kvs.scan_prefix(b"tmp/").unwrap().count(), 0);
+    assert_eq!(kvs.scan_prefix(b"user/").unwrap().count(), NUM_KEYS - 1);
+    let levels = filter.levels.lock().unwrap().clone();
+    assert_eq!(levels.len(), NUM_KEYS * 2 - 1);
+    assert!(levels.iter().all(|level| *level == 1));
+}
+
+#[test]
+fn test_compaction_filter_with_read_cache() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let dir = tempfile::tempdir().unwrap();
+    let filter = Arc::new(PrefixFilter {
+        prefix: b"tmp/",
+        levels: Mutex::new(Vec::new()),
+    });
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .read_cache_bytes(1024)
+        .l0_compaction_trigger(2)
+        .compaction_filter(filter)
+        .build();
+    let kvs = KVS::new("compaction_filter_with_read_cache_test", config).unwrap();
+
+    // a background compaction drops the cached pair
+    kvs.put(b"tmp/000", b"value").unwrap();
+    kvs.flush().unwrap();
+    assert_eq!(kvs.get(b"tmp/000").unwrap(), Some(b"value".to_vec()));
+    kvs.put(b"user/000", b"value").unwrap();
+    kvs.flush().unwrap();
+    let start = std::time::Instant::now();
+    while kvs.level_summary()[0].1 > 0 {
+        assert!(start.elapsed() < Duration::from_secs(10), "not compacted");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(kvs.get(b"tmp/000").unwrap(), None);
+
+    // a manual compaction drops the cached pair
+    kvs.put(b"tmp/001", b"value").unwrap();
+    kvs.flush().unwrap();
+    assert_eq!(kvs.get(b"tmp/001").unwrap(), Some(b"value".to_vec()));
+    kvs.compact().unwrap();
+    assert_eq!(kvs.get(b"tmp/001").unwrap(), None);
+    assert_eq!(kvs.get(b"user/000").unwrap(), Some(b"value".to_vec()));
+}
+
+/// Hold each flush after its table is written until it's released
+#[derive(Default)]
+struct FlushHoldingListener {
+    holding: AtomicBool,
+    released: AtomicBool,
+}
+
+impl Listener for FlushHoldingListener {
+    fn on_flush(&self, _table_id: usize, _entry_count: usize, _bytes: usize) {
+        self.holding.store(true, Ordering::Release);
+        while !self.released.load(Ordering::Acquire) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+#[test]
+fn test_read_during_flush() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_KEYS: usize = 2000;
+    const NUM_READERS: usize = 4;
+    let dir = tempfile::tempdir().unwrap();
+    let listener = Arc::new(FlushHoldingListener::default());
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .listener(listener.clone())
+        .build();
+    let kvs = KVS::new("read_during_flush_test", config).unwrap();
+    for i in 0..NUM_KEYS {
+        kvs.put(format!("k{:04}", i).as_bytes(), b"old").unwrap();
+    }
+
+    std::thread::scope(|s| {
+        // the flushed FPTree isn't switched until the listener returns
+        let flusher = s.spawn(|| kvs.flush());
+        while !listener.holding.load(Ordering::Acquire) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        kvs.put(b"new", b"value").unwrap();
+
+        // readers of both FPTrees aren't blocked by the flush
+        let readers: Vec<_> = (0..NUM_READERS)
+            .map(|_| {
+                s.spawn(|| {
+                    for i in 0..NUM_KEYS {
+                        let key = format!("k{:04}", i);
+                        assert_eq!(kvs.get(key.as_bytes()).unwrap(), Some(b"old".to_vec()));
+                        assert_eq!(kvs.get(b"new").unwrap(), Some(b"value".to_vec()));
+                    }
+                })
+            })
+            .collect();
+        let start = std::time::Instant::now();
+        while !readers.iter().all(|r| r.is_finished()) && start.elapsed() < Duration::from_secs(10)
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let stalled = readers.iter().filter(|r| !r.is_finished()).count();
+        assert!(!flusher.is_finished());
+
+        listener.released.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        flusher.join().unwrap().unwrap();
+        assert_eq!(stalled, 0, "reads stalled during the flush");
+    });
+
+    assert_eq!(kvs.get(b"k0000").unwrap(), Some(b"old".to_vec()));
+    assert_eq!(kvs.stats().flush_count, 1);
+}
+
+#[test]
+fn test_tombstone_in_newer_table() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let dir = tempfile::tempdir().unwrap();
+    for parallel_lookup in [false, true] {
+        let name = format!("tombstone_in_newer_table_test_{}", parallel_lookup);
+        let config = Config::builder()
+            .leaf_dir(dir.path().to_str().unwrap())
+            .table_dir(dir.path().to_str().unwrap())
+            .l0_compaction_trigger(100)
+            .parallel_lookup(parallel_lookup)
+            .build();
+        let kvs = KVS::new(&name, config.clone()).unwrap();
+
+        // the value and the tombstone in Level 0 tables
+        kvs.put(b"key", b"value").unwrap();
+        kvs.flush().unwrap();
+        kvs.delete(b"key").unwrap();
+        kvs.flush().unwrap();
+        assert_eq!(kvs.level_summary()[0].1, 2);
+        assert_eq!(kvs.get(b"key").unwrap(), None);
+
+        // the value in Level 1 and the tombstone in Level 0
+        kvs.put(b"key2", b"value").unwrap();
+        kvs.flush().unwrap();
+        kvs.compact().unwrap();
+        kvs.delete(b"key2").unwrap();
+        kvs.flush().unwrap();
+        assert_eq!(kvs.get(b"key2").unwrap(), None);
+        assert_eq!(
+            kvs.get_many(&[b"key".to_vec(), b"key2".to_vec()]).unwrap(),
+            vec![None, None]
+        );
+        assert_eq!(kvs.iter().unwrap().count(), 0);
+
+        drop(kvs);
+        let kvs = KVS::new(&name, config).unwrap();
+        assert_eq!(kvs.get(b"key").unwrap(), None);
+        assert_eq!(kvs.get(b"key2").unwrap(), None);
+    }
+}
+
+#[test]
+fn test_may_contain() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const NUM_INSERTION: usize = 3000;
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .l0_compaction_trigger(100)
+        .build();
+    let kvs = KVS::new("may_contain_test", config).unwrap();
+    // keys in Level 1, Level 0 and the FPTree
+    for i in 0..NUM_INSERTION {
+        kvs.put(format!("k{:05}", i).as_bytes(), b"value").unwrap();
+        if i == NUM_INSERTION / 3 {
+            kvs.flush().unwrap();
+            kvs.compact().unwrap();
+        } else if i == NUM_INSERTION * 2 / 3 {
+            kvs.flush().unwrap();
+        }
+    }
+    assert_eq!(kvs.level_summary()[0].1, 1);
+    for i in 0..NUM_INSERTION {
+        assert!(kvs.may_contain(format!("k{:05}", i).as_bytes()));
+    }
+
+    // deleted in the FPTree and in a newer table
+    kvs.delete(b"k00000").unwrap();
+    assert!(!kvs.may_contain(b"k00000"));
+    kvs.delete_range(b"k00010", b"k00020").unwrap();
+    kvs.flush().unwrap();
+    assert!(!kvs.may_contain(b"k00015"));
+    assert!(kvs.may_contain(b"k00020"));
+
+    // no SSTable file is read
+    for entry in std::fs::read_dir(dir.path().join("may_contain_test")).unwrap() {
+        let path = entry.unwrap().path();
+        if path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("sstable-")
+        {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    assert!(kvs.get(b"k00001").is_err());
+    for i in 20..NUM_INSERTION {
+        assert!(kvs.may_contain(format!("k{:05}", i).as_bytes()));
+    }
+    // false positives of the filters are rare
+    let false_positives = (0..NUM_INSERTION)
+        .filter(|i| kvs.may_contain(format!("k{:05}-missing", i).as_bytes()))
+        .count();
+    assert!(false_positives < NUM_INSERTION / 10);
+}
@@ -0,0 +1,102 @@
+//! Round trips through the TCP server
+//! Run with `cargo test --features server`
+use amphis::amphis_error::CrudError;
+use amphis::config::Config;
+use amphis::kvs::KVS;
+use amphis::server::{Client, Server, OP_SCAN, STATUS_OK};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+fn start_server(dir: &std::path::Path) -> (Arc<KVS>, SocketAddr) {
+    let config = Config::builder()
+        .leaf_dir(dir.to_str().unwrap())
+        .table_dir(dir.to_str().unwrap())
+        .build();
+    let kvs = Arc::new(KVS::new("server_test", config).unwrap());
+    // the ephemeral port
+    let server = Server::bind("127.0.0.1:0", kvs.clone(), 4).unwrap();
+    let addr = server.local_addr().unwrap();
+    std::thread::spawn(move || server.serve());
+
+    (kvs, addr)
+}
+
+#[test]
+fn test_round_trip() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let dir = tempfile::tempdir().unwrap();
+    let (kvs, addr) = start_server(dir.path());
+
+    let mut client = Client::connect(addr).unwrap();
+    for i in 0..10 {
+        let key = format!("k{}", i);
+        client
+            .put(key.as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+    }
+    assert_eq!(client.get(b"k3").unwrap(), Some(b"v3".to_vec()));
+    assert_eq!(client.get(b"x").unwrap(), None);
+    // an empty value is different from a missing key
+    client.put(b"empty", b"").unwrap();
+    assert_eq!(client.get(b"empty").unwrap(), Some(Vec::new()));
+
+    client.delete(b"k5").unwrap();
+    assert_eq!(client.get(b"k5").unwrap(), None);
+    assert_eq!(kvs.get(b"k5").unwrap(), None);
+    assert_eq!(kvs.get(b"k6").unwrap(), Some(b"v6".to_vec()));
+
+    let pairs = client.scan(b"k2", b"k8").unwrap();
+    let keys: Vec<&[u8]> = pairs.iter().map(|(k, _)| k.as_slice()).collect();
+    assert_eq!(keys, vec![&b"k2"[..], b"k3", b"k4", b"k6", b"k7"]);
+    assert_eq!(pairs[0].1, b"v2".to_vec());
+    assert!(client.scan(b"y", b"z").unwrap().is_empty());
+
+    // the error is returned and the connection is still usable
+    assert!(matches!(
+        client.put(b"", b"value"),
+        Err(CrudError::Remote(_))
+    ));
+    assert_eq!(client.get(b"k0").unwrap(), Some(b"v0".to_vec()));
+
+    // clients are served concurrently
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            std::thread::spawn(move || {
+                let mut client = Client::connect(addr).unwrap();
+                for i in 0..100 {
+                    let key = format!("t{}-{:03}", t, i);
+                    client.put(key.as_bytes(), b"value").unwrap();
+                }
+                client.scan(format!("t{}", t).as_bytes(), format!("t{}.", t).as_bytes())
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap().unwrap().len(), 100);
+    }
+}
+
+#[test]
+fn test_scan_truncated_key() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // the whole request not to reset the connection by closing it with unread bytes
+        let mut request = [0u8; 11];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(request[0], OP_SCAN);
+        // the key is shorter than its length and the connection is closed
+        stream.write_all(&[STATUS_OK]).unwrap();
+        stream.write_all(&10u32.to_le_bytes()).unwrap();
+        stream.write_all(b"abc").unwrap();
+    });
+
+    let mut client = Client::connect(addr).unwrap();
+    assert!(matches!(
+        client.scan(b"a", b"z"),
+        Err(CrudError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+    ));
+    handle.join().unwrap();
+}
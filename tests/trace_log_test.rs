@@ -0,0 +1,27 @@
+extern crate amphis;
+use amphis::config::Config;
+use amphis::kvs::KVS;
+
+// the logger is global, so this test is in its own binary to enable trace logging
+#[test]
+fn test_trace_non_utf8_key() {
+    env_logger::builder()
+        .is_test(true)
+        .filter_level(log::LevelFilter::Trace)
+        .init();
+    const TABLE_NAME: &str = "trace_log_test";
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::builder()
+        .leaf_dir(dir.path().to_str().unwrap())
+        .table_dir(dir.path().to_str().unwrap())
+        .build();
+    let kvs = KVS::new(TABLE_NAME, config).unwrap();
+    assert!(log::log_enabled!(log::Level::Trace));
+
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0xc0, 0xaf];
+    kvs.put(&key, &value).unwrap();
+    assert_eq!(kvs.get(&key).unwrap(), Some(value));
+    kvs.delete(&key).unwrap();
+    assert_eq!(kvs.get(&key).unwrap(), None);
+}